@@ -26,12 +26,21 @@ mod tests;
 mod worker;
 
 use self::{parser::Parsed, worker::worker};
-use anyhow::{Context, Result};
+use anyhow::{bail, Context, Result};
 use bytes::BytesMut;
-use droute::{builders::RouterBuilder, error::DrouteError, AsyncTryInto, Router};
+use domain::base::Dname;
+use droute::{
+    builders::RouterBuilder,
+    error::DrouteError,
+    preflight::{PreflightOptions, Status},
+    AsyncTryInto, Router, WarmUp,
+};
 use log::*;
 use simple_logger::SimpleLogger;
-use std::{net::SocketAddr, path::PathBuf, result::Result as StdResult, sync::Arc, time::Duration};
+use std::{
+    net::SocketAddr, path::PathBuf, result::Result as StdResult, str::FromStr, sync::Arc,
+    time::Duration,
+};
 use structopt::StructOpt;
 use tokio::{
     fs::File,
@@ -55,15 +64,37 @@ struct DcompassOpts {
     /// Set this flag to validate the configuration file only.
     #[structopt(short, long, parse(from_flag))]
     validate: bool,
+
+    /// Set this flag to check the resources of every rule and probe every upstream, printing a report.
+    #[structopt(short, long, parse(from_flag))]
+    preflight: bool,
+
+    /// Fail the preflight checks if any upstream is unreachable.
+    #[structopt(long, parse(from_flag))]
+    strict: bool,
 }
 
-async fn init(p: Parsed) -> StdResult<(Router, SocketAddr, LevelFilter), DrouteError> {
+async fn init(
+    p: Parsed,
+) -> StdResult<(Router, SocketAddr, LevelFilter, Option<WarmUp>), DrouteError> {
+    let mut builder = RouterBuilder::new(p.table, p.upstreams)
+        .timeout(Duration::from_secs(p.timeout))
+        .max_steps(p.max_steps)
+        .on_unanswered(p.on_unanswered)
+        // Clients are better off trying other servers than waiting for a timeout.
+        .servfail_on_shutdown(true);
+    if let Some(max) = p.max_concurrent_queries {
+        builder =
+            builder.max_concurrent_queries(max, p.queue_timeout_ms.map(Duration::from_millis));
+    }
     Ok((
-        RouterBuilder::new(p.table, p.upstreams)
-            .async_try_into()
-            .await?,
+        builder.async_try_into().await?,
         p.address,
         p.verbosity,
+        match p.warm_up {
+            Some(w) => Some(w.async_try_into().await?),
+            None => None,
+        },
     ))
 }
 
@@ -152,12 +183,25 @@ async fn main() -> Result<()> {
         }
     };
 
+    let parsed: Parsed = serde_yaml::from_str(&config)
+        .with_context(|| "Failed to parse the configuration file".to_string())?;
+
+    if args.preflight {
+        let options = PreflightOptions::new()
+            .with_probe(Dname::from_str("example.com")?, Duration::from_secs(3))
+            .with_strict(args.strict);
+        let report = RouterBuilder::new(parsed.table, parsed.upstreams)
+            .preflight(&options)
+            .await;
+        print!("{}", serde_yaml::to_string(&report)?);
+        if report.status == Status::Fail {
+            bail!("The preflight checks failed.");
+        }
+        return Ok(());
+    }
+
     // Create whatever we need for get dcompass up and running.
-    let (router, addr, verbosity) = init(
-        serde_yaml::from_str(&config)
-            .with_context(|| "Failed to parse the configuration file".to_string())?,
-    )
-    .await?;
+    let (router, addr, verbosity, warm_up) = init(parsed).await?;
 
     // If we are only required to validate the config, we shall be safe to exit now.
     if args.validate {
@@ -174,6 +218,12 @@ async fn main() -> Result<()> {
     info!("dcompass ready!");
 
     let router = Arc::new(router);
+
+    // Warm up the cache in the background, we don't wait for it.
+    if let Some(warm_up) = warm_up {
+        info!("warming up the cache with {} queries", warm_up.len());
+        router.clone().warm_up(warm_up);
+    }
     // Bind an UDP socket
     let socket = Arc::new(
         UdpSocket::bind(addr)
@@ -187,9 +237,11 @@ async fn main() -> Result<()> {
     // We don't have to worry about incoming requests when shutting down, because when we initiate shutdown, the loop was already terminated
     #[rustfmt::skip]
     tokio::select! {
-        _ = serve(socket, router, &tx) => (),
+        _ = serve(socket, router.clone(), &tx) => (),
         _ = signal::ctrl_c() => {
             log::warn!("Ctrl-C received, shutting down");
+	    // Let queries being resolved finish before stopping workers.
+	    router.shutdown(Duration::from_secs(5)).await;
 	    sleep(Duration::from_millis(500)).await;
             // Error implies that there is no receiver/active worker, we are done
             if tx.send(()).is_ok() {
@@ -14,10 +14,10 @@
 // along with this program.  If not, see <http://www.gnu.org/licenses/>.
 
 use async_trait::async_trait;
-use droute::{builders::*, matchers::*, AsyncTryInto};
+use droute::{builders::*, matchers::*, AsyncTryInto, Unanswered};
 use log::LevelFilter;
 use serde::Deserialize;
-use std::{collections::HashSet, net::SocketAddr, path::PathBuf};
+use std::{collections::HashSet, net::SocketAddr, num::NonZeroUsize, path::PathBuf};
 
 #[derive(Deserialize, Clone)]
 #[serde(rename_all = "lowercase")]
@@ -41,10 +41,17 @@ pub enum MatcherBuilders {
     /// Matches query types provided. Query types are like AAAA, A, TXT.
     QType(QTypeBuilder),
 
-    /// Matches if IP address in the record of the first response is in the list of countries.
+    /// Matches if IP address in the record of the first response, or the one the query is sent from, is in the list of countries, continents, or autonomous systems.
     GeoIp {
+        #[serde(default)]
+        mode: GeoIpMode,
+        #[serde(default)]
         codes: HashSet<String>,
         #[serde(default)]
+        asns: HashSet<u32>,
+        #[serde(default)]
+        invert: bool,
+        #[serde(default)]
         path: Option<PathBuf>,
     },
 
@@ -68,16 +75,33 @@ impl AsyncTryInto<Box<dyn Matcher>> for MatcherBuilders {
         Ok(match self {
             Self::Domain(v) => Box::new(v.async_try_into().await?),
             Self::QType(q) => Box::new(q.async_try_into().await?),
-            Self::Header { cond, query } => Box::new(Header { cond, query }),
+            Self::Header { cond, query } => Box::new(Header {
+                cond,
+                side: if query {
+                    HeaderSide::Query
+                } else {
+                    HeaderSide::Resp
+                },
+            }),
             Self::IpCidr(s) => Box::new(s.async_try_into().await?),
-            Self::GeoIp { path, codes } => Box::new(GeoIp::new(
+            Self::GeoIp {
+                mode,
+                path,
                 codes,
-                if let Some(p) = path {
+                asns,
+                invert,
+            } => {
+                let builder = GeoIpBuilder::from_buf(if let Some(p) = path {
                     tokio::fs::read(p).await?
                 } else {
                     get_builtin_db()?
-                },
-            )?),
+                })
+                .mode(mode)
+                .invert(invert);
+                let builder = codes.into_iter().fold(builder, |b, c| b.add_code(c));
+                let builder = asns.into_iter().fold(builder, |b, a| b.add_asn(a));
+                Box::new(builder.async_try_into().await?)
+            }
         })
     }
 
@@ -104,4 +128,29 @@ pub struct Parsed {
     pub address: SocketAddr,
     #[serde(with = "LevelFilterDef")]
     pub verbosity: LevelFilter,
+    #[serde(default)]
+    pub warm_up: Option<WarmUpBuilder>,
+    // Deadline in seconds for a query to be routed through the table.
+    #[serde(default = "default_timeout")]
+    pub timeout: u64,
+    // Maximum number of rules a query may go through.
+    #[serde(default = "default_max_steps")]
+    pub max_steps: usize,
+    // What to answer if routing reaches `end` without any response.
+    #[serde(default)]
+    pub on_unanswered: Unanswered,
+    // Maximum number of queries resolved at the same time.
+    #[serde(default)]
+    pub max_concurrent_queries: Option<NonZeroUsize>,
+    // Time in milliseconds queries beyond the limit wait before failing. They fail right away if not set.
+    #[serde(default)]
+    pub queue_timeout_ms: Option<u64>,
+}
+
+fn default_timeout() -> u64 {
+    5
+}
+
+fn default_max_steps() -> usize {
+    64
 }
@@ -25,93 +25,84 @@ async fn check_default() {
 
 #[tokio::test]
 async fn check_success_ipcidr() {
-    assert_eq!(
+    assert!(
         init(serde_yaml::from_str(include_str!("../../configs/success_cidr.yaml")).unwrap())
             .await
-            .is_ok(),
-        true
+            .is_ok()
     );
 }
 
 #[tokio::test]
 async fn check_success_logic_jumble() {
-    assert_eq!(
+    assert!(
         init(serde_yaml::from_str(include_str!("../../configs/logic_jumble.yaml")).unwrap())
             .await
-            .is_ok(),
-        true
+            .is_ok()
     );
 }
 
 #[cfg(all(feature = "geoip-maxmind", not(feature = "geoip-cn")))]
 #[tokio::test]
 async fn check_example_maxmind() {
-    assert_eq!(
+    assert!(
         init(serde_yaml::from_str(include_str!("../../configs/example.yaml")).unwrap())
             .await
-            .is_ok(),
-        true
+            .is_ok()
     );
 }
 
 #[cfg(all(feature = "geoip-cn", not(feature = "geoip-maxmind")))]
 #[tokio::test]
 async fn check_example_cn() {
-    assert_eq!(
+    assert!(
         init(serde_yaml::from_str(include_str!("../../configs/example.yaml")).unwrap())
             .await
-            .is_ok(),
-        true
+            .is_ok()
     );
 }
 
 #[tokio::test]
 async fn check_success_rule() {
-    assert_eq!(
+    assert!(
         init(serde_yaml::from_str(include_str!("../../configs/success_rule.json")).unwrap())
             .await
-            .is_ok(),
-        true
+            .is_ok()
     );
 }
 
 #[tokio::test]
 async fn check_success_query_cache_mode() {
-    assert_eq!(
-        init(serde_yaml::from_str(include_str!("../../configs/query_cache_policy.yaml")).unwrap())
-            .await
-            .is_ok(),
-        true
-    );
+    assert!(init(
+        serde_yaml::from_str(include_str!("../../configs/query_cache_policy.yaml")).unwrap()
+    )
+    .await
+    .is_ok());
 }
 
 #[tokio::test]
 async fn check_success_geoip() {
-    assert_eq!(
+    assert!(
         init(serde_yaml::from_str(include_str!("../../configs/success_geoip.yaml")).unwrap())
             .await
-            .is_ok(),
-        true
+            .is_ok()
     );
 }
 
 #[tokio::test]
 async fn check_success_rule_yaml() {
-    assert_eq!(
-        init(serde_yaml::from_str(include_str!("../../configs/success_rule_yaml.yaml")).unwrap())
-            .await
-            .is_ok(),
-        true
-    );
+    assert!(init(
+        serde_yaml::from_str(include_str!("../../configs/success_rule_yaml.yaml")).unwrap()
+    )
+    .await
+    .is_ok());
 }
 
 #[tokio::test]
 async fn check_success_header_yaml() {
-    assert_eq!(
+    assert!(
         init(serde_yaml::from_str(include_str!("../../configs/success_header.yaml")).unwrap())
             .await
-            .is_ok(),
-        true
+            .is_ok()
     );
 }
 
@@ -123,7 +114,11 @@ async fn check_fail_undef() {
             .err()
             .unwrap()
         {
-            DrouteError::UpstreamError(UpstreamError::MissingTag(tag)) => tag,
+            // The upstreams defined are not used by the table either, which is reported as well.
+            DrouteError::Multiple(v) => match &v[0] {
+                DrouteError::UpstreamError(UpstreamError::MissingTag(tag)) => tag.clone(),
+                e => panic!("Not the right error type: {}", e),
+            },
             e => panic!("Not the right error type: {}", e),
         },
         compact_str::CompactStr::new("undefined")
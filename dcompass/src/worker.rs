@@ -16,7 +16,7 @@
 use anyhow::Result;
 use bytes::Bytes;
 use domain::base::Message;
-use droute::{QueryContext, Router};
+use droute::{Protocol, QueryContext, Router};
 use log::*;
 use std::{net::SocketAddr, sync::Arc};
 use tokio::net::UdpSocket;
@@ -28,13 +28,12 @@ pub async fn worker(
     buf: Bytes,
     src: SocketAddr,
 ) -> Result<()> {
+    let mut qctx = QueryContext::new(src, Protocol::Udp);
+    qctx.local_addr = socket.local_addr().ok();
     socket
         .send_to(
             router
-                .resolve(
-                    Message::from_octets(buf)?,
-                    Some(QueryContext { ip: src.ip() }),
-                )
+                .resolve_with_ctx(Message::from_octets(buf)?, Some(qctx))
                 .await?
                 .as_slice(),
             src,
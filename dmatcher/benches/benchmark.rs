@@ -14,10 +14,19 @@
 // along with this program.  If not, see <http://www.gnu.org/licenses/>.
 
 use bytes::Bytes;
+use cidr_utils::{
+    cidr::{IpCidr, Ipv4Cidr, Ipv6Cidr},
+    utils::IpCidrCombiner,
+};
 use criterion::{criterion_group, criterion_main, Criterion};
-use dmatcher::domain::Domain;
+use dmatcher::{domain::Domain, ip::IpCidrs};
 use domain::base::Dname;
-use std::{fs::File, io::Read, str::FromStr};
+use std::{
+    fs::File,
+    io::Read,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    str::FromStr,
+};
 
 fn bench_match(c: &mut Criterion) {
     let mut file = File::open("./benches/sample.txt").unwrap();
@@ -31,11 +40,58 @@ fn bench_match(c: &mut Criterion) {
         .collect();
 
     let test = Dname::from_str("store.www.baidu.com").unwrap();
+    c.bench_function("build", |b| b.iter(|| Domain::new().insert_multi(&domains)));
+    c.bench_function("build_bulk", |b| {
+        b.iter(|| Domain::new().insert_iter(&domains))
+    });
+
     matcher.insert_multi(&domains);
-    c.bench_function("match", |b| {
-        b.iter(|| assert_eq!(matcher.matches(&test), true))
+    c.bench_function("match", |b| b.iter(|| assert!(matcher.matches(&test))));
+
+    let frozen = matcher.freeze();
+    c.bench_function("match_frozen", |b| {
+        b.iter(|| assert!(frozen.matches(&test)))
+    });
+}
+
+// 20000 IPv4 and IPv6 CIDRs, about the size of a list of Chinese and cloud ranges, against a linear scan.
+fn bench_ip(c: &mut Criterion) {
+    let mut seed = 0x2545_f491_4f6c_dd1d_u64;
+    let mut next = move || {
+        seed ^= seed << 13;
+        seed ^= seed >> 7;
+        seed ^= seed << 17;
+        seed
+    };
+    let mut cidrs = IpCidrs::new();
+    let mut combiner = IpCidrCombiner::new();
+    for _ in 0..10000 {
+        let (v4, len4) = (Ipv4Addr::from(next() as u32), 12 + (next() % 13) as u8);
+        let (v6, len6) = (
+            Ipv6Addr::from(u128::from(next()) << 64),
+            20 + (next() % 29) as u8,
+        );
+        cidrs.insert_v4(v4, len4);
+        cidrs.insert_v6(v6, len6);
+        combiner.push(IpCidr::V4(
+            Ipv4Cidr::from_prefix_and_bits(v4, len4).unwrap(),
+        ));
+        combiner.push(IpCidr::V6(
+            Ipv6Cidr::from_prefix_and_bits(v6, len6).unwrap(),
+        ));
+    }
+
+    let ips = [
+        IpAddr::from_str("180.101.49.12").unwrap(),
+        IpAddr::from_str("2400:da00::6666").unwrap(),
+    ];
+    c.bench_function("ip_match", |b| {
+        b.iter(|| ips.iter().filter(|&&ip| cidrs.contains(ip)).count())
+    });
+    c.bench_function("ip_match_linear", |b| {
+        b.iter(|| ips.iter().filter(|&&ip| combiner.contains(ip)).count())
     });
 }
 
-criterion_group!(benches, bench_match);
+criterion_group!(benches, bench_match, bench_ip);
 criterion_main!(benches);
@@ -0,0 +1,278 @@
+// Copyright 2020 LEXUGE
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! A binary trie to match IP addresses against a set of CIDRs.
+//!
+//! Each lookup takes at most one step per bit of the address, no matter how many CIDRs there are.
+
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+// The root is the first node, which is nobody's child, so index 0 marks the absence of a child.
+const NONE: u32 = 0;
+
+#[derive(Clone, Default)]
+struct Node {
+    children: [u32; 2],
+    // Whether a CIDR ends here, which covers everything below.
+    end: bool,
+}
+
+// Prefixes are stored from the most significant bit of the key.
+#[derive(Clone)]
+struct Trie {
+    nodes: Vec<Node>,
+}
+
+impl Default for Trie {
+    fn default() -> Self {
+        Self {
+            nodes: vec![Node::default()],
+        }
+    }
+}
+
+impl Trie {
+    fn bit(key: u128, depth: u8) -> usize {
+        ((key >> (127 - depth)) & 1) as usize
+    }
+
+    fn insert(&mut self, key: u128, len: u8) {
+        let mut node = 0;
+        for depth in 0..len {
+            // Covered by a shorter prefix already
+            if self.nodes[node].end {
+                return;
+            }
+            let bit = Self::bit(key, depth);
+            node = match self.nodes[node].children[bit] {
+                NONE => {
+                    self.nodes.push(Node::default());
+                    let child = self.nodes.len() - 1;
+                    self.nodes[node].children[bit] = child as u32;
+                    child
+                }
+                child => child as usize,
+            };
+        }
+        let node = &mut self.nodes[node];
+        node.end = true;
+        // Longer prefixes below are covered now. Their nodes are left unreachable.
+        node.children = [NONE; 2];
+    }
+
+    fn contains(&self, key: u128, bits: u8) -> bool {
+        let mut node = &self.nodes[0];
+        for depth in 0..bits {
+            if node.end {
+                return true;
+            }
+            match node.children[Self::bit(key, depth)] {
+                NONE => return false,
+                child => node = &self.nodes[child as usize],
+            }
+        }
+        node.end
+    }
+}
+
+fn v4_key(addr: Ipv4Addr) -> u128 {
+    u128::from(u32::from(addr)) << 96
+}
+
+/// A set of IP CIDRs to match IP addresses against.
+/// IPv4 and IPv6 CIDRs are kept apart, so IPv4-mapped IPv6 addresses are only matched by IPv6 CIDRs.
+#[derive(Clone, Default)]
+pub struct IpCidrs {
+    v4: Trie,
+    v6: Trie,
+}
+
+impl IpCidrs {
+    /// Create an empty set.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Insert the IPv4 CIDR made up of the first `len` bits of `addr`. The bits after are ignored. CIDRs may overlap each other.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `len` is greater than 32.
+    pub fn insert_v4(&mut self, addr: Ipv4Addr, len: u8) {
+        assert!(len <= 32, "invalid IPv4 prefix length {}", len);
+        self.v4.insert(v4_key(addr), len);
+    }
+
+    /// Insert the IPv6 CIDR made up of the first `len` bits of `addr`. The bits after are ignored. CIDRs may overlap each other.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `len` is greater than 128.
+    pub fn insert_v6(&mut self, addr: Ipv6Addr, len: u8) {
+        assert!(len <= 128, "invalid IPv6 prefix length {}", len);
+        self.v6.insert(u128::from(addr), len);
+    }
+
+    /// Whether the address is in any of the CIDRs.
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match ip {
+            IpAddr::V4(addr) => self.v4.contains(v4_key(addr), 32),
+            IpAddr::V6(addr) => self.v6.contains(u128::from(addr), 128),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::IpCidrs;
+    use cidr_utils::{
+        cidr::{IpCidr, Ipv4Cidr, Ipv6Cidr},
+        utils::IpCidrCombiner,
+    };
+    use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    fn build(list: &[(&str, u8)]) -> IpCidrs {
+        let mut cidrs = IpCidrs::new();
+        for &(addr, len) in list {
+            match ip(addr) {
+                IpAddr::V4(addr) => cidrs.insert_v4(addr, len),
+                IpAddr::V6(addr) => cidrs.insert_v6(addr, len),
+            }
+        }
+        cidrs
+    }
+
+    #[test]
+    fn empty() {
+        let cidrs = IpCidrs::new();
+        assert!(!cidrs.contains(ip("0.0.0.0")));
+        assert!(!cidrs.contains(ip("::")));
+    }
+
+    #[test]
+    fn whole() {
+        let cidrs = build(&[("0.0.0.0", 0)]);
+        assert!(cidrs.contains(ip("0.0.0.0")));
+        assert!(cidrs.contains(ip("255.255.255.255")));
+        assert!(!cidrs.contains(ip("::")));
+
+        let cidrs = build(&[("::", 0)]);
+        assert!(cidrs.contains(ip("::")));
+        assert!(cidrs.contains(ip("ffff:ffff:ffff:ffff:ffff:ffff:ffff:ffff")));
+        assert!(!cidrs.contains(ip("1.1.1.1")));
+    }
+
+    #[test]
+    fn hosts() {
+        let cidrs = build(&[("1.1.1.1", 32), ("2001:db8::1", 128)]);
+        assert!(cidrs.contains(ip("1.1.1.1")));
+        assert!(!cidrs.contains(ip("1.1.1.0")));
+        assert!(!cidrs.contains(ip("1.1.1.2")));
+        assert!(cidrs.contains(ip("2001:db8::1")));
+        assert!(!cidrs.contains(ip("2001:db8::")));
+        assert!(!cidrs.contains(ip("2001:db8::3")));
+        // IPv4-mapped addresses are IPv6 addresses.
+        assert!(!cidrs.contains(ip("::ffff:1.1.1.1")));
+    }
+
+    #[test]
+    fn unaligned() {
+        // 2001:db8:0:0000::/49 to 2001:db8:0:7fff:ffff:ffff:ffff:ffff
+        let cidrs = build(&[("2001:db8::", 49), ("10.128.0.0", 9)]);
+        assert!(cidrs.contains(ip("2001:db8::1")));
+        assert!(cidrs.contains(ip("2001:db8:0:7fff:ffff:ffff:ffff:ffff")));
+        assert!(!cidrs.contains(ip("2001:db8:0:8000::")));
+        assert!(!cidrs.contains(ip("2001:db8:1::")));
+        assert!(cidrs.contains(ip("10.128.0.0")));
+        assert!(cidrs.contains(ip("10.255.255.255")));
+        assert!(!cidrs.contains(ip("10.127.255.255")));
+
+        // Bits after the prefix are ignored.
+        let cidrs = build(&[("2001:db8:0:ffff::1", 49)]);
+        assert!(cidrs.contains(ip("2001:db8:0:8000::")));
+        assert!(!cidrs.contains(ip("2001:db8:0:7fff::")));
+    }
+
+    #[test]
+    fn overlapping() {
+        let list = [
+            ("10.0.0.0", 8),
+            ("10.1.0.0", 16),
+            ("10.1.0.0", 16),
+            ("192.168.1.0", 24),
+            ("192.168.0.0", 16),
+        ];
+        // The order of insertion doesn't matter.
+        for cidrs in [
+            build(&list),
+            build(&list.iter().rev().copied().collect::<Vec<_>>()),
+        ] {
+            assert!(cidrs.contains(ip("10.1.2.3")));
+            assert!(cidrs.contains(ip("10.2.3.4")));
+            assert!(cidrs.contains(ip("192.168.2.1")));
+            assert!(cidrs.contains(ip("192.168.1.1")));
+            assert!(!cidrs.contains(ip("11.0.0.0")));
+            assert!(!cidrs.contains(ip("192.169.0.0")));
+        }
+    }
+
+    // Check against the linear scan of `cidr-utils` on CIDRs, which mostly overlap each other with few bits to differ in.
+    #[test]
+    fn random() {
+        let mut seed = 0x2545_f491_4f6c_dd1d_u64;
+        let mut next = move || {
+            seed ^= seed << 13;
+            seed ^= seed >> 7;
+            seed ^= seed << 17;
+            seed
+        };
+        let mut cidrs = IpCidrs::new();
+        let mut combiner = IpCidrCombiner::new();
+        for _ in 0..1000 {
+            let (v4, v6) = (
+                Ipv4Addr::from(next() as u32 & 0xff0f_0f0f),
+                Ipv6Addr::from(u128::from(next()) << 64 & 0xff0f_0f0f << 96),
+            );
+            // Short prefixes would cover about everything.
+            let (len4, len6) = (8 + (next() % 25) as u8, 16 + (next() % 113) as u8);
+            cidrs.insert_v4(v4, len4);
+            cidrs.insert_v6(v6, len6);
+            combiner.push(IpCidr::V4(
+                Ipv4Cidr::from_prefix_and_bits(v4, len4).unwrap(),
+            ));
+            combiner.push(IpCidr::V6(
+                Ipv6Cidr::from_prefix_and_bits(v6, len6).unwrap(),
+            ));
+        }
+        for _ in 0..2000 {
+            for ip in [
+                IpAddr::V4(Ipv4Addr::from(next() as u32 & 0xff0f_0f0f)),
+                IpAddr::V6(Ipv6Addr::from(u128::from(next()) << 64 & 0xff0f_0f0f << 96)),
+            ] {
+                assert_eq!(cidrs.contains(ip), combiner.contains(ip), "{}", ip);
+            }
+        }
+    }
+
+    #[test]
+    #[should_panic]
+    fn invalid_len() {
+        build(&[("1.1.1.1", 33)]);
+    }
+}
@@ -49,7 +49,7 @@ static QUERY: Lazy<Message<Bytes>> = Lazy::new(|| {
     builder.into_message()
 });
 
-async fn create_router(c: usize) -> Router {
+async fn create_router(c: usize, addr: &str) -> Router {
     RouterBuilder::new(
         TableBuilder::new().add_rule(
             "start",
@@ -69,10 +69,11 @@ async fn create_router(c: usize) -> Router {
         UpstreamsBuilder::new(c).unwrap().add_upstream(
             "mock",
             UpstreamBuilder::Udp(UdpBuilder {
-                addr: "127.0.0.1:53533".parse().unwrap(),
+                addr: addr.parse().unwrap(),
                 max_pool_size: 256,
                 timeout: 1,
                 ratelimit: None,
+                cache: CacheSettings::default(),
             }),
         ),
     )
@@ -88,20 +89,15 @@ fn bench_resolve(c: &mut Criterion) {
         .unwrap();
 
     let socket = rt.block_on(UdpSocket::bind(&"127.0.0.1:53533")).unwrap();
-    let server = Server::new(socket, vec![0; 1024], None);
-    rt.spawn(server.run(DUMMY_MSG.clone()));
+    rt.spawn(Server::answering(socket, &DUMMY_MSG).run());
 
-    let router = rt.block_on(create_router(1));
-    let cached_router = rt.block_on(create_router(4096));
+    let router = rt.block_on(create_router(1, "127.0.0.1:53533"));
+    let cached_router = rt.block_on(create_router(4096, "127.0.0.1:53533"));
 
     c.bench_function("non_cache_resolve", |b| {
         b.to_async(&rt).iter(|| async {
             assert_eq!(
-                router
-                    .resolve(QUERY.clone(), None)
-                    .await
-                    .unwrap()
-                    .into_octets(),
+                router.resolve(QUERY.clone()).await.unwrap().into_octets(),
                 DUMMY_MSG.clone().into_octets()
             );
         })
@@ -111,7 +107,7 @@ fn bench_resolve(c: &mut Criterion) {
         b.to_async(&rt).iter(|| async {
             assert_eq!(
                 cached_router
-                    .resolve(QUERY.clone(), None)
+                    .resolve(QUERY.clone())
                     .await
                     .unwrap()
                     .into_octets(),
@@ -121,5 +117,36 @@ fn bench_resolve(c: &mut Criterion) {
     });
 }
 
-criterion_group!(benches, bench_resolve);
+fn bench_resolve_batch(c: &mut Criterion) {
+    const N: usize = 64;
+
+    let rt = tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()
+        .unwrap();
+
+    let socket = rt.block_on(UdpSocket::bind(&"127.0.0.1:53534")).unwrap();
+    rt.spawn(Server::answering(socket, &DUMMY_MSG).run());
+
+    let router = rt.block_on(create_router(1, "127.0.0.1:53534"));
+
+    c.bench_function("sequential_resolve_64", |b| {
+        b.to_async(&rt).iter(|| async {
+            for _ in 0..N {
+                router.resolve(QUERY.clone()).await.unwrap();
+            }
+        })
+    });
+
+    c.bench_function("batch_resolve_64", |b| {
+        b.to_async(&rt).iter(|| async {
+            let resps = router
+                .resolve_batch(vec![(QUERY.clone(), None); N], None)
+                .await;
+            assert!(resps.iter().all(|r| r.is_ok()));
+        })
+    });
+}
+
+criterion_group!(benches, bench_resolve, bench_resolve_batch);
 criterion_main!(benches);
@@ -14,10 +14,10 @@
 // along with this program.  If not, see <http://www.gnu.org/licenses/>.
 
 use self::RecordStatus::*;
-use crate::{Label, MAX_TTL};
+use crate::Label;
 use bytes::Bytes;
 use clru::CLruCache;
-use domain::base::{name::ToDname, Message};
+use domain::base::{name::ToDname, Dname, Message, Rtype};
 use log::*;
 use std::{
     borrow::Borrow,
@@ -25,8 +25,9 @@ use std::{
     net::IpAddr,
     num::NonZeroUsize,
     sync::{Arc, Mutex},
-    time::{Duration, Instant},
+    time::Duration,
 };
+use tokio::time::Instant;
 
 // Expire every hour
 const ECS_CACHE_TTL: Duration = Duration::from_secs(60 * 60);
@@ -51,20 +52,20 @@ where
     }
 }
 
-impl<A: Hash + ?Sized, B: Hash + ?Sized> Hash for (dyn KeyPair<A, B> + '_) {
+impl<A: Hash + ?Sized, B: Hash + ?Sized> Hash for dyn KeyPair<A, B> + '_ {
     fn hash<H: Hasher>(&self, state: &mut H) {
         self.a().hash(state);
         self.b().hash(state);
     }
 }
 
-impl<A: Eq + ?Sized, B: Eq + ?Sized> PartialEq for (dyn KeyPair<A, B> + '_) {
+impl<A: Eq + ?Sized, B: Eq + ?Sized> PartialEq for dyn KeyPair<A, B> + '_ {
     fn eq(&self, other: &Self) -> bool {
         self.a() == other.a() && self.b() == other.b()
     }
 }
 
-impl<A: Eq + ?Sized, B: Eq + ?Sized> Eq for (dyn KeyPair<A, B> + '_) {}
+impl<A: Eq + ?Sized, B: Eq + ?Sized> Eq for dyn KeyPair<A, B> + '_ {}
 
 impl<A: ?Sized, B: ?Sized, C, D> KeyPair<A, B> for (C, D)
 where
@@ -84,6 +85,7 @@ pub struct CacheRecord<T> {
     created_instant: Instant,
     content: T,
     ttl: Duration,
+    hits: u64,
 }
 
 impl<T: Clone> CacheRecord<T> {
@@ -92,6 +94,7 @@ impl<T: Clone> CacheRecord<T> {
             created_instant: Instant::now(),
             content,
             ttl,
+            hits: 0,
         }
     }
 
@@ -102,6 +105,11 @@ impl<T: Clone> CacheRecord<T> {
     pub fn validate(&self) -> bool {
         Instant::now().saturating_duration_since(self.created_instant) <= self.ttl
     }
+
+    // Whether the record has been expired for longer than `max_stale`, in which case it shall not be served anymore.
+    pub fn outdated(&self, max_stale: Duration) -> bool {
+        Instant::now().saturating_duration_since(self.created_instant) > self.ttl + max_stale
+    }
 }
 
 pub enum RecordStatus<T> {
@@ -109,26 +117,87 @@ pub enum RecordStatus<T> {
     Expired(T),
 }
 
+/// The reason a record left the response cache.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CacheEventKind {
+    /// The record was evicted to make room for a new one as the cache is full.
+    Evicted,
+    /// The record was removed explicitly.
+    Removed,
+    /// The record was found expired for longer than the stale window allowed on lookup.
+    Expired,
+}
+
+/// An event emitted when a record leaves the response cache.
+#[derive(Clone, Debug)]
+pub struct CacheEvent {
+    /// The reason of the event
+    pub kind: CacheEventKind,
+    /// Tag of the upstream that the record belongs to
+    pub tag: Label,
+    /// Query name of the record
+    pub qname: Dname<Bytes>,
+    /// Query type of the record
+    pub qtype: Rtype,
+    /// Time elapsed since the record was inserted
+    pub age: Duration,
+    /// Number of times the record was served from the cache
+    pub hits: u64,
+}
+
+impl CacheEvent {
+    fn new(kind: CacheEventKind, tag: Label, record: &CacheRecord<Message<Bytes>>) -> Option<Self> {
+        let question = record.content.first_question()?;
+        Some(Self {
+            kind,
+            tag,
+            qname: question.qname().to_bytes(),
+            qtype: question.qtype(),
+            age: record.created_instant.elapsed(),
+            hits: record.hits,
+        })
+    }
+}
+
+/// Callback invoked on cache events. It is always called without holding the cache lock.
+pub type CacheCallback = Arc<dyn Fn(&CacheEvent) + Send + Sync>;
+
 // A LRU cache for responses
 #[derive(Clone)]
 pub struct RespCache {
     #[allow(clippy::type_complexity)]
     cache: Arc<Mutex<CLruCache<(Label, Bytes), CacheRecord<Message<Bytes>>>>>,
+    callback: Option<CacheCallback>,
 }
 
 impl RespCache {
     pub fn new(size: NonZeroUsize) -> Self {
         Self {
             cache: Arc::new(Mutex::new(CLruCache::new(size))),
+            callback: None,
+        }
+    }
+
+    pub fn set_callback(&mut self, callback: CacheCallback) {
+        self.callback = Some(callback);
+    }
+
+    // Only call this after the lock is released.
+    fn emit(&self, kind: CacheEventKind, tag: Label, record: Option<CacheRecord<Message<Bytes>>>) {
+        if let (Some(callback), Some(event)) = (
+            &self.callback,
+            record.and_then(|r| CacheEvent::new(kind, tag, &r)),
+        ) {
+            callback(&event)
         }
     }
 
-    pub fn put(&self, tag: Label, query: &Message<Bytes>, msg: Message<Bytes>) {
+    // `max_ttl` is used for responses without any answer, and it caps the TTL of the others.
+    pub fn put(&self, tag: Label, query: &Message<Bytes>, msg: Message<Bytes>, max_ttl: u32) {
         if msg.no_error() {
             // We are assured that it should parse and exist
             let ttl = Duration::from_secs(u64::from(
-                query
-                    .answer()
+                msg.answer()
                     .ok()
                     .and_then(|records| {
                         records
@@ -136,41 +205,76 @@ impl RespCache {
                             .map(|r| r.unwrap().ttl())
                             .min()
                     })
-                    .unwrap_or(MAX_TTL),
+                    .map_or(max_ttl, |ttl| ttl.min(max_ttl)),
             ));
-            self.cache.lock().unwrap().put(
-                // We discard the first two bytes which are the places for ID
-                (tag, query.as_octets().slice(2..)),
+            // We discard the first two bytes which are the places for ID
+            let key = (tag, query.as_octets().slice(2..));
+            let evicted = {
+                let mut cache = self.cache.lock().unwrap();
+                // CLruCache doesn't tell us what it evicted, so we make room ourselves.
+                let evicted = if !cache.contains(&key) && cache.is_full() {
+                    cache.pop_back()
+                } else {
+                    None
+                };
                 // Clone should be cheap here
-                CacheRecord::new(msg, ttl),
-            );
+                cache.put(key, CacheRecord::new(msg, ttl));
+                evicted
+            };
+            if let Some((key, record)) = evicted {
+                self.emit(CacheEventKind::Evicted, key.0, Some(record));
+            }
         } else {
             info!("response errored, not caching erroneous upstream response.");
         };
     }
 
-    pub fn get(&self, tag: &Label, msg: &Message<Bytes>) -> Option<RecordStatus<Message<Bytes>>> {
+    pub fn get(
+        &self,
+        tag: &Label,
+        msg: &Message<Bytes>,
+        max_stale: Duration,
+    ) -> Option<RecordStatus<Message<Bytes>>> {
         let question = msg.first_question().unwrap();
         let qname = question.qname().to_bytes();
 
-        match self
+        let key = (tag, msg.as_octets().slice(2..));
+        let key = &key as &dyn KeyPair<Label, Bytes>;
+        let outdated = {
+            let mut cache = self.cache.lock().unwrap();
+
+            let r = cache.get_mut(key)?;
+            // Get record only once.
+            if r.validate() {
+                info!("cache hit for {}", qname);
+                r.hits += 1;
+                return Some(Alive(r.get()));
+            } else if !r.outdated(max_stale) {
+                info!("TTL passed for {}, returning expired record.", qname);
+                r.hits += 1;
+                return Some(Expired(r.get()));
+            }
+            info!(
+                "record for {} has been expired for longer than the stale window, evicting.",
+                qname
+            );
+            cache.pop(key)
+        };
+        self.emit(CacheEventKind::Expired, tag.clone(), outdated);
+        None
+    }
+
+    // Remove the cached response of the query sent to the upstream `tag`. Returns whether there was such a record.
+    pub fn remove(&self, tag: &Label, msg: &Message<Bytes>) -> bool {
+        let key = (tag, msg.as_octets().slice(2..));
+        let removed = self
             .cache
             .lock()
             .unwrap()
-            .get(&(tag, msg.as_octets().slice(2..)) as &dyn KeyPair<Label, Bytes>)
-        {
-            Some(r) => {
-                // Get record only once.
-                if r.validate() {
-                    info!("cache hit for {}", qname);
-                    Some(Alive(r.get()))
-                } else {
-                    info!("TTL passed for {}, returning expired record.", qname);
-                    Some(Expired(r.get()))
-                }
-            }
-            Option::None => Option::None,
-        }
+            .pop(&key as &dyn KeyPair<Label, Bytes>);
+        let found = removed.is_some();
+        self.emit(CacheEventKind::Removed, tag.clone(), removed);
+        found
     }
 }
 
@@ -216,3 +320,152 @@ impl Default for EcsCache {
         Self::new()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{CacheEvent, CacheEventKind, RecordStatus::*, RespCache};
+    use crate::{Label, MAX_TTL};
+    use bytes::{Bytes, BytesMut};
+    use domain::{
+        base::{Dname, Message, MessageBuilder, Rtype},
+        rdata::A,
+    };
+    use std::{
+        net::Ipv4Addr,
+        num::NonZeroUsize,
+        str::FromStr,
+        sync::{Arc, Mutex},
+        time::Duration,
+    };
+    use tokio::time::advance;
+
+    // Create a query and its answer, which contains a single A record if `ttl` is given.
+    fn create_pair(ttl: Option<u32>) -> (Message<Bytes>, Message<Bytes>) {
+        create_pair_for("example.com", ttl)
+    }
+
+    fn create_pair_for(name: &str, ttl: Option<u32>) -> (Message<Bytes>, Message<Bytes>) {
+        let name = Dname::<Bytes>::from_str(name).unwrap();
+        let mut builder = MessageBuilder::from_target(BytesMut::with_capacity(1024))
+            .unwrap()
+            .question();
+        builder.push((&name, Rtype::A)).unwrap();
+        let query = Message::from_octets(builder.finish().freeze()).unwrap();
+        let mut builder = MessageBuilder::from_target(BytesMut::with_capacity(1024))
+            .unwrap()
+            .start_answer(&query, domain::base::iana::Rcode::NoError)
+            .unwrap();
+        if let Some(ttl) = ttl {
+            builder
+                .push((&name, ttl, A::new(Ipv4Addr::new(1, 1, 1, 1))))
+                .unwrap();
+        }
+        let resp = Message::from_octets(builder.finish().freeze()).unwrap();
+        (query, resp)
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn stale_window() {
+        let cache = RespCache::new(NonZeroUsize::new(1).unwrap());
+        let tag: Label = "mock".into();
+        let (query, resp) = create_pair(Some(300));
+        let ttl = Duration::from_secs(300);
+        let window = Duration::from_secs(60);
+        cache.put(tag.clone(), &query, resp, MAX_TTL);
+
+        advance(ttl).await;
+        assert!(matches!(cache.get(&tag, &query, window), Some(Alive(_))));
+
+        advance(window - Duration::from_secs(1)).await;
+        assert!(matches!(cache.get(&tag, &query, window), Some(Expired(_))));
+
+        advance(Duration::from_secs(2)).await;
+        assert!(cache.get(&tag, &query, window).is_none());
+
+        // The record should have been evicted, even a wider window won't bring it back.
+        assert!(cache.get(&tag, &query, window * 10).is_none());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn cap_ttl() {
+        let cache = RespCache::new(NonZeroUsize::new(1).unwrap());
+        let tag: Label = "mock".into();
+        // A week long TTL should be capped to the maximum.
+        let (query, resp) = create_pair(Some(7 * 86400));
+        cache.put(tag.clone(), &query, resp, MAX_TTL);
+
+        advance(Duration::from_secs(MAX_TTL.into())).await;
+        assert!(matches!(
+            cache.get(&tag, &query, Duration::ZERO),
+            Some(Alive(_))
+        ));
+
+        advance(Duration::from_secs(1)).await;
+        assert!(cache.get(&tag, &query, Duration::ZERO).is_none());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn no_answer_ttl() {
+        let cache = RespCache::new(NonZeroUsize::new(1).unwrap());
+        let tag: Label = "mock".into();
+        // Response without answers is cached with the maximum TTL given.
+        let (query, resp) = create_pair(None);
+        cache.put(tag.clone(), &query, resp, 60);
+
+        advance(Duration::from_secs(60)).await;
+        assert!(matches!(
+            cache.get(&tag, &query, Duration::ZERO),
+            Some(Alive(_))
+        ));
+
+        advance(Duration::from_secs(1)).await;
+        assert!(cache.get(&tag, &query, Duration::ZERO).is_none());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn events() {
+        let events: Arc<Mutex<Vec<CacheEvent>>> = Arc::new(Mutex::new(Vec::new()));
+        let mut cache = RespCache::new(NonZeroUsize::new(1).unwrap());
+        {
+            let events = events.clone();
+            let inner = cache.clone();
+            cache.set_callback(Arc::new(move |e| {
+                // Callbacks should never be invoked while the cache is locked.
+                assert!(inner.cache.try_lock().is_ok());
+                events.lock().unwrap().push(e.clone())
+            }));
+        }
+        let tag: Label = "mock".into();
+        let (foo_query, foo_resp) = create_pair_for("foo.com", Some(10));
+        let (bar_query, bar_resp) = create_pair_for("bar.com", Some(10));
+
+        cache.put(tag.clone(), &foo_query, foo_resp.clone(), MAX_TTL);
+        cache.get(&tag, &foo_query, Duration::ZERO);
+        cache.get(&tag, &foo_query, Duration::ZERO);
+        advance(Duration::from_secs(1)).await;
+
+        // Capacity is 1, foo.com gets evicted.
+        cache.put(tag.clone(), &bar_query, bar_resp, MAX_TTL);
+        // Removing the record that doesn't exist shouldn't emit any event.
+        assert!(!cache.remove(&tag, &foo_query));
+        assert!(cache.remove(&tag, &bar_query));
+
+        cache.put(tag.clone(), &foo_query, foo_resp, MAX_TTL);
+        advance(Duration::from_secs(11)).await;
+        assert!(cache.get(&tag, &foo_query, Duration::ZERO).is_none());
+
+        let events = events.lock().unwrap();
+        assert_eq!(
+            events
+                .iter()
+                .map(|e| (e.kind, e.qname.to_string(), e.hits, e.age.as_secs()))
+                .collect::<Vec<_>>(),
+            vec![
+                (CacheEventKind::Evicted, "foo.com".to_string(), 2, 1),
+                (CacheEventKind::Removed, "bar.com".to_string(), 0, 0),
+                (CacheEventKind::Expired, "foo.com".to_string(), 0, 11),
+            ]
+        );
+        assert!(events.iter().all(|e| e.tag == tag && e.qtype == Rtype::A));
+    }
+}
@@ -21,13 +21,51 @@ pub use super::router::{
         TableError,
     },
     upstreams::error::UpstreamError,
+    warmup::WarmUpError,
 };
-use std::fmt::Debug;
+use crate::Label;
+use std::fmt::{Debug, Display, Write};
 use thiserror::Error;
 
 // We don't expose this as this is useless for external
 pub(crate) type Result<T> = std::result::Result<T, DrouteError>;
 
+/// Errors of parsing configurations from strings. The section failed is carried.
+#[derive(Error, Debug)]
+pub enum ConfigError {
+    /// Failed to parse the section as YAML.
+    #[error("Failed to parse the `{section}` section: {source}")]
+    Yaml {
+        /// The section failed
+        section: &'static str,
+        /// The underlying error
+        source: serde_yaml::Error,
+    },
+
+    /// Failed to parse the section as JSON.
+    #[error("Failed to parse the `{section}` section: {source}")]
+    Json {
+        /// The section failed
+        section: &'static str,
+        /// The underlying error
+        source: serde_json::Error,
+    },
+
+    /// A required section is missing.
+    #[error("The `{0}` section is missing")]
+    MissingSection(&'static str),
+}
+
+impl ConfigError {
+    pub(crate) fn yaml(section: &'static str) -> impl FnOnce(serde_yaml::Error) -> Self {
+        move |source| Self::Yaml { section, source }
+    }
+
+    pub(crate) fn json(section: &'static str) -> impl FnOnce(serde_json::Error) -> Self {
+        move |source| Self::Json { section, source }
+    }
+}
+
 /// DrouteError enumerates all possible errors returned by this library.
 #[derive(Error, Debug)]
 pub enum DrouteError {
@@ -39,7 +77,57 @@ pub enum DrouteError {
     #[error(transparent)]
     UpstreamError(#[from] UpstreamError),
 
+    /// Error related to the warm-up list.
+    #[error(transparent)]
+    WarmUpError(#[from] WarmUpError),
+
+    /// The routing table selected for the query doesn't exist.
+    #[error("No routing table named `{0}`")]
+    UnknownTable(Label),
+
+    /// The router is shutting down and no longer accepts queries.
+    #[error("The router is shutting down")]
+    ShuttingDown,
+
     /// The buffer is too short
     #[error(transparent)]
     ShortBuf(#[from] domain::base::ShortBuf),
+
+    /// More than one problem is found in the configuration.
+    #[error("{}", numbered(.0))]
+    Multiple(Vec<DrouteError>),
+}
+
+impl DrouteError {
+    // Report the problems found, with a sole one kept as it is. Problems nested in `Multiple` are flattened.
+    pub(crate) fn collect(errors: impl IntoIterator<Item = Self>) -> Result<()> {
+        let mut flat = Vec::new();
+        for e in errors {
+            match e {
+                Self::Multiple(v) => flat.extend(v),
+                Self::TableError(TableError::Multiple(v)) => {
+                    flat.extend(v.into_iter().map(Self::TableError))
+                }
+                Self::UpstreamError(UpstreamError::Multiple(v)) => {
+                    flat.extend(v.into_iter().map(Self::UpstreamError))
+                }
+                e => flat.push(e),
+            }
+        }
+        match flat.len() {
+            0 => Ok(()),
+            1 => Err(flat.pop().unwrap()),
+            _ => Err(Self::Multiple(flat)),
+        }
+    }
+}
+
+// Render the errors as a numbered list.
+pub(crate) fn numbered<E: Display>(errors: &[E]) -> String {
+    let mut s = format!("{} problems found:", errors.len());
+    for (n, e) in errors.iter().enumerate() {
+        // Writing to a `String` never fails.
+        let _ = write!(s, "\n  {}. {}", n + 1, e);
+    }
+    s
 }
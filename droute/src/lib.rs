@@ -25,11 +25,22 @@
 #![deny(unsafe_code)]
 // Documentation
 //! This is the core library for dcompass. It implements configuration parsing scheme, DNS query routing rules, and upstream managements.
+
+// Emit a structured `tracing` event at the debug level if the `tracing` feature is enabled. Arguments are not evaluated otherwise.
+macro_rules! event {
+    ($($arg:tt)*) => {
+        #[cfg(feature = "tracing")]
+        tracing::debug!($($arg)*);
+    };
+}
+
 pub(crate) mod cache;
 pub mod error;
 #[doc(hidden)]
 pub mod mock;
 mod router;
+#[cfg(any(feature = "doh-server", feature = "dot-server"))]
+pub mod serve;
 
 #[cfg(all(feature = "doh-native-tls", feature = "doh-rustls"))]
 compile_error!("You should only choose one TLS backend for DNS over HTTPS implementation");
@@ -39,6 +50,8 @@ compile_error!("You should only choose one TLS backend for DNS over TLS implemen
 
 use async_trait::async_trait;
 use compact_str::CompactStr;
+use futures::{future::BoxFuture, FutureExt, StreamExt};
+use std::collections::HashMap;
 
 /// All the builders
 // API guideline: when we are exporting, make sure we aggregate builders by pub using them in parent builder(s) modules.
@@ -50,22 +63,35 @@ pub mod builders {
             TableBuilder,
         },
         upstreams::builder::*,
+        warmup::WarmUpBuilder,
         RouterBuilder,
     };
 }
 
+pub use self::cache::{CacheCallback, CacheEvent, CacheEventKind};
+
+#[cfg(feature = "schema")]
+pub use self::router::schema::config_schema;
+
+#[cfg(feature = "metrics-export")]
+pub use self::router::metrics_export;
+
 // All the major components
 pub use self::router::{
+    metrics, preflight,
     table::{
-        rule::{actions, matchers, Rule},
-        QueryContext, Table,
+        describe,
+        rule::{actions, matchers, Next, Rule},
+        stats, trace, Protocol, QueryContext, Table, Unanswered,
     },
-    upstreams::{Upstream, Upstreams},
-    Router,
+    upstreams::{RespSource, Upstream, Upstreams},
+    warmup::{WarmUp, WarmUpHandle},
+    Router, DEFAULT_MAX_CONCURRENT_BUILDS, DEFAULT_TABLE,
 };
 
 // Maximum TTL as defined in https://tools.ietf.org/html/rfc2181, 2147483647
 //   Setting this to a value of 1 day, in seconds
+// This is only the default value, the maximum TTL for cache is configurable per upstream.
 const MAX_TTL: u32 = 86400_u32;
 
 // Size recommended by DNS Flag Day 2020: "This is practical for the server operators that know their environment, and the defaults in the DNS software should reflect the minimum safe size which is 1232."
@@ -85,6 +111,28 @@ pub trait AsyncTryInto<T>: Send {
     async fn async_try_into(self) -> Result<T, Self::Error>;
 }
 
+// Results of converting builders, paired with their tags.
+type Built<K, T, E> = Vec<(K, Result<T, E>)>;
+
+// Convert the builders concurrently with at most `max` of them in progress, pairing each result with its tag.
+// The results are in the order they are ready. The future is boxed, otherwise it cannot be proven `Send` in `async_trait` methods building `Box<dyn Rule>`.
+fn build_all<'a, K, B, T>(
+    builders: HashMap<K, B>,
+    max: usize,
+) -> BoxFuture<'a, Built<K, T, B::Error>>
+where
+    K: Send + 'a,
+    B: AsyncTryInto<T> + 'a,
+    B::Error: Send,
+    T: Send + 'a,
+{
+    futures::stream::iter(builders)
+        .map(|(k, b)| b.async_try_into().map(|r| (k, r)))
+        .buffer_unordered(max)
+        .collect()
+        .boxed()
+}
+
 /// A object that can be validated
 pub trait Validatable {
     /// The possible errors from the validation.
@@ -14,52 +14,160 @@
 // along with this program.  If not, see <http://www.gnu.org/licenses/>.
 
 //! This module is NOT intended to be used by regular users. It is used for mocking purpose only.
-use bytes::BytesMut;
-use domain::base::Message;
-use std::net::SocketAddr;
+use crate::router::upstreams::DUMMY_QUERY;
+use bytes::{Bytes, BytesMut};
+use domain::base::{iana::Rcode, Message, MessageBuilder};
+use std::{
+    collections::VecDeque,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
 use tokio::net::UdpSocket;
 
-/// Mock echo server
+/// What the mock server does upon a query.
+#[derive(Clone)]
+pub enum MockBehavior {
+    /// Answer with the message, with its ID set to the one of the query.
+    Answer(Message<Bytes>),
+    /// Answer with an empty `SERVFAIL` response.
+    Servfail,
+    /// Answer with an empty `NXDOMAIN` response.
+    Nxdomain,
+    /// Answer with an empty `NOERROR` response after the duration, without holding up other queries.
+    Timeout(Duration),
+    /// Never answer.
+    Drop,
+    /// Answer with an empty `NOERROR` response with the TC bit set.
+    Truncate,
+}
+
+/// Picks the behavior of the mock server by the query.
+pub type BehaviorFn = Arc<dyn Fn(&Message<Bytes>) -> MockBehavior + Send + Sync>;
+
+/// Decides the behavior of the mock server on each query.
+#[derive(Clone)]
+pub enum Handler {
+    /// Behaviors consumed in order, one per query. The last one is repeated once the others are used up, and queries are dropped if there is none.
+    Script(Arc<Mutex<VecDeque<MockBehavior>>>),
+    /// Behavior picked by the query.
+    Fn(BehaviorFn),
+}
+
+impl Handler {
+    /// Behaviors consumed in order, see `Handler::Script`.
+    pub fn script(behaviors: Vec<MockBehavior>) -> Self {
+        Self::Script(Arc::new(Mutex::new(behaviors.into())))
+    }
+
+    /// Behavior picked by the query, see `Handler::Fn`.
+    pub fn from_fn(f: impl Fn(&Message<Bytes>) -> MockBehavior + Send + Sync + 'static) -> Self {
+        Self::Fn(Arc::new(f))
+    }
+
+    fn next(&self, query: &Message<Bytes>) -> MockBehavior {
+        match self {
+            Self::Script(script) => {
+                let mut script = script.lock().unwrap();
+                if script.len() > 1 {
+                    script.pop_front().unwrap()
+                } else {
+                    script.front().cloned().unwrap_or(MockBehavior::Drop)
+                }
+            }
+            Self::Fn(f) => f(query),
+        }
+    }
+}
+
+// Build the response to the query with the behavior, `None` if nothing should be sent.
+fn respond(query: &Message<Bytes>, behavior: &MockBehavior) -> Option<Bytes> {
+    let empty = |rcode| {
+        MessageBuilder::from_target(BytesMut::with_capacity(1024))
+            .unwrap()
+            .start_answer(query, rcode)
+            .unwrap()
+    };
+    Some(match behavior {
+        MockBehavior::Answer(msg) => {
+            let mut msg = Message::from_octets(BytesMut::from(msg.as_slice())).unwrap();
+            msg.header_mut().set_id(query.header().id());
+            msg.into_octets().freeze()
+        }
+        MockBehavior::Servfail => empty(Rcode::ServFail).finish().freeze(),
+        MockBehavior::Nxdomain => empty(Rcode::NXDomain).finish().freeze(),
+        MockBehavior::Timeout(_) => empty(Rcode::NoError).finish().freeze(),
+        MockBehavior::Drop => return None,
+        MockBehavior::Truncate => {
+            let mut builder = empty(Rcode::NoError);
+            builder.header_mut().set_tc(true);
+            builder.finish().freeze()
+        }
+    })
+}
+
+/// Mock DNS server over UDP.
+/// Liveness probes sent by the connection pools of UDP upstreams are ignored, they are neither answered, counted, nor consume the script.
 pub struct Server {
     socket: UdpSocket,
-    buf: Vec<u8>,
-    to_send: Option<SocketAddr>,
+    handler: Handler,
+    received: Arc<AtomicUsize>,
 }
 
 impl Server {
-    /// Create a new mock server
-    pub fn new(socket: UdpSocket, buf: Vec<u8>, to_send: Option<SocketAddr>) -> Self {
+    /// Create a new mock server acting as the handler decides.
+    pub fn new(socket: UdpSocket, handler: Handler) -> Self {
         Self {
             socket,
-            buf,
-            to_send,
+            handler,
+            received: Arc::new(AtomicUsize::new(0)),
         }
     }
 
-    /// Run it
-    pub async fn run(self, mut msg: Message<BytesMut>) -> Result<(), std::io::Error> {
-        let Server {
-            socket,
-            mut buf,
-            mut to_send,
-        } = self;
+    /// Create a new mock server answering every query with the message.
+    pub fn answering(socket: UdpSocket, msg: &Message<impl AsRef<[u8]>>) -> Self {
+        let msg = Message::from_octets(Bytes::copy_from_slice(msg.as_slice())).unwrap();
+        Self::new(socket, Handler::script(vec![MockBehavior::Answer(msg)]))
+    }
 
-        let mut id = 0_u16;
+    /// The number of queries received, which keeps counting after the server starts running.
+    pub fn received(&self) -> Arc<AtomicUsize> {
+        self.received.clone()
+    }
 
+    /// Run it
+    pub async fn run(self) -> Result<(), std::io::Error> {
+        let socket = Arc::new(self.socket);
+        let mut buf = vec![0; 1024];
         loop {
-            // First we check to see if there's a message we need to echo back.
-            // If so then we try to send it back to the original source, waiting
-            // until it's writable and we're able to do so.
-            if let Some(peer) = to_send {
-                msg.header_mut().set_id(id);
-                socket.send_to(msg.as_slice(), &peer).await?;
+            let (len, src) = socket.recv_from(&mut buf).await?;
+            let query = match Message::from_octets(Bytes::copy_from_slice(&buf[..len])) {
+                Ok(q) if q.as_slice() != DUMMY_QUERY.as_slice() => q,
+                _ => continue,
+            };
+            self.received.fetch_add(1, Ordering::SeqCst);
+            let behavior = self.handler.next(&query);
+            let resp = match respond(&query, &behavior) {
+                Some(resp) => resp,
+                None => continue,
+            };
+            match behavior {
+                MockBehavior::Timeout(delay) => {
+                    tokio::spawn(send_after(socket.clone(), resp, src, delay));
+                }
+                _ => {
+                    socket.send_to(&resp, src).await?;
+                }
             }
-
-            // If we're here then `to_send` is `None`, so we take a look for the
-            // next message we're going to echo back.
-            let res = socket.recv_from(&mut buf).await?;
-            to_send = Some(res.1);
-            id = Message::from_octets(&buf[..res.0]).unwrap().header().id();
         }
     }
 }
+
+async fn send_after(socket: Arc<UdpSocket>, resp: Bytes, src: SocketAddr, delay: Duration) {
+    tokio::time::sleep(delay).await;
+    // The client may have gone already.
+    let _ = socket.send_to(&resp, src).await;
+}
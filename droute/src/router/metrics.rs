@@ -0,0 +1,371 @@
+// Copyright 2020 LEXUGE
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Aggregate metrics of the router.
+
+use super::upstreams::RespSource;
+use crate::Label;
+use domain::base::iana::Rcode;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        RwLock,
+    },
+    time::Duration,
+};
+
+/// Upper bounds of the latency buckets in milliseconds. Latencies above the last bound fall into an extra bucket.
+pub const LATENCY_BUCKETS_MS: [u64; 12] = [1, 2, 5, 10, 20, 50, 100, 200, 500, 1000, 2000, 5000];
+
+/// A snapshot of a fixed-bucket latency histogram.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq, Eq)]
+pub struct LatencyHistogram {
+    /// Upper bounds of the buckets in milliseconds, in ascending order.
+    pub bounds_ms: Vec<u64>,
+    /// Number of samples in each bucket. It has one more element than `bounds_ms` for samples above the last bound.
+    pub counts: Vec<u64>,
+    /// Sum of all the samples in microseconds.
+    pub sum_us: u64,
+}
+
+impl LatencyHistogram {
+    /// Total number of samples.
+    pub fn count(&self) -> u64 {
+        self.counts.iter().sum()
+    }
+
+    /// The upper bound of the bucket the `q`-quantile (`0.0` to `1.0`) falls into.
+    /// Returns `None` if there is no sample or the quantile falls above the last bound.
+    pub fn quantile(&self, q: f64) -> Option<Duration> {
+        let total = self.count();
+        if total == 0 {
+            return None;
+        }
+        let rank = ((q.clamp(0.0, 1.0) * total as f64).ceil() as u64).max(1);
+        let mut seen = 0;
+        for (n, c) in self.counts.iter().enumerate() {
+            seen += c;
+            if seen >= rank {
+                return self.bounds_ms.get(n).map(|b| Duration::from_millis(*b));
+            }
+        }
+        None
+    }
+}
+
+/// A snapshot of the counters of upstream queries sent through a single upstream.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct UpstreamMetrics {
+    /// Number of upstream queries answered by a cached record within its TTL.
+    pub cache_hits: u64,
+    /// Number of upstream queries answered by a cached record with its TTL passed.
+    pub stale_hits: u64,
+    /// Number of upstream queries answered by the upstream itself.
+    pub responses: u64,
+    /// Number of upstream queries failed.
+    pub errors: u64,
+}
+
+/// A snapshot of the aggregate metrics of the router, accumulated since the router is created or the metrics are reset.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct RouterMetrics {
+    /// Number of queries resolved.
+    pub queries: u64,
+    /// Number of responses by response code. Response codes never seen are omitted.
+    pub rcodes: BTreeMap<String, u64>,
+    /// Number of queries being resolved at the moment.
+    pub concurrent: u64,
+    /// The highest number of queries resolved at the same time.
+    pub peak_concurrent: u64,
+    /// Number of queries answered with `SERVFAIL` right away because of the concurrency limit.
+    pub rejected: u64,
+    /// Number of queries failed to be routed or parsed, which are answered with `SERVFAIL` or `FORMERR` respectively.
+    pub errors: u64,
+    /// Number of upstream queries answered by a cached record within its TTL.
+    pub cache_hits: u64,
+    /// Number of upstream queries answered by a cached record with its TTL passed.
+    pub stale_hits: u64,
+    /// Number of upstream queries answered by the upstream itself.
+    pub upstream_responses: u64,
+    /// Number of upstream queries failed.
+    pub upstream_errors: u64,
+    /// Latency of resolving queries.
+    pub latency: LatencyHistogram,
+    /// Latency of upstream queries, including the ones answered by cache.
+    pub upstream_latency: LatencyHistogram,
+    /// Counters of upstream queries by the tag of the upstream. Hybrid upstreams are accounted by the upstreams they race. Upstreams never queried are omitted.
+    pub upstreams: BTreeMap<Label, UpstreamMetrics>,
+}
+
+impl RouterMetrics {
+    /// The ratio of upstream queries answered by cache, stale or not. Returns `None` if there is no successful upstream query.
+    pub fn cache_hit_ratio(&self) -> Option<f64> {
+        let hits = self.cache_hits + self.stale_hits;
+        let total = hits + self.upstream_responses;
+        (total != 0).then(|| hits as f64 / total as f64)
+    }
+
+    /// The median latency of resolving queries. See also `LatencyHistogram::quantile`.
+    pub fn p50(&self) -> Option<Duration> {
+        self.latency.quantile(0.5)
+    }
+
+    /// The 95th percentile latency of resolving queries. See also `LatencyHistogram::quantile`.
+    pub fn p95(&self) -> Option<Duration> {
+        self.latency.quantile(0.95)
+    }
+
+    /// The 99th percentile latency of resolving queries. See also `LatencyHistogram::quantile`.
+    pub fn p99(&self) -> Option<Duration> {
+        self.latency.quantile(0.99)
+    }
+}
+
+// Counters are only for statistics, relaxed ordering is fine.
+#[derive(Default)]
+struct Histogram {
+    counts: [AtomicU64; LATENCY_BUCKETS_MS.len() + 1],
+    sum_us: AtomicU64,
+}
+
+impl Histogram {
+    fn observe(&self, elapsed: Duration) {
+        let n = LATENCY_BUCKETS_MS
+            .iter()
+            .position(|b| elapsed <= Duration::from_millis(*b))
+            .unwrap_or(LATENCY_BUCKETS_MS.len());
+        self.counts[n].fetch_add(1, Ordering::Relaxed);
+        self.sum_us
+            .fetch_add(elapsed.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    fn snapshot(&self) -> LatencyHistogram {
+        LatencyHistogram {
+            bounds_ms: LATENCY_BUCKETS_MS.to_vec(),
+            counts: self
+                .counts
+                .iter()
+                .map(|c| c.load(Ordering::Relaxed))
+                .collect(),
+            sum_us: self.sum_us.load(Ordering::Relaxed),
+        }
+    }
+
+    fn reset(&self) {
+        self.counts
+            .iter()
+            .chain(std::iter::once(&self.sum_us))
+            .for_each(|c| c.store(0, Ordering::Relaxed));
+    }
+}
+
+#[derive(Default)]
+struct UpstreamCounters {
+    cache_hits: AtomicU64,
+    stale_hits: AtomicU64,
+    responses: AtomicU64,
+    errors: AtomicU64,
+}
+
+impl UpstreamCounters {
+    fn observe(&self, source: Option<&RespSource>) {
+        match source {
+            Some(RespSource::Cache) => &self.cache_hits,
+            Some(RespSource::StaleCache) => &self.stale_hits,
+            Some(RespSource::Upstream(_)) => &self.responses,
+            None => &self.errors,
+        }
+        .fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn snapshot(&self) -> UpstreamMetrics {
+        UpstreamMetrics {
+            cache_hits: self.cache_hits.load(Ordering::Relaxed),
+            stale_hits: self.stale_hits.load(Ordering::Relaxed),
+            responses: self.responses.load(Ordering::Relaxed),
+            errors: self.errors.load(Ordering::Relaxed),
+        }
+    }
+}
+
+pub(super) struct ConcurrencyGuard<'a>(&'a Metrics);
+
+impl Drop for ConcurrencyGuard<'_> {
+    fn drop(&mut self) {
+        self.0.concurrent.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+#[derive(Default)]
+pub(super) struct Metrics {
+    queries: AtomicU64,
+    concurrent: AtomicU64,
+    peak_concurrent: AtomicU64,
+    rejected: AtomicU64,
+    // Indexed by the value of the response code, which is four bits in the header.
+    rcodes: [AtomicU64; 16],
+    errors: AtomicU64,
+    cache_hits: AtomicU64,
+    stale_hits: AtomicU64,
+    upstream_responses: AtomicU64,
+    upstream_errors: AtomicU64,
+    latency: Histogram,
+    upstream_latency: Histogram,
+    // Upstreams are only added on their first query, so the write lock is rarely taken.
+    upstreams: RwLock<HashMap<Label, UpstreamCounters>>,
+}
+
+impl Metrics {
+    pub fn observe_query(&self, rcode: Rcode, elapsed: Duration, failed: bool) {
+        self.queries.fetch_add(1, Ordering::Relaxed);
+        self.rcodes[(rcode.to_int() & 0x0f) as usize].fetch_add(1, Ordering::Relaxed);
+        if failed {
+            self.errors.fetch_add(1, Ordering::Relaxed);
+        }
+        self.latency.observe(elapsed);
+    }
+
+    // A query starts being resolved, it finishes when the guard is dropped.
+    pub fn enter(&self) -> ConcurrencyGuard<'_> {
+        let n = self.concurrent.fetch_add(1, Ordering::Relaxed) + 1;
+        self.peak_concurrent.fetch_max(n, Ordering::Relaxed);
+        ConcurrencyGuard(self)
+    }
+
+    pub fn reject(&self) {
+        self.rejected.fetch_add(1, Ordering::Relaxed);
+    }
+
+    // `source` is `None` if the upstream query failed.
+    pub fn observe_upstream(&self, tag: &Label, source: Option<&RespSource>, elapsed: Duration) {
+        match source {
+            Some(RespSource::Cache) => &self.cache_hits,
+            Some(RespSource::StaleCache) => &self.stale_hits,
+            Some(RespSource::Upstream(_)) => &self.upstream_responses,
+            None => &self.upstream_errors,
+        }
+        .fetch_add(1, Ordering::Relaxed);
+        self.upstream_latency.observe(elapsed);
+
+        if let Some(counters) = self.upstreams.read().unwrap().get(tag) {
+            counters.observe(source);
+            return;
+        }
+        self.upstreams
+            .write()
+            .unwrap()
+            .entry(tag.clone())
+            .or_default()
+            .observe(source);
+    }
+
+    pub fn snapshot(&self) -> RouterMetrics {
+        RouterMetrics {
+            queries: self.queries.load(Ordering::Relaxed),
+            concurrent: self.concurrent.load(Ordering::Relaxed),
+            peak_concurrent: self.peak_concurrent.load(Ordering::Relaxed),
+            rejected: self.rejected.load(Ordering::Relaxed),
+            rcodes: self
+                .rcodes
+                .iter()
+                .enumerate()
+                .map(|(n, c)| (n, c.load(Ordering::Relaxed)))
+                .filter(|(_, c)| *c != 0)
+                .map(|(n, c)| (Rcode::from_int(n as u8).to_string(), c))
+                .collect(),
+            errors: self.errors.load(Ordering::Relaxed),
+            cache_hits: self.cache_hits.load(Ordering::Relaxed),
+            stale_hits: self.stale_hits.load(Ordering::Relaxed),
+            upstream_responses: self.upstream_responses.load(Ordering::Relaxed),
+            upstream_errors: self.upstream_errors.load(Ordering::Relaxed),
+            latency: self.latency.snapshot(),
+            upstream_latency: self.upstream_latency.snapshot(),
+            upstreams: self
+                .upstreams
+                .read()
+                .unwrap()
+                .iter()
+                .map(|(tag, c)| (tag.clone(), c.snapshot()))
+                .collect(),
+        }
+    }
+
+    pub fn reset(&self) {
+        // Queries being resolved are still there, so the peak starts from them.
+        self.peak_concurrent
+            .store(self.concurrent.load(Ordering::Relaxed), Ordering::Relaxed);
+        [
+            &self.queries,
+            &self.rejected,
+            &self.errors,
+            &self.cache_hits,
+            &self.stale_hits,
+            &self.upstream_responses,
+            &self.upstream_errors,
+        ]
+        .into_iter()
+        .chain(self.rcodes.iter())
+        .for_each(|c| c.store(0, Ordering::Relaxed));
+        self.latency.reset();
+        self.upstream_latency.reset();
+        self.upstreams.write().unwrap().clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Histogram, LATENCY_BUCKETS_MS};
+    use std::time::Duration;
+
+    #[test]
+    fn bucket_boundaries() {
+        let h = Histogram::default();
+        // Bounds are inclusive
+        h.observe(Duration::ZERO);
+        h.observe(Duration::from_millis(1));
+        h.observe(Duration::from_micros(1001));
+        h.observe(Duration::from_millis(5000));
+        h.observe(Duration::from_millis(5001));
+        let s = h.snapshot();
+        assert_eq!(s.bounds_ms, LATENCY_BUCKETS_MS.to_vec());
+        assert_eq!(s.counts.len(), LATENCY_BUCKETS_MS.len() + 1);
+        assert_eq!(s.counts[0], 2);
+        assert_eq!(s.counts[1], 1);
+        assert_eq!(s.counts[LATENCY_BUCKETS_MS.len() - 1], 1);
+        assert_eq!(s.counts[LATENCY_BUCKETS_MS.len()], 1);
+        assert_eq!(s.count(), 5);
+        assert_eq!(s.sum_us, 10_003_001);
+    }
+
+    #[test]
+    fn quantile() {
+        let h = Histogram::default();
+        assert_eq!(h.snapshot().quantile(0.5), None);
+        (0..90).for_each(|_| h.observe(Duration::from_millis(3)));
+        (0..9).for_each(|_| h.observe(Duration::from_millis(150)));
+        h.observe(Duration::from_secs(10));
+        let s = h.snapshot();
+        assert_eq!(s.quantile(0.5), Some(Duration::from_millis(5)));
+        assert_eq!(s.quantile(0.95), Some(Duration::from_millis(200)));
+        assert_eq!(s.quantile(0.99), Some(Duration::from_millis(200)));
+        // Falls above the last bound
+        assert_eq!(s.quantile(1.0), None);
+
+        h.reset();
+        assert_eq!(h.snapshot().count(), 0);
+    }
+}
@@ -0,0 +1,244 @@
+// Copyright 2020 LEXUGE
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Render the metrics of a router in the Prometheus text exposition format, ready to be served from any HTTP framework.
+//!
+//! Labels only take values bounded by the configuration, namely tags of upstreams, tables, and rules, or by the protocol, namely response codes.
+//! Query names and client addresses are never recorded, so they can never end up as labels.
+
+use super::{metrics::LatencyHistogram, Router};
+use std::fmt::Display;
+
+/// The content type of the rendered metrics.
+pub const CONTENT_TYPE: &str = "text/plain; version=0.0.4";
+
+// Every label there is. A new label has to be added here, so that its cardinality gets reviewed.
+#[derive(Clone, Copy)]
+enum LabelName {
+    // Response code, at most 16 of them.
+    Rcode,
+    // Tag of an upstream.
+    Upstream,
+    // Where the response to an upstream query comes from.
+    Outcome,
+    // Name of a routing table.
+    Table,
+    // Tag of a rule.
+    Rule,
+    // Branch of a rule taken.
+    Branch,
+    // Upper bound of a histogram bucket.
+    Le,
+}
+
+impl LabelName {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Self::Rcode => "rcode",
+            Self::Upstream => "upstream",
+            Self::Outcome => "outcome",
+            Self::Table => "table",
+            Self::Rule => "rule",
+            Self::Branch => "branch",
+            Self::Le => "le",
+        }
+    }
+}
+
+// Escape a label value as required by the exposition format.
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', r"\\")
+        .replace('"', r#"\""#)
+        .replace('\n', r"\n")
+}
+
+#[derive(Default)]
+struct Exposition(String);
+
+impl Exposition {
+    fn family(&mut self, name: &str, kind: &str, help: &str) {
+        self.0.push_str(&format!(
+            "# HELP droute_{name} {help}\n# TYPE droute_{name} {kind}\n"
+        ));
+    }
+
+    fn sample(&mut self, name: &str, labels: &[(LabelName, &str)], value: impl Display) {
+        let labels = labels
+            .iter()
+            .map(|(k, v)| format!("{}=\"{}\"", k.as_str(), escape(v)))
+            .collect::<Vec<_>>();
+        if labels.is_empty() {
+            self.0.push_str(&format!("droute_{name} {value}\n"));
+        } else {
+            self.0
+                .push_str(&format!("droute_{name}{{{}}} {value}\n", labels.join(",")));
+        }
+    }
+
+    fn counter(&mut self, name: &str, help: &str, value: u64) {
+        self.family(name, "counter", help);
+        self.sample(name, &[], value);
+    }
+
+    fn gauge(&mut self, name: &str, help: &str, value: u64) {
+        self.family(name, "gauge", help);
+        self.sample(name, &[], value);
+    }
+
+    fn histogram(&mut self, name: &str, help: &str, h: &LatencyHistogram) {
+        self.family(name, "histogram", help);
+        let bucket = format!("{name}_bucket");
+        let mut cumulative = 0;
+        for (bound, count) in h.bounds_ms.iter().zip(&h.counts) {
+            cumulative += count;
+            let le = (*bound as f64 / 1000.0).to_string();
+            self.sample(&bucket, &[(LabelName::Le, &le)], cumulative);
+        }
+        self.sample(&bucket, &[(LabelName::Le, "+Inf")], h.count());
+        self.sample(&format!("{name}_sum"), &[], h.sum_us as f64 / 1_000_000.0);
+        self.sample(&format!("{name}_count"), &[], h.count());
+    }
+}
+
+/// Render the aggregate metrics, the per-upstream counters, and the per-rule counters of all the current routing tables. Latencies are in seconds.
+/// Metric names and labels are stable, and samples are sorted by their labels.
+pub fn render_prometheus(router: &Router) -> String {
+    let metrics = router.metrics();
+    let mut e = Exposition::default();
+
+    e.counter(
+        "queries_total",
+        "Number of queries resolved.",
+        metrics.queries,
+    );
+    e.family(
+        "responses_total",
+        "counter",
+        "Number of responses by response code.",
+    );
+    for (rcode, count) in &metrics.rcodes {
+        e.sample("responses_total", &[(LabelName::Rcode, rcode)], count);
+    }
+    e.counter(
+        "query_errors_total",
+        "Number of queries failed to be routed or parsed.",
+        metrics.errors,
+    );
+    e.counter(
+        "queries_rejected_total",
+        "Number of queries rejected because of the concurrency limit.",
+        metrics.rejected,
+    );
+    e.gauge(
+        "queries_in_flight",
+        "Number of queries being resolved.",
+        metrics.concurrent,
+    );
+    e.gauge(
+        "queries_in_flight_peak",
+        "The highest number of queries resolved at the same time.",
+        metrics.peak_concurrent,
+    );
+    e.histogram(
+        "query_duration_seconds",
+        "Latency of resolving queries.",
+        &metrics.latency,
+    );
+
+    e.family(
+        "upstream_queries_total",
+        "counter",
+        "Number of upstream queries by upstream and where the response comes from.",
+    );
+    for (tag, u) in &metrics.upstreams {
+        for (outcome, count) in [
+            ("cache", u.cache_hits),
+            ("error", u.errors),
+            ("stale", u.stale_hits),
+            ("upstream", u.responses),
+        ] {
+            e.sample(
+                "upstream_queries_total",
+                &[(LabelName::Upstream, tag), (LabelName::Outcome, outcome)],
+                count,
+            );
+        }
+    }
+    e.histogram(
+        "upstream_query_duration_seconds",
+        "Latency of upstream queries, including the ones answered by cache.",
+        &metrics.upstream_latency,
+    );
+
+    let core = router.core.load();
+    let mut tables: Vec<_> = core
+        .tables
+        .iter()
+        .map(|(name, table)| {
+            let mut stats: Vec<_> = table.stats().into_iter().collect();
+            stats.sort_by(|a, b| a.0.cmp(&b.0));
+            (name, stats)
+        })
+        .collect();
+    tables.sort_by(|a, b| a.0.cmp(b.0));
+    e.family(
+        "rule_evaluations_total",
+        "counter",
+        "Number of times a rule was entered.",
+    );
+    for (table, stats) in &tables {
+        for (rule, s) in stats {
+            e.sample(
+                "rule_evaluations_total",
+                &[(LabelName::Table, table), (LabelName::Rule, rule)],
+                s.evaluations,
+            );
+        }
+    }
+    e.family(
+        "rule_branches_total",
+        "counter",
+        "Number of times a branch of a rule with a matcher was taken.",
+    );
+    for (table, stats) in &tables {
+        for (rule, s) in stats {
+            for (branch, count) in [("else", s.no_match), ("then", s.on_match)] {
+                e.sample(
+                    "rule_branches_total",
+                    &[
+                        (LabelName::Table, table),
+                        (LabelName::Rule, rule),
+                        (LabelName::Branch, branch),
+                    ],
+                    count,
+                );
+            }
+        }
+    }
+
+    e.0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::escape;
+
+    #[test]
+    fn escape_label_values() {
+        assert_eq!(escape(r#"a\b"c"#), r#"a\\b\"c"#);
+        assert_eq!(escape("a\nb"), r"a\nb");
+    }
+}
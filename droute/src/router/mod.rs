@@ -15,77 +15,562 @@
 
 //! Router is the core concept of `droute`.
 
+pub mod metrics;
+#[cfg(feature = "metrics-export")]
+pub mod metrics_export;
+pub mod preflight;
+#[cfg(feature = "schema")]
+pub(crate) mod schema;
 pub mod table;
 pub mod upstreams;
+pub mod warmup;
 
 use self::{
-    table::{QueryContext, Table, TableError},
+    metrics::{Metrics, RouterMetrics},
+    table::{
+        stats::RuleStats, trace::RouteTrace, QueryContext, RouteOptions, Table, TableError,
+        Unanswered,
+    },
     upstreams::{error::UpstreamError, Upstreams},
 };
 use crate::{
-    error::{DrouteError, Result},
+    error::{ConfigError, DrouteError, Result},
     AsyncTryInto, Label, Validatable, MAX_LEN,
 };
+use arc_swap::ArcSwap;
 use async_trait::async_trait;
 use bytes::{Bytes, BytesMut};
 use domain::base::{iana::rcode::Rcode, Message, MessageBuilder};
-use log::warn;
+use futures::StreamExt;
+use log::{info, warn};
+use serde::{de::DeserializeOwned, Deserialize};
+use serde_yaml::{Mapping, Value};
+use std::{
+    collections::HashMap,
+    num::NonZeroUsize,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+use tokio::{
+    sync::{Notify, Semaphore, SemaphorePermit},
+    time::Instant,
+};
 
-/// Router implementation.
-pub struct Router {
-    table: Table,
-    upstreams: Upstreams,
+/// The default deadline for a query to be routed through the table.
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The default maximum number of rules a query may go through.
+pub const DEFAULT_MAX_STEPS: usize = 64;
+
+/// The name of the routing table queries go through if no table is selected. `Router::new` names its sole table so.
+pub const DEFAULT_TABLE: &str = "default";
+
+/// The default maximum number of rules or upstreams built at the same time, so that e.g. a list server is not hit by every download at once.
+pub const DEFAULT_MAX_CONCURRENT_BUILDS: usize = 8;
+
+// Picks the routing table for a query by its context.
+type Selector = Box<dyn Fn(Option<&QueryContext>) -> Label + Send + Sync>;
+
+// Queries being resolved, used to drain them on shutdown.
+#[derive(Default)]
+struct InFlight {
+    count: AtomicUsize,
+    // Notified when the last query finishes.
+    drained: Notify,
+}
+
+// Decrements the count on drop, so that queries cancelled are also accounted for.
+struct InFlightGuard<'a>(&'a InFlight);
+
+impl<'a> InFlightGuard<'a> {
+    fn new(inflight: &'a InFlight) -> Self {
+        inflight.count.fetch_add(1, Ordering::SeqCst);
+        Self(inflight)
+    }
+}
+
+impl Drop for InFlightGuard<'_> {
+    fn drop(&mut self) {
+        if self.0.count.fetch_sub(1, Ordering::SeqCst) == 1 {
+            // A permit is stored if no one is waiting yet.
+            self.0.drained.notify_one();
+        }
+    }
+}
+
+// Limit on the number of queries resolved at the same time.
+struct Limit {
+    semaphore: Semaphore,
+    // How long to wait for a permit. Fail right away if `None`.
+    wait: Option<Duration>,
+}
+
+impl Limit {
+    async fn acquire(&self) -> Option<SemaphorePermit<'_>> {
+        match self.wait {
+            Some(wait) => tokio::time::timeout(wait, self.semaphore.acquire())
+                .await
+                .ok()?
+                .ok(),
+            None => self.semaphore.try_acquire().ok(),
+        }
+    }
+}
+
+// Build an empty response to the query with the response code.
+fn reply(msg: &Message<Bytes>, rcode: Rcode) -> Result<Message<Bytes>> {
+    Ok(
+        MessageBuilder::from_target(BytesMut::with_capacity(MAX_LEN))?
+            .start_answer(msg, rcode)?
+            .into_message(),
+    )
+}
+
+// The routing tables and the upstreams they share, which are always swapped together.
+struct Core {
+    tables: HashMap<Label, Table>,
+    upstreams: Arc<Upstreams>,
 }
 
-impl Validatable for Router {
+impl Core {
+    // A sole default table, as created by `Router::new`.
+    fn single(table: Table, upstreams: Arc<Upstreams>) -> Self {
+        Self {
+            tables: HashMap::from([(DEFAULT_TABLE.into(), table)]),
+            upstreams,
+        }
+    }
+}
+
+impl Validatable for Core {
     type Error = DrouteError;
     fn validate(&self, _: Option<&Vec<Label>>) -> Result<()> {
-        self.table.validate(None)?;
-        self.upstreams.validate(Some(self.table.used_upstreams()))?;
-        Ok(())
+        let mut names: Vec<&Label> = self.tables.keys().collect();
+        names.sort();
+        let mut errors = Vec::new();
+        let mut used = Vec::new();
+        for name in names {
+            let table = &self.tables[name];
+            if let Err(e) = table.validate(None) {
+                errors.push(e.into());
+            }
+            used.extend(table.used_upstreams().iter().cloned());
+        }
+        // Every table shares the upstreams, so they have to serve the union.
+        used.sort();
+        used.dedup();
+        if let Err(e) = self.upstreams.validate(Some(&used)) {
+            errors.push(e.into());
+        }
+        DrouteError::collect(errors)
     }
 }
 
+/// Router implementation.
+pub struct Router {
+    core: ArcSwap<Core>,
+    timeout: Duration,
+    max_steps: usize,
+    on_unanswered: Unanswered,
+    metrics: Arc<Metrics>,
+    shutting_down: AtomicBool,
+    // Answer queries with SERVFAIL instead of returning an error after shutdown.
+    servfail_on_shutdown: bool,
+    inflight: InFlight,
+    limit: Option<Limit>,
+    // ID of the next query resolved.
+    next_id: AtomicU64,
+    // Picks the table if the caller doesn't name one. The default table is used if `None`.
+    selector: Option<Selector>,
+}
+
 impl Router {
     /// Create a new `Router` from raw
     pub fn new(table: Table, upstreams: Upstreams) -> Result<Self> {
-        let router = Self { table, upstreams };
-        router.validate(None)?;
-        Ok(router)
+        Self::with_core(|upstreams| Core::single(table, upstreams), upstreams)
+    }
+
+    /// Create a new `Router` with multiple named routing tables sharing the same upstreams, together with their caches and connections.
+    /// Queries go through the table picked by the selector set with `with_table_selector`, the one named explicitly with `resolve_in_table`, or otherwise the one named `DEFAULT_TABLE`.
+    /// Every table is validated against the upstreams.
+    pub fn new_multi(tables: HashMap<Label, Table>, upstreams: Upstreams) -> Result<Self> {
+        Self::with_core(|upstreams| Core { tables, upstreams }, upstreams)
+    }
+
+    fn with_core(
+        core: impl FnOnce(Arc<Upstreams>) -> Core,
+        mut upstreams: Upstreams,
+    ) -> Result<Self> {
+        let metrics = Arc::new(Metrics::default());
+        Self::observe(&mut upstreams, &metrics);
+        let core = core(Arc::new(upstreams));
+        core.validate(None)?;
+        Ok(Self {
+            core: ArcSwap::from_pointee(core),
+            timeout: DEFAULT_TIMEOUT,
+            max_steps: DEFAULT_MAX_STEPS,
+            on_unanswered: Unanswered::default(),
+            metrics,
+            shutting_down: AtomicBool::new(false),
+            servfail_on_shutdown: false,
+            inflight: InFlight::default(),
+            limit: None,
+            next_id: AtomicU64::new(1),
+            selector: None,
+        })
+    }
+
+    /// Pick the routing table for each query resolved by `resolve`, `resolve_with_ctx`, and `resolve_traced` by its context.
+    /// The context is `None` if the query comes without one. Queries selecting a table that doesn't exist fail with `DrouteError::UnknownTable`.
+    pub fn with_table_selector(
+        mut self,
+        selector: impl Fn(Option<&QueryContext>) -> Label + Send + Sync + 'static,
+    ) -> Self {
+        self.selector = Some(Box::new(selector));
+        self
+    }
+
+    /// Limit the number of queries resolved at the same time. Queries beyond the limit wait up to `wait` for others to finish, or fail right away if `wait` is `None`, and are answered with `SERVFAIL`.
+    /// Queries are only limited on entry, routing never resolves through the router again, so it cannot deadlock.
+    pub fn with_max_concurrent_queries(
+        mut self,
+        max: NonZeroUsize,
+        wait: Option<Duration>,
+    ) -> Self {
+        self.limit = Some(Limit {
+            semaphore: Semaphore::new(max.get()),
+            wait,
+        });
+        self
+    }
+
+    /// Whether queries arriving after shutdown are answered with `SERVFAIL` instead of returning `DrouteError::ShuttingDown`. Default to `false`.
+    pub fn with_servfail_on_shutdown(mut self, servfail: bool) -> Self {
+        self.servfail_on_shutdown = servfail;
+        self
+    }
+
+    /// Stop accepting new queries and wait up to `grace` for the queries being resolved to finish.
+    /// Background tasks started by the router, like cache warm-up, stop sending queries as well.
+    /// Returns whether all the queries finished within the grace period.
+    pub async fn shutdown(&self, grace: Duration) -> bool {
+        self.shutting_down.store(true, Ordering::SeqCst);
+        info!(
+            "router shutting down, waiting for {} queries",
+            self.inflight.count.load(Ordering::SeqCst)
+        );
+        let deadline = Instant::now() + grace;
+        while self.inflight.count.load(Ordering::SeqCst) != 0 {
+            if tokio::time::timeout_at(deadline, self.inflight.drained.notified())
+                .await
+                .is_err()
+            {
+                warn!(
+                    "router shut down with {} queries unfinished",
+                    self.inflight.count.load(Ordering::SeqCst)
+                );
+                return false;
+            }
+        }
+        info!("router shut down");
+        true
+    }
+
+    /// Whether the router is shutting down.
+    pub fn is_shutting_down(&self) -> bool {
+        self.shutting_down.load(Ordering::SeqCst)
+    }
+
+    // Feed the metrics with upstream queries.
+    fn observe(upstreams: &mut Upstreams, metrics: &Arc<Metrics>) {
+        let metrics = metrics.clone();
+        upstreams.set_observer(Arc::new(move |tag, source, elapsed| {
+            metrics.observe_upstream(tag, source, elapsed)
+        }));
+    }
+
+    /// Set the deadline for a query to be routed through the table, after which `SERVFAIL` is returned. Default to `DEFAULT_TIMEOUT`.
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Set the maximum number of rules a query may go through, after which `SERVFAIL` is returned. Default to `DEFAULT_MAX_STEPS`.
+    pub fn with_max_steps(mut self, max_steps: usize) -> Self {
+        self.max_steps = max_steps;
+        self
     }
 
-    /// Resolve the DNS query with routing rules defined.
-    pub async fn resolve(
+    /// Set what to answer if routing reaches `end` without any action having set a response. Default to `Unanswered::Echo`.
+    pub fn with_on_unanswered(mut self, on_unanswered: Unanswered) -> Self {
+        self.on_unanswered = on_unanswered;
+        self
+    }
+
+    /// Atomically replace the routing table, and optionally the upstreams.
+    /// Queries already being processed finish with the old configuration.
+    /// If `upstreams` is `None`, the current upstreams, together with their caches and connections, are kept and the new table is validated against them.
+    /// On validation failure, the current configuration stays in effect.
+    /// The sole routing table replaces all the tables, if there were multiple, as the default one.
+    pub fn reload(&self, table: Table, upstreams: Option<Upstreams>) -> Result<()> {
+        self.reload_core(|upstreams| Core::single(table, upstreams), upstreams)
+    }
+
+    /// Atomically replace all the named routing tables, and optionally the upstreams, like `reload`.
+    pub fn reload_multi(
         &self,
+        tables: HashMap<Label, Table>,
+        upstreams: Option<Upstreams>,
+    ) -> Result<()> {
+        self.reload_core(|upstreams| Core { tables, upstreams }, upstreams)
+    }
+
+    fn reload_core(
+        &self,
+        core: impl FnOnce(Arc<Upstreams>) -> Core,
+        upstreams: Option<Upstreams>,
+    ) -> Result<()> {
+        let core = core(match upstreams {
+            Some(mut u) => {
+                Self::observe(&mut u, &self.metrics);
+                Arc::new(u)
+            }
+            None => self.core.load().upstreams.clone(),
+        });
+        core.validate(None)?;
+        self.core.store(Arc::new(core));
+        info!("router reloaded");
+        Ok(())
+    }
+
+    /// A snapshot of the per-rule counters of the current default routing table. Counters start from zero after a reload.
+    /// Returns an empty map if there is no default table.
+    pub fn table_stats(&self) -> HashMap<Label, RuleStats> {
+        self.table_stats_of(DEFAULT_TABLE).unwrap_or_default()
+    }
+
+    /// A snapshot of the per-rule counters of the current routing table named `table`, if it exists.
+    pub fn table_stats_of(&self, table: &str) -> Option<HashMap<Label, RuleStats>> {
+        self.core.load().tables.get(table).map(Table::stats)
+    }
+
+    /// Reset the per-rule counters of all the current routing tables.
+    pub fn reset_table_stats(&self) {
+        self.core
+            .load()
+            .tables
+            .values()
+            .for_each(Table::reset_stats)
+    }
+
+    /// A snapshot of the aggregate metrics of every query resolved. Metrics are kept across reloads.
+    pub fn metrics(&self) -> RouterMetrics {
+        self.metrics.snapshot()
+    }
+
+    /// Reset the aggregate metrics to zero.
+    pub fn reset_metrics(&self) {
+        self.metrics.reset()
+    }
+
+    /// Resolve the DNS query with routing rules defined, without any query context.
+    /// Queries without exactly one well-formed question are answered with `FORMERR`, and errors during routing with `SERVFAIL`.
+    pub async fn resolve(&self, msg: Message<Bytes>) -> Result<Message<Bytes>> {
+        self.resolve_with_ctx(msg, None).await
+    }
+
+    /// Resolve the DNS query with routing rules defined. `qctx` is handed to the table so that matchers and actions depending on it (e.g. the sender's IP) work.
+    pub async fn resolve_with_ctx(
+        &self,
+        msg: Message<Bytes>,
+        qctx: Option<QueryContext>,
+    ) -> Result<Message<Bytes>> {
+        Ok(self.resolve_inner(msg, qctx, None, false).await?.0)
+    }
+
+    /// Resolve the DNS query like `resolve_with_ctx` through the routing table named `table`, regardless of the table selector.
+    pub async fn resolve_in_table(
+        &self,
+        table: &str,
         msg: Message<Bytes>,
         qctx: Option<QueryContext>,
     ) -> Result<Message<Bytes>> {
-        // We have to ensure the number of queries is larger than 0 as it is a gurantee for actions/matchers.
-        // Not using `query_count()` because it is manually set, and may not be correct.
-        Ok(match msg.sole_question() {
-            Ok(_) => {
+        Ok(self.resolve_inner(msg, qctx, Some(table), false).await?.0)
+    }
+
+    /// Resolve the DNS query like `resolve_with_ctx`, and record the path it took through the routing table.
+    /// This is meant for debugging, tracing is never done on the other entry points.
+    pub async fn resolve_traced(
+        &self,
+        msg: Message<Bytes>,
+        qctx: Option<QueryContext>,
+    ) -> Result<(Message<Bytes>, RouteTrace)> {
+        let (msg, trace) = self.resolve_inner(msg, qctx, None, true).await?;
+        Ok((msg, trace.unwrap_or_default()))
+    }
+
+    /// Resolve a batch of DNS queries like `resolve_with_ctx`, concurrently within the calling task. At most `max_concurrent` queries of the batch are resolved at the same time if given.
+    /// Responses are in the same order as the queries, and failure of a single query doesn't affect the rest.
+    pub async fn resolve_batch(
+        &self,
+        msgs: Vec<(Message<Bytes>, Option<QueryContext>)>,
+        max_concurrent: Option<NonZeroUsize>,
+    ) -> Vec<Result<Message<Bytes>>> {
+        // `buffered` never makes progress with a limit of zero, which would only happen on an empty batch.
+        let max = max_concurrent.map_or(msgs.len(), NonZeroUsize::get).max(1);
+        futures::stream::iter(msgs)
+            .map(|(msg, qctx)| self.resolve_with_ctx(msg, qctx))
+            .buffered(max)
+            .collect()
+            .await
+    }
+
+    async fn resolve_inner(
+        &self,
+        msg: Message<Bytes>,
+        qctx: Option<QueryContext>,
+        table: Option<&str>,
+        traced: bool,
+    ) -> Result<(Message<Bytes>, Option<RouteTrace>)> {
+        // Count the query before checking the flag, so that shutdown never misses a query admitted.
+        let _guard = InFlightGuard::new(&self.inflight);
+        if self.is_shutting_down() {
+            return if self.servfail_on_shutdown {
+                Ok((reply(&msg, Rcode::ServFail)?, None))
+            } else {
+                Err(DrouteError::ShuttingDown)
+            };
+        }
+
+        // Hold the current configuration until we are done, even if it is swapped out in the meantime.
+        let core = self.core.load_full();
+        let name: Label = match (table, &self.selector) {
+            (Some(t), _) => t.into(),
+            (None, Some(selector)) => selector(qctx.as_ref()),
+            (None, None) => DEFAULT_TABLE.into(),
+        };
+        let table = core
+            .tables
+            .get(&name)
+            .ok_or_else(|| DrouteError::UnknownTable(name.clone()))?;
+
+        let start = Instant::now();
+        // Hold the permit until we are done.
+        let _permit = match &self.limit {
+            Some(limit) => match limit.acquire().await {
+                Some(permit) => Some(permit),
+                None => {
+                    warn!("too many queries being resolved, returning SERVFAIL");
+                    self.metrics.reject();
+                    let resp = reply(&msg, Rcode::ServFail)?;
+                    self.metrics
+                        .observe_query(Rcode::ServFail, start.elapsed(), false);
+                    return Ok((resp, None));
+                }
+            },
+            None => None,
+        };
+        let _concurrency = self.metrics.enter();
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let mut failed = false;
+        // We have to ensure there is exactly one question which parses as it is a gurantee for actions/matchers.
+        // Multiple questions are legal but practically never used, we answer them with FORMERR as most servers do.
+        let (resp, trace) = match msg.sole_question() {
+            Ok(_q) => {
+                let opts = RouteOptions {
+                    id,
+                    deadline: Some(Instant::now() + self.timeout),
+                    max_steps: self.max_steps,
+                    on_unanswered: self.on_unanswered,
+                };
+                #[cfg(feature = "tracing")]
+                let span = {
+                    let span = tracing::info_span!(
+                        "query",
+                        id,
+                        qname = %_q.qname(),
+                        qtype = %_q.qtype(),
+                        client = tracing::field::Empty
+                    );
+                    if let Some(qctx) = &qctx {
+                        span.record("client", &tracing::field::display(qctx.ip()));
+                    }
+                    span
+                };
                 // Clone should be cheap here guaranteed by Bytes
-                match self.table.route(msg.clone(), qctx, &self.upstreams).await {
-                    Ok(m) => m,
+                let route = async {
+                    if traced {
+                        let (r, trace) = table
+                            .route_traced(msg.clone(), qctx, opts, &core.upstreams)
+                            .await;
+                        (r, Some(trace))
+                    } else {
+                        (
+                            table.route(msg.clone(), qctx, opts, &core.upstreams).await,
+                            None,
+                        )
+                    }
+                };
+                #[cfg(feature = "tracing")]
+                let route = tracing::Instrument::instrument(route, span);
+                let (r, trace) = route.await;
+                match r {
+                    Ok(m) => (m, trace),
                     Err(e) => {
                         // Catch all server failure here and return server fail
-                        warn!("upstream encountered error: {}, returning SERVFAIL", e);
-                        MessageBuilder::from_target(BytesMut::with_capacity(MAX_LEN))?
-                            .start_answer(&msg, Rcode::ServFail)?
-                            .into_message()
+                        warn!(
+                            "query {}: upstream encountered error: {}, returning SERVFAIL",
+                            id, e
+                        );
+                        failed = true;
+                        (reply(&msg, Rcode::ServFail)?, trace)
                     }
                 }
             }
             Err(e) => {
-                warn!("DNS message parsing errored: {}.", e);
-                MessageBuilder::from_target(BytesMut::with_capacity(MAX_LEN))?
-                    .start_answer(&msg, Rcode::ServFail)?
-                    .into_message()
+                warn!(
+                    "query {}: malformed question section: {}, returning FORMERR",
+                    id, e
+                );
+                failed = true;
+                // Malformed questions are skipped when building the response.
+                (reply(&msg, Rcode::FormErr)?, None)
             }
-        })
+        };
+        self.metrics
+            .observe_query(resp.header().rcode(), start.elapsed(), failed);
+        Ok((resp, trace))
     }
 }
 
+// Settings of the router in the configuration besides the table and the upstreams.
+#[derive(Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+#[serde(deny_unknown_fields)]
+struct Settings {
+    /// Deadline in seconds for a query to be routed through the table.
+    #[serde(default)]
+    timeout: Option<u64>,
+    /// Maximum number of rules a query may go through.
+    #[serde(default)]
+    max_steps: Option<usize>,
+    /// What to answer if routing reaches `end` without any response.
+    #[serde(default)]
+    on_unanswered: Option<Unanswered>,
+    /// Maximum number of queries resolved at the same time.
+    #[serde(default)]
+    max_concurrent_queries: Option<NonZeroUsize>,
+    /// Time in milliseconds queries beyond the limit wait before failing. They fail right away if not set.
+    #[serde(default)]
+    queue_timeout_ms: Option<u64>,
+}
+
+// Fields of the `dcompass` configuration only meaningful to the server itself.
+const SERVER_FIELDS: [&str; 3] = ["address", "verbosity", "warm_up"];
+
 /// A Builder for Router.
 pub struct RouterBuilder<T, U>
 where
@@ -94,6 +579,11 @@ where
 {
     table: T,
     upstreams: U,
+    timeout: Duration,
+    max_steps: usize,
+    on_unanswered: Unanswered,
+    servfail_on_shutdown: bool,
+    limit: Option<(NonZeroUsize, Option<Duration>)>,
 }
 
 impl<T, U> RouterBuilder<T, U>
@@ -103,7 +593,88 @@ where
 {
     /// Create a RouteBuilder
     pub fn new(table: T, upstreams: U) -> Self {
-        Self { table, upstreams }
+        Self {
+            table,
+            upstreams,
+            timeout: DEFAULT_TIMEOUT,
+            max_steps: DEFAULT_MAX_STEPS,
+            on_unanswered: Unanswered::default(),
+            servfail_on_shutdown: false,
+            limit: None,
+        }
+    }
+
+    /// Set the deadline for a query to be routed through the table. Default to `DEFAULT_TIMEOUT`.
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Set the maximum number of rules a query may go through. Default to `DEFAULT_MAX_STEPS`.
+    pub fn max_steps(mut self, max_steps: usize) -> Self {
+        self.max_steps = max_steps;
+        self
+    }
+
+    /// Set what to answer if routing reaches `end` without any action having set a response. Default to `Unanswered::Echo`.
+    pub fn on_unanswered(mut self, on_unanswered: Unanswered) -> Self {
+        self.on_unanswered = on_unanswered;
+        self
+    }
+
+    /// Whether queries arriving after shutdown are answered with `SERVFAIL` instead of an error. Default to `false`.
+    pub fn servfail_on_shutdown(mut self, servfail: bool) -> Self {
+        self.servfail_on_shutdown = servfail;
+        self
+    }
+
+    /// Limit the number of queries resolved at the same time. See also `Router::with_max_concurrent_queries`.
+    pub fn max_concurrent_queries(mut self, max: NonZeroUsize, wait: Option<Duration>) -> Self {
+        self.limit = Some((max, wait));
+        self
+    }
+}
+
+impl<T, U> RouterBuilder<T, U>
+where
+    T: AsyncTryInto<Table, Error = TableError> + DeserializeOwned,
+    U: AsyncTryInto<Upstreams, Error = UpstreamError> + DeserializeOwned,
+{
+    /// Parse the configuration in the same form as the `dcompass` configuration, either in YAML or JSON.
+    /// Fields only meaningful to the server, namely `address`, `verbosity`, and `warm_up`, are ignored. Any other unknown field is rejected.
+    pub fn from_config_str(s: &str) -> std::result::Result<Self, ConfigError> {
+        let mut config: Mapping = serde_yaml::from_str(s).map_err(ConfigError::yaml("config"))?;
+        let table = config
+            .remove(&"table".into())
+            .ok_or(ConfigError::MissingSection("table"))?;
+        let table = serde_yaml::from_value(table).map_err(ConfigError::yaml("table"))?;
+        let upstreams: Mapping = ["upstreams", "cache_size"]
+            .into_iter()
+            .filter_map(|k| config.remove(&k.into()).map(|v| (k.into(), v)))
+            .collect();
+        let upstreams = serde_yaml::from_value(Value::Mapping(upstreams))
+            .map_err(ConfigError::yaml("upstreams"))?;
+        for k in SERVER_FIELDS {
+            config.remove(&k.into());
+        }
+        let settings: Settings =
+            serde_yaml::from_value(Value::Mapping(config)).map_err(ConfigError::yaml("router"))?;
+
+        let mut builder = Self::new(table, upstreams);
+        if let Some(timeout) = settings.timeout {
+            builder = builder.timeout(Duration::from_secs(timeout));
+        }
+        if let Some(max_steps) = settings.max_steps {
+            builder = builder.max_steps(max_steps);
+        }
+        if let Some(on_unanswered) = settings.on_unanswered {
+            builder = builder.on_unanswered(on_unanswered);
+        }
+        if let Some(max) = settings.max_concurrent_queries {
+            builder = builder
+                .max_concurrent_queries(max, settings.queue_timeout_ms.map(Duration::from_millis));
+        }
+        Ok(builder)
     }
 }
 
@@ -116,9 +687,670 @@ where
     type Error = DrouteError;
 
     /// Build a new `Router` from configuration and check the validity. `data` is the content of the configuration file.
+    /// Problems of both the table and the upstreams are reported together.
     async fn async_try_into(self) -> Result<Router> {
-        let table = self.table.async_try_into().await?;
-        let upstreams = self.upstreams.async_try_into().await?;
-        Router::new(table, upstreams)
+        // Neither waits for the other, so rules are loaded while upstreams are bootstrapped.
+        let (table, upstreams) =
+            match futures::join!(self.table.async_try_into(), self.upstreams.async_try_into()) {
+                (Ok(table), Ok(upstreams)) => (table, upstreams),
+                (table, upstreams) => {
+                    // At least one of them failed.
+                    return Err(DrouteError::collect(
+                        [
+                            table.err().map(DrouteError::from),
+                            upstreams.err().map(DrouteError::from),
+                        ]
+                        .into_iter()
+                        .flatten(),
+                    )
+                    .unwrap_err());
+                }
+            };
+        let router = Router::new(table, upstreams)?
+            .with_timeout(self.timeout)
+            .with_max_steps(self.max_steps)
+            .with_on_unanswered(self.on_unanswered)
+            .with_servfail_on_shutdown(self.servfail_on_shutdown);
+        Ok(match self.limit {
+            Some((max, wait)) => router.with_max_concurrent_queries(max, wait),
+            None => router,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        table::{
+            rule::{
+                actions::{Action, Blackhole, Result as ActionResult},
+                matchers::Matcher,
+                IfBlock, Next, Rule, SeqBlock,
+            },
+            Protocol, QueryContext, RouteOptions, State, Table, TableError, Unanswered,
+        },
+        upstreams::Upstreams,
+        Router,
+    };
+    use crate::{builders::*, error::ConfigError, stats::RuleStats, AsyncTryInto, Label, WarmUp};
+    use async_trait::async_trait;
+    use bytes::{Bytes, BytesMut};
+    use domain::base::{iana::Rcode, Dname, Message, MessageBuilder, Rtype};
+    use std::{
+        collections::HashMap,
+        net::{IpAddr, SocketAddr},
+        num::NonZeroUsize,
+        str::FromStr,
+        sync::{Arc, Mutex},
+        time::Duration,
+    };
+
+    // Matches if the query is sent from the IP given
+    struct SrcIp(IpAddr);
+
+    impl Matcher for SrcIp {
+        fn matches(&self, state: &State) -> bool {
+            state.origin_ip() == Some(self.0)
+        }
+    }
+
+    // Matches on a field of the query context
+    struct Ctx(fn(&QueryContext) -> bool);
+
+    impl Matcher for Ctx {
+        fn matches(&self, state: &State) -> bool {
+            state.qctx().map(self.0).unwrap_or(false)
+        }
+    }
+
+    // Records the time left before the deadline
+    struct Remaining(Arc<Mutex<Option<Duration>>>);
+
+    #[async_trait]
+    impl Action for Remaining {
+        async fn act(&self, state: &mut State, _: &Upstreams) -> ActionResult<()> {
+            *self.0.lock().unwrap() = state.remaining();
+            Ok(())
+        }
+
+        fn used_upstream(&self) -> Option<Label> {
+            None
+        }
+    }
+
+    // Goes to the first destination at runtime while only declaring the second one, which validation cannot see through.
+    struct Bounce(Next, Next);
+
+    #[async_trait]
+    impl Rule for Bounce {
+        async fn route<'a>(
+            &'a self,
+            _: &str,
+            _: &mut State,
+            _: &Upstreams,
+            _: &Dname<Bytes>,
+        ) -> std::result::Result<&'a Next, TableError> {
+            Ok(&self.0)
+        }
+
+        fn dsts(&self) -> Vec<Next> {
+            vec![self.1.clone()]
+        }
+
+        fn used_upstreams(&self) -> Vec<Label> {
+            vec![]
+        }
+    }
+
+    #[tokio::test]
+    async fn query_context() {
+        let mut rules: HashMap<Label, Box<dyn Rule>> = HashMap::new();
+        rules.insert(
+            "start".into(),
+            Box::new(IfBlock::new(
+                Box::new(SrcIp("192.168.1.1".parse().unwrap())),
+                (vec![Box::new(Blackhole::default())], "end".into()),
+                (vec![], "end".into()),
+            )),
+        );
+        let router = Router::new(
+            Table::new(rules).unwrap(),
+            Upstreams::new(HashMap::new(), NonZeroUsize::new(1).unwrap()).unwrap(),
+        )
+        .unwrap();
+        let query = WarmUp::query(&Dname::from_str("example.com").unwrap(), Rtype::A);
+        let ctx = |ip: &str| {
+            Some(QueryContext::new(
+                SocketAddr::new(ip.parse().unwrap(), 53),
+                Protocol::Udp,
+            ))
+        };
+
+        // Blackhole adds an SOA record.
+        let resp = router
+            .resolve_with_ctx(query.clone(), ctx("192.168.1.1"))
+            .await
+            .unwrap();
+        assert_eq!(resp.header_counts().nscount(), 1);
+
+        let resp = router
+            .resolve_with_ctx(query.clone(), ctx("192.168.1.2"))
+            .await
+            .unwrap();
+        assert_eq!(resp.header_counts().nscount(), 0);
+
+        let resp = router.resolve(query).await.unwrap();
+        assert_eq!(resp.header_counts().nscount(), 0);
+    }
+
+    #[tokio::test]
+    async fn query_context_fields() {
+        let router = |f: fn(&QueryContext) -> bool| {
+            let mut rules: HashMap<Label, Box<dyn Rule>> = HashMap::new();
+            rules.insert(
+                "start".into(),
+                Box::new(IfBlock::new(
+                    Box::new(Ctx(f)),
+                    (vec![Box::new(Blackhole::default())], "end".into()),
+                    (vec![], "end".into()),
+                )),
+            );
+            Router::new(
+                Table::new(rules).unwrap(),
+                Upstreams::new(HashMap::new(), NonZeroUsize::new(1).unwrap()).unwrap(),
+            )
+            .unwrap()
+        };
+        // Blackhole adds an SOA record.
+        async fn matched(router: &Router, qctx: QueryContext) -> bool {
+            let query = WarmUp::query(&Dname::from_str("example.com").unwrap(), Rtype::A);
+            router
+                .resolve_with_ctx(query, Some(qctx))
+                .await
+                .unwrap()
+                .header_counts()
+                .nscount()
+                == 1
+        }
+        let src: SocketAddr = "192.168.1.1:5353".parse().unwrap();
+        let local: SocketAddr = "127.0.0.1:53".parse().unwrap();
+
+        let r = router(|c| c.protocol == Protocol::Dot);
+        assert!(matched(&r, QueryContext::new(src, Protocol::Dot)).await);
+        assert!(!matched(&r, QueryContext::new(src, Protocol::Udp)).await);
+
+        let r = router(|c| c.local_addr == Some("127.0.0.1:53".parse().unwrap()));
+        assert!(
+            matched(
+                &r,
+                QueryContext::new(src, Protocol::Tcp).with_local_addr(local)
+            )
+            .await
+        );
+        assert!(!matched(&r, QueryContext::new(src, Protocol::Tcp)).await);
+
+        let r = router(|c| c.src.port() == 5353 && c.ip() == IpAddr::from([192, 168, 1, 1]));
+        assert!(matched(&r, QueryContext::new(src, Protocol::Udp)).await);
+        assert!(
+            !matched(
+                &r,
+                QueryContext::new("192.168.1.1:53".parse().unwrap(), Protocol::Udp)
+            )
+            .await
+        );
+    }
+
+    #[tokio::test]
+    async fn table_stats() {
+        let router = Router::new(
+            TableBuilder::new()
+                .add_rule(
+                    "start",
+                    RuleBuilders::<BuiltinMatcherBuilders, _>::IfBlock(IfBlockBuilder::new(
+                        "qtype([A])",
+                        BranchBuilder::new("blackhole"),
+                        BranchBuilder::new("end"),
+                    )),
+                )
+                .add_rule(
+                    "blackhole",
+                    RuleBuilders::SeqBlock(
+                        BranchBuilder::new("end").add_action(BuiltinActionBuilders::Blackhole),
+                    ),
+                )
+                .async_try_into()
+                .await
+                .unwrap(),
+            Upstreams::new(HashMap::new(), NonZeroUsize::new(1).unwrap()).unwrap(),
+        )
+        .unwrap();
+        let name = Dname::from_str("example.com").unwrap();
+        for qtype in [Rtype::A, Rtype::Aaaa, Rtype::A, Rtype::Aaaa, Rtype::A] {
+            router.resolve(WarmUp::query(&name, qtype)).await.unwrap();
+        }
+
+        let stats = router.table_stats();
+        assert_eq!(
+            stats.get("start").unwrap(),
+            &RuleStats {
+                evaluations: 5,
+                on_match: 3,
+                no_match: 2
+            }
+        );
+        assert_eq!(
+            stats.get("blackhole").unwrap(),
+            &RuleStats {
+                evaluations: 3,
+                on_match: 0,
+                no_match: 0
+            }
+        );
+
+        router.reset_table_stats();
+        assert!(router
+            .table_stats()
+            .values()
+            .all(|s| s == &RuleStats::default()));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn deadline() {
+        let remaining = Arc::new(Mutex::new(None));
+        let mut rules: HashMap<Label, Box<dyn Rule>> = HashMap::new();
+        rules.insert(
+            "start".into(),
+            Box::new(SeqBlock::new((
+                vec![Box::new(Remaining(remaining.clone()))],
+                "end".into(),
+            ))),
+        );
+        let router = Router::new(
+            Table::new(rules).unwrap(),
+            Upstreams::new(HashMap::new(), NonZeroUsize::new(1).unwrap()).unwrap(),
+        )
+        .unwrap()
+        .with_timeout(Duration::from_millis(100));
+
+        router
+            .resolve(WarmUp::query(
+                &Dname::from_str("example.com").unwrap(),
+                Rtype::A,
+            ))
+            .await
+            .unwrap();
+        // Clock is paused, so no time has passed.
+        assert_eq!(*remaining.lock().unwrap(), Some(Duration::from_millis(100)));
+    }
+
+    #[tokio::test]
+    async fn too_many_steps() {
+        let mut rules: HashMap<Label, Box<dyn Rule>> = HashMap::new();
+        rules.insert(
+            "start".into(),
+            Box::new(SeqBlock::new((vec![], "ping".into()))),
+        );
+        rules.insert(
+            "ping".into(),
+            Box::new(Bounce("pong".into(), "pong".into())),
+        );
+        rules.insert("pong".into(), Box::new(Bounce("ping".into(), "end".into())));
+        let router = Router::new(
+            Table::new(rules).unwrap(),
+            Upstreams::new(HashMap::new(), NonZeroUsize::new(1).unwrap()).unwrap(),
+        )
+        .unwrap()
+        .with_max_steps(10);
+
+        let (resp, trace) = router
+            .resolve_traced(
+                WarmUp::query(&Dname::from_str("example.com").unwrap(), Rtype::A),
+                None,
+            )
+            .await
+            .unwrap();
+        assert_eq!(resp.header().rcode(), Rcode::ServFail);
+        assert_eq!(trace.step_count, 10);
+        assert_eq!(trace.steps.len(), 10);
+        assert_eq!(
+            trace.error.unwrap(),
+            r#"Routing exceeded the maximum number of steps, recent rules: ["start", "ping", "pong", "ping", "pong", "ping", "pong", "ping", "pong", "ping"]"#
+        );
+        // Exactly the cap of rules are evaluated.
+        assert_eq!(
+            router
+                .table_stats()
+                .values()
+                .map(|s| s.evaluations)
+                .sum::<u64>(),
+            10
+        );
+    }
+
+    type Builder = RouterBuilder<
+        TableBuilder<RuleBuilders<BuiltinMatcherBuilders, BuiltinActionBuilders>>,
+        UpstreamsBuilder<UpstreamBuilder>,
+    >;
+
+    const CONFIG: &str = r#"
+address: 0.0.0.0:2053
+verbosity: info
+timeout: 2
+table:
+  start:
+    if: "qtype([AAAA])"
+    then:
+      - blackhole
+      - end
+    else:
+      - query: secure
+      - end
+upstreams:
+  local:
+    udp:
+      addr: 127.0.0.1:53
+      cache:
+        max_stale_secs: 60
+        max_ttl: 300
+  secure:
+    hybrid:
+      - local
+cache_size: 128
+"#;
+
+    #[tokio::test]
+    async fn from_config_str() {
+        let json =
+            serde_json::to_string(&serde_yaml::from_str::<serde_json::Value>(CONFIG).unwrap())
+                .unwrap();
+        for config in [CONFIG, json.as_str()] {
+            let router: Router = Builder::from_config_str(config)
+                .unwrap()
+                .async_try_into()
+                .await
+                .unwrap();
+            assert_eq!(router.timeout, Duration::from_secs(2));
+            // AAAA queries never reach the upstreams
+            let resp = router
+                .resolve(WarmUp::query(
+                    &Dname::from_str("example.com").unwrap(),
+                    Rtype::Aaaa,
+                ))
+                .await
+                .unwrap();
+            assert_eq!(resp.header().rcode(), Rcode::NXDomain);
+        }
+
+        // Sections on their own
+        let v: serde_json::Value = serde_json::from_str(&json).unwrap();
+        TableBuilder::<RuleBuilders<BuiltinMatcherBuilders, BuiltinActionBuilders>>::from_json(
+            &v["table"].to_string(),
+        )
+        .unwrap()
+        .async_try_into()
+        .await
+        .unwrap();
+        UpstreamsBuilder::<UpstreamBuilder>::from_json(
+            &serde_json::json!({"upstreams": v["upstreams"], "cache_size": v["cache_size"]})
+                .to_string(),
+        )
+        .unwrap()
+        .async_try_into()
+        .await
+        .unwrap();
+        UpstreamsBuilder::<UpstreamBuilder>::from_yaml(
+            "upstreams:\n  local:\n    udp:\n      addr: 127.0.0.1:53",
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn from_config_str_errors() {
+        // Typos are rejected with the section where they are
+        for (field, typo, section) in [
+            ("max_ttl", "max_tll", "upstreams"),
+            ("timeout: 2", "timout: 2", "router"),
+            ("blackhole", "blackhoel", "table"),
+        ] {
+            match Builder::from_config_str(&CONFIG.replace(field, typo))
+                .err()
+                .unwrap()
+            {
+                ConfigError::Yaml { section: s, .. } => assert_eq!(s, section),
+                e => panic!("Not the right error type: {}", e),
+            }
+        }
+
+        assert!(matches!(
+            Builder::from_config_str("upstreams: {}").err().unwrap(),
+            ConfigError::MissingSection("table")
+        ));
+        assert!(matches!(
+            TableBuilder::<RuleBuilders<BuiltinMatcherBuilders, BuiltinActionBuilders>>::from_yaml(
+                "start: ["
+            )
+            .err()
+            .unwrap(),
+            ConfigError::Yaml {
+                section: "table",
+                ..
+            }
+        ));
+    }
+
+    fn blackhole_router() -> Router {
+        action_router(Box::new(Blackhole::default()))
+    }
+
+    fn action_router(action: Box<dyn Action>) -> Router {
+        let mut rules: HashMap<Label, Box<dyn Rule>> = HashMap::new();
+        rules.insert(
+            "start".into(),
+            Box::new(SeqBlock::new((vec![action], "end".into()))),
+        );
+        Router::new(
+            Table::new(rules).unwrap(),
+            Upstreams::new(HashMap::new(), NonZeroUsize::new(1).unwrap()).unwrap(),
+        )
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn on_unanswered() {
+        let query = WarmUp::query(&Dname::from_str("example.com").unwrap(), Rtype::A);
+        for (on_unanswered, rcode) in [
+            (Unanswered::Echo, Rcode::NoError),
+            (Unanswered::ServFail, Rcode::ServFail),
+            (Unanswered::NxDomain, Rcode::NXDomain),
+            (Unanswered::Refused, Rcode::Refused),
+            (Unanswered::EmptyNoError, Rcode::NoError),
+        ] {
+            let mut rules: HashMap<Label, Box<dyn Rule>> = HashMap::new();
+            rules.insert(
+                "start".into(),
+                Box::new(SeqBlock::new((vec![], "end".into()))),
+            );
+            let router = Router::new(
+                Table::new(rules).unwrap(),
+                Upstreams::new(HashMap::new(), NonZeroUsize::new(1).unwrap()).unwrap(),
+            )
+            .unwrap()
+            .with_on_unanswered(on_unanswered);
+            let resp = router.resolve(query.clone()).await.unwrap();
+            assert_eq!(resp.header().rcode(), rcode);
+            assert_eq!(resp.header().id(), query.header().id());
+            assert!(resp.header().qr());
+            assert_eq!(resp.header_counts().ancount(), 0);
+
+            // Responses set by actions are never touched
+            let resp = blackhole_router()
+                .with_on_unanswered(on_unanswered)
+                .resolve(query.clone())
+                .await
+                .unwrap();
+            assert_eq!(resp.header().rcode(), Rcode::NXDomain);
+            assert_eq!(resp.header_counts().nscount(), 1);
+        }
+    }
+
+    #[tokio::test]
+    async fn blackhole() {
+        let query = WarmUp::query(&Dname::from_str("example.com").unwrap(), Rtype::Aaaa);
+        for (action, ttl) in [
+            ("blackhole", Some(86400)),
+            ("blackhole_with: {negative_ttl: 300}", Some(300)),
+            ("blackhole_with: {soa: false}", None),
+        ] {
+            let action = serde_yaml::from_str::<BuiltinActionBuilders>(action)
+                .unwrap()
+                .async_try_into()
+                .await
+                .unwrap();
+            let resp = action_router(action).resolve(query.clone()).await.unwrap();
+            assert_eq!(resp.header().rcode(), Rcode::NXDomain);
+            assert_eq!(resp.header().id(), query.header().id());
+            assert!(resp.header().qr());
+            // The question is echoed
+            let question = resp.first_question().unwrap();
+            assert_eq!(question.qname().to_string(), "example.com");
+            assert_eq!(question.qtype(), Rtype::Aaaa);
+            assert_eq!(resp.header_counts().ancount(), 0);
+            // The SOA record tells how long the denial may be cached.
+            let soa: Vec<_> = resp
+                .authority()
+                .unwrap()
+                .flatten()
+                .map(|r| (r.rtype(), r.ttl()))
+                .collect();
+            assert_eq!(
+                soa,
+                ttl.map(|ttl| (Rtype::Soa, ttl))
+                    .into_iter()
+                    .collect::<Vec<_>>()
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn set_rcode() {
+        let query = WarmUp::query(&Dname::from_str("example.com").unwrap(), Rtype::A);
+        for (action, rcode) in [
+            ("set_rcode: REFUSED", Rcode::Refused),
+            ("set_rcode: NOTIMP", Rcode::NotImp),
+        ] {
+            let action = serde_yaml::from_str::<BuiltinActionBuilders>(action)
+                .unwrap()
+                .async_try_into()
+                .await
+                .unwrap();
+            // Responses set by actions are kept whatever is answered to those unanswered.
+            let resp = action_router(action)
+                .with_on_unanswered(Unanswered::ServFail)
+                .resolve(query.clone())
+                .await
+                .unwrap();
+            assert_eq!(resp.header().rcode(), rcode);
+            // The rcode is the lower half of the fourth octet on the wire.
+            assert_eq!(resp.as_slice()[3] & 0x0f, rcode.to_int());
+            assert_eq!(resp.header().id(), query.header().id());
+            assert!(resp.header().qr());
+            let question = resp.first_question().unwrap();
+            assert_eq!(question.qname().to_string(), "example.com");
+            assert_eq!(question.qtype(), Rtype::A);
+            assert_eq!(resp.header_counts().ancount(), 0);
+            assert_eq!(resp.header_counts().nscount(), 0);
+        }
+    }
+
+    #[tokio::test]
+    async fn truncated_question() {
+        let router = blackhole_router();
+        let query = WarmUp::query(&Dname::from_str("example.com").unwrap(), Rtype::A);
+        let octets = query.as_slice();
+        // Header is kept intact with one question claimed, while the question section is cut at every possible point.
+        for len in 12..octets.len() {
+            let msg = Message::from_octets(Bytes::copy_from_slice(&octets[..len])).unwrap();
+            let resp = router.resolve(msg.clone()).await.unwrap();
+            assert_eq!(resp.header().rcode(), Rcode::FormErr);
+            assert_eq!(resp.header().id(), msg.header().id());
+
+            // The table itself doesn't panic either
+            let core = router.core.load();
+            assert!(matches!(
+                core.tables[super::DEFAULT_TABLE]
+                    .route(
+                        msg,
+                        None,
+                        RouteOptions {
+                            id: 0,
+                            deadline: None,
+                            max_steps: 64,
+                            on_unanswered: Unanswered::default(),
+                        },
+                        &core.upstreams
+                    )
+                    .await
+                    .err()
+                    .unwrap(),
+                TableError::ParseError(_)
+            ));
+        }
+        assert_eq!(
+            router.resolve(query).await.unwrap().header().rcode(),
+            Rcode::NXDomain
+        );
+    }
+
+    #[tokio::test]
+    async fn garbage_question() {
+        let router = blackhole_router();
+        let header = WarmUp::query(&Dname::root_bytes(), Rtype::A).as_slice()[..12].to_vec();
+        // A simple xorshift generator so that the test is deterministic without extra dependencies.
+        let mut x: u32 = 0x9e37_79b9;
+        let mut next = || {
+            x ^= x << 13;
+            x ^= x >> 17;
+            x ^= x << 5;
+            x
+        };
+        for _ in 0..1024 {
+            let mut octets = header.clone();
+            let len = next() % 64;
+            octets.extend((0..len).map(|_| next() as u8));
+            let msg = Message::from_octets(Bytes::from(octets)).unwrap();
+            // Some garbage may happen to be a valid question.
+            let rcode = router.resolve(msg).await.unwrap().header().rcode();
+            assert!(rcode == Rcode::FormErr || rcode == Rcode::NXDomain);
+        }
+    }
+
+    #[tokio::test]
+    async fn question_count() {
+        let router = blackhole_router();
+
+        let mut builder = MessageBuilder::from_target(BytesMut::with_capacity(1024))
+            .unwrap()
+            .question();
+        builder
+            .push((Dname::<Bytes>::from_str("example.com").unwrap(), Rtype::A))
+            .unwrap();
+        builder
+            .push((Dname::<Bytes>::from_str("example.org").unwrap(), Rtype::A))
+            .unwrap();
+        let resp = router.resolve(builder.into_message()).await.unwrap();
+        assert_eq!(resp.header().rcode(), Rcode::FormErr);
+        // Questions are echoed
+        assert_eq!(resp.header_counts().qdcount(), 2);
+
+        let resp = router
+            .resolve(
+                MessageBuilder::from_target(BytesMut::with_capacity(1024))
+                    .unwrap()
+                    .into_message(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(resp.header().rcode(), Rcode::FormErr);
+        assert_eq!(router.metrics().errors, 2);
     }
 }
@@ -0,0 +1,269 @@
+// Copyright 2020 LEXUGE
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Preflight checks of a configuration. Every rule and upstream is built on its own so that problems are attributed to their tags, and upstreams can be probed with a query before serving.
+
+use super::{
+    table::{
+        rule::{actions::CacheMode, Rule},
+        Table, TableBuilder, TableError,
+    },
+    upstreams::{builder::UpstreamsBuilder, QHandleError, Upstreams},
+    warmup::WarmUp,
+    Router, RouterBuilder,
+};
+use crate::{AsyncTryInto, Label, Upstream};
+use bytes::Bytes;
+use domain::base::{Dname, Rtype};
+use futures::future::join_all;
+use serde::{Deserialize, Serialize};
+use std::{collections::HashMap, fmt::Display, time::Duration};
+use tokio::time::{timeout, Instant};
+
+/// Outcome of a check, ordered by severity.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "lowercase")]
+pub enum Status {
+    /// Nothing wrong is found.
+    Pass,
+    /// Something looks wrong, but the router can still be built, e.g. an empty list or an unreachable upstream.
+    Warn,
+    /// The router cannot be built, or an upstream is unreachable in strict mode.
+    Fail,
+}
+
+/// A resource loaded by a matcher.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct Resource {
+    /// Kind of the matcher, e.g. `domain` and `ipcidr`.
+    pub matcher: String,
+    /// Where the resource is loaded from.
+    pub source: String,
+    /// Number of entries loaded.
+    pub entries: usize,
+}
+
+impl Resource {
+    pub(crate) fn new(matcher: &str, source: impl Display, entries: usize) -> Self {
+        Self {
+            matcher: matcher.to_string(),
+            source: source.to_string(),
+            entries,
+        }
+    }
+}
+
+/// Result of building a rule.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct RuleReport {
+    /// Tag of the rule.
+    pub tag: Label,
+    /// `Warn` if any of the resources is empty.
+    pub status: Status,
+    /// Resources loaded by the matchers of the rule.
+    pub resources: Vec<Resource>,
+    /// Why the rule failed to build.
+    pub error: Option<String>,
+}
+
+/// Result of building and probing an upstream.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct UpstreamReport {
+    /// Tag of the upstream.
+    pub tag: Label,
+    /// Status of the upstream.
+    pub status: Status,
+    /// Time taken to answer the probe, in milliseconds.
+    pub latency_ms: Option<u64>,
+    /// Why the upstream failed to build or to answer the probe.
+    pub error: Option<String>,
+}
+
+/// Report of the preflight checks.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct PreflightReport {
+    /// The most severe status of all the checks.
+    pub status: Status,
+    /// Rules sorted by tag.
+    pub rules: Vec<RuleReport>,
+    /// Upstreams sorted by tag.
+    pub upstreams: Vec<UpstreamReport>,
+    /// Problems of the configuration as a whole, e.g. undefined tags and unused rules. They are only checked if every rule and upstream is built.
+    pub errors: Vec<String>,
+}
+
+/// How to run the preflight checks.
+#[derive(Clone, Default)]
+pub struct PreflightOptions {
+    probe: Option<(Dname<Bytes>, Duration)>,
+    strict: bool,
+}
+
+impl PreflightOptions {
+    /// Create the options with neither probing nor strict mode.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Probe every upstream with an `A` query of the name, bypassing caches. An upstream not answering within the timeout is considered unreachable.
+    pub fn with_probe(mut self, name: Dname<Bytes>, timeout: Duration) -> Self {
+        self.probe = Some((name, timeout));
+        self
+    }
+
+    /// Whether unreachable upstreams fail the checks rather than being warned about. Default to `false`.
+    pub fn with_strict(mut self, strict: bool) -> Self {
+        self.strict = strict;
+        self
+    }
+}
+
+impl<R, U> RouterBuilder<TableBuilder<R>, UpstreamsBuilder<U>>
+where
+    R: AsyncTryInto<Box<dyn Rule>, Error = TableError> + Clone,
+    U: AsyncTryInto<Upstream, Error = QHandleError> + Clone,
+{
+    /// Build every rule and upstream on their own and report the outcome of each, along with the problems of the configuration as a whole.
+    /// The builder is left untouched so that the router can be built afterwards.
+    pub async fn preflight(&self, options: &PreflightOptions) -> PreflightReport {
+        let (rules, upstreams) =
+            futures::join!(self.table.build_each(), self.upstreams.build_each());
+        let mut errors = Vec::new();
+
+        let mut rule_reports = Vec::new();
+        let mut built_rules = Some(HashMap::new());
+        for (tag, r) in rules {
+            match r {
+                Ok(r) => {
+                    let resources = r.resources();
+                    rule_reports.push(RuleReport {
+                        tag: tag.clone(),
+                        status: if resources.iter().any(|r| r.entries == 0) {
+                            Status::Warn
+                        } else {
+                            Status::Pass
+                        },
+                        resources,
+                        error: None,
+                    });
+                    if let Some(built) = &mut built_rules {
+                        built.insert(tag, r);
+                    }
+                }
+                Err(e) => {
+                    rule_reports.push(RuleReport {
+                        tag,
+                        status: Status::Fail,
+                        resources: Vec::new(),
+                        error: Some(e.to_string()),
+                    });
+                    built_rules = None;
+                }
+            }
+        }
+        rule_reports.sort_by(|a, b| a.tag.cmp(&b.tag));
+        let table = built_rules.and_then(|r| match Table::new(r) {
+            Ok(table) => Some(table),
+            Err(e) => {
+                errors.push(e.to_string());
+                None
+            }
+        });
+
+        let mut upstream_reports = Vec::new();
+        let mut built_upstreams = Some(HashMap::new());
+        for (tag, u) in upstreams {
+            match u {
+                Ok(u) => {
+                    upstream_reports.push(UpstreamReport {
+                        tag: tag.clone(),
+                        status: Status::Pass,
+                        latency_ms: None,
+                        error: None,
+                    });
+                    if let Some(built) = &mut built_upstreams {
+                        built.insert(tag, u);
+                    }
+                }
+                Err(e) => {
+                    upstream_reports.push(UpstreamReport {
+                        tag,
+                        status: Status::Fail,
+                        latency_ms: None,
+                        error: Some(e.to_string()),
+                    });
+                    built_upstreams = None;
+                }
+            }
+        }
+        upstream_reports.sort_by(|a, b| a.tag.cmp(&b.tag));
+        let upstreams =
+            built_upstreams.and_then(|u| match Upstreams::new(u, self.upstreams.cache_size()) {
+                Ok(upstreams) => Some(upstreams),
+                Err(e) => {
+                    errors.push(e.to_string());
+                    None
+                }
+            });
+
+        if let (Some(upstreams), Some((name, limit))) = (&upstreams, &options.probe) {
+            let query = WarmUp::query(name, Rtype::A);
+            let probes = upstream_reports.iter_mut().map(|report| {
+                let query = &query;
+                async move {
+                    let start = Instant::now();
+                    let r = timeout(
+                        *limit,
+                        upstreams.resolve(&report.tag, &CacheMode::Disabled, query),
+                    )
+                    .await;
+                    match r {
+                        Ok(Ok(_)) => report.latency_ms = Some(start.elapsed().as_millis() as u64),
+                        Ok(Err(e)) => report.error = Some(e.to_string()),
+                        Err(_) => report.error = Some("the probe timed out".to_string()),
+                    }
+                    if report.error.is_some() {
+                        report.status = if options.strict {
+                            Status::Fail
+                        } else {
+                            Status::Warn
+                        };
+                    }
+                }
+            });
+            join_all(probes).await;
+        }
+
+        if let (Some(table), Some(upstreams)) = (table, upstreams) {
+            if let Err(e) = Router::new(table, upstreams) {
+                errors.push(e.to_string());
+            }
+        }
+
+        let status = rule_reports
+            .iter()
+            .map(|r| r.status)
+            .chain(upstream_reports.iter().map(|u| u.status))
+            .chain((!errors.is_empty()).then_some(Status::Fail))
+            .max()
+            .unwrap_or(Status::Pass);
+        PreflightReport {
+            status,
+            rules: rule_reports,
+            upstreams: upstream_reports,
+            errors,
+        }
+    }
+}
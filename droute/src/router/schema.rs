@@ -0,0 +1,135 @@
+// Copyright 2020 LEXUGE
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use super::{
+    table::{
+        rule::{
+            actions::builder::BuiltinActionBuilders, builders::RuleBuilders,
+            matchers::builder::BuiltinMatcherBuilders,
+        },
+        TableBuilder,
+    },
+    upstreams::builder::{UpstreamBuilder, UpstreamsBuilder},
+    warmup::WarmUpBuilder,
+    Settings,
+};
+use schemars::{schema::RootSchema, schema_for, JsonSchema};
+use std::net::SocketAddr;
+
+// Log levels of the server.
+#[allow(dead_code)]
+#[derive(JsonSchema)]
+#[schemars(rename_all = "lowercase")]
+enum Verbosity {
+    Off,
+    Error,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+/// The configuration of `dcompass`, which is also accepted by `RouterBuilder::from_config_str`.
+#[allow(dead_code)]
+#[derive(JsonSchema)]
+#[schemars(rename = "Config", deny_unknown_fields)]
+struct Config {
+    /// The routing table. Rules are keyed by their tags, and routing starts at `start`.
+    table: TableBuilder<RuleBuilders<BuiltinMatcherBuilders, BuiltinActionBuilders>>,
+    #[schemars(flatten)]
+    upstreams: UpstreamsBuilder<UpstreamBuilder>,
+    #[schemars(flatten)]
+    settings: Settings,
+    /// The address to serve on. Only meaningful to the server.
+    address: Option<SocketAddr>,
+    /// The log level. Only meaningful to the server.
+    verbosity: Option<Verbosity>,
+    /// The list of names to resolve on start. Only meaningful to the server.
+    warm_up: Option<WarmUpBuilder>,
+}
+
+/// The JSON Schema of the configuration, namely the table, the upstreams, and the settings of the router and the server.
+/// Matching expressions are only described as strings, as they are parsed on building the rules.
+pub fn config_schema() -> RootSchema {
+    schema_for!(Config)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::config_schema;
+    use jsonschema::JSONSchema;
+    use serde_json::Value;
+
+    fn validate(config: &str) -> bool {
+        let schema = serde_json::to_value(config_schema()).unwrap();
+        let schema = JSONSchema::compile(&schema).unwrap();
+        schema.is_valid(&serde_yaml::from_str::<Value>(config).unwrap())
+    }
+
+    #[test]
+    fn accept_example() {
+        assert!(validate(include_str!("../../../configs/logic_jumble.yaml")));
+        assert!(validate(
+            r#"
+table:
+  start:
+    switch:
+      - if: "qtype([AAAA])"
+        then:
+          - blackhole
+          - call: dispatch
+            then: end
+    default:
+      - ecs:
+          manual: 1.1.1.1
+      - query:
+          tag: domestic
+          cache_policy: persistent
+      - end
+    deadline_ms: 500
+  dispatch:
+    - query: domestic
+    - end
+upstreams:
+  domestic:
+    udp:
+      addr: 114.114.114.114:53
+      cache:
+        max_ttl: 3600
+cache_size: 4096
+on_unanswered: empty_noerror
+max_concurrent_queries: 128
+warm_up:
+  path: warmup.txt
+"#
+        ));
+    }
+
+    #[test]
+    fn reject_misspelled() {
+        let example = include_str!("../../../configs/logic_jumble.yaml");
+        // Unknown fields are rejected at every level.
+        assert!(!validate(&example.replace("addr:", "adress:")));
+        assert!(!validate(&example.replace("upstreams:", "upstream:")));
+        assert!(!validate(&example.replace("else:", "otherwise:")));
+        assert!(!validate(
+            &example.replace("verbosity: \"info\"", "verbosity: loud")
+        ));
+        assert!(!validate(&example.replace(
+            "query: Ali",
+            "query:\n          tag: Ali\n          cache_polcy: disabled"
+        )));
+    }
+}
@@ -0,0 +1,140 @@
+// Copyright 2020 LEXUGE
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Human-readable descriptions of the routing table.
+
+use super::rule::{actions::Action, Next};
+use crate::Label;
+use serde::{Deserialize, Serialize};
+use std::fmt::Write;
+
+/// A branch of a rule, which is a sequence of actions followed by where to route next.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct BranchDescription {
+    /// Name of the branch, e.g. `on_match` and `no_match` of an `if` rule.
+    pub name: String,
+    /// The expression guarding this branch, if it is an arm of a `switch` rule.
+    pub expr: Option<String>,
+    /// Descriptions of the actions in order.
+    pub actions: Vec<String>,
+    /// Where to route after the actions.
+    pub next: Next,
+}
+
+impl BranchDescription {
+    pub(crate) fn new(
+        name: impl ToString,
+        expr: Option<&str>,
+        (actions, next): &(Vec<Box<dyn Action>>, Next),
+    ) -> Self {
+        Self {
+            name: name.to_string(),
+            expr: expr.map(|e| e.to_string()),
+            actions: actions.iter().map(|a| a.describe()).collect(),
+            next: next.clone(),
+        }
+    }
+}
+
+/// A rule in the routing table.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct RuleDescription {
+    /// Tag of the rule.
+    pub tag: Label,
+    /// Kind of the rule, e.g. `seq`, `if`, and `switch`.
+    pub kind: String,
+    /// The matching expression of the rule, if the rule has a single one and it was built from one.
+    pub expr: Option<String>,
+    /// Branches in the order they are evaluated.
+    pub branches: Vec<BranchDescription>,
+}
+
+// Escape a string to be put in a quoted DOT ID.
+fn escape(s: &str) -> String {
+    s.trim()
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+// Render the rules as a Graphviz digraph. Rules are expected to be sorted.
+pub(super) fn to_dot(rules: &[RuleDescription]) -> String {
+    // Writing to a `String` never fails.
+    let mut dot = String::from("digraph table {\n    node [shape=box];\n");
+    let _ = writeln!(dot, "    \"end\" [shape=doublecircle];");
+    for r in rules {
+        let mut label = format!("{}\\n{}", escape(&r.tag), r.kind);
+        if let Some(expr) = &r.expr {
+            let _ = write!(label, ": {}", escape(expr));
+        }
+        let style = if r.tag == "start" {
+            ", peripheries=2"
+        } else {
+            ""
+        };
+        let _ = writeln!(
+            dot,
+            "    \"{}\" [label=\"{}\"{}];",
+            escape(&r.tag),
+            label,
+            style
+        );
+    }
+    for r in rules {
+        for b in &r.branches {
+            let tag = escape(&r.tag);
+            let mut head = escape(&b.name);
+            if let Some(expr) = &b.expr {
+                let _ = write!(head, ": {}", escape(expr));
+            }
+            let actions: String = b
+                .actions
+                .iter()
+                .map(|a| format!("\\n{}", escape(a)))
+                .collect();
+            match &b.next {
+                Next::Goto(next) => {
+                    let _ = writeln!(
+                        dot,
+                        "    \"{}\" -> \"{}\" [label=\"{}{}\"];",
+                        tag,
+                        escape(next),
+                        head,
+                        actions
+                    );
+                }
+                Next::Call { call, then } => {
+                    let _ = writeln!(
+                        dot,
+                        "    \"{}\" -> \"{}\" [label=\"{} (call){}\", style=dashed];",
+                        tag,
+                        escape(call),
+                        head,
+                        actions
+                    );
+                    let _ = writeln!(
+                        dot,
+                        "    \"{}\" -> \"{}\" [label=\"{} (return)\"];",
+                        tag,
+                        escape(then),
+                        head
+                    );
+                }
+            }
+        }
+    }
+    dot.push_str("}\n");
+    dot
+}
@@ -13,28 +13,56 @@
 // You should have received a copy of the GNU General Public License
 // along with this program.  If not, see <http://www.gnu.org/licenses/>.
 
+pub mod describe;
 pub mod rule;
+pub mod stats;
+pub mod trace;
 
-use self::rule::{actions::ActionError, matchers::MatchError, Rule};
-use super::upstreams::Upstreams;
-use crate::{AsyncTryInto, Label, Validatable, ValidateCell};
+use self::{
+    describe::RuleDescription,
+    rule::{
+        actions::{Action, ActionError},
+        matchers::MatchError,
+        Next, Rule,
+    },
+    stats::{RuleCounters, RuleStats},
+    trace::{ActionTrace, MatcherTrace, RouteTrace, TraceStep},
+};
+use super::upstreams::{RespSource, Upstreams};
+use crate::{
+    build_all,
+    error::{numbered, ConfigError},
+    AsyncTryInto, Label, Validatable, ValidateCell, DEFAULT_MAX_CONCURRENT_BUILDS, MAX_LEN,
+};
 use async_trait::async_trait;
 use bytes::{Bytes, BytesMut};
 use compact_str::CompactStr;
 use domain::{
-    base::{name::PushError, octets::ParseError, Message, ParsedDname, Rtype, ToDname},
+    base::{
+        iana::Rcode, name::PushError, octets::ParseError, Message, MessageBuilder, ParsedDname,
+        RecordSection, Rtype, ToDname,
+    },
     rdata::AllRecordData,
 };
 use log::*;
-use serde::{Deserialize, Serialize};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
 use std::{
-    collections::{HashMap, HashSet},
-    net::IpAddr,
+    collections::{HashMap, HashSet, VecDeque},
+    net::{IpAddr, SocketAddr},
+    num::NonZeroUsize,
+    time::Duration,
 };
 use thiserror::Error;
+use tokio::time::Instant;
 
 type Result<T> = std::result::Result<T, TableError>;
 
+// Maximum depth of nested calls of rule chains.
+const MAX_CALL_DEPTH: usize = 32;
+
+// Number of recent tags carried by `TooManySteps`.
+const HISTORY_LEN: usize = 16;
+
 /// Errors generated by the `table` section.
 #[derive(Error, Debug)]
 pub enum TableError {
@@ -60,6 +88,18 @@ pub enum TableError {
     )]
     UndefinedTag(Label),
 
+    /// Calls of rule chains are nested too deep.
+    #[error("Calls of rule chains are nested deeper than the limit of {0}")]
+    CallDepthExceeded(usize),
+
+    /// The query went through more rules than allowed, which usually indicates a loop. Recent tags are carried in order.
+    #[error("Routing exceeded the maximum number of steps, recent rules: {0:?}")]
+    TooManySteps(Vec<Label>),
+
+    /// The deadline of routing is reached.
+    #[error("Routing timed out at the rule with tag `{0}`")]
+    Timeout(Label),
+
     /// Failed to push the record
     #[error(transparent)]
     PushError(#[from] PushError),
@@ -74,25 +114,219 @@ pub enum TableError {
 
     /// Failed to parse Expr
     #[error(transparent)]
-    ExprError(#[from] crate::matchers::expr::ExprError),
+    ExprError(#[from] Box<crate::matchers::expr::ExprError>),
+
+    /// The rule with the tag failed to build.
+    #[error("The rule with tag `{0}` failed to build: {1}")]
+    InRule(Label, #[source] Box<TableError>),
+
+    /// More than one problem is found.
+    #[error("{}", numbered(.0))]
+    Multiple(Vec<TableError>),
+}
+
+impl TableError {
+    // Report the problems found, with a sole one kept as it is.
+    fn collect(mut errors: Vec<Self>) -> Result<()> {
+        match errors.len() {
+            0 => Ok(()),
+            1 => Err(errors.pop().unwrap()),
+            _ => Err(Self::Multiple(errors)),
+        }
+    }
+}
+
+/// What to answer if routing reaches `end` without any action having set a response.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+#[serde(rename_all = "lowercase")]
+pub enum Unanswered {
+    /// Send the query itself back as the response, which is an empty `NOERROR` response carrying the other sections of the query.
+    #[default]
+    Echo,
+    /// Answer with `SERVFAIL`.
+    ServFail,
+    /// Answer with `NXDOMAIN`.
+    NxDomain,
+    /// Answer with `REFUSED`.
+    Refused,
+    /// Answer with an empty `NOERROR` response.
+    #[serde(rename = "empty_noerror")]
+    EmptyNoError,
+}
+
+// Per-query parameters of routing handed over by `Router`.
+#[derive(Clone, Copy)]
+pub(crate) struct RouteOptions {
+    // Identifies the query in logs and events.
+    pub id: u64,
+    // The deadline of the whole routing, if any.
+    pub deadline: Option<Instant>,
+    // Maximum number of rules the query may go through.
+    pub max_steps: usize,
+    // What to answer if no action has set a response.
+    pub on_unanswered: Unanswered,
+}
+
+/// Transport protocol a query is received over.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[serde(rename_all = "lowercase")]
+pub enum Protocol {
+    /// Plain DNS over UDP.
+    Udp,
+    /// Plain DNS over TCP.
+    Tcp,
+    /// DNS over TLS.
+    Dot,
+    /// DNS over HTTPS.
+    Doh,
+    /// DNS over QUIC.
+    Doq,
 }
 
 /// Query Context
+#[derive(Clone, Debug)]
 pub struct QueryContext {
     /// Query sender's IP address
+    #[deprecated(note = "use `QueryContext::ip()` or `QueryContext::src` instead")]
     pub ip: IpAddr,
+    /// Query sender's socket address
+    pub src: SocketAddr,
+    /// Address of the listener which received the query, if known.
+    pub local_addr: Option<SocketAddr>,
+    /// Transport protocol the query is received over.
+    pub protocol: Protocol,
+}
+
+impl QueryContext {
+    /// Create a context for a query sent from `src` over `protocol`.
+    #[allow(deprecated)]
+    pub fn new(src: SocketAddr, protocol: Protocol) -> Self {
+        Self {
+            ip: src.ip(),
+            src,
+            local_addr: None,
+            protocol,
+        }
+    }
+
+    /// Set the address of the listener which received the query.
+    pub fn with_local_addr(mut self, addr: SocketAddr) -> Self {
+        self.local_addr = Some(addr);
+        self
+    }
+
+    /// Query sender's IP address
+    pub fn ip(&self) -> IpAddr {
+        self.src.ip()
+    }
 }
 
+// The query always has exactly one question which parses, so it is fine to unwrap `first_question()` on it.
 pub struct State {
     qctx: Option<QueryContext>,
     resp: Message<Bytes>,
+    // Whether any action has set the response.
+    answered: bool,
     query: Message<Bytes>,
+    // Where the current response came from, set by the `Query` action.
+    resp_source: Option<RespSource>,
+    // Tag of the upstream that answered the current response, set by the `Query` action. It is never a hybrid one.
+    answered_by: Option<Label>,
+    // Result of the matcher evaluated in the current rule, if any.
+    matched: Option<bool>,
+    // Only present if the query is being traced.
+    trace: Option<RouteTrace>,
+    // The deadline in effect for the current rule, if any.
+    deadline: Option<Instant>,
+    // ID of the query assigned by `Router`.
+    id: u64,
 }
 
 // Some helper functions on response and query DNS messages
 impl State {
-    fn origin_ip(&self) -> Option<IpAddr> {
-        self.qctx.as_ref().map(|x| x.ip)
+    fn new(
+        query: Message<Bytes>,
+        qctx: Option<QueryContext>,
+        opts: &RouteOptions,
+        traced: bool,
+    ) -> Self {
+        Self {
+            deadline: opts.deadline,
+            id: opts.id,
+            qctx,
+            // Clone is cheap, just a ref count increment
+            query: query.clone(),
+            resp: query,
+            answered: false,
+            resp_source: None,
+            answered_by: None,
+            matched: None,
+            trace: traced.then(RouteTrace::default),
+        }
+    }
+
+    // Set the response, actions should always go through this.
+    pub(crate) fn set_resp(&mut self, resp: Message<Bytes>) {
+        self.resp = resp;
+        self.answered = true;
+    }
+
+    /// The answer section of the current response. It is `None` before any action has set a response, as `resp` is merely the query echoed then, or if the response is malformed, as we can't tell what it answers.
+    pub(crate) fn answer(&self) -> Option<RecordSection<&Bytes>> {
+        if !self.answered {
+            return None;
+        }
+        self.resp.answer().ok()
+    }
+
+    // Record the result of the matcher evaluated in the current rule.
+    fn record_match(&mut self, expr: Option<&str>, result: bool) {
+        self.matched = Some(result);
+        if let Some(step) = self.trace.as_mut().and_then(|t| t.steps.last_mut()) {
+            step.matcher = Some(MatcherTrace {
+                expr: expr.map(|e| e.to_string()),
+                result,
+            });
+        }
+    }
+
+    // Record the action just executed in the current step if we are tracing.
+    fn trace_action(&mut self, action: &dyn Action) {
+        if let Some(step) = self.trace.as_mut().and_then(|t| t.steps.last_mut()) {
+            let upstream = action.used_upstream();
+            step.actions.push(ActionTrace {
+                source: upstream.as_ref().and(self.resp_source.clone()),
+                upstream,
+                rcode: self.resp.header().rcode().to_string(),
+            });
+        }
+    }
+
+    /// ID of the query, unique within the router. It shows up in logs and `tracing` events of the query.
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    /// The deadline in effect for the current rule, if any. It is the tightest of the one of the whole routing, the budget of the rule, and the budgets of the rules calling into it.
+    /// Long-running actions may trim their own budgets with it.
+    pub fn deadline(&self) -> Option<Instant> {
+        self.deadline
+    }
+
+    /// Time left before the deadline in effect, if any.
+    pub fn remaining(&self) -> Option<Duration> {
+        self.deadline
+            .map(|d| d.saturating_duration_since(Instant::now()))
+    }
+
+    pub(crate) fn origin_ip(&self) -> Option<IpAddr> {
+        self.qctx.as_ref().map(|x| x.ip())
+    }
+
+    /// The context of the query, if the router is given one.
+    pub fn qctx(&self) -> Option<&QueryContext> {
+        self.qctx.as_ref()
     }
 
     fn resp_ip(&self) -> Result<Option<IpAddr>> {
@@ -128,41 +362,98 @@ impl Default for State {
         Self {
             resp: Message::from_octets(Bytes::from_static(&[0; 1024])).unwrap(),
             query: Message::from_octets(Bytes::from_static(&[0; 1024])).unwrap(),
+            answered: false,
             qctx: None,
+            resp_source: None,
+            answered_by: None,
+            matched: None,
+            trace: None,
+            deadline: None,
+            id: 0,
         }
     }
 }
 
-// Traverse and validate the routing table.
+// Traverse and validate the routing table, collecting the problems found.
 fn traverse(
-    // A bucket to count the time each tag being used.
-    bucket: &mut HashMap<&Label, (ValidateCell, &dyn Rule)>,
+    // A bucket to count the time each tag being used. Rules failed to build have no `Rule`, their destinations are unknown.
+    bucket: &mut HashMap<&Label, (ValidateCell, Option<&dyn Rule>)>,
     // Tag of the rule that we are currently on.
     tag: &Label,
-) -> Result<()> {
+    errors: &mut Vec<TableError>,
+) {
     // Hacky workaround on the borrow checker.
-    let (val, dsts) = if let Some((c, r)) = bucket.get_mut(tag) {
-        (c.val(), r.dsts())
+    let (val, used, dsts) = if let Some((c, r)) = bucket.get_mut(tag) {
+        (*c.val(), c.used(), r.map(|r| r.dsts()))
     } else {
-        return Err(TableError::UndefinedTag(tag.clone()));
+        if !errors
+            .iter()
+            .any(|e| matches!(e, TableError::UndefinedTag(t) if t == tag))
+        {
+            errors.push(TableError::UndefinedTag(tag.clone()));
+        }
+        return;
     };
-    if val >= &1 {
-        Err(TableError::RuleRecursion(tag.clone()))
-    } else {
+    if val >= 1 {
+        errors.push(TableError::RuleRecursion(tag.clone()));
+    } else if !used {
+        // Rules fully traversed before are skipped, any recursion through them has already been found.
         bucket.get_mut(tag).unwrap().0.add(1);
-        for dst in dsts {
-            if dst != CompactStr::new("end") {
-                traverse(bucket, &dst)?;
+        for dst in dsts.into_iter().flatten() {
+            // Rules on the current path stay marked while traversing the called chain, so recursion through calls is also detected.
+            let (call, then) = match dst {
+                Next::Goto(then) => (None, then),
+                Next::Call { call, then } => (Some(call), then),
+            };
+            if let Some(call) = call {
+                traverse(bucket, &call, errors);
+            }
+            if then != CompactStr::new("end") {
+                traverse(bucket, &then, errors);
             }
         }
         bucket.get_mut(tag).unwrap().0.sub(1);
-        Ok(())
     }
 }
 
+// Validate the rules with the ones failed to build tagged in `broken`, collecting the problems found. Returns the upstreams used.
+fn check(
+    rules: &HashMap<Label, Box<dyn Rule>>,
+    broken: &HashSet<Label>,
+    errors: &mut Vec<TableError>,
+) -> Vec<Label> {
+    // A bucket used to count the time each rule being used.
+    let mut bucket: HashMap<&Label, (ValidateCell, Option<&dyn Rule>)> = rules
+        .iter()
+        .map(|(k, v)| (k, (ValidateCell::default(), Some(v.as_ref()))))
+        .chain(broken.iter().map(|k| (k, (ValidateCell::default(), None))))
+        .collect();
+    traverse(&mut bucket, &"start".into(), errors);
+    let used_upstreams = bucket
+        .iter()
+        .filter(|(_, (c, _))| c.used())
+        .flat_map(|(_, (_, v))| v.map(|v| v.used_upstreams()).unwrap_or_default())
+        .collect();
+    // Rules only reachable from the broken ones would be reported unused by mistake.
+    if broken.is_empty() {
+        let unused: HashSet<Label> = bucket
+            .into_iter()
+            .filter(|(_, (c, _))| !c.used())
+            .map(|(k, _)| k)
+            .cloned()
+            .collect();
+        if !unused.is_empty() {
+            errors.push(TableError::UnusedRules(unused));
+        }
+    }
+    used_upstreams
+}
+
 /// A simple routing table.
 pub struct Table {
     rules: HashMap<Label, Box<dyn Rule>>,
+    // Counters of each rule, keys are never changed after creation.
+    counters: HashMap<Label, RuleCounters>,
     // Upstreams used in this table.
     used_upstreams: Vec<Label>,
 }
@@ -170,56 +461,55 @@ pub struct Table {
 impl Validatable for Table {
     type Error = TableError;
     fn validate(&self, _: Option<&Vec<Label>>) -> Result<()> {
-        // A bucket used to count the time each rule being used.
-        let mut bucket: HashMap<&Label, (ValidateCell, &dyn Rule)> = self
-            .rules
-            .iter()
-            .map(|(k, v)| (k, (ValidateCell::default(), v.as_ref())))
-            .collect();
-        traverse(&mut bucket, &"start".into())?;
-        let unused: HashSet<Label> = bucket
-            .into_iter()
-            .filter(|(_, (c, _))| !c.used())
-            .map(|(k, _)| k)
-            .cloned()
-            .collect();
-        if unused.is_empty() {
-            Ok(())
-        } else {
-            Err(TableError::UnusedRules(unused))
-        }
+        let mut errors = Vec::new();
+        check(&self.rules, &HashSet::new(), &mut errors);
+        TableError::collect(errors)
     }
 }
 
 impl Table {
     /// Create a routing table from a bunch of `Rule`s.
+    /// Every problem found is reported, in `TableError::Multiple` if there are more than one.
     pub fn new(table: HashMap<Label, Box<dyn Rule>>) -> Result<Self> {
-        // A bucket used to count the time each rule being used.
-        let mut bucket: HashMap<&Label, (ValidateCell, &dyn Rule)> = table
-            .iter()
-            .map(|(k, v)| (k, (ValidateCell::default(), v.as_ref())))
-            .collect();
-        traverse(&mut bucket, &"start".into())?;
-        let used_upstreams = bucket
-            .iter()
-            .filter(|(_, (c, _))| c.used())
-            .flat_map(|(_, (_, v))| v.used_upstreams())
-            .collect();
-        let unused: HashSet<Label> = bucket
-            .into_iter()
-            .filter(|(_, (c, _))| !c.used())
-            .map(|(k, _)| k)
-            .cloned()
-            .collect();
-        if !unused.is_empty() {
-            return Err(TableError::UnusedRules(unused));
-        }
+        let mut errors = Vec::new();
+        let used_upstreams = check(&table, &HashSet::new(), &mut errors);
+        TableError::collect(errors)?;
         Ok(Self {
+            counters: table
+                .keys()
+                .map(|k| (k.clone(), RuleCounters::default()))
+                .collect(),
             rules: table,
             used_upstreams,
         })
     }
 
+    /// A snapshot of the counters of each rule.
+    pub fn stats(&self) -> HashMap<Label, RuleStats> {
+        self.counters
+            .iter()
+            .map(|(k, v)| (k.clone(), v.snapshot()))
+            .collect()
+    }
+
+    /// Reset the counters of all rules to zero.
+    pub fn reset_stats(&self) {
+        self.counters.values().for_each(|c| c.reset());
+    }
+
+    /// Describe every rule in the table, sorted by tag.
+    pub fn describe(&self) -> Vec<RuleDescription> {
+        let mut rules: Vec<_> = self.rules.iter().map(|(k, v)| v.describe(k)).collect();
+        rules.sort_by(|a, b| a.tag.cmp(&b.tag));
+        rules
+    }
+
+    /// Render the table as a Graphviz digraph, with rules as nodes and branches as edges labeled with their actions.
+    /// `start` is drawn with a double border and `end` as a double circle. Calls of chains are dashed.
+    pub fn to_dot(&self) -> String {
+        describe::to_dot(&self.describe())
+    }
+
     // Not intended to be used by end-users
     pub(super) fn used_upstreams(&self) -> &Vec<Label> {
         &self.used_upstreams
@@ -230,26 +520,165 @@ impl Table {
         &self,
         query: Message<Bytes>,
         qctx: Option<QueryContext>,
+        opts: RouteOptions,
         upstreams: &Upstreams,
     ) -> Result<Message<Bytes>> {
-        let name = query.first_question().unwrap().qname().to_dname()?;
-        let mut s = State {
-            qctx,
-            // Clone is cheap, just a ref count increment
-            query: query.clone(),
-            resp: query,
-        };
+        self.route_state(&mut State::new(query, qctx, &opts, false), &opts, upstreams)
+            .await
+    }
+
+    // Route the query and record the trace along the way.
+    pub(super) async fn route_traced(
+        &self,
+        query: Message<Bytes>,
+        qctx: Option<QueryContext>,
+        opts: RouteOptions,
+        upstreams: &Upstreams,
+    ) -> (Result<Message<Bytes>>, RouteTrace) {
+        let mut s = State::new(query, qctx, &opts, true);
+        let r = self.route_state(&mut s, &opts, upstreams).await;
+        let mut trace = s.trace.take().unwrap_or_default();
+        if let Err(e) = &r {
+            trace.error = Some(e.to_string());
+        }
+        (r, trace)
+    }
+
+    async fn route_state(
+        &self,
+        s: &mut State,
+        opts: &RouteOptions,
+        upstreams: &Upstreams,
+    ) -> Result<Message<Bytes>> {
+        let max_steps = opts.max_steps;
+        // `Router` only hands over queries with a sole well-formed question, which matchers and actions rely on. Mirror the check here so that we never panic on it.
+        let name = s.query.sole_question()?.qname().to_dname()?;
+        info!("query {}: domain \"{}\" starts routing", s.id, name);
 
         let mut tag = "start";
-        while tag != "end" {
-            tag = self
-                .rules
-                .get(tag)
-                .unwrap()
-                .route(tag, &mut s, upstreams, &name)
-                .await?;
+        // The deadline of the current chain, which is the one of the whole routing unless the chain is called by a rule with its own budget.
+        let mut chain_deadline = opts.deadline;
+        // Tags to return to once the called chains reach `end`, with the deadlines of the chains calling them.
+        let mut returns: Vec<(&str, Option<Instant>)> = Vec::new();
+        // Validation cannot see every loop, so we bound the number of rules a query goes through.
+        let mut steps = 0;
+        let mut history: VecDeque<&str> = VecDeque::with_capacity(HISTORY_LEN);
+        loop {
+            if tag == "end" {
+                match returns.pop() {
+                    Some((t, d)) => {
+                        tag = t;
+                        chain_deadline = d;
+                        continue;
+                    }
+                    None => break,
+                }
+            }
+
+            if steps >= max_steps {
+                warn!(
+                    "query {}: domain \"{}\" exceeded the maximum of {} steps at rule `{}`",
+                    s.id, name, max_steps, tag
+                );
+                event!(rule = tag, steps, "maximum steps exceeded");
+                return Err(TableError::TooManySteps(
+                    history.into_iter().map(Label::from).collect(),
+                ));
+            }
+            steps += 1;
+            if history.len() == HISTORY_LEN {
+                history.pop_front();
+            }
+            history.push_back(tag);
+
+            // Only check the time when we are tracing
+            let start = s.trace.as_mut().map(|t| {
+                t.step_count = steps;
+                t.steps.push(TraceStep::new(tag));
+                Instant::now()
+            });
+            let counters = self.counters.get(tag).unwrap();
+            counters.evaluated();
+            s.matched = None;
+            let rule = self.rules.get(tag).unwrap();
+            // The budget of the rule starts when we enter it, and it never extends the deadline in effect.
+            s.deadline = match (chain_deadline, rule.deadline()) {
+                (Some(d), Some(budget)) => Some(d.min(Instant::now() + budget)),
+                (None, Some(budget)) => Some(Instant::now() + budget),
+                (d, None) => d,
+            };
+            let (id, deadline) = (s.id, s.deadline);
+            let timed_out = || {
+                warn!(
+                    "query {}: domain \"{}\" timed out at rule `{}`",
+                    id, name, tag
+                );
+                event!(rule = tag, "timed out");
+                TableError::Timeout(tag.into())
+            };
+            // Rules are awaited under the deadline one after another, which is the same as putting the whole routing under it while knowing where we stopped.
+            let next = match deadline {
+                // Don't even enter the rule if the deadline has passed, e.g. in a chain called by a rule with its budget used up.
+                Some(deadline) if deadline <= Instant::now() => return Err(timed_out()),
+                Some(deadline) => {
+                    match tokio::time::timeout_at(deadline, rule.route(tag, s, upstreams, &name))
+                        .await
+                    {
+                        Ok(Err(TableError::ActionError(ActionError::DeadlineExceeded)))
+                        | Err(_) => return Err(timed_out()),
+                        Ok(r) => r?,
+                    }
+                }
+                None => rule.route(tag, s, upstreams, &name).await?,
+            };
+            if let Some(matched) = s.matched {
+                counters.matched(matched);
+            }
+            event!(rule = tag, matched = ?s.matched, next = ?next, "rule finished");
+            tag = match next {
+                Next::Goto(next) => next,
+                Next::Call { call, then } => {
+                    if returns.len() >= MAX_CALL_DEPTH {
+                        return Err(TableError::CallDepthExceeded(MAX_CALL_DEPTH));
+                    }
+                    returns.push((then, chain_deadline));
+                    // The called chain inherits the deadline of the caller, which may be tighter than ours.
+                    chain_deadline = s.deadline;
+                    call
+                }
+            };
+            if let (Some(start), Some(step)) =
+                (start, s.trace.as_mut().and_then(|t| t.steps.last_mut()))
+            {
+                step.elapsed_us = start.elapsed().as_micros() as u64;
+            }
         }
-        info!("domain \"{}\" has finished routing", name);
+        info!("query {}: domain \"{}\" has finished routing", s.id, name);
+
+        let rcode = match opts.on_unanswered {
+            _ if s.answered => None,
+            Unanswered::Echo => None,
+            Unanswered::ServFail => Some(Rcode::ServFail),
+            Unanswered::NxDomain => Some(Rcode::NXDomain),
+            Unanswered::Refused => Some(Rcode::Refused),
+            Unanswered::EmptyNoError => Some(Rcode::NoError),
+        };
+        if let Some(rcode) = rcode {
+            info!(
+                "query {}: domain \"{}\" is not answered, returning {}",
+                s.id, name, rcode
+            );
+            s.resp = MessageBuilder::from_target(BytesMut::with_capacity(MAX_LEN))?
+                .start_answer(&s.query, rcode)?
+                .into_message();
+        }
+        event!(
+            steps,
+            rcode = %s.resp.header().rcode(),
+            source = ?s.resp_source,
+            answered_by = ?s.answered_by,
+            "routing finished"
+        );
 
         // Reset the header to make sure it is answering the query
         let mut msg = Message::from_octets(BytesMut::from(s.resp.as_slice()))?;
@@ -265,46 +694,136 @@ impl Table {
     }
 }
 
+fn default_max_concurrent_builds() -> usize {
+    DEFAULT_MAX_CONCURRENT_BUILDS
+}
+
 /// A builder for the routing table.
 #[derive(Serialize, Deserialize, Clone)]
-pub struct TableBuilder<R: AsyncTryInto<Box<dyn Rule>, Error = TableError>>(HashMap<Label, R>);
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+#[serde(transparent)]
+pub struct TableBuilder<R: AsyncTryInto<Box<dyn Rule>, Error = TableError>> {
+    #[cfg_attr(feature = "schema", schemars(with = "HashMap<String, R>"))]
+    rules: HashMap<Label, R>,
+    #[serde(skip, default = "default_max_concurrent_builds")]
+    max_concurrent_builds: usize,
+}
+
+impl<R: AsyncTryInto<Box<dyn Rule>, Error = TableError>> Default for TableBuilder<R> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
 
 impl<R: AsyncTryInto<Box<dyn Rule>, Error = TableError>> TableBuilder<R> {
     /// Create a `TableBuilder` from a set of rules
     pub fn from_map(table: HashMap<impl Into<Label>, R>) -> Self {
-        Self(table.into_iter().map(|(k, v)| (k.into(), v)).collect())
+        Self {
+            rules: table.into_iter().map(|(k, v)| (k.into(), v)).collect(),
+            max_concurrent_builds: DEFAULT_MAX_CONCURRENT_BUILDS,
+        }
     }
 
     /// Create a builder with an empty set of rules
     pub fn new() -> Self {
-        Self(HashMap::new())
+        Self::from_map(HashMap::<Label, R>::new())
     }
 
     /// Add new rule
     pub fn add_rule(mut self, tag: impl Into<Label>, rule: R) -> Self {
-        self.0.insert(tag.into(), rule);
+        self.rules.insert(tag.into(), rule);
         self
     }
+
+    /// Set the maximum number of rules built at the same time. Default to `DEFAULT_MAX_CONCURRENT_BUILDS`.
+    pub fn with_max_concurrent_builds(mut self, max: NonZeroUsize) -> Self {
+        self.max_concurrent_builds = max.get();
+        self
+    }
+}
+
+impl<R: AsyncTryInto<Box<dyn Rule>, Error = TableError> + Clone> TableBuilder<R> {
+    // Build every rule on its own without consuming the builder, pairing each result with its tag.
+    pub(crate) async fn build_each(&self) -> Vec<(Label, Result<Box<dyn Rule>>)> {
+        build_all(self.rules.clone(), self.max_concurrent_builds).await
+    }
+}
+
+impl<R: AsyncTryInto<Box<dyn Rule>, Error = TableError> + DeserializeOwned> TableBuilder<R> {
+    /// Parse from YAML in the form of the `table` section of the `dcompass` configuration.
+    pub fn from_yaml(s: &str) -> std::result::Result<Self, ConfigError> {
+        serde_yaml::from_str(s).map_err(ConfigError::yaml("table"))
+    }
+
+    /// Parse from JSON in the same form as `from_yaml`.
+    pub fn from_json(s: &str) -> std::result::Result<Self, ConfigError> {
+        serde_json::from_str(s).map_err(ConfigError::json("table"))
+    }
 }
 
 #[async_trait]
 impl<R: AsyncTryInto<Box<dyn Rule>, Error = TableError>> AsyncTryInto<Table> for TableBuilder<R> {
     type Error = TableError;
 
-    /// Build the rounting table from a `TableBuilder`
+    /// Build the rounting table from a `TableBuilder`. Rules are built concurrently. Rules failed to build don't stop the others from being built and validated, and every problem found is reported.
     async fn async_try_into(self) -> Result<Table> {
         let mut rules = HashMap::new();
-        for (tag, r) in self.0 {
-            rules.insert(tag, r.async_try_into().await?);
+        let mut failed = Vec::new();
+        for (tag, r) in build_all(self.rules, self.max_concurrent_builds).await {
+            match r {
+                Ok(r) => {
+                    rules.insert(tag, r);
+                }
+                Err(e) => failed.push((tag, e)),
+            }
         }
-        Table::new(rules)
+        if failed.is_empty() {
+            return Table::new(rules);
+        }
+
+        failed.sort_by(|a, b| a.0.cmp(&b.0));
+        let broken = failed.iter().map(|(tag, _)| tag.clone()).collect();
+        let mut errors = Vec::new();
+        check(&rules, &broken, &mut errors);
+        // A sole error is kept as it is.
+        if errors.is_empty() && failed.len() == 1 {
+            return Err(failed.pop().unwrap().1);
+        }
+        let errors = failed
+            .into_iter()
+            .map(|(tag, e)| TableError::InRule(tag, Box::new(e)))
+            .chain(errors)
+            .collect();
+        Err(TableError::Multiple(errors))
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use super::{rule::actions::CacheMode, TableError};
+    use super::{
+        rule::{actions::CacheMode, Next, Rule},
+        Table, TableError,
+    };
     use crate::{builders::*, AsyncTryInto};
+    use async_trait::async_trait;
+    use std::{num::NonZeroUsize, time::Duration};
+    use tokio::time::Instant;
+
+    // Builds the rule after a delay in milliseconds.
+    struct Slow(
+        u64,
+        RuleBuilders<BuiltinMatcherBuilders, BuiltinActionBuilders>,
+    );
+
+    #[async_trait]
+    impl AsyncTryInto<Box<dyn Rule>> for Slow {
+        type Error = TableError;
+
+        async fn async_try_into(self) -> Result<Box<dyn Rule>, TableError> {
+            tokio::time::sleep(Duration::from_millis(self.0)).await;
+            self.1.async_try_into().await
+        }
+    }
 
     #[tokio::test]
     async fn is_not_recursion() {
@@ -386,6 +905,92 @@ mod tests {
         }
     }
 
+    #[tokio::test]
+    async fn fail_multiple() {
+        let e = TableBuilder::new()
+            .add_rule(
+                "start",
+                RuleBuilders::IfBlock(IfBlockBuilder::<BuiltinMatcherBuilders, _>::new(
+                    "true",
+                    BranchBuilder::<BuiltinActionBuilders>::new("missing"),
+                    BranchBuilder::<BuiltinActionBuilders>::new("loop"),
+                )),
+            )
+            .add_rule(
+                "loop",
+                RuleBuilders::<BuiltinMatcherBuilders, _>::SeqBlock(BranchBuilder::<
+                    BuiltinActionBuilders,
+                >::new("loop")),
+            )
+            .add_rule(
+                "unused",
+                RuleBuilders::<BuiltinMatcherBuilders, _>::SeqBlock(BranchBuilder::<
+                    BuiltinActionBuilders,
+                >::default()),
+            )
+            .async_try_into()
+            .await
+            .err()
+            .unwrap();
+        assert_eq!(
+            e.to_string(),
+            "3 problems found:
+  1. Rule with tag `missing` is not found in the `table` section. Note that tag `start` is required
+  2. The `rule` block with tag `loop` is being recursively called in the `table` section
+  3. Some of the rules in table are not used: {\"unused\"}"
+        );
+        match e {
+            TableError::Multiple(v) => {
+                assert!(matches!(&v[0], TableError::UndefinedTag(t) if t.as_str() == "missing"));
+                assert!(matches!(&v[1], TableError::RuleRecursion(t) if t.as_str() == "loop"));
+                assert!(matches!(&v[2], TableError::UnusedRules(_)));
+            }
+            e => panic!("Not the right error type: {}", e),
+        }
+    }
+
+    #[tokio::test]
+    async fn fail_multiple_with_broken_rule() {
+        // `next` is only reachable from the broken rule, it is not reported as unused.
+        match TableBuilder::new()
+            .add_rule(
+                "start",
+                RuleBuilders::<BuiltinMatcherBuilders, _>::SeqBlock(BranchBuilder::<
+                    BuiltinActionBuilders,
+                >::call(
+                    "broken", "missing"
+                )),
+            )
+            .add_rule(
+                "broken",
+                RuleBuilders::IfBlock(IfBlockBuilder::<BuiltinMatcherBuilders, _>::new(
+                    "true &&",
+                    BranchBuilder::<BuiltinActionBuilders>::new("next"),
+                    BranchBuilder::<BuiltinActionBuilders>::default(),
+                )),
+            )
+            .add_rule(
+                "next",
+                RuleBuilders::<BuiltinMatcherBuilders, _>::SeqBlock(BranchBuilder::<
+                    BuiltinActionBuilders,
+                >::default()),
+            )
+            .async_try_into()
+            .await
+            .err()
+            .unwrap()
+        {
+            TableError::Multiple(v) => {
+                assert_eq!(v.len(), 2);
+                assert!(
+                    matches!(&v[0], TableError::InRule(t, e) if t.as_str() == "broken" && matches!(**e, TableError::ExprError(_)))
+                );
+                assert!(matches!(&v[1], TableError::UndefinedTag(t) if t.as_str() == "missing"));
+            }
+            e => panic!("Not the right error type: {}", e),
+        }
+    }
+
     #[tokio::test]
     async fn success_domain_table() {
         TableBuilder::new()
@@ -406,4 +1011,211 @@ mod tests {
             .ok()
             .unwrap();
     }
+
+    #[tokio::test]
+    async fn call_is_not_recursion() {
+        // Calling the same chain twice in a row is fine.
+        TableBuilder::new()
+            .add_rule(
+                "start",
+                RuleBuilders::<BuiltinMatcherBuilders, _>::SeqBlock(BranchBuilder::<
+                    BuiltinActionBuilders,
+                >::call(
+                    "chain", "next"
+                )),
+            )
+            .add_rule(
+                "next",
+                RuleBuilders::<BuiltinMatcherBuilders, _>::SeqBlock(BranchBuilder::<
+                    BuiltinActionBuilders,
+                >::call(
+                    "chain", "end"
+                )),
+            )
+            .add_rule(
+                "chain",
+                RuleBuilders::<BuiltinMatcherBuilders, _>::SeqBlock(BranchBuilder::<
+                    BuiltinActionBuilders,
+                >::default()),
+            )
+            .async_try_into()
+            .await
+            .ok()
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn fail_call_recursion() {
+        // `chain` calls back into `start` which is still on the path.
+        match TableBuilder::new()
+            .add_rule(
+                "start",
+                RuleBuilders::<BuiltinMatcherBuilders, _>::SeqBlock(BranchBuilder::<
+                    BuiltinActionBuilders,
+                >::call(
+                    "chain", "end"
+                )),
+            )
+            .add_rule(
+                "chain",
+                RuleBuilders::<BuiltinMatcherBuilders, _>::SeqBlock(BranchBuilder::<
+                    BuiltinActionBuilders,
+                >::call(
+                    "start", "end"
+                )),
+            )
+            .async_try_into()
+            .await
+            .err()
+            .unwrap()
+        {
+            TableError::RuleRecursion(_) => {}
+            e => panic!("Not the right error type: {}", e),
+        }
+    }
+
+    #[tokio::test]
+    async fn fail_unused_after_call() {
+        // Rules only reachable from a called chain are used, others are not.
+        match TableBuilder::new()
+            .add_rule(
+                "start",
+                RuleBuilders::<BuiltinMatcherBuilders, _>::SeqBlock(BranchBuilder::<
+                    BuiltinActionBuilders,
+                >::call(
+                    "chain", "end"
+                )),
+            )
+            .add_rule(
+                "chain",
+                RuleBuilders::<BuiltinMatcherBuilders, _>::SeqBlock(BranchBuilder::<
+                    BuiltinActionBuilders,
+                >::new("inner")),
+            )
+            .add_rule(
+                "inner",
+                RuleBuilders::<BuiltinMatcherBuilders, _>::SeqBlock(BranchBuilder::<
+                    BuiltinActionBuilders,
+                >::default()),
+            )
+            .add_rule(
+                "unused",
+                RuleBuilders::<BuiltinMatcherBuilders, _>::SeqBlock(BranchBuilder::<
+                    BuiltinActionBuilders,
+                >::default()),
+            )
+            .async_try_into()
+            .await
+            .err()
+            .unwrap()
+        {
+            TableError::UnusedRules(v) => {
+                assert_eq!(v, vec!["unused".into()].into_iter().collect())
+            }
+            e => panic!("Not the right error type: {}", e),
+        }
+    }
+
+    #[tokio::test]
+    async fn parse_call() {
+        let branch: BranchBuilder<BuiltinActionBuilders> =
+            ron::from_str(r#"[(call: "chain", then: "next")]"#).unwrap();
+        let (actions, next) = branch.async_try_into().await.unwrap();
+        assert!(actions.is_empty());
+        assert_eq!(
+            next,
+            Next::Call {
+                call: "chain".into(),
+                then: "next".into()
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn to_dot() {
+        let table = TableBuilder::new()
+            .add_rule(
+                "start",
+                RuleBuilders::IfBlock(IfBlockBuilder::<BuiltinMatcherBuilders, _>::new(
+                    "qtype([AAAA])",
+                    BranchBuilder::new("end").add_action(BuiltinActionBuilders::Blackhole),
+                    BranchBuilder::new("dispatch"),
+                )),
+            )
+            .add_rule(
+                "dispatch",
+                RuleBuilders::<BuiltinMatcherBuilders, _>::SeqBlock(
+                    BranchBuilder::call("chain", "end").add_action(BuiltinActionBuilders::Query(
+                        QueryBuilder::new("mock", CacheMode::Persistent),
+                    )),
+                ),
+            )
+            .add_rule(
+                "chain",
+                RuleBuilders::SwitchBlock(
+                    SwitchBuilder::<BuiltinMatcherBuilders, _>::new(BranchBuilder::<
+                        BuiltinActionBuilders,
+                    >::default(
+                    ))
+                    .add_arm(
+                        r#"domain([qname("example.com")])"#,
+                        BranchBuilder::new("end"),
+                    ),
+                ),
+            )
+            .async_try_into()
+            .await
+            .unwrap();
+        let desc = table.describe();
+        assert_eq!(
+            desc.iter().map(|r| r.tag.as_str()).collect::<Vec<_>>(),
+            ["chain", "dispatch", "start"]
+        );
+        assert_eq!(desc[2].kind, "if");
+        assert_eq!(desc[2].expr.as_deref(), Some("qtype([AAAA])"));
+        assert_eq!(desc[2].branches[0].actions, ["blackhole"]);
+        assert_eq!(desc[2].branches[1].next, Next::from("dispatch"));
+
+        assert_eq!(
+            table.to_dot(),
+            r#"digraph table {
+    node [shape=box];
+    "end" [shape=doublecircle];
+    "chain" [label="chain\nswitch"];
+    "dispatch" [label="dispatch\nseq"];
+    "start" [label="start\nif: qtype([AAAA])", peripheries=2];
+    "chain" -> "end" [label="arm 0: domain([qname(\"example.com\")])"];
+    "chain" -> "end" [label="default"];
+    "dispatch" -> "chain" [label="then (call)\nquery(mock, persistent)", style=dashed];
+    "dispatch" -> "end" [label="then (return)"];
+    "start" -> "end" [label="on_match\nblackhole"];
+    "start" -> "dispatch" [label="no_match"];
+}
+"#
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn build_concurrently() {
+        let goto = |next| RuleBuilders::SeqBlock(BranchBuilder::new(next));
+        let builder = || {
+            TableBuilder::new()
+                .add_rule("start", Slow(100, goto("foo")))
+                .add_rule("foo", Slow(200, goto("bar")))
+                .add_rule("bar", Slow(300, goto("end")))
+        };
+
+        let start = Instant::now();
+        let _: Table = builder().async_try_into().await.unwrap();
+        // As long as the slowest one rather than all of them together.
+        assert!(start.elapsed() < Duration::from_millis(350));
+
+        let start = Instant::now();
+        let _: Table = builder()
+            .with_max_concurrent_builds(NonZeroUsize::new(1).unwrap())
+            .async_try_into()
+            .await
+            .unwrap();
+        assert!(start.elapsed() >= Duration::from_millis(600));
+    }
 }
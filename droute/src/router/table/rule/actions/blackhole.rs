@@ -19,55 +19,77 @@ use super::{
     super::super::{super::upstreams::Upstreams, State},
     Action, Result,
 };
-use crate::{Label, MAX_TTL};
+use crate::{Label, MAX_LEN, MAX_TTL};
 use async_trait::async_trait;
 use bytes::{Bytes, BytesMut};
 use domain::{
-    base::{Dname, MessageBuilder},
+    base::{iana::Rcode, Dname, MessageBuilder},
     rdata::Soa,
 };
 use once_cell::sync::Lazy;
 
 // Data from smartdns. https://github.com/pymumu/smartdns/blob/42b3e98b2a3ca90ea548f8cb5ed19a3da6011b74/src/dns_server.c#L651
-static SOA_RDATA: Lazy<(Dname<Bytes>, u32, Soa<Dname<Bytes>>)> = Lazy::new(|| {
+static SOA_NAMES: Lazy<(Dname<Bytes>, Dname<Bytes>)> = Lazy::new(|| {
     (
-        Dname::root_bytes(),
-        MAX_TTL,
-        Soa::new(
-            Dname::from_str("a.gtld-servers.net").unwrap(),
-            Dname::from_str("nstld.verisign-grs.com").unwrap(),
-            1800.into(),
-            1800,
-            900,
-            604800,
-            86400,
-        ),
+        Dname::from_str("a.gtld-servers.net").unwrap(),
+        Dname::from_str("nstld.verisign-grs.com").unwrap(),
     )
 });
 
-/// An action that sends back the message that may refrain the sender to continue to query.
-pub struct Blackhole;
+pub(super) fn default_soa() -> bool {
+    true
+}
+
+pub(super) fn default_negative_ttl() -> u32 {
+    MAX_TTL
+}
+
+/// An action that answers the query with `NXDOMAIN`, which refrains the sender from querying it again.
+/// The question is echoed, and unless `negative_ttl` is `None`, an SOA record is put in the authority section for the denial to be cached for that many seconds.
+pub struct Blackhole {
+    negative_ttl: Option<u32>,
+}
+
+impl Blackhole {
+    /// Create a new `Blackhole` action, with an SOA record of the negative TTL given if any.
+    pub fn new(negative_ttl: Option<u32>) -> Self {
+        Self { negative_ttl }
+    }
+}
 
 impl Default for Blackhole {
-    /// Create a default `Disable` action.
+    /// Create a default `Blackhole` action, with an SOA record of a day as the negative TTL.
     fn default() -> Self {
-        Self
+        Self::new(Some(default_negative_ttl()))
     }
 }
 
 #[async_trait]
 impl Action for Blackhole {
     async fn act(&self, state: &mut State, _: &Upstreams) -> Result<()> {
-        // Is 50 a good number?
-        let mut builder = MessageBuilder::from_target(BytesMut::with_capacity(50))?.additional();
+        let mut builder = MessageBuilder::from_target(BytesMut::with_capacity(MAX_LEN))?
+            .start_answer(&state.query, Rcode::NXDomain)?
+            .authority();
 
-        builder.push(SOA_RDATA.clone())?;
+        // Negative answers are cached for the lesser of the TTL and the minimum field of the SOA record (RFC 2308).
+        if let Some(ttl) = self.negative_ttl {
+            let (mname, rname) = SOA_NAMES.clone();
+            builder.push((
+                Dname::root_bytes(),
+                ttl,
+                Soa::new(mname, rname, 1800.into(), 1800, 900, 604800, ttl),
+            ))?;
+        }
 
-        state.resp = builder.into_message();
+        state.set_resp(builder.into_message());
         Ok(())
     }
 
     fn used_upstream(&self) -> Option<Label> {
         None
     }
+
+    fn describe(&self) -> String {
+        "blackhole".to_string()
+    }
 }
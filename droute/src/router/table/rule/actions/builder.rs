@@ -14,50 +14,80 @@
 // along with this program.  If not, see <http://www.gnu.org/licenses/>.
 
 pub use super::query::QueryBuilder;
-use super::{Action, ActionError, Blackhole, CacheMode, EcsBuilder, Result as ActionResult};
-use crate::{AsyncTryInto, Label};
+use super::{
+    blackhole, Action, ActionError, Blackhole, CacheMode, EcsBuilder, Result as ActionResult,
+    SetRcode,
+};
+use crate::{matchers::RcodeDef, AsyncTryInto, Label};
 use async_trait::async_trait;
+use domain::base::iana::Rcode;
 use serde::{Deserialize, Deserializer};
 
 /// Builtin Parsed Actions
 /// This is a default enum which implements serde's deserialize trait to help you parse stuff into an action.
 /// You can rewrite your own parsed enum to support customized action and more functionalities on your needs.
 #[derive(Clone, Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
 #[serde(rename_all = "lowercase")]
 pub enum BuiltinActionBuilders {
-    /// Set response to a message that "disables" requestor to retry.
+    /// Set response to `NXDOMAIN` with an SOA record, which "disables" requestor to retry.
     Blackhole,
 
+    /// Same as `Blackhole`, with the SOA record configured.
+    #[serde(rename = "blackhole_with")]
+    BlackholeWith {
+        /// Whether to put an SOA record in the authority section
+        #[serde(default = "blackhole::default_soa")]
+        soa: bool,
+        /// Seconds for the denial to be cached, which are the TTL and the minimum field of the SOA record
+        #[serde(default = "blackhole::default_negative_ttl")]
+        negative_ttl: u32,
+    },
+
+    /// Set response to an empty one with the response code given, e.g. `REFUSED` or `NOTIMP`.
+    #[serde(rename = "set_rcode")]
+    SetRcode(
+        #[serde(with = "RcodeDef")]
+        #[cfg_attr(feature = "schema", schemars(with = "String"))]
+        Rcode,
+    ),
+
     /// Send query through an upstream with the specified tag name.
     #[serde(deserialize_with = "de_query")]
-    Query(QueryBuilder),
+    Query(#[cfg_attr(feature = "schema", schemars(with = "QueryForms"))] QueryBuilder),
 
     /// Automatically append ECS information to the OPT section.
     Ecs(EcsBuilder),
 }
 
+#[derive(Clone, Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+#[serde(rename_all = "lowercase")]
+struct ExplicitQuery {
+    /// Tag of the upstream
+    #[cfg_attr(feature = "schema", schemars(with = "String"))]
+    tag: Label,
+    /// Cache policy of the query
+    cache_policy: CacheMode,
+}
+
+// Forms accepted by the query action. This also describes the action in the schema.
+#[derive(Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+#[serde(untagged)]
+enum QueryForms {
+    Explicit(ExplicitQuery),
+    Default(#[cfg_attr(feature = "schema", schemars(with = "String"))] Label),
+}
+
 // Deserialize either a tag with default policy or a tag with a policy for query.
 fn de_query<'de, D>(deserializer: D) -> Result<QueryBuilder, D::Error>
 where
     D: Deserializer<'de>,
 {
-    #[derive(Clone, Deserialize)]
-    #[serde(rename_all = "lowercase")]
-    struct ExplicitQuery {
-        tag: Label,
-        cache_policy: CacheMode,
-    }
-
-    #[derive(Deserialize)]
-    #[serde(untagged)]
-    enum Either {
-        Explicit(ExplicitQuery),
-        Default(Label),
-    }
-
-    Ok(match Either::deserialize(deserializer) {
-        Ok(Either::Explicit(ExplicitQuery { tag, cache_policy })) => QueryBuilder(tag, cache_policy),
-        Ok(Either::Default(t)) => QueryBuilder(t, CacheMode::default()),
+    Ok(match QueryForms::deserialize(deserializer) {
+        Ok(QueryForms::Explicit(ExplicitQuery { tag, cache_policy })) => QueryBuilder(tag, cache_policy),
+        Ok(QueryForms::Default(t)) => QueryBuilder(t, CacheMode::default()),
 	// Currently, because BranchBuilder cannot provide precise information, this error message doesn't take effect.
 	Err(_) => return Err(serde::de::Error::custom("Failed to parse query action using either explicit form (tag, cache_policy) or the simplified form (tag only)"))
     })
@@ -68,7 +98,11 @@ impl AsyncTryInto<Box<dyn Action>> for BuiltinActionBuilders {
     // Should only be accessible from `Rule`.
     async fn async_try_into(self) -> ActionResult<Box<dyn Action>> {
         Ok(match self {
-            Self::Blackhole => Box::new(Blackhole),
+            Self::Blackhole => Box::new(Blackhole::default()),
+            Self::BlackholeWith { soa, negative_ttl } => {
+                Box::new(Blackhole::new(soa.then_some(negative_ttl)))
+            }
+            Self::SetRcode(rcode) => Box::new(SetRcode::new(rcode)),
             Self::Query(q) => Box::new(q.async_try_into().await?),
             Self::Ecs(e) => Box::new(e.async_try_into().await?),
         })
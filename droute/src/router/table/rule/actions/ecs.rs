@@ -223,9 +223,14 @@ impl Action for Ecs {
     fn used_upstream(&self) -> Option<Label> {
         None
     }
+
+    fn describe(&self) -> String {
+        "ecs".to_string()
+    }
 }
 
 #[derive(Serialize, Deserialize, Clone)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
 #[serde(rename_all = "lowercase")]
 /// Build ECS with two modes
 pub enum EcsBuilder {
@@ -18,11 +18,13 @@ mod blackhole;
 pub mod builder;
 mod ecs;
 mod query;
+mod set_rcode;
 
 pub use self::{
     blackhole::Blackhole,
     ecs::{Ecs, EcsBuilder},
     query::{CacheMode, Query},
+    set_rcode::SetRcode,
 };
 
 use super::super::{
@@ -63,6 +65,10 @@ pub enum ActionError {
     /// invalid domain
     #[error("the URL '{0}' doesn't contain a valid domain")]
     InvalidUrl(String),
+
+    /// The deadline in effect is reached before the action starts.
+    #[error("The deadline is reached before the action starts")]
+    DeadlineExceeded,
 }
 
 #[async_trait]
@@ -73,4 +79,12 @@ pub trait Action: Sync + Send {
 
     /// All upstreams may used by this `Action`.
     fn used_upstream(&self) -> Option<Label>;
+
+    /// A human-readable description of this `Action`. By default, it is the name of the type.
+    fn describe(&self) -> String {
+        let name = std::any::type_name::<Self>();
+        // Strip the path, which is at the front unless the type is generic.
+        let path = name.split('<').next().unwrap_or(name);
+        name[path.rfind("::").map(|n| n + 2).unwrap_or(0)..].to_string()
+    }
 }
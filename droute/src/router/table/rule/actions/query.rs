@@ -20,25 +20,22 @@ use super::{
 use crate::{AsyncTryInto, Label};
 use async_trait::async_trait;
 use serde::{Deserialize, Serialize};
+use std::time::Duration;
 
-#[derive(Deserialize, Serialize, Clone, PartialEq, Eq)]
+#[derive(Deserialize, Serialize, Clone, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
 #[serde(rename_all = "lowercase")]
 /// Cache Policy per query. this only affect the cache results adoption, and it will NOT change the cache results storing behaviors.
 pub enum CacheMode {
     /// Do not use any cached result
     Disabled,
     /// Use cache records within the TTL
+    #[default]
     Standard,
     /// Use cache results regardless of the time elapsed, and update the results on need.
     Persistent,
 }
 
-impl Default for CacheMode {
-    fn default() -> Self {
-        Self::Standard
-    }
-}
-
 /// An action that send the query to an `Upstream` named with `tag`.
 pub struct Query {
     tag: Label,
@@ -55,15 +52,30 @@ impl Query {
 #[async_trait]
 impl Action for Query {
     async fn act(&self, state: &mut State, upstreams: &Upstreams) -> Result<()> {
-        state.resp = upstreams
+        // Rules are awaited under the deadline, which cuts the attempt short. Yet we should never start one once the budget is used up.
+        if state.remaining() == Some(Duration::ZERO) {
+            return Err(ActionError::DeadlineExceeded);
+        }
+        let (resp, source, answered_by) = upstreams
             .resolve(&self.tag, &self.cache_mode, &state.query)
             .await?;
+        state.set_resp(resp);
+        state.resp_source = Some(source);
+        state.answered_by = Some(answered_by);
         Ok(())
     }
 
     fn used_upstream(&self) -> Option<Label> {
         Some(self.tag.clone())
     }
+
+    fn describe(&self) -> String {
+        match self.cache_mode {
+            CacheMode::Standard => format!("query({})", self.tag),
+            CacheMode::Disabled => format!("query({}, disabled)", self.tag),
+            CacheMode::Persistent => format!("query({}, persistent)", self.tag),
+        }
+    }
 }
 
 /// A builder for query action plugin
@@ -0,0 +1,55 @@
+// Copyright 2020 LEXUGE
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use super::{
+    super::super::{super::upstreams::Upstreams, State},
+    Action, Result,
+};
+use crate::{Label, MAX_LEN};
+use async_trait::async_trait;
+use bytes::BytesMut;
+use domain::base::{iana::Rcode, MessageBuilder};
+
+/// An action that answers the query with the response code given, echoing the question with nothing else.
+/// It is meant for answers like `REFUSED` to clients out of the ACL, or `NOTIMP` to opcodes not supported.
+pub struct SetRcode(Rcode);
+
+impl SetRcode {
+    /// Create a new `SetRcode` action.
+    pub fn new(rcode: Rcode) -> Self {
+        Self(rcode)
+    }
+}
+
+#[async_trait]
+impl Action for SetRcode {
+    async fn act(&self, state: &mut State, _: &Upstreams) -> Result<()> {
+        // The header is reset to answer the query once routing finishes, which keeps the rcode of the response.
+        state.set_resp(
+            MessageBuilder::from_target(BytesMut::with_capacity(MAX_LEN))?
+                .start_answer(&state.query, self.0)?
+                .into_message(),
+        );
+        Ok(())
+    }
+
+    fn used_upstream(&self) -> Option<Label> {
+        None
+    }
+
+    fn describe(&self) -> String {
+        format!("set_rcode({})", self.0)
+    }
+}
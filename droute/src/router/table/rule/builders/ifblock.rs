@@ -22,10 +22,15 @@ use crate::{
 };
 use async_trait::async_trait;
 use serde::{Deserialize, Serialize};
-use std::marker::PhantomData;
+use std::{marker::PhantomData, time::Duration};
 
 /// A rule composed of tag name, matcher, and branches.
 #[derive(Deserialize, Serialize, Clone)]
+#[cfg_attr(
+    feature = "schema",
+    derive(schemars::JsonSchema),
+    schemars(rename = "IfBlockBuilder", bound = "A: schemars::JsonSchema")
+)]
 #[serde(rename_all = "lowercase")]
 #[serde(deny_unknown_fields)]
 pub struct IfBlockBuilder<M, A>
@@ -38,16 +43,21 @@ where
     pub expr: String,
 
     /// If matcher matches, this branch specifies action and next rule name to route. Defaut to `(Vec::new(), "end".into())`
-    #[serde(default = "BranchBuilder::default")]
+    #[serde(default = "BranchBuilder::<A>::default")]
     #[serde(rename = "then")]
     pub on_match: BranchBuilder<A>,
 
     /// If matcher doesn't, this branch specifies action and next rule name to route. Defaut to `(Vec::new(), "end".into())`
-    #[serde(default = "BranchBuilder::default")]
+    #[serde(default = "BranchBuilder::<A>::default")]
     #[serde(rename = "else")]
     pub no_match: BranchBuilder<A>,
 
+    /// The time budget of the rule in milliseconds, which covers the chains it calls as well. Default to `None`, which is unlimited.
     #[serde(default)]
+    pub deadline_ms: Option<u64>,
+
+    #[serde(default)]
+    #[cfg_attr(feature = "schema", schemars(skip))]
     _guard: PhantomData<M>,
 }
 
@@ -66,9 +76,16 @@ where
             expr: expr.to_string(),
             on_match,
             no_match,
+            deadline_ms: None,
             _guard: PhantomData,
         }
     }
+
+    /// Set the time budget of the rule in milliseconds.
+    pub fn with_deadline_ms(mut self, deadline_ms: u64) -> Self {
+        self.deadline_ms = Some(deadline_ms);
+        self
+    }
 }
 
 #[async_trait]
@@ -89,6 +106,10 @@ where
         );
         let on_match = self.on_match.async_try_into().await?;
         let no_match = self.no_match.async_try_into().await?;
-        Ok(IfBlock::new(matcher, on_match, no_match))
+        let rule = IfBlock::new(matcher, on_match, no_match).with_expr(self.expr);
+        Ok(match self.deadline_ms {
+            Some(ms) => rule.with_deadline(Duration::from_millis(ms)),
+            None => rule,
+        })
     }
 }
@@ -14,12 +14,16 @@
 // along with this program.  If not, see <http://www.gnu.org/licenses/>.
 
 mod ifblock;
+mod switch;
 // Please pub use every block builder here
-pub use self::ifblock::IfBlockBuilder;
+pub use self::{
+    ifblock::IfBlockBuilder,
+    switch::{SwitchArmBuilder, SwitchBuilder},
+};
 
 use super::{
     actions::{Action, Result as ActionResult},
-    IfBlock, Result, SeqBlock,
+    IfBlock, Next, Result, SeqBlock, SwitchBlock,
 };
 use crate::{
     actions::ActionError,
@@ -28,6 +32,15 @@ use crate::{
     AsyncTryInto, Label, Rule,
 };
 use async_trait::async_trait;
+#[cfg(feature = "schema")]
+use schemars::{
+    gen::SchemaGenerator,
+    schema::{
+        ArrayValidation, InstanceType, Metadata, Schema, SchemaObject, SingleOrVec,
+        SubschemaValidation,
+    },
+    JsonSchema,
+};
 use serde::{
     de::{Deserializer, Error as _, SeqAccess, Visitor},
     Deserialize, Serialize,
@@ -38,7 +51,7 @@ use std::marker::PhantomData;
 #[derive(Serialize, Clone)]
 pub struct BranchBuilder<A: AsyncTryInto<Box<dyn Action>, Error = ActionError>> {
     seq: Vec<A>,
-    next: Label,
+    next: Next,
 }
 
 // This customized deserialization process accept branches of this form:
@@ -48,6 +61,11 @@ pub struct BranchBuilder<A: AsyncTryInto<Box<dyn Action>, Error = ActionError>>
 // - ...
 // - next
 // ```
+// where `next` can also be a call to another chain:
+// ```
+// - call: chain
+//   then: next
+// ```
 // Here the lifetime constraints are compatible with the ones from serde derivation. We are not adding them to `AggregatedActionBuilder` as they are gonna be automatically generated by serde.
 impl<'de, A: AsyncTryInto<Box<dyn Action>, Error = ActionError> + Deserialize<'de>> Deserialize<'de>
     for BranchBuilder<A>
@@ -60,7 +78,7 @@ impl<'de, A: AsyncTryInto<Box<dyn Action>, Error = ActionError> + Deserialize<'d
         #[serde(untagged)]
         enum Either<A: AsyncTryInto<Box<dyn Action>, Error = ActionError>> {
             Action(A),
-            Tag(Label),
+            Next(Next),
         }
 
         struct BranchVisitor<A> {
@@ -74,7 +92,9 @@ impl<'de, A: AsyncTryInto<Box<dyn Action>, Error = ActionError> + Deserialize<'d
             type Value = BranchBuilder<A>;
 
             fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
-                f.write_str("a list of actions with the tag of the next rule as the last element")
+                f.write_str(
+                    "a list of actions with the tag of the next rule or a call as the last element",
+                )
             }
 
             fn visit_seq<V: SeqAccess<'de>>(
@@ -83,17 +103,17 @@ impl<'de, A: AsyncTryInto<Box<dyn Action>, Error = ActionError> + Deserialize<'d
             ) -> std::result::Result<Self::Value, V::Error> {
                 let mut seq = Vec::new();
 
-                // Get the `next` from the first element of the type Label.
+                // Get the `next` from the first element of the type Next.
                 let next = loop {
                     match sv.next_element::<Either<A>>() {
                         Ok(Some(Either::Action(a))) => seq.push(a),
-                        Ok(Some(Either::Tag(l))) => break l,
+                        Ok(Some(Either::Next(n))) => break n,
                         Ok(None) => {
                             return Err(V::Error::custom("Missing the tag of the next rule"))
                         }
                         Err(_) => {
                             return Err(V::Error::custom(
-                                "Make sure elements in branch are either valid actions or label in the end. Failed to parse the branch",
+                                "Make sure elements in branch are either valid actions or label (or call) in the end. Failed to parse the branch",
                             ))
                         }
                     }
@@ -114,12 +134,65 @@ impl<'de, A: AsyncTryInto<Box<dyn Action>, Error = ActionError> + Deserialize<'d
     }
 }
 
+// The schema cannot tell the last element apart from the others, so every element is allowed to be either an action or the next rule.
+#[cfg(feature = "schema")]
+impl<A: AsyncTryInto<Box<dyn Action>, Error = ActionError> + JsonSchema> JsonSchema
+    for BranchBuilder<A>
+{
+    fn schema_name() -> String {
+        format!("Branch_for_{}", A::schema_name())
+    }
+
+    fn json_schema(gen: &mut SchemaGenerator) -> Schema {
+        SchemaObject {
+            metadata: Some(Box::new(Metadata {
+                description: Some(
+                    "A list of actions with the tag of the next rule or a call as the last element"
+                        .to_string(),
+                ),
+                ..Default::default()
+            })),
+            instance_type: Some(InstanceType::Array.into()),
+            array: Some(Box::new(ArrayValidation {
+                items: Some(SingleOrVec::Single(Box::new(
+                    SchemaObject {
+                        subschemas: Some(Box::new(SubschemaValidation {
+                            any_of: Some(vec![
+                                gen.subschema_for::<A>(),
+                                gen.subschema_for::<Next>(),
+                            ]),
+                            ..Default::default()
+                        })),
+                        ..Default::default()
+                    }
+                    .into(),
+                ))),
+                min_items: Some(1),
+                ..Default::default()
+            })),
+            ..Default::default()
+        }
+        .into()
+    }
+}
+
 impl<A: AsyncTryInto<Box<dyn Action>, Error = ActionError>> BranchBuilder<A> {
     /// Create a new BranchBuilder from a sequence of actions and the destination tag name.
     pub fn new(next: impl Into<Label>) -> Self {
         Self {
             seq: Vec::new(),
-            next: next.into(),
+            next: Next::Goto(next.into()),
+        }
+    }
+
+    /// Create a new BranchBuilder which calls the chain starting at `call` and continues at `then` once the chain reaches `end`.
+    pub fn call(call: impl Into<Label>, then: impl Into<Label>) -> Self {
+        Self {
+            seq: Vec::new(),
+            next: Next::Call {
+                call: call.into(),
+                then: then.into(),
+            },
         }
     }
 
@@ -127,7 +200,7 @@ impl<A: AsyncTryInto<Box<dyn Action>, Error = ActionError>> BranchBuilder<A> {
     pub fn from_actions(seq: Vec<A>, next: impl Into<Label>) -> Self {
         Self {
             seq,
-            next: next.into(),
+            next: Next::Goto(next.into()),
         }
     }
 
@@ -140,10 +213,10 @@ impl<A: AsyncTryInto<Box<dyn Action>, Error = ActionError>> BranchBuilder<A> {
 
 #[async_trait]
 impl<A: AsyncTryInto<Box<dyn Action>, Error = ActionError>>
-    AsyncTryInto<(Vec<Box<dyn Action>>, Label)> for BranchBuilder<A>
+    AsyncTryInto<(Vec<Box<dyn Action>>, Next)> for BranchBuilder<A>
 {
     /// Build the ParMatchArm into the internal-used tuple by `Rule`.
-    async fn async_try_into(self) -> ActionResult<(Vec<Box<dyn Action>>, Label)> {
+    async fn async_try_into(self) -> ActionResult<(Vec<Box<dyn Action>>, Next)> {
         let mut built: Vec<Box<dyn Action>> = Vec::new();
         for a in self.seq {
             // TODO: Can we make this into a map?
@@ -159,13 +232,18 @@ impl<A: AsyncTryInto<Box<dyn Action>, Error = ActionError>> Default for BranchBu
     fn default() -> Self {
         Self {
             seq: vec![],
-            next: "end".into(),
+            next: Next::Goto("end".into()),
         }
     }
 }
 
 /// A builder for rule block
 #[derive(Deserialize, Clone)]
+#[cfg_attr(
+    feature = "schema",
+    derive(schemars::JsonSchema),
+    schemars(rename = "RuleBuilders", bound = "A: schemars::JsonSchema")
+)]
 #[serde(untagged)]
 pub enum RuleBuilders<M, A>
 where
@@ -177,6 +255,9 @@ where
 
     /// If syntax rule
     IfBlock(IfBlockBuilder<M, A>),
+
+    /// Switch syntax rule
+    SwitchBlock(SwitchBuilder<M, A>),
 }
 
 #[async_trait]
@@ -190,6 +271,7 @@ where
     async fn async_try_into(self) -> Result<Box<dyn Rule>> {
         Ok(match self {
             Self::IfBlock(i) => Box::new(i.async_try_into().await?),
+            Self::SwitchBlock(s) => Box::new(s.async_try_into().await?),
             Self::SeqBlock(s) => Box::new(SeqBlock::new(s.async_try_into().await?)),
         })
     }
@@ -0,0 +1,140 @@
+// Copyright 2020 LEXUGE
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use super::{super::actions::Action, BranchBuilder, Result, SwitchBlock};
+use crate::{
+    actions::ActionError,
+    matchers::{expr::ExprParser, MatchError, Matcher},
+    router::table::TableError,
+    AsyncTryInto,
+};
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use std::{marker::PhantomData, time::Duration};
+
+/// A single arm of the switch rule.
+#[derive(Deserialize, Serialize, Clone)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+#[serde(rename_all = "lowercase")]
+#[serde(deny_unknown_fields)]
+pub struct SwitchArmBuilder<A: AsyncTryInto<Box<dyn Action>, Error = ActionError>> {
+    /// The matching expression.
+    #[serde(rename = "if")]
+    pub expr: String,
+
+    /// If matcher matches, this branch specifies action and next rule name to route.
+    #[serde(rename = "then")]
+    pub branch: BranchBuilder<A>,
+}
+
+impl<A: AsyncTryInto<Box<dyn Action>, Error = ActionError>> SwitchArmBuilder<A> {
+    /// Create a new SwitchArmBuilder
+    pub fn new(expr: impl ToString, branch: BranchBuilder<A>) -> Self {
+        Self {
+            expr: expr.to_string(),
+            branch,
+        }
+    }
+}
+
+/// A rule composed of an ordered list of arms and a default branch.
+#[derive(Deserialize, Serialize, Clone)]
+#[cfg_attr(
+    feature = "schema",
+    derive(schemars::JsonSchema),
+    schemars(rename = "SwitchBuilder", bound = "A: schemars::JsonSchema")
+)]
+#[serde(rename_all = "lowercase")]
+#[serde(deny_unknown_fields)]
+pub struct SwitchBuilder<M, A>
+where
+    M: AsyncTryInto<Box<dyn Matcher>, Error = MatchError>,
+    A: AsyncTryInto<Box<dyn Action>, Error = ActionError>,
+{
+    /// Arms evaluated in order. The first arm whose matcher matches is taken.
+    #[serde(rename = "switch")]
+    pub arms: Vec<SwitchArmBuilder<A>>,
+
+    /// If none of the arms matches, this branch specifies action and next rule name to route. Defaut to `(Vec::new(), "end".into())`
+    #[serde(default = "BranchBuilder::<A>::default")]
+    pub default: BranchBuilder<A>,
+
+    /// The time budget of the rule in milliseconds, which covers the chains it calls as well. Default to `None`, which is unlimited.
+    #[serde(default)]
+    pub deadline_ms: Option<u64>,
+
+    #[serde(default)]
+    #[cfg_attr(feature = "schema", schemars(skip))]
+    _guard: PhantomData<M>,
+}
+
+impl<M, A> SwitchBuilder<M, A>
+where
+    M: AsyncTryInto<Box<dyn Matcher>, Error = MatchError>,
+    A: AsyncTryInto<Box<dyn Action>, Error = ActionError>,
+{
+    /// Create a new SwitchBuilder with no arms
+    pub fn new(default: BranchBuilder<A>) -> Self {
+        Self {
+            arms: Vec::new(),
+            default,
+            deadline_ms: None,
+            _guard: PhantomData,
+        }
+    }
+
+    /// Set the time budget of the rule in milliseconds.
+    pub fn with_deadline_ms(mut self, deadline_ms: u64) -> Self {
+        self.deadline_ms = Some(deadline_ms);
+        self
+    }
+
+    /// Add an arm to the end of the list
+    pub fn add_arm(mut self, expr: impl ToString, branch: BranchBuilder<A>) -> Self {
+        self.arms.push(SwitchArmBuilder::new(expr, branch));
+        self
+    }
+}
+
+#[async_trait]
+impl<M, A> AsyncTryInto<SwitchBlock> for SwitchBuilder<M, A>
+where
+    for<'a> M: AsyncTryInto<Box<dyn Matcher>, Error = MatchError> + Deserialize<'a>,
+    A: AsyncTryInto<Box<dyn Action>, Error = ActionError>,
+{
+    type Error = TableError;
+
+    async fn async_try_into(self) -> Result<SwitchBlock> {
+        let mut arms = Vec::new();
+        let mut exprs = Vec::new();
+        for arm in self.arms {
+            let matcher: Box<dyn Matcher> = Box::new(
+                ExprParser
+                    .build_node::<M>(&arm.expr)?
+                    .trim()
+                    .async_try_into()
+                    .await?,
+            );
+            arms.push((matcher, arm.branch.async_try_into().await?));
+            exprs.push(arm.expr);
+        }
+        let default = self.default.async_try_into().await?;
+        let rule = SwitchBlock::new(arms, default).with_exprs(exprs);
+        Ok(match self.deadline_ms {
+            Some(ms) => rule.with_deadline(Duration::from_millis(ms)),
+            None => rule,
+        })
+    }
+}
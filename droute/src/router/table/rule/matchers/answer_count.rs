@@ -0,0 +1,163 @@
+// Copyright 2020 LEXUGE
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use super::{super::super::State, MatchError, Matcher, Result};
+use domain::base::Rtype;
+
+// Default upper bound, which no count exceeds.
+pub(super) fn max_count() -> u16 {
+    u16::MAX
+}
+
+/// A matcher that matches if the number of records in the answer section of the current response is within `min` and `max`, both inclusive.
+/// It never matches before any action has set a response.
+pub struct AnswerCount {
+    min: u16,
+    max: u16,
+    qtype_only: bool,
+}
+
+impl AnswerCount {
+    /// Create a new `AnswerCount` matcher. If `qtype_only` is set, only records of the type queried are counted, so that e.g. a response with nothing but a CNAME has none.
+    pub fn new(min: u16, max: u16, qtype_only: bool) -> Result<Self> {
+        if min > max {
+            return Err(MatchError::Other(format!(
+                "minimum count {} of the answer_count matcher is greater than the maximum {}",
+                min, max
+            )));
+        }
+        Ok(Self {
+            min,
+            max,
+            qtype_only,
+        })
+    }
+}
+
+impl Matcher for AnswerCount {
+    fn matches(&self, state: &State) -> bool {
+        let answer = match state.answer() {
+            Some(answer) => answer,
+            None => return false,
+        };
+        let count = if self.qtype_only {
+            let qtype = state.query.first_question().unwrap().qtype();
+            answer
+                .flatten()
+                .filter(|r| qtype == Rtype::Any || r.rtype() == qtype)
+                .count()
+        } else {
+            state.resp.header_counts().ancount().into()
+        };
+        (usize::from(self.min)..=usize::from(self.max)).contains(&count)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        super::{Matcher, State},
+        max_count, AnswerCount,
+    };
+    use crate::MAX_LEN;
+    use bytes::{Bytes, BytesMut};
+    use domain::{
+        base::{iana::Rcode, Dname, Message, MessageBuilder, Rtype},
+        rdata::{Cname, A},
+    };
+    use once_cell::sync::Lazy;
+    use std::str::FromStr;
+
+    static NAME: Lazy<Dname<Bytes>> = Lazy::new(|| Dname::from_str("www.example.com").unwrap());
+
+    static QUERY: Lazy<Message<Bytes>> = Lazy::new(|| {
+        let mut builder = MessageBuilder::from_target(BytesMut::with_capacity(MAX_LEN))
+            .unwrap()
+            .question();
+        builder.push((&*NAME, Rtype::A)).unwrap();
+        builder.into_message()
+    });
+
+    // Build a response to `QUERY` with a CNAME record if asked, followed by the number of A records given in the answer section.
+    fn create_state(cname: bool, a: u8) -> State {
+        let target = Dname::<Bytes>::from_str("cdn.example.net").unwrap();
+        let mut builder = MessageBuilder::from_target(BytesMut::with_capacity(MAX_LEN))
+            .unwrap()
+            .start_answer(&*QUERY, Rcode::NoError)
+            .unwrap();
+        if cname {
+            builder
+                .push((&*NAME, 10, Cname::new(target.clone())))
+                .unwrap();
+        }
+        for n in 0..a {
+            builder
+                .push((&target, 10, A::from_octets(1, 1, 1, n)))
+                .unwrap();
+        }
+        let mut state = State {
+            query: QUERY.clone(),
+            ..Default::default()
+        };
+        state.set_resp(builder.into_message());
+        state
+    }
+
+    #[test]
+    fn bounds() {
+        let matcher = AnswerCount::new(2, max_count(), false).unwrap();
+        assert!(!matcher.matches(&create_state(false, 0)));
+        assert!(!matcher.matches(&create_state(false, 1)));
+        assert!(matcher.matches(&create_state(false, 5)));
+
+        let matcher = AnswerCount::new(1, 1, false).unwrap();
+        assert!(!matcher.matches(&create_state(false, 0)));
+        assert!(matcher.matches(&create_state(false, 1)));
+        assert!(!matcher.matches(&create_state(false, 5)));
+
+        assert!(AnswerCount::new(2, 1, false).is_err());
+    }
+
+    #[test]
+    fn qtype_only() {
+        let matcher = AnswerCount::new(0, 0, true).unwrap();
+        assert!(matcher.matches(&create_state(false, 0)));
+        // A CNAME alone doesn't answer an A query.
+        assert!(matcher.matches(&create_state(true, 0)));
+        assert!(!matcher.matches(&create_state(true, 1)));
+        assert!(!matcher.matches(&create_state(false, 5)));
+        // While it counts otherwise.
+        assert!(!AnswerCount::new(0, 0, false)
+            .unwrap()
+            .matches(&create_state(true, 0)));
+
+        let matcher = AnswerCount::new(5, 5, true).unwrap();
+        assert!(matcher.matches(&create_state(true, 5)));
+        assert!(!AnswerCount::new(5, 5, false)
+            .unwrap()
+            .matches(&create_state(true, 5)));
+    }
+
+    #[test]
+    fn unanswered() {
+        let state = State {
+            query: QUERY.clone(),
+            ..Default::default()
+        };
+        assert!(!AnswerCount::new(0, max_count(), false)
+            .unwrap()
+            .matches(&state));
+    }
+}
@@ -0,0 +1,104 @@
+// Copyright 2020 LEXUGE
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use super::{super::super::State, MatchError, Matcher, Result};
+use crate::{AsyncTryInto, Label};
+use async_trait::async_trait;
+use serde::Deserialize;
+use std::collections::HashSet;
+
+/// A matcher that matches if the response of the last `query` action was answered by any of the upstreams provided.
+/// Upstreams queried through a hybrid one are told apart, so the tags to provide are the ones that actually answered, never the hybrid one.
+/// It never matches before any `query` action has run.
+pub struct AnsweredBy(HashSet<Label>);
+
+impl AnsweredBy {
+    /// Create a new `AnsweredBy` matcher.
+    pub fn new(tags: HashSet<Label>) -> Result<Self> {
+        Ok(Self(tags))
+    }
+}
+
+impl Matcher for AnsweredBy {
+    fn matches(&self, state: &State) -> bool {
+        state
+            .answered_by
+            .as_ref()
+            .is_some_and(|tag| self.0.contains(tag))
+    }
+}
+
+/// A builder for answered-by matcher plugin
+#[derive(Deserialize, Clone, Debug, PartialEq, Eq)]
+#[serde(transparent)]
+pub struct AnsweredByBuilder(HashSet<Label>);
+
+impl Default for AnsweredByBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl AnsweredByBuilder {
+    /// Create an empty builder
+    pub fn new() -> Self {
+        Self(HashSet::new())
+    }
+
+    /// Add a tag of upstream to match
+    pub fn add_tag(mut self, tag: impl Into<Label>) -> Self {
+        self.0.insert(tag.into());
+        self
+    }
+}
+
+#[async_trait]
+impl AsyncTryInto<AnsweredBy> for AnsweredByBuilder {
+    type Error = MatchError;
+
+    async fn async_try_into(self) -> Result<AnsweredBy> {
+        AnsweredBy::new(self.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        super::{Matcher, State},
+        AnsweredByBuilder,
+    };
+    use crate::AsyncTryInto;
+
+    fn create_state(tag: Option<&str>) -> State {
+        State {
+            answered_by: tag.map(Into::into),
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn test() {
+        let matcher = AnsweredByBuilder::new()
+            .add_tag("dirty")
+            .add_tag("backup")
+            .async_try_into()
+            .await
+            .unwrap();
+        assert!(matcher.matches(&create_state(Some("dirty"))));
+        assert!(matcher.matches(&create_state(Some("backup"))));
+        assert!(!matcher.matches(&create_state(Some("clean"))));
+        assert!(!matcher.matches(&create_state(None)));
+    }
+}
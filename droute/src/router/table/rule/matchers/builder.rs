@@ -17,10 +17,30 @@ use crate::AsyncTryInto;
 
 #[cfg(feature = "geoip")]
 pub use super::geoip::GeoIpBuilder;
-pub use super::{domain::DomainBuilder, ipcidr::IpCidrBuilder, qtype::QTypeBuilder};
-use super::{header::Header, MatchError, Matcher, Result as MatcherResult};
+#[cfg(feature = "regex")]
+pub use super::qname_regex::QNameRegexBuilder;
+use super::{
+    answer_count::{self, AnswerCount},
+    client_rate::ClientRate,
+    cookie::Cookie,
+    duplicate::{self, Duplicate},
+    edns::{Edns, EdnsCond},
+    header::Header,
+    resp_rtype::{RespRType, RespRTypeMode},
+    resp_ttl::{self, RespTtl},
+    MatchError, Matcher, Result as MatcherResult,
+};
+pub use super::{
+    answered_by::AnsweredByBuilder, cname_chain::CnameChainBuilder, domain::DomainBuilder,
+    ecs::EcsBuilder, empty_answer::EmptyAnswerBuilder, full_qname::FullQnameBuilder,
+    ipcidr::IpCidrBuilder, opcode::OpCodeBuilder, ptr_addr::PtrAddrBuilder, qclass::QClassBuilder,
+    qname_contains::QNameContainsBuilder, qtype::QTypeBuilder, random::RandomBuilder,
+    rcode::RCodeBuilder, resp_source::RespSourceBuilder, schedule::ScheduleBuilder,
+    src_ip::SrcIpBuilder, transport::TransportBuilder,
+};
 use async_trait::async_trait;
 use serde::Deserialize;
+use std::time::Duration;
 
 /// The builder for Builtin Matchers
 #[derive(Deserialize, Clone, Debug)]
@@ -29,9 +49,79 @@ pub enum BuiltinMatcherBuilders {
     /// Matches domains in domain list files specified.
     Domain(DomainBuilder),
 
+    /// Matches if the name of the first query is exactly any of the names provided, without their subdomains.
+    #[serde(rename = "full_qname")]
+    FullQname(FullQnameBuilder),
+
     /// Matches query types provided. Query types are like AAAA, A, TXT.
     QType(QTypeBuilder),
 
+    /// Matches query classes provided. Query classes are like IN, CH, HS, ANY.
+    QClass(QClassBuilder),
+
+    /// Matches if the name of the first query contains any of the keywords provided, case-insensitively.
+    #[serde(rename = "qname_contains")]
+    QNameContains(QNameContainsBuilder),
+
+    /// Matches if the name of the first query matches any of the regular expressions provided.
+    #[cfg(feature = "regex")]
+    #[serde(rename = "qname_regex")]
+    QNameRegex(QNameRegexBuilder),
+
+    /// Matches opcodes provided on the query. Opcodes are like QUERY, NOTIFY, UPDATE, STATUS.
+    OpCode(OpCodeBuilder),
+
+    /// Matches response codes provided on the current response. Response codes are like NOERROR, NXDOMAIN, SERVFAIL.
+    RCode(RCodeBuilder),
+
+    /// Matches if the current response has no records in the answer section. If set, only records of the type queried are counted.
+    #[serde(rename = "empty_answer")]
+    EmptyAnswer(EmptyAnswerBuilder),
+
+    /// Matches if any, all, or none of the records in the answer section of the current response are of the record types provided.
+    #[serde(rename = "resp_rtype")]
+    RespRType(RespRTypeMode, QTypeBuilder),
+
+    /// Matches on the CNAME chain from the query name in the current response, either if it is longer than the depth provided or if any target in it is in the domain lists provided.
+    #[serde(rename = "cname_chain")]
+    CnameChain(CnameChainBuilder),
+
+    /// Matches if the response of the last query action came from any of the sources provided. Sources are like cache, stale_cache, upstream, upstream_tag("domestic").
+    #[serde(rename = "resp_source")]
+    RespSource(RespSourceBuilder),
+
+    /// Matches if the response of the last query action was answered by any of the upstreams provided, rather than the hybrid ones they are queried through.
+    #[serde(rename = "answered_by")]
+    AnsweredBy(AnsweredByBuilder),
+
+    /// Matches if the number of records in the answer section of the current response is within the bounds provided. If `qtype_only` is set, only records of the type queried are counted.
+    #[serde(rename = "answer_count")]
+    AnswerCount {
+        /// Fewest records to match
+        #[serde(default)]
+        min: u16,
+        /// Most records to match
+        #[serde(default = "answer_count::max_count")]
+        max: u16,
+        /// Whether to count only records of the type queried
+        #[serde(default)]
+        qtype_only: bool,
+    },
+
+    /// Matches if any of the records in the answer section of the current response has a TTL out of the bounds provided, or if all of them have if `all` is set.
+    #[serde(rename = "resp_ttl")]
+    RespTtl {
+        /// Records with TTLs below this are out of bounds
+        #[serde(default)]
+        min: u32,
+        /// Records with TTLs above this are out of bounds
+        #[serde(default = "resp_ttl::max_ttl")]
+        max: u32,
+        /// Whether all the records rather than any of them have to be out of bounds
+        #[serde(default)]
+        all: bool,
+    },
+
     /// Matches if IP address in the record of the first response is in the list of countries.
     #[cfg(feature = "geoip")]
     GeoIp(GeoIpBuilder),
@@ -39,6 +129,60 @@ pub enum BuiltinMatcherBuilders {
     /// Matches if IP address in the record of the first response is in the list of IP CIDR.
     IpCidr(IpCidrBuilder),
 
+    /// Matches if the query is received over any of the transport protocols provided. Protocols are like udp, tcp, dot, doh.
+    Protocol(TransportBuilder),
+
+    /// Matches if the EDNS Client Subnet of the query is within any of the IP CIDRs provided.
+    Ecs(EcsBuilder),
+
+    /// Matches on the OPT record of the query, i.e. whether it is present or whether the DO bit is set.
+    Edns(EdnsCond),
+
+    /// Matches if the OPT record of the query carries an EDNS COOKIE option of a valid length, with a server cookie if `server_cookie_required` is set.
+    Cookie {
+        /// Whether only cookies with a server cookie match
+        #[serde(default)]
+        server_cookie_required: bool,
+    },
+
+    /// Matches if the IP address the query is sent from is in the list of IP CIDR.
+    #[serde(rename = "src_ip")]
+    SrcIp(SrcIpBuilder),
+
+    /// Matches reverse lookups of the addresses in the list of IP CIDR, i.e. queries of names in `in-addr.arpa` or `ip6.arpa`.
+    #[serde(rename = "ptr_addr")]
+    PtrAddr(PtrAddrBuilder),
+
+    /// Matches once the client sending the query exceeds the number of queries in the sliding window.
+    #[serde(rename = "client_rate")]
+    ClientRate {
+        /// Maximum number of queries in the window
+        limit: u32,
+        /// Length of the window in seconds
+        window: u64,
+    },
+
+    /// Matches once the same client sends the same query name and type more than the threshold in the sliding window.
+    Duplicate {
+        /// Maximum number of the same queries in the window
+        threshold: u32,
+        /// Length of the window in seconds
+        window: u64,
+        /// Maximum number of different queries tracked
+        #[serde(default = "duplicate::default_capacity")]
+        capacity: usize,
+    },
+
+    /// Matches if the time now falls in any of the weekly windows provided.
+    Schedule(ScheduleBuilder),
+
+    /// Matches a fraction of evaluations at random.
+    Random(RandomBuilder),
+
+    /// Matches a fraction of query names, so that the same name always gets the same result.
+    #[serde(rename = "random_sticky")]
+    RandomSticky(RandomBuilder),
+
     /// Matches if header fulfills given condition
     Header(Header),
 }
@@ -49,9 +193,50 @@ impl AsyncTryInto<Box<dyn Matcher>> for BuiltinMatcherBuilders {
     async fn async_try_into(self) -> MatcherResult<Box<dyn Matcher>> {
         Ok(match self {
             Self::Domain(v) => Box::new(v.async_try_into().await?),
+            Self::FullQname(f) => Box::new(f.async_try_into().await?),
             Self::Header(h) => Box::new(h),
             Self::QType(q) => Box::new(q.async_try_into().await?),
+            Self::QClass(q) => Box::new(q.async_try_into().await?),
+            Self::QNameContains(q) => Box::new(q.async_try_into().await?),
+            #[cfg(feature = "regex")]
+            Self::QNameRegex(q) => Box::new(q.async_try_into().await?),
+            Self::OpCode(o) => Box::new(o.async_try_into().await?),
+            Self::RCode(r) => Box::new(r.async_try_into().await?),
+            Self::EmptyAnswer(e) => Box::new(e.async_try_into().await?),
+            Self::RespRType(mode, types) => Box::new(RespRType::new(mode, types.into_types())?),
+            Self::CnameChain(c) => Box::new(c.async_try_into().await?),
+            Self::RespSource(r) => Box::new(r.async_try_into().await?),
+            Self::AnsweredBy(a) => Box::new(a.async_try_into().await?),
+            Self::AnswerCount {
+                min,
+                max,
+                qtype_only,
+            } => Box::new(AnswerCount::new(min, max, qtype_only)?),
+            Self::RespTtl { min, max, all } => Box::new(RespTtl::new(min, max, all)?),
             Self::IpCidr(s) => Box::new(s.async_try_into().await?),
+            Self::SrcIp(s) => Box::new(s.async_try_into().await?),
+            Self::PtrAddr(p) => Box::new(p.async_try_into().await?),
+            Self::Protocol(p) => Box::new(p.async_try_into().await?),
+            Self::Ecs(e) => Box::new(e.async_try_into().await?),
+            Self::Edns(c) => Box::new(Edns::new(c)),
+            Self::Cookie {
+                server_cookie_required,
+            } => Box::new(Cookie::new(server_cookie_required)),
+            Self::Random(r) => Box::new(r.async_try_into().await?),
+            Self::RandomSticky(r) => Box::new(r.sticky().async_try_into().await?),
+            Self::ClientRate { limit, window } => {
+                Box::new(ClientRate::new(limit, Duration::from_secs(window))?)
+            }
+            Self::Duplicate {
+                threshold,
+                window,
+                capacity,
+            } => Box::new(Duplicate::new(
+                threshold,
+                Duration::from_secs(window),
+                capacity,
+            )?),
+            Self::Schedule(s) => Box::new(s.async_try_into().await?),
             #[cfg(feature = "geoip")]
             Self::GeoIp(g) => Box::new(g.async_try_into().await?),
         })
@@ -0,0 +1,145 @@
+// Copyright 2020 LEXUGE
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use super::{super::super::State, Matcher};
+use crate::preflight::Resource;
+use bytes::Bytes;
+use clru::CLruCache;
+use domain::base::{name::ToDname, Dname, Rtype};
+use std::{num::NonZeroUsize, sync::Mutex};
+
+/// A matcher that memoizes the results of the matcher it wraps in a LRU cache, keyed by the name and the type of the first query.
+///
+/// It must only wrap matchers whose result is a pure function of the question, like `domain`, `qname_regex`, and `qtype`.
+/// Anything depending on the client, the response, or the time would be served stale results.
+/// Entries are never invalidated, they only get evicted by newer ones.
+pub struct Cached {
+    inner: Box<dyn Matcher>,
+    cache: Mutex<CLruCache<(Dname<Bytes>, Rtype), bool>>,
+}
+
+impl Cached {
+    /// Create a new `Cached` matcher wrapping `inner` with a cache of `size` entries.
+    pub fn new(inner: Box<dyn Matcher>, size: NonZeroUsize) -> Self {
+        Self {
+            inner,
+            cache: Mutex::new(CLruCache::new(size)),
+        }
+    }
+}
+
+impl Matcher for Cached {
+    fn matches(&self, state: &State) -> bool {
+        let question = state.query.first_question().unwrap();
+        let key: (Dname<Bytes>, Rtype) = match question.qname().to_dname() {
+            Ok(qname) => (qname, question.qtype()),
+            Err(_) => return self.inner.matches(state),
+        };
+        if let Some(&matched) = self.cache.lock().unwrap().get(&key) {
+            return matched;
+        }
+        // The lock is not held while the inner matcher is evaluated, so concurrent queries of a new name may all evaluate it.
+        let matched = self.inner.matches(state);
+        self.cache.lock().unwrap().put(key, matched);
+        matched
+    }
+
+    fn resources(&self) -> Vec<Resource> {
+        self.inner.resources()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        super::{Matcher, State},
+        Cached,
+    };
+    use crate::MAX_LEN;
+    use bytes::{Bytes, BytesMut};
+    use domain::base::{Dname, MessageBuilder, Rtype};
+    use std::{
+        num::NonZeroUsize,
+        str::FromStr,
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+    };
+
+    // Matches names starting with `a`, counting how many times it is evaluated.
+    struct Counter(Arc<AtomicUsize>);
+
+    impl Matcher for Counter {
+        fn matches(&self, state: &State) -> bool {
+            self.0.fetch_add(1, Ordering::Relaxed);
+            state
+                .query
+                .first_question()
+                .unwrap()
+                .qname()
+                .to_string()
+                .starts_with('a')
+        }
+    }
+
+    fn create_state(name: &str, qtype: Rtype) -> State {
+        let name = Dname::<Bytes>::from_str(name).unwrap();
+        let mut builder = MessageBuilder::from_target(BytesMut::with_capacity(MAX_LEN))
+            .unwrap()
+            .question();
+        builder.push((&name, qtype)).unwrap();
+        State {
+            query: builder.into_message(),
+            ..Default::default()
+        }
+    }
+
+    fn cached(size: usize) -> (Cached, Arc<AtomicUsize>) {
+        let count = Arc::new(AtomicUsize::new(0));
+        (
+            Cached::new(
+                Box::new(Counter(count.clone())),
+                NonZeroUsize::new(size).unwrap(),
+            ),
+            count,
+        )
+    }
+
+    #[test]
+    fn once_per_name() {
+        let (matcher, count) = cached(10);
+        for name in ["a.com", "b.com", "a.com", "A.COM", "b.com", "a.com"] {
+            assert_eq!(
+                matcher.matches(&create_state(name, Rtype::A)),
+                name.eq_ignore_ascii_case("a.com")
+            );
+        }
+        assert_eq!(count.load(Ordering::Relaxed), 2);
+
+        // The type queried is part of the key.
+        assert!(matcher.matches(&create_state("a.com", Rtype::Aaaa)));
+        assert_eq!(count.load(Ordering::Relaxed), 3);
+    }
+
+    #[test]
+    fn eviction() {
+        let (matcher, count) = cached(1);
+        for name in ["a.com", "a.com", "b.com", "a.com"] {
+            matcher.matches(&create_state(name, Rtype::A));
+        }
+        assert_eq!(count.load(Ordering::Relaxed), 3);
+    }
+}
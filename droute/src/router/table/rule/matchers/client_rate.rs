@@ -0,0 +1,206 @@
+// Copyright 2020 LEXUGE
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use super::{super::super::State, MatchError, Matcher, Result};
+use std::{
+    collections::{hash_map::DefaultHasher, HashMap},
+    hash::{Hash, Hasher},
+    net::IpAddr,
+    sync::Mutex,
+    time::Duration,
+};
+use tokio::time::Instant;
+
+// Number of shards the counters are spread over, so that queries from different clients rarely contend on the same lock.
+const SHARDS: usize = 16;
+
+// Queries in the current window and the one before.
+pub(super) struct Counter {
+    start: Instant,
+    current: u32,
+    previous: u32,
+}
+
+impl Counter {
+    pub(super) fn new(now: Instant) -> Self {
+        Self {
+            start: now,
+            current: 0,
+            previous: 0,
+        }
+    }
+
+    // Count a query and estimate the number of queries in the sliding window ending now.
+    pub(super) fn hit(&mut self, now: Instant, window: Duration) -> f64 {
+        let elapsed = now.duration_since(self.start);
+        if elapsed >= window * 2 {
+            *self = Self::new(now);
+        } else if elapsed >= window {
+            self.previous = self.current;
+            self.current = 0;
+            self.start += window;
+        }
+        self.current += 1;
+
+        // Assume queries of the previous window are evenly distributed, and count the part still in the sliding window.
+        let overlap = 1.0 - now.duration_since(self.start).as_secs_f64() / window.as_secs_f64();
+        self.previous as f64 * overlap + self.current as f64
+    }
+}
+
+struct Shard {
+    counters: HashMap<IpAddr, Counter>,
+    swept: Instant,
+}
+
+/// A matcher that matches once the client sending the query exceeds `limit` queries in the sliding `window`.
+/// Every evaluation counts as a query of the client. Queries without a context never match.
+pub struct ClientRate {
+    limit: u32,
+    window: Duration,
+    shards: Vec<Mutex<Shard>>,
+}
+
+impl ClientRate {
+    /// Create a new `ClientRate` matcher.
+    pub fn new(limit: u32, window: Duration) -> Result<Self> {
+        if window.is_zero() {
+            return Err(MatchError::Other(
+                "window of the client rate matcher must not be zero".to_string(),
+            ));
+        }
+        let now = Instant::now();
+        Ok(Self {
+            limit,
+            window,
+            shards: (0..SHARDS)
+                .map(|_| {
+                    Mutex::new(Shard {
+                        counters: HashMap::new(),
+                        swept: now,
+                    })
+                })
+                .collect(),
+        })
+    }
+
+    // Count a query of the client and estimate its number of queries in the sliding window ending now.
+    fn hit(&self, ip: IpAddr) -> f64 {
+        let mut hasher = DefaultHasher::new();
+        ip.hash(&mut hasher);
+        let mut shard = self.shards[hasher.finish() as usize % SHARDS]
+            .lock()
+            .unwrap();
+        let now = Instant::now();
+
+        // Forget clients having been quiet for a whole window, they would start over anyway.
+        if now.duration_since(shard.swept) >= self.window {
+            let window = self.window;
+            shard
+                .counters
+                .retain(|_, c| now.duration_since(c.start) < window * 2);
+            shard.swept = now;
+        }
+
+        shard
+            .counters
+            .entry(ip)
+            .or_insert_with(|| Counter::new(now))
+            .hit(now, self.window)
+    }
+}
+
+impl Matcher for ClientRate {
+    fn matches(&self, state: &State) -> bool {
+        state
+            .qctx
+            .as_ref()
+            .is_some_and(|qctx| self.hit(qctx.ip()) > self.limit as f64)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        super::{Matcher, State},
+        ClientRate,
+    };
+    use crate::{Protocol, QueryContext};
+    use std::time::Duration;
+    use tokio::time::advance;
+
+    fn create_state(src: &str) -> State {
+        State {
+            qctx: Some(QueryContext::new(src.parse().unwrap(), Protocol::Udp)),
+            ..Default::default()
+        }
+    }
+
+    // Number of queries matched in a burst of `n` queries.
+    fn burst(matcher: &ClientRate, state: &State, n: usize) -> usize {
+        (0..n).filter(|_| matcher.matches(state)).count()
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn threshold() {
+        let matcher = ClientRate::new(5, Duration::from_secs(1)).unwrap();
+        let noisy = create_state("192.168.1.10:5353");
+        let quiet = create_state("[fd00::1]:5353");
+
+        assert_eq!(burst(&matcher, &noisy, 5), 0);
+        assert_eq!(burst(&matcher, &noisy, 3), 3);
+        // Other clients are not affected
+        assert_eq!(burst(&matcher, &quiet, 5), 0);
+        assert!(matcher.matches(&quiet));
+
+        // The burst is still in the sliding window right after the window turns.
+        advance(Duration::from_millis(1100)).await;
+        assert!(matcher.matches(&noisy));
+        // But most of it has slid out later on: 8 * 0.2 + 2 queries.
+        advance(Duration::from_millis(700)).await;
+        assert!(!matcher.matches(&noisy));
+
+        // Everything is forgotten after two windows.
+        advance(Duration::from_secs(2)).await;
+        assert_eq!(burst(&matcher, &noisy, 5), 0);
+        assert_eq!(burst(&matcher, &quiet, 5), 0);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn steady() {
+        let matcher = ClientRate::new(5, Duration::from_secs(1)).unwrap();
+        let state = create_state("10.0.0.1:53");
+        // Around 3 queries per second never exceed the limit of 5
+        for _ in 0..40 {
+            assert!(!matcher.matches(&state));
+            advance(Duration::from_millis(300)).await;
+        }
+        // 8 queries per second do once the rate has built up.
+        let mut hits = 0;
+        for _ in 0..40 {
+            hits += matcher.matches(&state) as usize;
+            advance(Duration::from_millis(125)).await;
+        }
+        assert!(hits > 30, "{} hits", hits);
+    }
+
+    #[tokio::test]
+    async fn no_context() {
+        let matcher = ClientRate::new(0, Duration::from_secs(1)).unwrap();
+        assert!(!matcher.matches(&State::default()));
+        assert!(matcher.matches(&create_state("127.0.0.1:53")));
+        assert!(ClientRate::new(5, Duration::ZERO).is_err());
+    }
+}
@@ -0,0 +1,240 @@
+// Copyright 2020 LEXUGE
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use super::{super::super::State, domain::DomainBuilder, Domain, MatchError, Matcher, Result};
+use crate::{preflight::Resource, AsyncTryInto};
+use async_trait::async_trait;
+use bytes::Bytes;
+use domain::{
+    base::{Dname, ParsedDname, ToDname},
+    rdata::Cname,
+};
+use serde::Deserialize;
+use std::collections::{HashMap, HashSet};
+
+enum Cond {
+    Depth(usize),
+    Target(Domain),
+}
+
+/// A matcher that follows the CNAME chain starting from the name queried through the answer section of the current response.
+/// It never matches before any action has set a response.
+pub struct CnameChain(Cond);
+
+impl CnameChain {
+    /// Create a matcher that matches if the chain is made up of more than `depth` CNAME records.
+    pub fn depth(depth: usize) -> Self {
+        Self(Cond::Depth(depth))
+    }
+
+    /// Create a matcher that matches if any target in the chain is matched by the domain matcher.
+    pub fn target(domain: Domain) -> Self {
+        Self(Cond::Target(domain))
+    }
+}
+
+impl Matcher for CnameChain {
+    fn matches(&self, state: &State) -> bool {
+        // The targets of the CNAME records keyed by their owners.
+        let cnames: HashMap<Dname<Bytes>, Dname<Bytes>> = match state.answer() {
+            Some(answer) => answer
+                .limit_to::<Cname<ParsedDname<&Bytes>>>()
+                .flatten()
+                .filter_map(|r| {
+                    Some((
+                        r.owner().to_dname().ok()?,
+                        r.data().cname().to_dname().ok()?,
+                    ))
+                })
+                .collect(),
+            None => return false,
+        };
+        let mut name: Dname<Bytes> = match state.query.first_question().unwrap().qname().to_dname()
+        {
+            Ok(name) => name,
+            Err(_) => return false,
+        };
+
+        // Names seen so far, so that chains looping back stop there.
+        let mut seen = HashSet::from([name.clone()]);
+        while let Some(target) = cnames.get(&name) {
+            if !seen.insert(target.clone()) {
+                break;
+            }
+            let matched = match &self.0 {
+                // The names seen are the query name plus one for each record followed.
+                Cond::Depth(depth) => seen.len() - 1 > *depth,
+                Cond::Target(domain) => domain.matches_name(target),
+            };
+            if matched {
+                return true;
+            }
+            name = target.clone();
+        }
+        false
+    }
+
+    fn resources(&self) -> Vec<Resource> {
+        match &self.0 {
+            Cond::Depth(_) => Vec::new(),
+            Cond::Target(domain) => domain.resources(),
+        }
+    }
+}
+
+/// A builder for CNAME chain matcher plugin
+#[derive(Deserialize, Clone, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum CnameChainBuilder {
+    /// Matches if the chain is made up of more CNAME records than this
+    Depth(usize),
+    /// Matches if any target in the chain is in the domain lists
+    Target(DomainBuilder),
+}
+
+#[async_trait]
+impl AsyncTryInto<CnameChain> for CnameChainBuilder {
+    type Error = MatchError;
+
+    async fn async_try_into(self) -> Result<CnameChain> {
+        Ok(match self {
+            Self::Depth(depth) => CnameChain::depth(depth),
+            Self::Target(domain) => CnameChain::target(domain.async_try_into().await?),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        super::{domain::DomainBuilder, Matcher, State},
+        CnameChainBuilder,
+    };
+    use crate::{AsyncTryInto, MAX_LEN};
+    use bytes::{Bytes, BytesMut};
+    use domain::{
+        base::{iana::Rcode, Dname, Message, MessageBuilder, Rtype},
+        rdata::{Cname, A},
+    };
+    use once_cell::sync::Lazy;
+    use std::str::FromStr;
+
+    static QUERY: Lazy<Message<Bytes>> = Lazy::new(|| {
+        let mut builder = MessageBuilder::from_target(BytesMut::with_capacity(MAX_LEN))
+            .unwrap()
+            .question();
+        builder
+            .push((&Dname::<Bytes>::from_str("www.site.com").unwrap(), Rtype::A))
+            .unwrap();
+        builder.into_message()
+    });
+
+    // Build a response to `QUERY` with the CNAME records from owners to targets given, and an A record for the last target.
+    fn create_state(cnames: &[(&str, &str)]) -> State {
+        let name = |s| Dname::<Bytes>::from_str(s).unwrap();
+        let mut builder = MessageBuilder::from_target(BytesMut::with_capacity(MAX_LEN))
+            .unwrap()
+            .start_answer(&*QUERY, Rcode::NoError)
+            .unwrap();
+        for &(owner, target) in cnames {
+            builder
+                .push((name(owner), 10, Cname::new(name(target))))
+                .unwrap();
+        }
+        let last = cnames.last().map_or("www.site.com", |&(_, target)| target);
+        builder
+            .push((name(last), 10, A::from_octets(1, 1, 1, 1)))
+            .unwrap();
+        let mut state = State {
+            query: QUERY.clone(),
+            ..Default::default()
+        };
+        state.set_resp(builder.into_message());
+        state
+    }
+
+    const CHAIN: [(&str, &str); 3] = [
+        ("www.site.com", "a.tracker.net"),
+        ("a.tracker.net", "b.cdn.net"),
+        ("b.cdn.net", "c.edge.net"),
+    ];
+
+    #[tokio::test]
+    async fn depth() {
+        let matcher = CnameChainBuilder::Depth(2).async_try_into().await.unwrap();
+        assert!(matcher.matches(&create_state(&CHAIN)));
+        assert!(!matcher.matches(&create_state(&CHAIN[..2])));
+        assert!(!matcher.matches(&create_state(&[])));
+
+        let matcher = CnameChainBuilder::Depth(3).async_try_into().await.unwrap();
+        assert!(!matcher.matches(&create_state(&CHAIN)));
+        // Records not in the chain are not counted.
+        assert!(!matcher.matches(&create_state(&[
+            ("www.other.com", "a.other.com"),
+            ("www.site.com", "a.tracker.net"),
+            ("a.tracker.net", "b.cdn.net"),
+            ("b.cdn.net", "c.edge.net"),
+        ])));
+    }
+
+    #[tokio::test]
+    async fn target() {
+        let matcher = CnameChainBuilder::Target(DomainBuilder::new().add_qnmae("tracker.net"))
+            .async_try_into()
+            .await
+            .unwrap();
+        assert!(matcher.matches(&create_state(&CHAIN)));
+        assert!(!matcher.matches(&create_state(&CHAIN[1..])));
+        assert!(!matcher.matches(&create_state(&[])));
+
+        // The name queried is not a target.
+        let matcher = CnameChainBuilder::Target(DomainBuilder::new().add_qnmae("site.com"))
+            .async_try_into()
+            .await
+            .unwrap();
+        assert!(!matcher.matches(&create_state(&CHAIN)));
+    }
+
+    #[tokio::test]
+    async fn looping() {
+        let chain = [
+            ("www.site.com", "a.loop.net"),
+            ("a.loop.net", "b.loop.net"),
+            ("b.loop.net", "www.site.com"),
+        ];
+        let matcher = CnameChainBuilder::Depth(1).async_try_into().await.unwrap();
+        assert!(matcher.matches(&create_state(&chain)));
+        // The walk stops at the record looping back.
+        let matcher = CnameChainBuilder::Depth(2).async_try_into().await.unwrap();
+        assert!(!matcher.matches(&create_state(&chain)));
+        assert!(!matcher.matches(&create_state(&[("www.site.com", "www.site.com")])));
+
+        let matcher = CnameChainBuilder::Target(DomainBuilder::new().add_qnmae("example.com"))
+            .async_try_into()
+            .await
+            .unwrap();
+        assert!(!matcher.matches(&create_state(&chain)));
+    }
+
+    #[tokio::test]
+    async fn unanswered() {
+        let matcher = CnameChainBuilder::Depth(0).async_try_into().await.unwrap();
+        let state = State {
+            query: QUERY.clone(),
+            ..Default::default()
+        };
+        assert!(!matcher.matches(&state));
+    }
+}
@@ -0,0 +1,147 @@
+// Copyright 2020 LEXUGE
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use super::{super::super::State, Matcher};
+use bytes::Bytes;
+use domain::base::{iana::OptionCode, opt::UnknownOptData};
+
+// A client cookie alone is 8 bytes, and one with a server cookie is 16 to 40 bytes.
+const CLIENT_LEN: usize = 8;
+const FULL_LEN: std::ops::RangeInclusive<usize> = 16..=40;
+
+/// A matcher that matches if the OPT record of the query carries an EDNS COOKIE option (RFC 7873) of a valid length.
+/// If `server_cookie_required` is set, only cookies with a server cookie following the client cookie match.
+/// The cookies are not validated in any way, only their presence and lengths are checked.
+pub struct Cookie {
+    server_cookie_required: bool,
+}
+
+impl Cookie {
+    /// Create a new `Cookie` matcher.
+    pub fn new(server_cookie_required: bool) -> Self {
+        Self {
+            server_cookie_required,
+        }
+    }
+
+    fn valid(&self, len: usize) -> bool {
+        FULL_LEN.contains(&len) || (!self.server_cookie_required && len == CLIENT_LEN)
+    }
+}
+
+impl Matcher for Cookie {
+    fn matches(&self, state: &State) -> bool {
+        // Options are taken raw, as cookies of any valid length are to be told apart.
+        state.query.opt().is_some_and(|opt| {
+            opt.as_opt()
+                .iter::<UnknownOptData<Bytes>>()
+                .flatten()
+                .any(|o| o.code() == OptionCode::Cookie && self.valid(o.data().len()))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        super::{Matcher, State},
+        Cookie,
+    };
+    use crate::MAX_LEN;
+    use bytes::{Bytes, BytesMut};
+    use domain::base::{
+        iana::OptionCode,
+        opt::{AllOptData, ClientSubnet, UnknownOptData},
+        Dname, Message, MessageBuilder, Rtype,
+    };
+    use std::str::FromStr;
+
+    // Build a query with an OPT record if `opt` is set, carrying an ECS option and a COOKIE option of the length given if any.
+    fn query(opt: bool, cookie: Option<usize>) -> Message<Bytes> {
+        let name = Dname::<Bytes>::from_str("example.com").unwrap();
+        let mut builder = MessageBuilder::from_target(BytesMut::with_capacity(MAX_LEN))
+            .unwrap()
+            .question();
+        builder.push((&name, Rtype::A)).unwrap();
+        let mut builder = builder.additional();
+        if opt {
+            builder
+                .opt(|opt| {
+                    ClientSubnet::push(opt, 24, 0, "10.0.0.0".parse().unwrap())?;
+                    if let Some(len) = cookie {
+                        opt.push(&AllOptData::Other(UnknownOptData::from_octets(
+                            OptionCode::Cookie,
+                            Bytes::from(vec![7; len]),
+                        )))?;
+                    }
+                    Ok(())
+                })
+                .unwrap();
+        }
+        builder.into_message()
+    }
+
+    fn create_state(query: Message<Bytes>) -> State {
+        State {
+            query,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn client_cookie() {
+        let matcher = Cookie::new(false);
+        for (opt, cookie, expected) in [
+            (false, None, false),
+            (true, None, false),
+            (true, Some(8), true),
+            (true, Some(16), true),
+            (true, Some(40), true),
+            // Neither a client cookie alone nor one with a server cookie
+            (true, Some(0), false),
+            (true, Some(7), false),
+            (true, Some(12), false),
+            (true, Some(41), false),
+        ] {
+            assert_eq!(
+                matcher.matches(&create_state(query(opt, cookie))),
+                expected,
+                "{:?}",
+                cookie
+            );
+        }
+    }
+
+    #[test]
+    fn server_cookie_required() {
+        let matcher = Cookie::new(true);
+        for (cookie, expected) in [
+            (None, false),
+            (Some(8), false),
+            (Some(16), true),
+            (Some(24), true),
+            (Some(40), true),
+            (Some(15), false),
+            (Some(41), false),
+        ] {
+            assert_eq!(
+                matcher.matches(&create_state(query(true, cookie))),
+                expected,
+                "{:?}",
+                cookie
+            );
+        }
+    }
+}
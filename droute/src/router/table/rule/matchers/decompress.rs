@@ -0,0 +1,80 @@
+// Copyright 2020 LEXUGE
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+// Lists of matchers may be compressed, which is detected from their magic bytes.
+// gzip and bzip2 are always supported, while xz and zstd are behind the features of the same names.
+
+use super::{MatchError, Result};
+use niffler::compression::Format;
+use std::{
+    fs::File,
+    io::{BufReader, Read},
+    path::Path,
+};
+
+// Open the file and decompress it if compressed.
+pub(super) fn open(path: impl AsRef<Path>) -> Result<Box<dyn Read>> {
+    decompress(Box::new(BufReader::new(File::open(path)?)))
+}
+
+// Decompress the data if compressed.
+pub(super) fn decompress<'a>(data: Box<dyn Read + 'a>) -> Result<Box<dyn Read + 'a>> {
+    let (data, format) = niffler::sniff(data)?;
+    niffler::get_reader(data)
+        .map(|(data, _)| data)
+        .map_err(|e| match (e, format) {
+            (niffler::Error::FeatureDisabled, Format::Lzma) => {
+                MatchError::UnsupportedCompression("xz")
+            }
+            (niffler::Error::FeatureDisabled, Format::Zstd) => {
+                MatchError::UnsupportedCompression("zstd")
+            }
+            (e, _) => e.into(),
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{super::MatchError, open};
+    use std::io::Read;
+
+    fn read(path: &str) -> Result<String, MatchError> {
+        let mut data = String::new();
+        open(path)?.read_to_string(&mut data)?;
+        Ok(data)
+    }
+
+    #[test]
+    fn formats() {
+        let plain = read("../data/apple.txt").unwrap();
+        assert_eq!(read("../data/apple.txt.gz").unwrap(), plain);
+
+        #[cfg(feature = "zstd")]
+        assert_eq!(read("../data/apple.txt.zst").unwrap(), plain);
+        #[cfg(not(feature = "zstd"))]
+        assert!(matches!(
+            read("../data/apple.txt.zst"),
+            Err(MatchError::UnsupportedCompression("zstd"))
+        ));
+
+        #[cfg(feature = "xz")]
+        assert_eq!(read("../data/apple.txt.xz").unwrap(), plain);
+        #[cfg(not(feature = "xz"))]
+        assert!(matches!(
+            read("../data/apple.txt.xz"),
+            Err(MatchError::UnsupportedCompression("xz"))
+        ));
+    }
+}
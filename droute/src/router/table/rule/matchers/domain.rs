@@ -13,18 +13,79 @@
 // You should have received a copy of the GNU General Public License
 // along with this program.  If not, see <http://www.gnu.org/licenses/>.
 
-use crate::AsyncTryInto;
+use crate::{preflight::Resource, AsyncTryInto};
 
-use super::{super::super::State, MatchError, Matcher, Result};
+#[cfg(feature = "geosite")]
+use super::geosite;
+use super::{super::super::State, decompress, remote, MatchError, Matcher, Result};
+use arc_swap::ArcSwap;
 use async_trait::async_trait;
 use bytes::Bytes;
-use dmatcher::domain::Domain as DomainAlg;
-use domain::base::{name::FromStrError, Dname, ToDname};
+use dmatcher::domain::{
+    parse_domain, Charset, Domain as DomainAlg, FrozenDomain, ListFormat, MAGIC,
+};
+use domain::base::{Dname, ToDname};
+use log::{debug, info, log_enabled, warn, Level};
+#[cfg(feature = "watch")]
+use notify::{Event, EventKind, RecommendedWatcher, RecursiveMode, Watcher};
 use serde::Deserialize;
-use std::{path::PathBuf, str::FromStr};
+use std::{
+    io::{BufReader, ErrorKind, Read},
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::Arc,
+};
+#[cfg(feature = "watch")]
+use tokio::sync::mpsc::unbounded_channel;
+
+// How long the files watched have to stay unchanged before the matcher is rebuilt.
+#[cfg(feature = "watch")]
+const WATCH_DEBOUNCE: std::time::Duration = std::time::Duration::from_millis(500);
 
 /// A matcher that matches if first query's domain is within the domain list provided
-pub struct Domain(DomainAlg);
+pub struct Domain {
+    loaded: Arc<ArcSwap<Loaded>>,
+    // Changes of the files watched are sent to the reloading task until the watcher is dropped along with the matcher.
+    #[cfg(feature = "watch")]
+    _watcher: Option<RecommendedWatcher>,
+}
+
+// The matcher is only replaced as a whole once rebuilt, so it is frozen to be shared compactly across routing threads. Each rule carries where it comes from.
+struct Loaded {
+    matcher: FrozenDomain<Arc<str>>,
+    sets: Vec<SetOp>,
+    resources: Vec<Resource>,
+}
+
+// Set operations can't be applied to the rules of the lists in general, e.g. taking `mail.google.com` out of `google.com`, so each side gets a matcher of its own.
+enum SetOp {
+    Diff(Loaded, Loaded),
+    Intersect(Loaded, Loaded),
+}
+
+impl Loaded {
+    fn matches(&self, name: &Dname<Bytes>) -> bool {
+        // Finding out the rule is slower, only do it if it is going to be logged.
+        if !log_enabled!(Level::Debug) {
+            if self.matcher.matches(name) {
+                return true;
+            }
+        } else if let (Some(rule), Some(source)) = (
+            self.matcher.matches_rule(name),
+            self.matcher.matches_value(name),
+        ) {
+            debug!(
+                "domain `{}` matches the rule `{}` from `{}`",
+                name, rule, source
+            );
+            return true;
+        }
+        self.sets.iter().any(|s| match s {
+            SetOp::Diff(a, b) => a.matches(name) && !b.matches(name),
+            SetOp::Intersect(a, b) => a.matches(name) && b.matches(name),
+        })
+    }
+}
 
 #[derive(Deserialize, Clone, Eq, PartialEq, Debug)]
 #[serde(rename_all = "lowercase")]
@@ -35,53 +96,397 @@ pub enum ResourceType {
 
     /// A file
     File(PathBuf),
+
+    /// A file of AdBlock filters, e.g. `||example.com^` and `@@||cdn.example.com^`
+    AdBlock(PathBuf),
+
+    /// A hosts file, e.g. `0.0.0.0 ads.example.com`
+    Hosts(PathBuf),
+
+    /// A dnsmasq configuration file, e.g. `server=/google.com/8.8.8.8`
+    Dnsmasq(PathBuf),
+
+    /// A category of v2ray's `geosite.dat`, e.g. `cn`, optionally only the entries with (e.g. `@cn`) or without (e.g. `@!cn`) an attribute
+    #[cfg(feature = "geosite")]
+    Geosite(PathBuf, String, #[serde(default)] String),
+
+    /// A list downloaded from an http(s) URL and stored at the cache path, e.g. `remote("https://example.com/list.txt", "cache/list.txt")`. The cached copy is used if the list cannot be downloaded.
+    Remote(String, PathBuf, #[serde(default)] RemoteOptions),
+
+    /// A resource of a file, which is reloaded on changes, e.g. `watch(file("block.list"))`
+    #[cfg(feature = "watch")]
+    Watch(Box<ResourceType>),
+
+    /// Domains in the first resources but not in the second ones, e.g. `diff([file("gfw.txt")], [file("whitelist.txt")])`
+    Diff(Vec<ResourceType>, Vec<ResourceType>),
+
+    /// Domains in both the first resources and the second ones, e.g. `intersect([file("a.txt")], [file("b.txt")])`
+    Intersect(Vec<ResourceType>, Vec<ResourceType>),
+}
+
+/// Options of a domain list downloaded.
+#[derive(Deserialize, Clone, Eq, PartialEq, Debug, Default)]
+pub struct RemoteOptions {
+    /// Format of the list. Default to a domain per line.
+    #[serde(default)]
+    pub format: RemoteFormat,
+    /// Proxy to download the list through, e.g. `socks5://127.0.0.1:1080`
+    #[serde(default)]
+    pub proxy: Option<String>,
+}
+
+/// Format of a domain list downloaded, the same as the one of the file resources.
+#[derive(Deserialize, Clone, Copy, Eq, PartialEq, Debug, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum RemoteFormat {
+    /// A domain per line, like `file`
+    #[default]
+    Plain,
+    /// AdBlock filters, like `adblock`
+    AdBlock,
+    /// A hosts file, like `hosts`
+    Hosts,
+    /// A dnsmasq configuration file, like `dnsmasq`
+    Dnsmasq,
+}
+
+impl From<RemoteFormat> for ListFormat {
+    fn from(format: RemoteFormat) -> Self {
+        match format {
+            RemoteFormat::Plain => ListFormat::Plain,
+            RemoteFormat::AdBlock => ListFormat::AdBlock,
+            RemoteFormat::Hosts => ListFormat::Hosts,
+            RemoteFormat::Dnsmasq => ListFormat::Dnsmasq,
+        }
+    }
+}
+
+impl ResourceType {
+    #[cfg(feature = "watch")]
+    fn path(&self) -> Option<&Path> {
+        match self {
+            Self::Qname(_) | Self::Diff(_, _) | Self::Intersect(_, _) => None,
+            Self::File(l) | Self::AdBlock(l) | Self::Hosts(l) | Self::Dnsmasq(l) => Some(l),
+            Self::Remote(_, l, _) => Some(l),
+            #[cfg(feature = "geosite")]
+            Self::Geosite(l, _, _) => Some(l),
+            Self::Watch(r) => r.path(),
+        }
+    }
+
+    // The resources and the ones of the set operations among them.
+    fn flatten(p: &[Self]) -> Vec<&Self> {
+        p.iter()
+            .flat_map(|r| match r {
+                Self::Diff(a, b) | Self::Intersect(a, b) => {
+                    let mut all = vec![r];
+                    all.extend(Self::flatten(a));
+                    all.extend(Self::flatten(b));
+                    all
+                }
+                _ => vec![r],
+            })
+            .collect()
+    }
+
+    fn as_remote(&self) -> Option<(&str, &Path, &RemoteOptions)> {
+        match self {
+            Self::Remote(url, cache, options) => Some((url.as_str(), cache.as_path(), options)),
+            #[cfg(feature = "watch")]
+            Self::Watch(r) => r.as_remote(),
+            _ => None,
+        }
+    }
 }
 
-fn into_dnames(list: &str) -> std::result::Result<Vec<Dname<Bytes>>, FromStrError> {
+// Internationalized domain names are converted into their ASCII form. Lines that are still not made up of letters, digits, hyphens, underscores, and dots afterwards are ignored.
+// Parse the domains of a `qname` resource, one per line like a plain list. Unlike lists, a malformed domain is an error rather than skipped, as it is given in the configuration.
+fn into_dnames(list: &str) -> Result<Vec<Dname<Bytes>>> {
     list.split('\n')
-        .filter(|&x| {
-            (!x.is_empty())
-                && (x.chars().all(|c| {
-                    char::is_ascii_alphabetic(&c)
-                        | char::is_ascii_digit(&c)
-                        | (c == '-')
-                        | (c == '.')
-                }))
+        .filter(|x| !x.is_empty() && !x.starts_with('#'))
+        .map(|x| {
+            parse_domain(x, Charset::default())
+                .ok_or_else(|| MatchError::InvalidDomain(x.to_owned()))
         })
-        .map(Dname::from_str)
         .collect()
 }
 
 impl Domain {
     /// Create a new `Domain` matcher from a list of files where each domain is seperated from one another by `\n`.
     pub async fn new(p: Vec<ResourceType>) -> Result<Self> {
-        Ok({
-            let mut matcher = DomainAlg::new();
-            for r in p {
-                match r {
-                    ResourceType::Qname(n) => matcher.insert_multi(&into_dnames(&n)?),
-                    ResourceType::File(l) => {
-                        // TODO: Can we make it async?
-                        let (mut file, _) = niffler::from_path(l)?;
-                        let mut data = String::new();
-                        file.read_to_string(&mut data)?;
-                        matcher.insert_multi(&into_dnames(&data)?);
+        // Lists downloaded are loaded from where they are cached.
+        for (url, cache, options) in ResourceType::flatten(&p)
+            .into_iter()
+            .filter_map(ResourceType::as_remote)
+        {
+            Self::fetch(url, cache, options).await?;
+        }
+        let loaded = Arc::new(ArcSwap::from_pointee(Self::build(&p)?));
+        Ok(Self {
+            #[cfg(feature = "watch")]
+            _watcher: Self::watch(&loaded, p)?,
+            loaded,
+        })
+    }
+
+    // Download the list, or fall back to the cached one.
+    async fn fetch(url: &str, cache: &Path, options: &RemoteOptions) -> Result<()> {
+        match Self::download(url, cache, options).await {
+            Ok(()) => Ok(()),
+            Err(e) if cache.exists() => {
+                warn!(
+                    "failed to download domains from `{}`, using the copy cached at `{}`: {}",
+                    url,
+                    cache.display(),
+                    e
+                );
+                Ok(())
+            }
+            Err(e) => Err(MatchError::resource(url)(e)),
+        }
+    }
+
+    // Download the list and store it at the cache path once it is known to be valid.
+    async fn download(url: &str, cache: &Path, options: &RemoteOptions) -> Result<()> {
+        let data = remote::download(url, options.proxy.as_deref()).await?;
+        let file = decompress::decompress(Box::new(&data[..]))?;
+        Self::parse(file, options.format.into(), cache)?;
+        remote::store(cache, &data).await
+    }
+
+    fn build(p: &[ResourceType]) -> Result<Loaded> {
+        let mut matcher = DomainAlg::default();
+        let mut sets = Vec::new();
+        let mut resources = Vec::new();
+        // Rules of query names are all from the configuration.
+        let config: Arc<str> = Arc::from("configuration");
+        for r in p {
+            Self::add(&mut matcher, &mut sets, &mut resources, r, &config)?;
+        }
+        Ok(Loaded {
+            matcher: matcher.freeze(),
+            sets,
+            resources,
+        })
+    }
+
+    fn add(
+        matcher: &mut DomainAlg<Arc<str>>,
+        sets: &mut Vec<SetOp>,
+        resources: &mut Vec<Resource>,
+        r: &ResourceType,
+        config: &Arc<str>,
+    ) -> Result<()> {
+        match r {
+            ResourceType::Qname(n) => {
+                let mut qnames = DomainAlg::new();
+                qnames.insert_multi(&into_dnames(n)?);
+                matcher.merge(qnames.map_values(|()| config.clone()))
+            }
+            ResourceType::File(l) => Self::load_list(matcher, resources, l, ListFormat::Plain)?,
+            ResourceType::AdBlock(l) => {
+                Self::load_list(matcher, resources, l, ListFormat::AdBlock)?
+            }
+            ResourceType::Hosts(l) => Self::load_list(matcher, resources, l, ListFormat::Hosts)?,
+            ResourceType::Dnsmasq(l) => {
+                Self::load_list(matcher, resources, l, ListFormat::Dnsmasq)?
+            }
+            ResourceType::Remote(_, l, options) => {
+                Self::load_list(matcher, resources, l, options.format.into())?
+            }
+            #[cfg(feature = "geosite")]
+            ResourceType::Geosite(l, category, attr) => {
+                Self::load_into(matcher, resources, l, |mut file| {
+                    let mut data = Vec::new();
+                    file.read_to_end(&mut data)?;
+                    geosite::load(&data, category, attr)
+                })?
+            }
+            #[cfg(feature = "watch")]
+            ResourceType::Watch(r) => Self::add(matcher, sets, resources, r, config)?,
+            ResourceType::Diff(a, b) | ResourceType::Intersect(a, b) => {
+                let (mut a, mut b) = (Self::build(a)?, Self::build(b)?);
+                resources.append(&mut a.resources);
+                resources.append(&mut b.resources);
+                sets.push(match r {
+                    ResourceType::Diff(_, _) => SetOp::Diff(a, b),
+                    _ => SetOp::Intersect(a, b),
+                });
+            }
+        }
+        Ok(())
+    }
+
+    // Watch the files marked, and rebuild the matcher once they stop changing for a while.
+    #[cfg(feature = "watch")]
+    fn watch(
+        loaded: &Arc<ArcSwap<Loaded>>,
+        p: Vec<ResourceType>,
+    ) -> Result<Option<RecommendedWatcher>> {
+        let paths = ResourceType::flatten(&p)
+            .into_iter()
+            .filter_map(|r| match r {
+                ResourceType::Watch(w) => {
+                    Some(w.path().map(Path::to_path_buf).ok_or_else(|| {
+                        MatchError::Other(format!("`{:?}` is not a file to watch", w))
+                    }))
+                }
+                _ => None,
+            })
+            .collect::<Result<Vec<_>>>()?;
+        if paths.is_empty() {
+            return Ok(None);
+        }
+
+        let (tx, mut rx) = unbounded_channel();
+        let mut watcher = notify::recommended_watcher(move |event: notify::Result<Event>| {
+            let _ = tx.send(event);
+        })?;
+        for path in &paths {
+            // Editors and scripts often replace the file rather than write to it, so we watch the directory instead.
+            let dir = match path.parent() {
+                Some(dir) if !dir.as_os_str().is_empty() => dir,
+                _ => Path::new("."),
+            };
+            watcher.watch(dir, RecursiveMode::NonRecursive)?;
+        }
+
+        let names: Vec<_> = paths
+            .iter()
+            .filter_map(|p| p.file_name())
+            .map(ToOwned::to_owned)
+            .collect();
+        let changed = move |event: notify::Result<Event>| match event {
+            Ok(event) => {
+                !matches!(event.kind, EventKind::Access(_))
+                    && event
+                        .paths
+                        .iter()
+                        .any(|p| p.file_name().is_some_and(|n| names.iter().any(|m| m == n)))
+            }
+            Err(e) => {
+                warn!("error watching domain lists: {}", e);
+                false
+            }
+        };
+        let loaded = Arc::downgrade(loaded);
+        let p = Arc::new(p);
+        tokio::spawn(async move {
+            while let Some(event) = rx.recv().await {
+                if !changed(event) {
+                    continue;
+                }
+                // Wait for successive writes to settle.
+                loop {
+                    match tokio::time::timeout(WATCH_DEBOUNCE, rx.recv()).await {
+                        Ok(Some(_)) => continue,
+                        Ok(None) => return,
+                        Err(_) => break,
                     }
                 }
+                let loaded = if let Some(loaded) = loaded.upgrade() {
+                    loaded
+                } else {
+                    return;
+                };
+                let p = p.clone();
+                let _ = tokio::task::spawn_blocking(move || Self::reload(&loaded, &p)).await;
             }
-            Self(matcher)
-        })
+        });
+        Ok(Some(watcher))
+    }
+
+    // Rebuild the matcher and swap it in. The working matcher is kept if rebuilding fails, e.g. on a file half written.
+    #[cfg(feature = "watch")]
+    fn reload(loaded: &ArcSwap<Loaded>, p: &[ResourceType]) {
+        match Self::build(p) {
+            Ok(new) => {
+                info!("reloaded domain lists");
+                loaded.store(Arc::new(new));
+            }
+            Err(e) => warn!(
+                "failed to reload domain lists, keeping the previous ones: {}",
+                e
+            ),
+        }
+    }
+
+    fn load_list(
+        matcher: &mut DomainAlg<Arc<str>>,
+        resources: &mut Vec<Resource>,
+        path: &Path,
+        format: ListFormat,
+    ) -> Result<()> {
+        Self::load_into(matcher, resources, path, |d| Self::parse(d, format, path))
+    }
+
+    fn load_into(
+        matcher: &mut DomainAlg<Arc<str>>,
+        resources: &mut Vec<Resource>,
+        path: &Path,
+        parse: impl FnOnce(Box<dyn Read>) -> Result<DomainAlg>,
+    ) -> Result<()> {
+        // TODO: Can we make it async?
+        let loaded = decompress::open(path)
+            .and_then(parse)
+            .map_err(MatchError::resource(path))?;
+        info!("loaded {} domains from `{}`", loaded.len(), path.display());
+        resources.push(Resource::new("domain", path.display(), loaded.len()));
+        let source: Arc<str> = Arc::from(path.display().to_string());
+        matcher.merge(loaded.map_values(|()| source.clone()));
+        Ok(())
+    }
+
+    // Parse either a list of the format, which is streamed line by line, or a matcher precompiled by `dmatcher::domain::Domain::to_bytes`, which skips parsing the list.
+    fn parse(mut file: impl Read, format: ListFormat, path: &Path) -> Result<DomainAlg> {
+        let mut head = Vec::new();
+        (&mut file)
+            .take(MAGIC.len() as u64)
+            .read_to_end(&mut head)?;
+        if DomainAlg::is_encoded(&head) {
+            file.read_to_end(&mut head)?;
+            return DomainAlg::from_bytes(&head).ok_or(MatchError::Malformatted);
+        }
+        let mut matcher = DomainAlg::new();
+        let stats = matcher
+            .insert_from_reader(BufReader::new(head.as_slice().chain(file)), format)
+            .map_err(|e| match e.kind() {
+                // The list is not valid UTF-8.
+                ErrorKind::InvalidData => MatchError::Malformatted,
+                _ => e.into(),
+            })?;
+        // Skipping most of the lines almost always means the list is in another format.
+        if stats.skipped > stats.inserted {
+            warn!(
+                "skipped {} of {} lines in `{}`, e.g. `{}`. Is it really in the {:?} format?",
+                stats.skipped,
+                stats.skipped + stats.inserted,
+                path.display(),
+                stats.first_skipped.unwrap_or_default(),
+                format
+            );
+        }
+        Ok(matcher)
+    }
+
+    // Whether the name is in the lists, which is shared with matchers on names other than the one queried.
+    pub(super) fn matches_name(&self, name: &Dname<Bytes>) -> bool {
+        self.loaded.load().matches(name)
     }
 }
 
 impl Matcher for Domain {
     fn matches(&self, state: &State) -> bool {
         if let Ok(name) = state.query.first_question().unwrap().qname().to_dname() {
-            self.0.matches(&name)
+            self.matches_name(&name)
         } else {
             false
         }
     }
+
+    fn resources(&self) -> Vec<Resource> {
+        self.loaded.load().resources.clone()
+    }
 }
 
 /// A builder for domain matcher
@@ -113,6 +518,83 @@ impl DomainBuilder {
             .push(ResourceType::File(PathBuf::from_str(s.as_ref()).unwrap()));
         self
     }
+
+    /// Add a file of AdBlock filters to the match list
+    pub fn add_adblock_file(mut self, s: impl AsRef<str>) -> Self {
+        self.0.push(ResourceType::AdBlock(
+            PathBuf::from_str(s.as_ref()).unwrap(),
+        ));
+        self
+    }
+
+    /// Add a hosts file to the match list
+    pub fn add_hosts_file(mut self, s: impl AsRef<str>) -> Self {
+        self.0
+            .push(ResourceType::Hosts(PathBuf::from_str(s.as_ref()).unwrap()));
+        self
+    }
+
+    /// Add a category of a v2ray `geosite.dat` to the match list
+    #[cfg(feature = "geosite")]
+    pub fn add_geosite(mut self, s: impl AsRef<str>, category: impl ToString) -> Self {
+        self.0.push(ResourceType::Geosite(
+            PathBuf::from_str(s.as_ref()).unwrap(),
+            category.to_string(),
+            String::new(),
+        ));
+        self
+    }
+
+    /// Add the entries of a category of a v2ray `geosite.dat` with (e.g. `@cn`) or without (e.g. `@!cn`) an attribute to the match list
+    #[cfg(feature = "geosite")]
+    pub fn add_geosite_with_attr(
+        mut self,
+        s: impl AsRef<str>,
+        category: impl ToString,
+        attr: impl ToString,
+    ) -> Self {
+        self.0.push(ResourceType::Geosite(
+            PathBuf::from_str(s.as_ref()).unwrap(),
+            category.to_string(),
+            attr.to_string(),
+        ));
+        self
+    }
+
+    /// Add a list downloaded from an http(s) URL to the match list, which is stored at the cache path
+    pub fn add_remote(
+        mut self,
+        url: impl ToString,
+        cache: impl AsRef<str>,
+        options: RemoteOptions,
+    ) -> Self {
+        self.0.push(ResourceType::Remote(
+            url.to_string(),
+            PathBuf::from_str(cache.as_ref()).unwrap(),
+            options,
+        ));
+        self
+    }
+
+    /// Add a dnsmasq configuration file to the match list
+    pub fn add_dnsmasq_file(mut self, s: impl AsRef<str>) -> Self {
+        self.0.push(ResourceType::Dnsmasq(
+            PathBuf::from_str(s.as_ref()).unwrap(),
+        ));
+        self
+    }
+
+    /// Add the domains matched by the first builder but not by the second one to the match list
+    pub fn add_diff(mut self, a: DomainBuilder, b: DomainBuilder) -> Self {
+        self.0.push(ResourceType::Diff(a.0, b.0));
+        self
+    }
+
+    /// Add the domains matched by both builders to the match list
+    pub fn add_intersect(mut self, a: DomainBuilder, b: DomainBuilder) -> Self {
+        self.0.push(ResourceType::Intersect(a.0, b.0));
+        self
+    }
 }
 
 #[async_trait]
@@ -123,3 +605,308 @@ impl AsyncTryInto<Domain> for DomainBuilder {
         Domain::new(self.0).await
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        super::remote::tests::serve, into_dnames, Domain, DomainBuilder, ListFormat, MatchError,
+        Matcher, RemoteFormat, RemoteOptions, ResourceType,
+    };
+    use crate::AsyncTryInto;
+    use bytes::Bytes;
+    use domain::base::Dname;
+    use std::{fs::File, path::Path, str::FromStr};
+
+    #[tokio::test]
+    async fn precompiled() {
+        let list = Path::new("../data/china.txt");
+        let text = Domain::parse(File::open(list).unwrap(), ListFormat::Plain, list).unwrap();
+        let dir = std::env::temp_dir().join("droute-precompiled-domain");
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("china.bin");
+        std::fs::write(&path, text.to_bytes()).unwrap();
+
+        let matcher = Domain::new(vec![ResourceType::File(path.clone())])
+            .await
+            .unwrap();
+        assert_eq!(matcher.resources()[0].entries, text.len());
+        for name in ["baidu.com", "www.baidu.com", "apple.com", "com"] {
+            let name = Dname::<Bytes>::from_str(name).unwrap();
+            assert_eq!(
+                matcher.loaded.load().matcher.matches(&name),
+                text.matches(&name)
+            );
+        }
+
+        std::fs::write(&path, &text.to_bytes()[..100]).unwrap();
+        assert!(Domain::new(vec![ResourceType::File(path)]).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn logged_rule() {
+        let dir = std::env::temp_dir().join("droute-domain-logged-rule");
+        std::fs::create_dir_all(&dir).unwrap();
+        let list = dir.join("apple.txt");
+        std::fs::write(&list, "apple.com\n").unwrap();
+        let matcher: Domain = DomainBuilder::new()
+            .add_file(list.to_str().unwrap())
+            .add_qnmae("www.apple.com")
+            .async_try_into()
+            .await
+            .unwrap();
+        // The rule logged and where it comes from are those of the deepest rule matched, even if a shallower one overlaps it.
+        for (name, rule, source) in [
+            ("store.www.apple.com", "www.apple.com", "configuration"),
+            ("www.apple.com", "www.apple.com", "configuration"),
+            ("store.apple.com", "apple.com", list.to_str().unwrap()),
+        ] {
+            let name = Dname::<Bytes>::from_str(name).unwrap();
+            let loaded = matcher.loaded.load();
+            assert_eq!(
+                loaded.matcher.matches_rule(&name),
+                Some(Dname::from_str(rule).unwrap())
+            );
+            assert_eq!(
+                loaded.matcher.matches_value(&name).map(|s| &**s),
+                Some(source)
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn set_operations() {
+        let dir = std::env::temp_dir().join("droute-domain-sets");
+        std::fs::create_dir_all(&dir).unwrap();
+        let (gfw, whitelist) = (dir.join("gfw.txt"), dir.join("whitelist.txt"));
+        std::fs::write(&gfw, "google.com\ntwitter.com\n").unwrap();
+        std::fs::write(&whitelist, "mail.google.com\ntwitter.com\n").unwrap();
+        let (gfw, whitelist) = (
+            DomainBuilder::new().add_file(gfw.to_str().unwrap()),
+            DomainBuilder::new().add_file(whitelist.to_str().unwrap()),
+        );
+
+        let build = |builder: DomainBuilder| async move {
+            let matcher: Domain = builder.async_try_into().await.unwrap();
+            assert_eq!(matcher.resources().len(), 2);
+            move |name: &str| matcher.matches_name(&Dname::from_str(name).unwrap())
+        };
+        let union = build(DomainBuilder(
+            gfw.0.iter().chain(&whitelist.0).cloned().collect(),
+        ))
+        .await;
+        let diff = build(DomainBuilder::new().add_diff(gfw.clone(), whitelist.clone())).await;
+        let intersect =
+            build(DomainBuilder::new().add_intersect(gfw.clone(), whitelist.clone())).await;
+        for (name, in_union, in_diff, in_intersect) in [
+            // In both lists
+            ("twitter.com", true, false, true),
+            ("mail.google.com", true, false, true),
+            ("imap.mail.google.com", true, false, true),
+            // Only in the first one
+            ("www.google.com", true, true, false),
+            ("example.com", false, false, false),
+        ] {
+            assert_eq!(union(name), in_union, "{}", name);
+            assert_eq!(diff(name), in_diff, "{}", name);
+            assert_eq!(intersect(name), in_intersect, "{}", name);
+        }
+
+        // Set operations are united with the other resources.
+        let matcher = build(
+            DomainBuilder::new()
+                .add_qnmae("example.com")
+                .add_diff(gfw, whitelist),
+        )
+        .await;
+        assert!(matcher("example.com"));
+        assert!(matcher("www.google.com"));
+        assert!(!matcher("twitter.com"));
+    }
+
+    #[tokio::test]
+    async fn formats() {
+        let dir = std::env::temp_dir().join("droute-domain-formats");
+        std::fs::create_dir_all(&dir).unwrap();
+        let (adblock, hosts, dnsmasq) = (
+            dir.join("filters.txt"),
+            dir.join("hosts"),
+            dir.join("accelerated-domains.china.conf"),
+        );
+        std::fs::write(
+            &adblock,
+            "! Title: Example filters\n||example.com^\n@@||cdn.example.com^\nexample.com##.ad\n",
+        )
+        .unwrap();
+        std::fs::write(&hosts, "127.0.0.1 localhost\n0.0.0.0 ads.example.net\n").unwrap();
+        std::fs::write(
+            &dnsmasq,
+            "server=/baidu.com/114.114.114.114\nserver=/bad*domain.cn/114.114.114.114\n",
+        )
+        .unwrap();
+
+        let matcher: Domain = DomainBuilder::new()
+            .add_adblock_file(adblock.to_str().unwrap())
+            .add_hosts_file(hosts.to_str().unwrap())
+            .add_dnsmasq_file(dnsmasq.to_str().unwrap())
+            .async_try_into()
+            .await
+            .unwrap();
+        let entries: Vec<_> = matcher.resources().iter().map(|r| r.entries).collect();
+        assert_eq!(entries, [2, 1, 1]);
+        for (name, matched) in [
+            ("www.example.com", true),
+            ("img.cdn.example.com", false),
+            ("ads.example.net", true),
+            ("www.ads.example.net", false),
+            ("localhost", false),
+            ("www.baidu.com", true),
+        ] {
+            let name = Dname::<Bytes>::from_str(name).unwrap();
+            assert_eq!(matcher.loaded.load().matcher.matches(&name), matched);
+        }
+        // Rules carry the file they come from.
+        let name = Dname::<Bytes>::from_str("ads.example.net").unwrap();
+        assert_eq!(
+            matcher
+                .loaded
+                .load()
+                .matcher
+                .matches_value(&name)
+                .map(|s| &**s),
+            hosts.to_str()
+        );
+    }
+
+    #[tokio::test]
+    async fn remote() {
+        let url = serve(vec!["server=/baidu.com/114.114.114.114\n"]).await
+            + "/accelerated-domains.china.conf";
+        let dir = std::env::temp_dir().join(format!("droute-domain-remote-{}", std::process::id()));
+        let cache = dir.join("china.conf");
+        let builder = DomainBuilder::new().add_remote(
+            &url,
+            cache.to_str().unwrap(),
+            RemoteOptions {
+                format: RemoteFormat::Dnsmasq,
+                proxy: None,
+            },
+        );
+        let name = Dname::<Bytes>::from_str("www.baidu.com").unwrap();
+        let matcher: Domain = builder.clone().async_try_into().await.unwrap();
+        assert!(matcher.loaded.load().matcher.matches(&name));
+        assert_eq!(
+            std::fs::read_to_string(&cache).unwrap(),
+            "server=/baidu.com/114.114.114.114\n"
+        );
+
+        // The server fails now, so the cached copy is used.
+        let matcher: Domain = builder.async_try_into().await.unwrap();
+        assert!(matcher.loaded.load().matcher.matches(&name));
+
+        // Nothing is cached to fall back to.
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert!(Domain::new(vec![ResourceType::Remote(
+            url,
+            cache,
+            RemoteOptions::default()
+        )])
+        .await
+        .is_err());
+    }
+
+    #[cfg(feature = "watch")]
+    #[tokio::test]
+    async fn reload() {
+        let dir = std::env::temp_dir().join("droute-domain-reload");
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("block.list");
+        std::fs::write(&path, "example.com\n").unwrap();
+        let p = vec![
+            ResourceType::Watch(Box::new(ResourceType::File(path.clone()))),
+            ResourceType::Qname("apple.com".to_string()),
+        ];
+        let matcher = Domain::new(p.clone()).await.unwrap();
+        let matches = |name| {
+            matcher
+                .loaded
+                .load()
+                .matcher
+                .matches(&Dname::<Bytes>::from_str(name).unwrap())
+        };
+        assert!(matches("example.com"));
+        assert!(!matches("example.net"));
+
+        std::fs::write(&path, "example.net\n").unwrap();
+        Domain::reload(&matcher.loaded, &p);
+        assert!(!matches("example.com"));
+        assert!(matches("example.net"));
+        assert!(matches("apple.com"));
+
+        // Not valid UTF-8, as if the file is half written.
+        std::fs::write(&path, [0xff, 0xfe, 0xfd]).unwrap();
+        Domain::reload(&matcher.loaded, &p);
+        assert!(matches("example.net"));
+
+        // Only files can be watched.
+        assert!(
+            Domain::new(vec![ResourceType::Watch(Box::new(ResourceType::Qname(
+                "apple.com".to_string()
+            )))])
+            .await
+            .is_err()
+        );
+    }
+
+    #[cfg(feature = "watch")]
+    #[tokio::test]
+    async fn watch() {
+        let dir = std::env::temp_dir().join("droute-domain-watch");
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("block.list");
+        std::fs::write(&path, "example.com\n").unwrap();
+        let matcher = Domain::new(vec![ResourceType::Watch(Box::new(ResourceType::File(
+            path.clone(),
+        )))])
+        .await
+        .unwrap();
+
+        // Replace the file as editors do.
+        let tmp = dir.join("block.list.tmp");
+        std::fs::write(&tmp, "example.net\n").unwrap();
+        std::fs::rename(&tmp, &path).unwrap();
+        let name = Dname::<Bytes>::from_str("example.net").unwrap();
+        for _ in 0..50 {
+            if matcher.loaded.load().matcher.matches(&name) {
+                return;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+        }
+        panic!("the list is not reloaded");
+    }
+
+    #[test]
+    fn idn_lists() {
+        assert_eq!(
+            into_dnames("例え.jp\n# 注释\napple.com\n_dmarc.example.com\n").unwrap(),
+            vec![
+                Dname::<Bytes>::from_str("xn--r8jz45g.jp").unwrap(),
+                Dname::from_str("apple.com").unwrap(),
+                Dname::from_str("_dmarc.example.com").unwrap()
+            ]
+        );
+    }
+
+    #[test]
+    fn malformed_qname() {
+        for (list, entry) in [
+            ("apple.com\nbad domain", "bad domain"),
+            ("a..b", "a..b"),
+            ("apple.com \n", "apple.com "),
+        ] {
+            match into_dnames(list) {
+                Err(MatchError::InvalidDomain(e)) => assert_eq!(e, entry),
+                _ => panic!("`{}` should not parse", list),
+            }
+        }
+    }
+}
@@ -0,0 +1,191 @@
+// Copyright 2020 LEXUGE
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use super::{super::super::State, client_rate::Counter, MatchError, Matcher, Result};
+use bytes::Bytes;
+use clru::CLruCache;
+use domain::base::{name::ToDname, Dname, Rtype};
+use std::{
+    collections::hash_map::DefaultHasher,
+    hash::{Hash, Hasher},
+    net::IpAddr,
+    num::NonZeroUsize,
+    sync::Mutex,
+    time::Duration,
+};
+use tokio::time::Instant;
+
+// Number of shards the counters are spread over, so that different queries rarely contend on the same lock.
+const SHARDS: usize = 16;
+
+// Default number of queries tracked.
+pub(super) fn default_capacity() -> usize {
+    4096
+}
+
+// Queries without a context are all taken as from the same client.
+type Key = (Option<IpAddr>, Dname<Bytes>, Rtype);
+
+/// A matcher that matches once the same client has sent the same query, i.e. the same name and type, more than `threshold` times in the sliding `window`.
+/// Every evaluation counts as a query. Queries without a context are counted regardless of the client.
+/// Only the `capacity` queries seen most recently are tracked, older ones start over.
+pub struct Duplicate {
+    threshold: u32,
+    window: Duration,
+    shards: Vec<Mutex<CLruCache<Key, Counter>>>,
+}
+
+impl Duplicate {
+    /// Create a new `Duplicate` matcher.
+    pub fn new(threshold: u32, window: Duration, capacity: usize) -> Result<Self> {
+        if window.is_zero() {
+            return Err(MatchError::Other(
+                "window of the duplicate matcher must not be zero".to_string(),
+            ));
+        }
+        let capacity = NonZeroUsize::new(capacity.div_ceil(SHARDS)).ok_or_else(|| {
+            MatchError::Other("capacity of the duplicate matcher must not be zero".to_string())
+        })?;
+        Ok(Self {
+            threshold,
+            window,
+            shards: (0..SHARDS)
+                .map(|_| Mutex::new(CLruCache::new(capacity)))
+                .collect(),
+        })
+    }
+
+    // Count the query and estimate its number in the sliding window ending now.
+    fn hit(&self, key: Key) -> f64 {
+        let mut hasher = DefaultHasher::new();
+        key.hash(&mut hasher);
+        let mut shard = self.shards[hasher.finish() as usize % SHARDS]
+            .lock()
+            .unwrap();
+        let now = Instant::now();
+        if let Some(c) = shard.get_mut(&key) {
+            return c.hit(now, self.window);
+        }
+        let mut c = Counter::new(now);
+        let hits = c.hit(now, self.window);
+        shard.put(key, c);
+        hits
+    }
+}
+
+impl Matcher for Duplicate {
+    fn matches(&self, state: &State) -> bool {
+        let question = match state.query.first_question() {
+            Some(question) => question,
+            None => return false,
+        };
+        let qname = match question.qname().to_dname() {
+            Ok(qname) => qname,
+            Err(_) => return false,
+        };
+        let ip = state.qctx.as_ref().map(|qctx| qctx.ip());
+        self.hit((ip, qname, question.qtype())) > self.threshold as f64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        super::{Matcher, State},
+        Duplicate,
+    };
+    use crate::{Protocol, QueryContext, MAX_LEN};
+    use bytes::{Bytes, BytesMut};
+    use domain::base::{Dname, MessageBuilder, Rtype};
+    use std::{str::FromStr, time::Duration};
+    use tokio::time::advance;
+
+    fn create_state(src: Option<&str>, name: &str, qtype: Rtype) -> State {
+        let name = Dname::<Bytes>::from_str(name).unwrap();
+        let mut builder = MessageBuilder::from_target(BytesMut::with_capacity(MAX_LEN))
+            .unwrap()
+            .question();
+        builder.push((&name, qtype)).unwrap();
+        State {
+            query: builder.into_message(),
+            qctx: src.map(|src| QueryContext::new(src.parse().unwrap(), Protocol::Udp)),
+            ..Default::default()
+        }
+    }
+
+    // Number of queries matched in a burst of `n` queries.
+    fn burst(matcher: &Duplicate, state: &State, n: usize) -> usize {
+        (0..n).filter(|_| matcher.matches(state)).count()
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn threshold() {
+        let matcher = Duplicate::new(3, Duration::from_secs(10), 1024).unwrap();
+        let retry = create_state(Some("192.168.1.10:5353"), "example.com", Rtype::A);
+
+        assert_eq!(burst(&matcher, &retry, 3), 0);
+        assert!(matcher.matches(&retry));
+        // Names are compared case-insensitively.
+        assert!(matcher.matches(&create_state(
+            Some("192.168.1.10:5353"),
+            "EXAMPLE.com",
+            Rtype::A
+        )));
+        // Other names, types, and clients are counted apart.
+        for state in [
+            create_state(Some("192.168.1.10:5353"), "example.org", Rtype::A),
+            create_state(Some("192.168.1.10:5353"), "example.com", Rtype::Aaaa),
+            create_state(Some("192.168.1.11:5353"), "example.com", Rtype::A),
+        ] {
+            assert_eq!(burst(&matcher, &state, 3), 0);
+        }
+
+        // The retries slide out of the window.
+        advance(Duration::from_secs(25)).await;
+        assert_eq!(burst(&matcher, &retry, 3), 0);
+        assert!(matcher.matches(&retry));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn no_context() {
+        let matcher = Duplicate::new(1, Duration::from_secs(1), 1024).unwrap();
+        let state = create_state(None, "example.com", Rtype::A);
+        assert!(!matcher.matches(&state));
+        assert!(matcher.matches(&state));
+        // Queries with a context are not counted along.
+        assert!(!matcher.matches(&create_state(Some("127.0.0.1:53"), "example.com", Rtype::A)));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn bounded() {
+        // One query tracked per shard
+        let matcher = Duplicate::new(1, Duration::from_secs(10), 16).unwrap();
+        let state = create_state(None, "example.com", Rtype::A);
+        assert!(!matcher.matches(&state));
+        for i in 0..1000 {
+            matcher.matches(&create_state(None, &format!("{}.example.org", i), Rtype::A));
+        }
+        // Forgotten in favor of newer ones
+        assert!(!matcher.matches(&state));
+        assert!(matcher.matches(&state));
+    }
+
+    #[test]
+    fn invalid() {
+        assert!(Duplicate::new(3, Duration::ZERO, 1024).is_err());
+        assert!(Duplicate::new(3, Duration::from_secs(1), 0).is_err());
+        assert!(Duplicate::new(3, Duration::from_secs(1), 1).is_ok());
+    }
+}
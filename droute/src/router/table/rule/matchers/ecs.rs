@@ -0,0 +1,181 @@
+// Copyright 2020 LEXUGE
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use super::{super::super::State, MatchError, Matcher, Result};
+use crate::AsyncTryInto;
+use async_trait::async_trait;
+use cidr_utils::cidr::IpCidr as Cidr;
+use domain::base::opt::ClientSubnet;
+use serde::Deserialize;
+
+/// A matcher that matches if the EDNS Client Subnet of the query is within any of the IP CIDRs provided.
+/// Queries without an ECS option never match.
+pub struct Ecs(Vec<Cidr>);
+
+impl Ecs {
+    /// Create a new `Ecs` matcher from a list of IP CIDRs.
+    pub fn new(cidrs: Vec<String>) -> Result<Self> {
+        Ok(Self(
+            cidrs
+                .iter()
+                .map(Cidr::from_str)
+                .collect::<std::result::Result<_, _>>()?,
+        ))
+    }
+
+    // Whether the subnet of the client is entirely within any of the CIDRs.
+    fn contains(&self, subnet: &ClientSubnet) -> bool {
+        // Only the source prefix tells the subnet of the client, the scope prefix is always zero in queries.
+        self.0.iter().any(|cidr| {
+            let bits = match cidr {
+                Cidr::V4(c) => c.get_bits(),
+                Cidr::V6(c) => c.get_bits(),
+            };
+            cidr.contains(subnet.addr()) && subnet.source_prefix_len() >= bits
+        })
+    }
+}
+
+impl Matcher for Ecs {
+    fn matches(&self, state: &State) -> bool {
+        state.query.opt().is_some_and(|opt| {
+            opt.as_opt()
+                .iter::<ClientSubnet>()
+                .flatten()
+                .any(|subnet| self.contains(&subnet))
+        })
+    }
+}
+
+/// A builder for ECS matcher plugin
+#[derive(Deserialize, Clone, Debug, PartialEq, Eq)]
+#[serde(transparent)]
+pub struct EcsBuilder(Vec<String>);
+
+impl Default for EcsBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl EcsBuilder {
+    /// Create an empty builder
+    pub fn new() -> Self {
+        Self(Vec::new())
+    }
+
+    /// Add an IP CIDR like `1.0.0.0/8` to the matcher builder
+    pub fn add_cidr(mut self, s: impl ToString) -> Self {
+        self.0.push(s.to_string());
+        self
+    }
+}
+
+#[async_trait]
+impl AsyncTryInto<Ecs> for EcsBuilder {
+    type Error = MatchError;
+
+    async fn async_try_into(self) -> Result<Ecs> {
+        Ecs::new(self.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        super::{Matcher, State},
+        EcsBuilder,
+    };
+    use crate::{AsyncTryInto, MAX_LEN};
+    use bytes::{Bytes, BytesMut};
+    use domain::base::{
+        opt::{AllOptData, ClientSubnet, Cookie},
+        Dname, Message, MessageBuilder, Rtype,
+    };
+    use std::str::FromStr;
+
+    // Build a query with the ECS option of the subnet if given, and an OPT record if `opt` is set.
+    fn query(opt: bool, subnet: Option<(&str, u8, u8)>) -> Message<Bytes> {
+        let name = Dname::<Bytes>::from_str("example.com").unwrap();
+        let mut builder = MessageBuilder::from_target(BytesMut::with_capacity(MAX_LEN))
+            .unwrap()
+            .question();
+        builder.push((&name, Rtype::A)).unwrap();
+        let mut builder = builder.additional();
+        if opt {
+            builder
+                .opt(|opt| {
+                    opt.push(&AllOptData::<Bytes>::Cookie(Cookie::new([7; 8])))?;
+                    if let Some((addr, source, scope)) = subnet {
+                        ClientSubnet::push(opt, source, scope, addr.parse().unwrap())?;
+                    }
+                    Ok(())
+                })
+                .unwrap();
+        }
+        builder.into_message()
+    }
+
+    fn create_state(query: Message<Bytes>) -> State {
+        State {
+            query,
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn test() {
+        let matcher = EcsBuilder::new()
+            .add_cidr("1.0.0.0/8")
+            .add_cidr("2001:db8::/32")
+            .async_try_into()
+            .await
+            .unwrap();
+        for (subnet, expected) in [
+            (("1.2.3.4", 24, 0), true),
+            (("1.2.3.4", 32, 0), true),
+            (("9.9.9.9", 24, 0), false),
+            // The subnet is wider than the CIDR
+            (("1.2.3.4", 4, 0), false),
+            (("2001:db8:1::", 56, 0), true),
+            (("2001:db9::", 56, 0), false),
+            // The scope prefix doesn't matter
+            (("1.2.3.4", 24, 16), true),
+            (("2001:db8:1::", 48, 64), true),
+        ] {
+            assert_eq!(
+                matcher.matches(&create_state(query(true, Some(subnet)))),
+                expected,
+                "{:?}",
+                subnet
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn no_ecs() {
+        let matcher = EcsBuilder::new()
+            .add_cidr("0.0.0.0/0")
+            .add_cidr("::/0")
+            .async_try_into()
+            .await
+            .unwrap();
+        assert!(matcher.matches(&create_state(query(true, Some(("1.2.3.4", 24, 0))))));
+        // No ECS option in the OPT record
+        assert!(!matcher.matches(&create_state(query(true, None))));
+        // No OPT record at all
+        assert!(!matcher.matches(&create_state(query(false, None))));
+    }
+}
@@ -0,0 +1,107 @@
+// Copyright 2020 LEXUGE
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use super::{super::super::State, Matcher};
+use serde::Deserialize;
+
+/// Conditions on the OPT record of the query
+#[derive(Deserialize, Clone, Copy, PartialEq, Eq, Hash, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum EdnsCond {
+    /// Whether the query has an OPT record
+    Present(bool),
+    /// Whether the DO (DNSSEC OK) bit is set. It is never set on queries without an OPT record.
+    DoBit(bool),
+}
+
+/// A matcher that matches on the OPT record in the additional section of the query.
+pub struct Edns(EdnsCond);
+
+impl Edns {
+    /// Create a new `Edns` matcher.
+    pub fn new(cond: EdnsCond) -> Self {
+        Self(cond)
+    }
+}
+
+impl Matcher for Edns {
+    fn matches(&self, state: &State) -> bool {
+        let opt = state.query.opt();
+        match self.0 {
+            EdnsCond::Present(present) => opt.is_some() == present,
+            EdnsCond::DoBit(do_bit) => opt.is_some_and(|opt| opt.dnssec_ok()) == do_bit,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        super::{Matcher, State},
+        Edns, EdnsCond,
+    };
+    use crate::MAX_LEN;
+    use bytes::{Bytes, BytesMut};
+    use domain::base::{Dname, Message, MessageBuilder, Rtype};
+    use std::str::FromStr;
+
+    // Build a query with an OPT record if `opt` is given, whose DO bit is set as given.
+    fn query(opt: Option<bool>) -> Message<Bytes> {
+        let name = Dname::<Bytes>::from_str("example.com").unwrap();
+        let mut builder = MessageBuilder::from_target(BytesMut::with_capacity(MAX_LEN))
+            .unwrap()
+            .question();
+        builder.push((&name, Rtype::A)).unwrap();
+        let mut builder = builder.additional();
+        if let Some(do_bit) = opt {
+            builder
+                .opt(|opt| {
+                    opt.set_dnssec_ok(do_bit);
+                    Ok(())
+                })
+                .unwrap();
+        }
+        builder.into_message()
+    }
+
+    fn create_state(query: Message<Bytes>) -> State {
+        State {
+            query,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn present() {
+        let present = Edns::new(EdnsCond::Present(true));
+        let absent = Edns::new(EdnsCond::Present(false));
+        for (opt, expected) in [(None, false), (Some(false), true), (Some(true), true)] {
+            let state = create_state(query(opt));
+            assert_eq!(present.matches(&state), expected, "{:?}", opt);
+            assert_eq!(absent.matches(&state), !expected, "{:?}", opt);
+        }
+    }
+
+    #[test]
+    fn do_bit() {
+        let set = Edns::new(EdnsCond::DoBit(true));
+        let unset = Edns::new(EdnsCond::DoBit(false));
+        for (opt, expected) in [(None, false), (Some(false), false), (Some(true), true)] {
+            let state = create_state(query(opt));
+            assert_eq!(set.matches(&state), expected, "{:?}", opt);
+            assert_eq!(unset.matches(&state), !expected, "{:?}", opt);
+        }
+    }
+}
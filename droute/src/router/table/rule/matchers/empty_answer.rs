@@ -0,0 +1,161 @@
+// Copyright 2020 LEXUGE
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use super::{super::super::State, MatchError, Matcher, Result};
+use crate::AsyncTryInto;
+use async_trait::async_trait;
+use domain::base::Rtype;
+use serde::Deserialize;
+
+/// A matcher that matches if the current response has no records in the answer section.
+/// It never matches before any action has set a response.
+pub struct EmptyAnswer {
+    qtype_only: bool,
+}
+
+impl EmptyAnswer {
+    /// Create a new `EmptyAnswer` matcher. If `qtype_only` is set, only records of the type queried are counted, so that e.g. a response with nothing but a CNAME is empty.
+    pub fn new(qtype_only: bool) -> Result<Self> {
+        Ok(Self { qtype_only })
+    }
+}
+
+impl Matcher for EmptyAnswer {
+    fn matches(&self, state: &State) -> bool {
+        let answer = match state.answer() {
+            Some(answer) => answer,
+            None => return false,
+        };
+        if !self.qtype_only {
+            return state.resp.header_counts().ancount() == 0;
+        }
+        let qtype = state.query.first_question().unwrap().qtype();
+        !answer
+            .flatten()
+            .any(|r| qtype == Rtype::Any || r.rtype() == qtype)
+    }
+}
+
+/// A builder for empty answer matcher plugin, which sets whether to count only records of the type queried.
+#[derive(Deserialize, Clone, Debug, PartialEq, Eq, Default)]
+#[serde(transparent)]
+pub struct EmptyAnswerBuilder(bool);
+
+impl EmptyAnswerBuilder {
+    /// Create a new builder
+    pub fn new(qtype_only: bool) -> Self {
+        Self(qtype_only)
+    }
+}
+
+#[async_trait]
+impl AsyncTryInto<EmptyAnswer> for EmptyAnswerBuilder {
+    type Error = MatchError;
+
+    async fn async_try_into(self) -> Result<EmptyAnswer> {
+        EmptyAnswer::new(self.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        super::{Matcher, State},
+        EmptyAnswerBuilder,
+    };
+    use crate::{AsyncTryInto, MAX_LEN};
+    use bytes::{Bytes, BytesMut};
+    use domain::{
+        base::{iana::Rcode, Dname, Message, MessageBuilder, Rtype},
+        rdata::{Cname, A},
+    };
+    use once_cell::sync::Lazy;
+    use std::str::FromStr;
+
+    static NAME: Lazy<Dname<Bytes>> = Lazy::new(|| Dname::from_str("www.example.com").unwrap());
+
+    static QUERY: Lazy<Message<Bytes>> = Lazy::new(|| {
+        let mut builder = MessageBuilder::from_target(BytesMut::with_capacity(MAX_LEN))
+            .unwrap()
+            .question();
+        builder.push((&*NAME, Rtype::A)).unwrap();
+        builder.into_message()
+    });
+
+    // Build a response to `QUERY` with a CNAME record and an A record in the answer section if asked.
+    fn create_state(cname: bool, a: bool) -> State {
+        let target = Dname::<Bytes>::from_str("cdn.example.net").unwrap();
+        let mut builder = MessageBuilder::from_target(BytesMut::with_capacity(MAX_LEN))
+            .unwrap()
+            .start_answer(&*QUERY, Rcode::NoError)
+            .unwrap();
+        if cname {
+            builder
+                .push((&*NAME, 10, Cname::new(target.clone())))
+                .unwrap();
+        }
+        if a {
+            builder
+                .push((&target, 10, A::from_octets(1, 1, 1, 1)))
+                .unwrap();
+        }
+        let mut state = State {
+            query: QUERY.clone(),
+            ..Default::default()
+        };
+        state.set_resp(builder.into_message());
+        state
+    }
+
+    #[tokio::test]
+    async fn any_record() {
+        let matcher = EmptyAnswerBuilder::new(false)
+            .async_try_into()
+            .await
+            .unwrap();
+        assert!(matcher.matches(&create_state(false, false)));
+        assert!(!matcher.matches(&create_state(true, false)));
+        assert!(!matcher.matches(&create_state(true, true)));
+        assert!(!matcher.matches(&create_state(false, true)));
+    }
+
+    #[tokio::test]
+    async fn qtype_only() {
+        let matcher = EmptyAnswerBuilder::new(true)
+            .async_try_into()
+            .await
+            .unwrap();
+        assert!(matcher.matches(&create_state(false, false)));
+        // A CNAME alone doesn't answer an A query.
+        assert!(matcher.matches(&create_state(true, false)));
+        assert!(!matcher.matches(&create_state(true, true)));
+        assert!(!matcher.matches(&create_state(false, true)));
+    }
+
+    #[tokio::test]
+    async fn unanswered() {
+        for qtype_only in [false, true] {
+            let matcher = EmptyAnswerBuilder::new(qtype_only)
+                .async_try_into()
+                .await
+                .unwrap();
+            let state = State {
+                query: QUERY.clone(),
+                ..Default::default()
+            };
+            assert!(!matcher.matches(&state));
+        }
+    }
+}
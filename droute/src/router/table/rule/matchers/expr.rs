@@ -13,29 +13,293 @@
 // You should have received a copy of the GNU General Public License
 // along with this program.  If not, see <http://www.gnu.org/licenses/>.
 
-use super::{MatchError, Matcher};
-use crate::{router::table::State, AsyncTryInto};
+use super::{Cached, MatchError, Matcher};
+use crate::{preflight::Resource, router::table::State, AsyncTryInto};
 use async_trait::async_trait;
+use dmatcher::domain::{parse_domain, Charset};
 use pest::{
+    error::{ErrorVariant, InputLocation},
     iterators::{Pair, Pairs},
     Parser,
 };
 use pest_derive::Parser;
-use serde::Deserialize;
-use std::iter::Iterator;
+use serde::{
+    de::{self, Visitor},
+    Deserialize, Deserializer,
+};
+use std::{fmt, iter::Iterator, num::NonZeroUsize};
 use thiserror::Error;
 
-#[allow(clippy::enum_variant_names)]
+/// What is wrong with an expression.
 #[derive(Error, Debug)]
-pub enum ExprError {
-    #[error(transparent)]
-    PestError(#[from] pest::error::Error<Rule>),
+pub enum ExprErrorKind {
+    #[error("syntax error")]
+    Syntax,
 
-    #[error(transparent)]
-    RonError(#[from] ron::Error),
+    #[error("unknown matcher `{0}`")]
+    UnknownMatcher(String),
+
+    #[error("{0}")]
+    RonError(ron::ErrorCode),
 
     #[error(transparent)]
-    MatchError(#[from] MatchError),
+    MatchError(MatchError),
+
+    #[error("malformed domain {0}")]
+    InvalidDomain(String),
+}
+
+/// An error in an expression, with where it is and what could have been there instead.
+#[derive(Debug)]
+pub struct ExprError {
+    pub kind: ExprErrorKind,
+    /// Characters before the error in the whole expression
+    pub offset: usize,
+    /// Line of the error, counted from 1
+    pub line: usize,
+    /// Column of the error in characters, counted from 1
+    pub col: usize,
+    /// The token at the error, which is `None` at the end of the expression
+    pub found: Option<String>,
+    /// What would have been accepted in place of `found`
+    pub expected: Vec<String>,
+    /// The known name closest to an unknown one
+    pub suggestion: Option<String>,
+    // Bytes of the expression the error is on, which are only turned into the fields above by `locate`.
+    span: (usize, usize),
+    // The line of the expression the error is on
+    snippet: String,
+}
+
+impl ExprError {
+    fn new(kind: ExprErrorKind, span: (usize, usize)) -> Self {
+        Self {
+            kind,
+            offset: 0,
+            line: 1,
+            col: 1,
+            found: None,
+            expected: Vec::new(),
+            suggestion: None,
+            span,
+            snippet: String::new(),
+        }
+    }
+
+    fn from_pest(e: pest::error::Error<Rule>) -> Self {
+        let span = match e.location {
+            InputLocation::Pos(pos) => (pos, pos),
+            InputLocation::Span(span) => span,
+        };
+        let mut expected = Vec::new();
+        if let ErrorVariant::ParsingError { positives, .. } = e.variant {
+            for name in positives.into_iter().flat_map(describe) {
+                if !expected.contains(&name) {
+                    expected.push(name);
+                }
+            }
+        }
+        Self {
+            expected,
+            ..Self::new(ExprErrorKind::Syntax, span)
+        }
+    }
+
+    // Fill in where the error is in the expression it comes from.
+    fn locate(mut self: Box<Self>, input: &str) -> Box<Self> {
+        let (start, end) = self.span;
+        let before = &input[..start];
+        let line_start = before.rfind('\n').map_or(0, |i| i + 1);
+        let line_end = input[start..].find('\n').map_or(input.len(), |i| start + i);
+        self.offset = before.chars().count();
+        self.line = before.matches('\n').count() + 1;
+        self.col = input[line_start..start].chars().count() + 1;
+        self.snippet = input[line_start..line_end]
+            .trim_end_matches('\r')
+            .to_owned();
+        self.found = if start < end {
+            Some(input[start..end].to_owned())
+        } else {
+            // A name or a single character
+            let rest = &input[start..];
+            let name = rest
+                .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+                .unwrap_or(rest.len());
+            match (name, rest.chars().next()) {
+                (_, None) => None,
+                (0, Some(c)) => Some(c.to_string()),
+                (name, _) => Some(rest[..name].to_owned()),
+            }
+        };
+        self
+    }
+}
+
+impl std::error::Error for ExprError {}
+
+// Render the line of the error with the token found underlined, like
+//
+// unknown matcher `domian` at line 1, column 9
+//   |
+// 1 | true && domian(["example.com"])
+//   |         ^^^^^^
+//   = did you mean `domain`?
+impl fmt::Display for ExprError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let pad = " ".repeat(self.line.to_string().len());
+        // Tabs are kept for the carets to line up
+        let indent: String = self
+            .snippet
+            .chars()
+            .take(self.col - 1)
+            .map(|c| if c == '\t' { '\t' } else { ' ' })
+            .collect();
+        let width = self
+            .found
+            .as_deref()
+            .and_then(|found| found.lines().next())
+            .map_or(1, |found| found.chars().count().max(1));
+        writeln!(
+            f,
+            "{} at line {}, column {}",
+            self.kind, self.line, self.col
+        )?;
+        writeln!(f, "{} |", pad)?;
+        writeln!(f, "{} | {}", self.line, self.snippet)?;
+        write!(f, "{} | {}{}", pad, indent, "^".repeat(width))?;
+        if let Some(suggestion) = &self.suggestion {
+            write!(f, "\n{} = did you mean `{}`?", pad, suggestion)?;
+        } else if let Some((last, init)) = self.expected.split_last() {
+            let expected = if init.is_empty() {
+                last.clone()
+            } else {
+                format!("one of {} or {}", init.join(", "), last)
+            };
+            match &self.found {
+                Some(found) => write!(f, "\n{} = expected {}, found `{}`", pad, expected, found)?,
+                None => write!(
+                    f,
+                    "\n{} = expected {}, found end of expression",
+                    pad, expected
+                )?,
+            }
+        }
+        Ok(())
+    }
+}
+
+// Friendly names of the rules pest expects, which are what may be written there.
+fn describe(rule: Rule) -> Vec<String> {
+    let names: &[&str] = match rule {
+        Rule::NegExpr | Rule::GeoIpInvert => &["`!`"],
+        Rule::Cached => &["`cached`"],
+        Rule::DomainMatcher => &["`domain`", "`full_qname`"],
+        Rule::GeoIp => &["`geoip`"],
+        Rule::True => &["`true`"],
+        Rule::False => &["`false`"],
+        Rule::Ron | Rule::enm => &["a matcher"],
+        Rule::Expr | Rule::OrExpr | Rule::AndExpr => &["an expression"],
+        Rule::EOI => &["end of expression"],
+        Rule::DomainSetOp => &["`diff`", "`intersect`"],
+        Rule::DomainList | Rule::GeoIpCodes | Rule::list => &["a list"],
+        Rule::Size => &["a size"],
+        Rule::ident => &["a name"],
+        Rule::string => &["a string"],
+        Rule::number => &["a number"],
+        Rule::value => &["a value"],
+        rule => return vec![format!("{:?}", rule)],
+    };
+    names.iter().map(|name| name.to_string()).collect()
+}
+
+// The names of the variants of `T` if it is an enum, which serde only tells the deserializer.
+fn variant_names<T>() -> Option<&'static [&'static str]>
+where
+    for<'a> T: Deserialize<'a>,
+{
+    struct Probe(Option<&'static [&'static str]>);
+
+    impl<'de> Deserializer<'de> for &mut Probe {
+        type Error = de::value::Error;
+
+        fn deserialize_any<V: Visitor<'de>>(self, _: V) -> Result<V::Value, Self::Error> {
+            Err(de::Error::custom("not an enum"))
+        }
+
+        fn deserialize_enum<V: Visitor<'de>>(
+            self,
+            _: &'static str,
+            variants: &'static [&'static str],
+            _: V,
+        ) -> Result<V::Value, Self::Error> {
+            self.0 = Some(variants);
+            Err(de::Error::custom("probed"))
+        }
+
+        serde::forward_to_deserialize_any! {
+            bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string bytes byte_buf
+            option unit unit_struct newtype_struct seq tuple tuple_struct map struct identifier
+            ignored_any
+        }
+    }
+
+    let mut probe = Probe(None);
+    let _ = T::deserialize(&mut probe);
+    probe.0
+}
+
+// Levenshtein distance in characters
+fn distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut row: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut diag = row[0];
+        row[0] = i + 1;
+        for (j, &cb) in b.iter().enumerate() {
+            let next = (diag + usize::from(ca != cb))
+                .min(row[j] + 1)
+                .min(row[j + 1] + 1);
+            diag = row[j + 1];
+            row[j + 1] = next;
+        }
+    }
+    row[b.len()]
+}
+
+// The error for the matcher named in `term` if `M` doesn't know it, with the closest name known suggested.
+fn unknown_matcher<M>(term: &Pair<Rule>) -> Option<Box<ExprError>>
+where
+    for<'a> M: Deserialize<'a>,
+{
+    let names = variant_names::<M>()?;
+    // Ron -> enm -> variant -> ident
+    let ident = term
+        .clone()
+        .into_inner()
+        .next()?
+        .into_inner()
+        .next()?
+        .into_inner()
+        .next()?;
+    let name = ident.as_str();
+    if names.contains(&name) {
+        return None;
+    }
+    let suggestion = names
+        .iter()
+        .map(|known| (distance(name, known), known))
+        .filter(|(d, _)| *d <= name.chars().count() / 3 + 1)
+        .min_by_key(|(d, _)| *d)
+        .map(|(_, known)| known.to_string());
+    Some(Box::new(ExprError {
+        expected: names.iter().map(|known| format!("`{}`", known)).collect(),
+        suggestion,
+        ..ExprError::new(ExprErrorKind::UnknownMatcher(name.to_owned()), span(&ident))
+    }))
+}
+
+fn span(pair: &Pair<Rule>) -> (usize, usize) {
+    (pair.as_span().start(), pair.as_span().end())
 }
 
 #[derive(Parser)]
@@ -43,63 +307,209 @@ pub enum ExprError {
 pub struct ExprParser;
 
 impl ExprParser {
-    pub fn build_node<M>(&self, input: &str) -> Result<Node<BuilderPrimitive<M>>, ExprError>
+    pub fn build_node<M>(&self, input: &str) -> Result<Node<BuilderPrimitive<M>>, Box<ExprError>>
     where
         for<'a> M: Deserialize<'a> + AsyncTryInto<Box<dyn Matcher>, Error = MatchError>,
     {
         // There should only be one prog
-        let prog = ExprParser::parse(Rule::Program, input)?.next().unwrap();
-        build_node_from_term::<M>(prog)
+        let prog = ExprParser::parse(Rule::Program, input)
+            .map_err(|e| Box::new(ExprError::from_pest(e)).locate(input))?
+            .next()
+            .unwrap();
+        build_node_from_term::<M>(prog).map_err(|e| e.locate(input))
     }
 }
 
-fn build_node_from_expr<M>(expr: Pair<Rule>) -> Result<Node<BuilderPrimitive<M>>, ExprError>
+fn build_node_from_expr<M>(expr: Pair<Rule>) -> Result<Node<BuilderPrimitive<M>>, Box<ExprError>>
 where
     for<'a> M: Deserialize<'a> + AsyncTryInto<Box<dyn Matcher>, Error = MatchError>,
 {
-    // Expr here can either be negexpr or orexpr
-    let expr = expr.into_inner().next().unwrap();
+    // Expr here is always an orexpr
+    let mut operands = expr.into_inner().next().unwrap().into_inner();
 
-    Ok(match expr.as_rule() {
-        Rule::NegExpr => Node::Neg(Box::new(build_node_from_term::<M>(
-            expr.into_inner().next().unwrap(),
-        )?)),
-        Rule::OrExpr => {
-            let mut operands = expr.into_inner();
-            // There is only one operand which is andexpr
-            if operands.clone().count() == 1 {
-                build_node_from_andexpr::<M>(operands.next().unwrap().into_inner())?
-            } else {
-                // This is a real orexpr
-                let mut v = Vec::new();
-                for x in operands {
-                    v.push(build_node_from_andexpr::<M>(x.into_inner())?);
-                }
-                Node::Or(v)
-            }
+    // There is only one operand which is andexpr
+    Ok(if operands.clone().count() == 1 {
+        build_node_from_andexpr::<M>(operands.next().unwrap().into_inner())?
+    } else {
+        // This is a real orexpr
+        let mut v = Vec::new();
+        for x in operands {
+            v.push(build_node_from_andexpr::<M>(x.into_inner())?);
         }
-        _ => unreachable!(),
+        Node::Or(v)
     })
 }
 
-fn build_node_from_term<M>(term: Pair<Rule>) -> Result<Node<BuilderPrimitive<M>>, ExprError>
+fn build_node_from_term<M>(term: Pair<Rule>) -> Result<Node<BuilderPrimitive<M>>, Box<ExprError>>
 where
     for<'a> M: Deserialize<'a> + AsyncTryInto<Box<dyn Matcher>, Error = MatchError>,
 {
     Ok(match term.as_rule() {
         Rule::True => Node::None(BuilderPrimitive::Bool(true)),
         Rule::False => Node::None(BuilderPrimitive::Bool(false)),
-        Rule::Ron => Node::None(BuilderPrimitive::MatcherBuilder(ron::from_str::<M>(
-            term.as_str(),
+        Rule::Ron => Node::None(BuilderPrimitive::MatcherBuilder(
+            from_ron::<M>(&term).map_err(|e| unknown_matcher::<M>(&term).unwrap_or(e))?,
+        )),
+        Rule::Domain => Node::None(BuilderPrimitive::MatcherBuilder(from_rewritten::<M>(
+            &build_domain(term.clone())?,
+            &term,
+        )?)),
+        Rule::GeoIp => Node::None(BuilderPrimitive::MatcherBuilder(from_rewritten::<M>(
+            &build_geoip(term.clone()),
+            &term,
+        )?)),
+        Rule::NegExpr => Node::Neg(Box::new(build_node_from_term::<M>(
+            term.into_inner().next().unwrap(),
         )?)),
+        Rule::Cached => {
+            let mut inner = term.into_inner();
+            let expr = build_node_from_expr::<M>(inner.next().unwrap())?;
+            let size = inner.next().unwrap();
+            Node::None(BuilderPrimitive::Cached(
+                Box::new(expr),
+                size.as_str().parse().map_err(|_| {
+                    Box::new(ExprError::new(
+                        ExprErrorKind::MatchError(MatchError::Other(format!(
+                            "the size of `cached` must be a positive integer, got `{}`",
+                            size.as_str()
+                        ))),
+                        span(&size),
+                    ))
+                })?,
+            ))
+        }
         Rule::Expr => build_node_from_expr(term)?,
         _ => unreachable!(),
     })
 }
 
+// Deserialize the RON value, with errors at positions in the whole expression rather than in the value.
+fn from_ron<T>(value: &Pair<Rule>) -> Result<T, Box<ExprError>>
+where
+    for<'a> T: Deserialize<'a>,
+{
+    let (start, end) = span(value);
+    ron::from_str(&strip_comments(value.as_str())).map_err(|e| {
+        let at = match e.position.line {
+            // Errors raised by the types deserialized have no position, so they are put on the whole value.
+            0 => (start, end),
+            // Lines are kept by `strip_comments`, and so are the bytes before the error on its line. Columns of RON are in bytes.
+            line => {
+                let line_start: usize = value
+                    .as_str()
+                    .split_inclusive('\n')
+                    .take(line - 1)
+                    .map(str::len)
+                    .sum();
+                let at = (start + line_start + e.position.col - 1).min(end);
+                (at, at)
+            }
+        };
+        Box::new(ExprError::new(ExprErrorKind::RonError(e.code), at))
+    })
+}
+
+// Deserialize the RON rewritten from the sugar of `term`, whose positions are of no use in the expression, so errors are put on the whole term.
+fn from_rewritten<T>(ron: &str, term: &Pair<Rule>) -> Result<T, Box<ExprError>>
+where
+    for<'a> T: Deserialize<'a>,
+{
+    ron::from_str(ron)
+        .map_err(|e| Box::new(ExprError::new(ExprErrorKind::RonError(e.code), span(term))))
+}
+
+// Comments are skipped by the grammar but still in the text of RON values, which RON doesn't take.
+// The newlines ending them are kept, so that positions in the text stay the same up to the comments.
+fn strip_comments(ron: &str) -> String {
+    let mut stripped = String::with_capacity(ron.len());
+    let mut chars = ron.chars();
+    let mut string = false;
+    while let Some(c) = chars.next() {
+        match c {
+            '#' if !string => {
+                if chars.by_ref().any(|c| c == '\n') {
+                    stripped.push('\n');
+                }
+                continue;
+            }
+            // The character escaped is never the end of the string.
+            '\\' if string => {
+                stripped.push(c);
+                if let Some(c) = chars.next() {
+                    stripped.push(c);
+                }
+                continue;
+            }
+            '"' => string = !string,
+            _ => (),
+        }
+        stripped.push(c);
+    }
+    stripped
+}
+
+// Rewrite the short form of `geoip` into the named fields.
+fn build_geoip(term: Pair<Rule>) -> String {
+    let mut invert = false;
+    let mut codes = String::new();
+    for pair in term.into_inner() {
+        match pair.as_rule() {
+            Rule::GeoIpInvert => invert = true,
+            _ => codes = strip_comments(pair.as_str()),
+        }
+    }
+    format!("geoip(codes: {}, invert: {})", codes, invert)
+}
+
+// Rewrite the domains given inline into `qname` resources, once they are validated the way lines of lists are.
+fn build_domain(term: Pair<Rule>) -> Result<String, Box<ExprError>> {
+    let mut inner = term.into_inner();
+    let matcher = inner.next().unwrap().as_str();
+    let list = inner.next().unwrap();
+    Ok(match list.as_rule() {
+        Rule::DomainList => format!("{}({})", matcher, build_domain_entry(list)?),
+        // A set operation standing for the whole list
+        _ => format!("{}([{}])", matcher, build_domain_entry(list)?),
+    })
+}
+
+fn build_domain_entry(entry: Pair<Rule>) -> Result<String, Box<ExprError>> {
+    Ok(match entry.as_rule() {
+        Rule::DomainList => format!(
+            "[{}]",
+            entry
+                .into_inner()
+                .map(build_domain_entry)
+                .collect::<Result<Vec<_>, _>>()?
+                .join(", ")
+        ),
+        Rule::DomainSet => {
+            let mut inner = entry.into_inner();
+            let op = inner.next().unwrap().as_str();
+            let (a, b) = (inner.next().unwrap(), inner.next().unwrap());
+            format!(
+                "{}({}, {})",
+                op,
+                build_domain_entry(a)?,
+                build_domain_entry(b)?
+            )
+        }
+        Rule::string => match parse_domain(&from_ron::<String>(&entry)?, Charset::default()) {
+            Some(name) => format!("qname(\"{}\")", name),
+            None => {
+                return Err(Box::new(ExprError::new(
+                    ExprErrorKind::InvalidDomain(entry.as_str().to_owned()),
+                    span(&entry),
+                )))
+            }
+        },
+        _ => strip_comments(entry.as_str()),
+    })
+}
+
 fn build_node_from_andexpr<M>(
     mut andexpr_operands: Pairs<Rule>,
-) -> Result<Node<BuilderPrimitive<M>>, ExprError>
+) -> Result<Node<BuilderPrimitive<M>>, Box<ExprError>>
 where
     for<'a> M: Deserialize<'a> + AsyncTryInto<Box<dyn Matcher>, Error = MatchError>,
 {
@@ -128,6 +538,8 @@ where
 {
     Bool(bool),
     MatcherBuilder(M),
+    // An expression with its results memoized in a cache of the size given
+    Cached(Box<Node<Self>>, NonZeroUsize),
 }
 
 impl Primitive {
@@ -152,6 +564,7 @@ impl<M> Node<BuilderPrimitive<M>>
 where
     for<'a> M: Deserialize<'a> + AsyncTryInto<Box<dyn Matcher>, Error = MatchError>,
 {
+    // Fold constant operands. Matchers made redundant by them are dropped and never evaluated.
     pub fn trim(self) -> Self {
         match self {
             Node::And(mut v) => {
@@ -198,12 +611,15 @@ where
             }
             Node::Neg(op) => match op.trim() {
                 Node::None(BuilderPrimitive::Bool(bl)) => Node::None(BuilderPrimitive::Bool(!bl)),
-                Node::None(BuilderPrimitive::MatcherBuilder(m)) => {
-                    Node::None(BuilderPrimitive::MatcherBuilder(m))
-                }
-                Node::And(v) => Node::And(v),
-                Node::Or(v) => Node::Or(v),
-                Node::Neg(op) => Node::Neg(op),
+                // Double negation cancels out
+                Node::Neg(op) => *op,
+                // Impure operands keep the negation
+                op => Node::Neg(Box::new(op)),
+            },
+            Node::None(BuilderPrimitive::Cached(node, size)) => match node.trim() {
+                // Constants need no cache
+                Node::None(BuilderPrimitive::Bool(bl)) => Node::None(BuilderPrimitive::Bool(bl)),
+                node => Node::None(BuilderPrimitive::Cached(Box::new(node), size)),
             },
             Node::None(_) => self,
         }
@@ -219,14 +635,24 @@ where
 }
 
 impl Matcher for Node<Primitive> {
+    // Operands are evaluated from left to right, stopping as soon as the result is known. Matchers with side effects (e.g. `client_rate`) are therefore only evaluated if everything before them leaves the result open.
     fn matches(&self, state: &State) -> bool {
         match self {
-            Node::And(v) => v.iter().map(|x| x.matches(state)).all(|x| x),
-            Node::Or(v) => v.iter().map(|x| x.matches(state)).any(|x| x),
+            Node::And(v) => v.iter().all(|x| x.matches(state)),
+            Node::Or(v) => v.iter().any(|x| x.matches(state)),
             Node::Neg(op) => !op.matches(state),
             Node::None(prim) => prim.eval(state),
         }
     }
+
+    fn resources(&self) -> Vec<Resource> {
+        match self {
+            Node::And(v) | Node::Or(v) => v.iter().flat_map(|x| x.resources()).collect(),
+            Node::Neg(op) => op.resources(),
+            Node::None(Primitive::Matcher(m)) => m.resources(),
+            Node::None(Primitive::Bool(_)) => Vec::new(),
+        }
+    }
 }
 
 #[async_trait]
@@ -257,24 +683,38 @@ where
             Node::None(BuilderPrimitive::MatcherBuilder(m)) => {
                 Node::None(Primitive::Matcher(m.async_try_into().await?))
             }
+            Node::None(BuilderPrimitive::Cached(node, size)) => Node::None(Primitive::Matcher(
+                Box::new(Cached::new(Box::new(node.async_try_into().await?), size)),
+            )),
         })
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use super::{Node, Primitive};
+    use super::{build_geoip, Node, Primitive, Rule};
     use crate::{
         matchers::{
-            builder::BuiltinMatcherBuilders,
-            expr::{BuilderPrimitive, ExprParser},
+            builder::{BuiltinMatcherBuilders, DomainBuilder, FullQnameBuilder},
+            expr::{BuilderPrimitive, ExprError, ExprErrorKind, ExprParser},
             MatchError, Matcher,
         },
         router::table::State,
-        AsyncTryInto,
+        AsyncTryInto, MAX_LEN,
     };
     use async_trait::async_trait;
+    use bytes::{Bytes, BytesMut};
+    use domain::base::{Dname, MessageBuilder, Rtype};
+    use pest::Parser;
     use serde::Deserialize;
+    use std::{
+        num::NonZeroUsize,
+        str::FromStr,
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+    };
 
     #[derive(Deserialize, Debug, PartialEq)]
     struct DummyMatcher;
@@ -293,31 +733,110 @@ mod tests {
         }
     }
 
+    // Counts the times it is evaluated.
+    struct Counter(Arc<AtomicUsize>, bool);
+
+    impl Matcher for Counter {
+        fn matches(&self, _: &State) -> bool {
+            self.0.fetch_add(1, Ordering::Relaxed);
+            self.1
+        }
+    }
+
+    fn create_state(name: &str, qtype: Rtype) -> State {
+        let name = Dname::<Bytes>::from_str(name).unwrap();
+        let mut builder = MessageBuilder::from_target(BytesMut::with_capacity(MAX_LEN))
+            .unwrap()
+            .question();
+        builder.push((&name, qtype)).unwrap();
+        State {
+            query: builder.into_message(),
+            ..Default::default()
+        }
+    }
+
     #[test]
     fn basic() {
-        assert_eq!(
-            Node::None(Primitive::Bool(true)).matches(&State::default()),
-            true
-        );
-        assert_eq!(
-            Node::And(vec![
-                Node::None(Primitive::Bool(true)),
+        assert!(Node::None(Primitive::Bool(true)).matches(&State::default()));
+        assert!(!Node::And(vec![
+            Node::None(Primitive::Bool(true)),
+            Node::None(Primitive::Bool(false)),
+            Node::None(Primitive::Bool(true))
+        ])
+        .matches(&State::default()));
+        assert!(Node::And(vec![
+            Node::Neg(Box::new(Node::None(Primitive::Bool(false)))),
+            Node::Or(vec![
                 Node::None(Primitive::Bool(false)),
                 Node::None(Primitive::Bool(true))
             ])
-            .matches(&State::default()),
-            false
+        ])
+        .matches(&State::default()));
+    }
+
+    #[test]
+    fn short_circuit() {
+        let hits = Arc::new(AtomicUsize::new(0));
+        let counter =
+            |result| Node::None(Primitive::Matcher(Box::new(Counter(hits.clone(), result))));
+
+        // Evaluated from left to right until the result is known
+        assert!(
+            Node::Or(vec![counter(false), counter(true), counter(true)]).matches(&State::default())
         );
+        assert_eq!(hits.swap(0, Ordering::Relaxed), 2);
+        assert!(
+            !Node::And(vec![counter(true), counter(false), counter(true)])
+                .matches(&State::default())
+        );
+        assert_eq!(hits.swap(0, Ordering::Relaxed), 2);
+        assert!(!Node::And(vec![
+            Node::None(Primitive::Bool(false)),
+            Node::Or(vec![counter(true), counter(true)])
+        ])
+        .matches(&State::default()));
+        assert_eq!(hits.load(Ordering::Relaxed), 0);
+    }
+
+    #[tokio::test]
+    async fn precedence() {
+        // `&&` binds tighter than `||`
+        let matcher = ExprParser
+            .build_node::<BuiltinMatcherBuilders>(
+                r#"qtype([A]) && domain([qname("example.com")]) || qtype([AAAA])"#,
+            )
+            .unwrap()
+            .async_try_into()
+            .await
+            .unwrap();
+        assert!(matcher.matches(&create_state("example.com", Rtype::A)));
+        assert!(!matcher.matches(&create_state("example.org", Rtype::A)));
+        assert!(matcher.matches(&create_state("example.org", Rtype::Aaaa)));
+        assert!(!matcher.matches(&create_state("example.com", Rtype::Mx)));
+
+        // Unless grouped otherwise
+        let matcher = ExprParser
+            .build_node::<BuiltinMatcherBuilders>(
+                r#"qtype([A]) && (domain([qname("example.com")]) || qtype([AAAA]))"#,
+            )
+            .unwrap()
+            .async_try_into()
+            .await
+            .unwrap();
+        assert!(matcher.matches(&create_state("example.com", Rtype::A)));
+        assert!(!matcher.matches(&create_state("example.org", Rtype::Aaaa)));
+
         assert_eq!(
-            Node::And(vec![
-                Node::Neg(Box::new(Node::None(Primitive::Bool(false)))),
-                Node::Or(vec![
-                    Node::None(Primitive::Bool(false)),
-                    Node::None(Primitive::Bool(true))
-                ])
+            ExprParser
+                .build_node::<DummyMatcher>("false && true || true")
+                .unwrap(),
+            Node::Or(vec![
+                Node::And(vec![
+                    Node::None(BuilderPrimitive::Bool(false)),
+                    Node::None(BuilderPrimitive::Bool(true))
+                ]),
+                Node::None(BuilderPrimitive::Bool(true)),
             ])
-            .matches(&State::default()),
-            true
         );
     }
 
@@ -368,42 +887,33 @@ mod tests {
         );
 
         // All true and
-        assert_eq!(
-            Node::And(vec![
-                Node::Neg(Box::new(Node::None(
-                    BuilderPrimitive::<DummyMatcher>::Bool(false)
-                ))),
-                Node::None(BuilderPrimitive::Bool(true)),
-                Node::None(BuilderPrimitive::Bool(true)),
-            ])
-            .trim()
-            .pure_unwrap(),
-            true
-        );
+        assert!(Node::And(vec![
+            Node::Neg(Box::new(Node::None(
+                BuilderPrimitive::<DummyMatcher>::Bool(false)
+            ))),
+            Node::None(BuilderPrimitive::Bool(true)),
+            Node::None(BuilderPrimitive::Bool(true)),
+        ])
+        .trim()
+        .pure_unwrap());
 
-        assert_eq!(
-            Node::And(vec![
-                Node::None(BuilderPrimitive::<DummyMatcher>::Bool(true)),
-                Node::None(BuilderPrimitive::Bool(false)),
-                Node::None(BuilderPrimitive::Bool(true)),
-            ])
-            .trim()
-            .pure_unwrap(),
-            false
-        );
+        assert!(!Node::And(vec![
+            Node::None(BuilderPrimitive::<DummyMatcher>::Bool(true)),
+            Node::None(BuilderPrimitive::Bool(false)),
+            Node::None(BuilderPrimitive::Bool(true)),
+        ])
+        .trim()
+        .pure_unwrap());
 
         // Short-circuited
-        assert_eq!(
-            Node::Or(vec![
-                Node::None(BuilderPrimitive::<DummyMatcher>::MatcherBuilder(
-                    DummyMatcher
-                )),
-                Node::None(BuilderPrimitive::Bool(true)),
-            ])
-            .trim()
-            .pure_unwrap(),
-            true
-        );
+        assert!(Node::Or(vec![
+            Node::None(BuilderPrimitive::<DummyMatcher>::MatcherBuilder(
+                DummyMatcher
+            )),
+            Node::None(BuilderPrimitive::Bool(true)),
+        ])
+        .trim()
+        .pure_unwrap());
 
         // Ireducible
         assert_eq!(
@@ -422,56 +932,108 @@ mod tests {
             ])
         );
 
-        assert_eq!(
-            Node::And(vec![
-                Node::None(BuilderPrimitive::<DummyMatcher>::Bool(true)),
-                // false
-                Node::Neg(Box::new(Node::Or(vec![
-                    Node::None(BuilderPrimitive::Bool(true)),
-                    Node::None(BuilderPrimitive::Bool(false))
-                ]))),
+        assert!(!Node::And(vec![
+            Node::None(BuilderPrimitive::<DummyMatcher>::Bool(true)),
+            // false
+            Node::Neg(Box::new(Node::Or(vec![
                 Node::None(BuilderPrimitive::Bool(true)),
-            ])
-            .trim()
-            .pure_unwrap(),
-            false,
-        );
+                Node::None(BuilderPrimitive::Bool(false))
+            ]))),
+            Node::None(BuilderPrimitive::Bool(true)),
+        ])
+        .trim()
+        .pure_unwrap());
     }
 
     #[tokio::test]
     async fn parser() {
-        assert_eq!(
-            ExprParser
-                .build_node::<BuiltinMatcherBuilders>("true")
-                .unwrap()
-                .async_try_into()
-                .await
-                .unwrap()
-                .matches(&State::default()),
-            true
-        );
-        assert_eq!(
-            ExprParser
-                .build_node::<BuiltinMatcherBuilders>("((true || (!false)) && false)")
-                .unwrap()
-                .async_try_into()
-                .await
-                .unwrap()
-                .matches(&State::default()),
-            false
-        );
-        assert_eq!(
+        assert!(ExprParser
+            .build_node::<BuiltinMatcherBuilders>("true")
+            .unwrap()
+            .async_try_into()
+            .await
+            .unwrap()
+            .matches(&State::default()));
+        assert!(!ExprParser
+            .build_node::<BuiltinMatcherBuilders>("((true || (!false)) && false)")
+            .unwrap()
+            .async_try_into()
+            .await
+            .unwrap()
+            .matches(&State::default()));
+        assert!(ExprParser
+            .build_node::<BuiltinMatcherBuilders>(
+                "(true && false || true && true || true && false)"
+            )
+            .unwrap()
+            .async_try_into()
+            .await
+            .unwrap()
+            .matches(&State::default()));
+        // Queries without a context never match on the source address.
+        assert!(!ExprParser
+            .build_node::<BuiltinMatcherBuilders>(r#"src_ip(["192.168.3.0/24", "::/0"])"#)
+            .unwrap()
+            .async_try_into()
+            .await
+            .unwrap()
+            .matches(&State::default()));
+        assert!(!ExprParser
+            .build_node::<BuiltinMatcherBuilders>("client_rate(limit: 0, window: 1)")
+            .unwrap()
+            .async_try_into()
+            .await
+            .unwrap()
+            .matches(&State::default()));
+        // Nor is a query without any question ever a duplicate.
+        assert!(!ExprParser
+            .build_node::<BuiltinMatcherBuilders>("duplicate(threshold: 0, window: 10)")
+            .unwrap()
+            .async_try_into()
+            .await
+            .unwrap()
+            .matches(&State::default()));
+        // Nor do matchers on the response before it is set.
+        assert!(!ExprParser
+            .build_node::<BuiltinMatcherBuilders>(
+                "empty_answer(true) || rcode([NOERROR]) || resp_rtype(none, [A, AAAA]) || cname_chain(depth(0)) || resp_ttl(min: 1, max: 86400) || resp_source([cache, stale_cache, upstream]) || answer_count(min: 2) || answer_count(max: 0, qtype_only: true)"
+            )
+            .unwrap()
+            .async_try_into()
+            .await
+            .unwrap()
+            .matches(&State::default()));
+        // The query has no OPT record either.
+        assert!(ExprParser
+            .build_node::<BuiltinMatcherBuilders>("edns(present(false)) && !edns(do_bit(true))")
+            .unwrap()
+            .async_try_into()
+            .await
+            .unwrap()
+            .matches(&State::default()));
+        // Windows lasting a whole day of every day always match.
+        assert!(ExprParser
+            .build_node::<BuiltinMatcherBuilders>(
+                r#"schedule([(days: [Mon, Tue, Wed, Thu, Fri, Sat, Sun], start: "07:00", end: "07:00", utc: true)])"#
+            )
+            .unwrap()
+            .async_try_into()
+            .await
+            .unwrap()
+            .matches(&State::default()));
+        // Record types without a mnemonic are given by number.
+        assert!(ExprParser
+            .build_node::<BuiltinMatcherBuilders>("qtype([TYPE65, addr])")
+            .is_ok());
+        assert!(matches!(
             ExprParser
-                .build_node::<BuiltinMatcherBuilders>(
-                    "(true && false || true && true || true && false)"
-                )
-                .unwrap()
-                .async_try_into()
-                .await
-                .unwrap()
-                .matches(&State::default()),
-            true
-        );
+                .build_node::<BuiltinMatcherBuilders>("qtype([HTTPS])")
+                .map_err(|e| *e),
+            Err(ExprError {
+                kind: ExprErrorKind::RonError(_),
+                ..
+            })
+        ));
 
         assert_eq!(
             ExprParser
@@ -505,4 +1067,451 @@ mod tests {
                 .unwrap(),
         );
     }
+
+    #[test]
+    fn inline_domains() {
+        let domains = |expr| match ExprParser.build_node::<BuiltinMatcherBuilders>(expr) {
+            Ok(Node::None(BuilderPrimitive::MatcherBuilder(BuiltinMatcherBuilders::Domain(d)))) => {
+                d
+            }
+            _ => panic!("`{}` should be a domain matcher", expr),
+        };
+        // Literals are normalized like lines of lists, and mixed with resources in order.
+        assert_eq!(
+            domains(r#"domain(["example.com", "例え.jp", file("big-list.gz"), qname("a.com"),])"#),
+            DomainBuilder::new()
+                .add_qnmae("example.com")
+                .add_qnmae("xn--r8jz45g.jp")
+                .add_file("big-list.gz")
+                .add_qnmae("a.com")
+        );
+        assert_eq!(
+            domains(r#"domain(["_dmarc.example.com"])"#),
+            DomainBuilder::new().add_qnmae("_dmarc.example.com")
+        );
+        assert_eq!(domains("domain([])"), DomainBuilder::new());
+        // A set operation may stand for the whole list, and literals are taken in it as well.
+        let (a, b) = (
+            DomainBuilder::new().add_qnmae("google.com"),
+            DomainBuilder::new()
+                .add_file("whitelist.txt")
+                .add_qnmae("mail.google.com"),
+        );
+        assert_eq!(
+            domains(r#"domain(diff(["google.com"], [file("whitelist.txt"), "mail.google.com"]))"#),
+            DomainBuilder::new().add_diff(a.clone(), b.clone())
+        );
+        assert_eq!(
+            domains(
+                r#"domain(["example.com", intersect([qname("google.com")], [file("whitelist.txt"), "mail.google.com"])])"#
+            ),
+            DomainBuilder::new()
+                .add_qnmae("example.com")
+                .add_intersect(a, b)
+        );
+
+        for (expr, literal, col) in [
+            (
+                r#"domain(["example.com", "bad domain"])"#,
+                "\"bad domain\"",
+                24,
+            ),
+            (r#"true && domain([ "" ])"#, "\"\"", 18),
+            (r#"domain(["a..b"])"#, "\"a..b\"", 9),
+        ] {
+            match ExprParser
+                .build_node::<BuiltinMatcherBuilders>(expr)
+                .map_err(|e| *e)
+            {
+                Err(ExprError {
+                    kind: ExprErrorKind::InvalidDomain(l),
+                    line: 1,
+                    col: c,
+                    ..
+                }) => assert_eq!((l.as_str(), c), (literal, col)),
+                _ => panic!("`{}` should not parse", expr),
+            }
+        }
+        // Other matchers don't take literals like that.
+        assert!(matches!(
+            ExprParser
+                .build_node::<BuiltinMatcherBuilders>(r#"qname_contains(["example", file("x")])"#)
+                .map_err(|e| *e),
+            Err(ExprError {
+                kind: ExprErrorKind::RonError(_),
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn geoip_short() {
+        for (expr, expanded) in [
+            (
+                r#"geoip(["CN", "cont:EU"])"#,
+                r#"geoip(codes: ["CN", "cont:EU"], invert: false)"#,
+            ),
+            (
+                r#"geoip( ! [ "CN", # mainland
+                  "HK", ])"#,
+                "geoip(codes: [ \"CN\", \n                  \"HK\", ], invert: true)",
+            ),
+            ("geoip([])", "geoip(codes: [], invert: false)"),
+        ] {
+            let term = ExprParser::parse(Rule::GeoIp, expr)
+                .unwrap()
+                .next()
+                .unwrap();
+            assert_eq!(build_geoip(term), expanded);
+        }
+        // The long form is still RON.
+        assert!(ExprParser::parse(Rule::GeoIp, r#"geoip(codes: ["CN"])"#).is_err());
+        assert!(ExprParser::parse(Rule::Program, r#"geoip(codes: ["CN"], invert: true)"#).is_ok());
+    }
+
+    #[tokio::test]
+    async fn full_qname() {
+        assert!(matches!(
+            ExprParser.build_node::<BuiltinMatcherBuilders>(r#"full_qname(["example.com", file("hosts.txt")])"#),
+            Ok(Node::None(BuilderPrimitive::MatcherBuilder(BuiltinMatcherBuilders::FullQname(f))))
+                if f == FullQnameBuilder::new().add_qname("example.com").add_file("hosts.txt")
+        ));
+        assert!(matches!(
+            ExprParser
+                .build_node::<BuiltinMatcherBuilders>(r#"full_qname(["example..com"])"#)
+                .map_err(|e| *e),
+            Err(ExprError {
+                kind: ExprErrorKind::InvalidDomain(_),
+                line: 1,
+                col: 13,
+                ..
+            })
+        ));
+
+        let matcher = ExprParser
+            .build_node::<BuiltinMatcherBuilders>(
+                r#"full_qname(["example.com"]) && !domain(["corp.example.com"])"#,
+            )
+            .unwrap()
+            .async_try_into()
+            .await
+            .unwrap();
+        assert!(matcher.matches(&create_state("example.com", Rtype::A)));
+        assert!(!matcher.matches(&create_state("www.example.com", Rtype::A)));
+        assert!(!matcher.matches(&create_state("corp.example.com", Rtype::A)));
+    }
+
+    #[tokio::test]
+    async fn cached() {
+        assert_eq!(
+            ExprParser
+                .build_node::<DummyMatcher>("!cached(DummyMatcher && true, 10)")
+                .unwrap(),
+            Node::Neg(Box::new(Node::None(BuilderPrimitive::Cached(
+                Box::new(Node::And(vec![
+                    Node::None(BuilderPrimitive::MatcherBuilder(DummyMatcher)),
+                    Node::None(BuilderPrimitive::Bool(true)),
+                ])),
+                NonZeroUsize::new(10).unwrap()
+            ))))
+        );
+        // Constants are not cached.
+        assert_eq!(
+            ExprParser
+                .build_node::<DummyMatcher>("cached(DummyMatcher || true, 10)")
+                .unwrap()
+                .trim(),
+            Node::None(BuilderPrimitive::Bool(true))
+        );
+        for size in ["0", "18446744073709551616"] {
+            assert!(matches!(
+                ExprParser
+                    .build_node::<DummyMatcher>(&format!("cached(DummyMatcher, {})", size))
+                    .map_err(|e| *e),
+                Err(ExprError {
+                    kind: ExprErrorKind::MatchError(MatchError::Other(_)),
+                    col: 22,
+                    ..
+                })
+            ));
+        }
+
+        let matcher = ExprParser
+            .build_node::<BuiltinMatcherBuilders>(
+                r#"cached(domain([qname("example.com")]) && qtype([A]), 10)"#,
+            )
+            .unwrap()
+            .async_try_into()
+            .await
+            .unwrap();
+        assert!(matcher.matches(&create_state("www.example.com", Rtype::A)));
+        assert!(!matcher.matches(&create_state("www.example.com", Rtype::Aaaa)));
+        assert!(!matcher.matches(&create_state("example.org", Rtype::A)));
+    }
+
+    #[tokio::test]
+    async fn negation() {
+        let matcher = ExprParser
+            .build_node::<BuiltinMatcherBuilders>(r#"!domain([qname("example.com")])"#)
+            .unwrap()
+            .trim()
+            .async_try_into()
+            .await
+            .unwrap();
+        assert!(!matcher.matches(&create_state("www.example.com", Rtype::A)));
+        assert!(matcher.matches(&create_state("example.org", Rtype::A)));
+
+        let matcher = ExprParser
+            .build_node::<BuiltinMatcherBuilders>(
+                r#"!(domain([qname("example.com")]) && qtype([A]))"#,
+            )
+            .unwrap()
+            .trim()
+            .async_try_into()
+            .await
+            .unwrap();
+        assert!(!matcher.matches(&create_state("example.com", Rtype::A)));
+        assert!(matcher.matches(&create_state("example.org", Rtype::A)));
+
+        // `!` binds tighter than both `&&` and `||`
+        assert_eq!(
+            ExprParser
+                .build_node::<DummyMatcher>("!false && true || !(true && false)")
+                .unwrap(),
+            Node::Or(vec![
+                Node::And(vec![
+                    Node::Neg(Box::new(Node::None(BuilderPrimitive::Bool(false)))),
+                    Node::None(BuilderPrimitive::Bool(true)),
+                ]),
+                Node::Neg(Box::new(Node::And(vec![
+                    Node::None(BuilderPrimitive::Bool(true)),
+                    Node::None(BuilderPrimitive::Bool(false)),
+                ]))),
+            ])
+        );
+        assert_eq!(
+            ExprParser
+                .build_node::<DummyMatcher>("true && !!false")
+                .unwrap(),
+            Node::And(vec![
+                Node::None(BuilderPrimitive::Bool(true)),
+                Node::Neg(Box::new(Node::Neg(Box::new(Node::None(
+                    BuilderPrimitive::Bool(false)
+                ))))),
+            ])
+        );
+
+        // Trimming keeps the negation of impure operands
+        assert_eq!(
+            Node::Neg(Box::new(Node::None(
+                BuilderPrimitive::<DummyMatcher>::MatcherBuilder(DummyMatcher)
+            )))
+            .trim(),
+            Node::Neg(Box::new(Node::None(BuilderPrimitive::MatcherBuilder(
+                DummyMatcher
+            ))))
+        );
+        assert_eq!(
+            Node::Neg(Box::new(Node::Neg(Box::new(Node::None(
+                BuilderPrimitive::<DummyMatcher>::MatcherBuilder(DummyMatcher)
+            )))))
+            .trim(),
+            Node::None(BuilderPrimitive::MatcherBuilder(DummyMatcher))
+        );
+
+        // Dangling negations are reported where the operand is missing
+        for (expr, col) in [("!", 2), ("true && !", 10), ("(!) || true", 3)] {
+            match ExprParser.build_node::<DummyMatcher>(expr).map_err(|e| *e) {
+                Err(
+                    e @ ExprError {
+                        kind: ExprErrorKind::Syntax,
+                        ..
+                    },
+                ) => assert_eq!((e.line, e.col), (1, col)),
+                _ => panic!("`{}` should not parse", expr),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn comments() {
+        let single =
+            r#"domain(["example.com", qname("example.org")]) && !qtype([AAAA]) || qtype([TXT])"#;
+        let multi = r##"
+# Names of our own
+domain([
+    "example.com", # the main site, with a "#" in the comment
+    qname("example.org"), # a qname("#")
+]) && !qtype([AAAA]) # drop v6 for these
+    || qtype([TXT])  # but answer TXT anyway
+"##;
+        let build = |expr| async move {
+            ExprParser
+                .build_node::<BuiltinMatcherBuilders>(expr)
+                .unwrap()
+                .trim()
+                .async_try_into()
+                .await
+                .unwrap()
+        };
+        let (single, multi) = (build(single).await, build(multi).await);
+        for name in ["www.example.com", "example.org", "example.net"] {
+            for qtype in [Rtype::A, Rtype::Aaaa, Rtype::Txt] {
+                let state = create_state(name, qtype);
+                assert_eq!(single.matches(&state), multi.matches(&state));
+            }
+        }
+        assert!(multi.matches(&create_state("www.example.com", Rtype::A)));
+        assert!(!multi.matches(&create_state("www.example.com", Rtype::Aaaa)));
+        assert!(multi.matches(&create_state("example.net", Rtype::Txt)));
+
+        assert_eq!(
+            ExprParser
+                .build_node::<DummyMatcher>("true # && false\n&& (false ||\n# true ||\n true)")
+                .unwrap(),
+            ExprParser
+                .build_node::<DummyMatcher>("true && (false || true)")
+                .unwrap(),
+        );
+
+        // Errors are reported at their lines and columns in the whole expression.
+        match ExprParser
+            .build_node::<DummyMatcher>("true &&\n  # the end\n  !")
+            .map_err(|e| *e)
+        {
+            Err(
+                e @ ExprError {
+                    kind: ExprErrorKind::Syntax,
+                    ..
+                },
+            ) => assert_eq!((e.line, e.col), (3, 4)),
+            _ => panic!("dangling negation should not parse"),
+        }
+        match ExprParser
+            .build_node::<BuiltinMatcherBuilders>(
+                "true # first\n&& qname_contains([\"a\", # second\n  1])",
+            )
+            .map_err(|e| *e)
+        {
+            Err(
+                e @ ExprError {
+                    kind: ExprErrorKind::RonError(_),
+                    ..
+                },
+            ) => assert_eq!((e.line, e.col), (3, 3)),
+            _ => panic!("`qname_contains` takes no numbers"),
+        }
+    }
+
+    #[test]
+    fn error_positions() {
+        // Unknown matchers, with the closest name known suggested if there is one close enough
+        for (expr, name, offset, suggestion) in [
+            (r#"domian([file("x")])"#, "domian", 0, Some("domain")),
+            ("true && qtyp([A])", "qtyp", 8, Some("qtype")),
+            (
+                "!(true || full_qnme([]))",
+                "full_qnme",
+                10,
+                Some("full_qname"),
+            ),
+            ("nonsense(1)", "nonsense", 0, None),
+        ] {
+            match ExprParser
+                .build_node::<BuiltinMatcherBuilders>(expr)
+                .map_err(|e| *e)
+            {
+                Err(
+                    e @ ExprError {
+                        kind: ExprErrorKind::UnknownMatcher(_),
+                        ..
+                    },
+                ) => {
+                    assert_eq!(
+                        (e.offset, e.line, e.col, e.found.as_deref()),
+                        (offset, 1, offset + 1, Some(name))
+                    );
+                    assert_eq!(e.suggestion.as_deref(), suggestion);
+                    assert!(e.expected.contains(&"`qtype`".to_string()));
+                }
+                _ => panic!("`{}` should not parse", expr),
+            }
+        }
+        // Matchers known but given wrong arguments are not suggested anything.
+        assert!(matches!(
+            ExprParser
+                .build_node::<BuiltinMatcherBuilders>("qtype(3)")
+                .map_err(|e| *e),
+            Err(ExprError {
+                kind: ExprErrorKind::RonError(_),
+                offset: 6,
+                suggestion: None,
+                ..
+            })
+        ));
+
+        // Offsets are in characters, not bytes.
+        for (expr, offset, line, col, found, expected) in [
+            (
+                "qtype([A]) qtype",
+                11,
+                1,
+                12,
+                Some("qtype"),
+                "end of expression",
+            ),
+            ("true &&", 7, 1, 8, None, "a matcher"),
+            ("# 注释\ntrue && !", 14, 2, 10, None, "`cached`"),
+            ("", 0, 1, 1, None, "an expression"),
+        ] {
+            match ExprParser.build_node::<DummyMatcher>(expr).map_err(|e| *e) {
+                Err(
+                    e @ ExprError {
+                        kind: ExprErrorKind::Syntax,
+                        ..
+                    },
+                ) => {
+                    assert_eq!(
+                        (e.offset, e.line, e.col, e.found.as_deref()),
+                        (offset, line, col, found)
+                    );
+                    assert!(e.expected.contains(&expected.to_string()), "{:?}", e);
+                }
+                _ => panic!("`{}` should not parse", expr),
+            }
+        }
+    }
+
+    #[test]
+    fn error_display() {
+        let display = |expr| {
+            ExprParser
+                .build_node::<BuiltinMatcherBuilders>(expr)
+                .unwrap_err()
+                .to_string()
+        };
+        assert_eq!(
+            display(r#"true && domian(["example.com"])"#),
+            r#"unknown matcher `domian` at line 1, column 9
+  |
+1 | true && domian(["example.com"])
+  |         ^^^^^^
+  = did you mean `domain`?"#
+        );
+        assert_eq!(
+            display("qtype([A])\n  || !"),
+            "syntax error at line 2, column 7
+  |
+2 |   || !
+  |       ^
+  = expected one of `!`, `cached`, `domain`, `full_qname`, `geoip`, `true`, `false` or a matcher, found end of expression"
+        );
+        assert_eq!(
+            display(r#"domain(["a..b"])"#),
+            r#"malformed domain "a..b" at line 1, column 9
+  |
+1 | domain(["a..b"])
+  |         ^^^^^^"#
+        );
+    }
 }
@@ -0,0 +1,229 @@
+// Copyright 2020 LEXUGE
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use super::{super::super::State, decompress, MatchError, Matcher, Result};
+use crate::{preflight::Resource, AsyncTryInto};
+use async_trait::async_trait;
+use dmatcher::domain::{parse_domain, Charset};
+use log::info;
+use serde::Deserialize;
+use std::{
+    collections::HashSet,
+    io::{BufRead, BufReader},
+    path::{Path, PathBuf},
+};
+
+/// Resources of the names to match exactly.
+#[derive(Deserialize, Clone, Eq, PartialEq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum FullQnameResource {
+    /// A name given in the configuration
+    Qname(String),
+
+    /// A file of a name per line
+    File(PathBuf),
+}
+
+// Names are parsed like lines of domain lists, and kept in lowercase without the trailing dot.
+fn normalize(name: &str) -> Option<Box<str>> {
+    parse_domain(name, Charset::default()).map(|name| name.to_string().to_ascii_lowercase().into())
+}
+
+/// A matcher that matches if the name of the first query is exactly any of the names provided, case-insensitively.
+/// Unlike `Domain`, subdomains of the names are not matched.
+pub struct FullQname {
+    names: HashSet<Box<str>>,
+    resources: Vec<Resource>,
+}
+
+impl FullQname {
+    /// Create a new `FullQname` matcher from the resources of names.
+    pub fn new(p: Vec<FullQnameResource>) -> Result<Self> {
+        let mut names = HashSet::new();
+        let mut resources = Vec::new();
+        for r in p {
+            match r {
+                FullQnameResource::Qname(name) => {
+                    names.insert(normalize(&name).ok_or_else(|| {
+                        MatchError::Other(format!("`{}` is not a valid domain", name))
+                    })?);
+                }
+                FullQnameResource::File(path) => {
+                    let loaded =
+                        Self::load(&path, &mut names).map_err(MatchError::resource(&path))?;
+                    info!("loaded {} names from `{}`", loaded, path.display());
+                    resources.push(Resource::new("full_qname", path.display(), loaded));
+                }
+            }
+        }
+        Ok(Self { names, resources })
+    }
+
+    // Insert the names in the file, returning the number of them. Empty lines, comments, and malformed names are skipped.
+    fn load(path: &Path, names: &mut HashSet<Box<str>>) -> Result<usize> {
+        let mut loaded = 0;
+        for line in BufReader::new(decompress::open(path)?).lines() {
+            let line = line?;
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            if let Some(name) = normalize(line) {
+                names.insert(name);
+                loaded += 1;
+            }
+        }
+        Ok(loaded)
+    }
+}
+
+impl Matcher for FullQname {
+    fn matches(&self, state: &State) -> bool {
+        let mut qname = state.query.first_question().unwrap().qname().to_string();
+        qname.make_ascii_lowercase();
+        self.names.contains(qname.as_str())
+    }
+
+    fn resources(&self) -> Vec<Resource> {
+        self.resources.clone()
+    }
+}
+
+/// A builder for the full qname matcher plugin
+#[derive(Deserialize, Clone, Debug, PartialEq, Eq)]
+#[serde(transparent)]
+pub struct FullQnameBuilder(Vec<FullQnameResource>);
+
+impl Default for FullQnameBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl FullQnameBuilder {
+    /// Create an empty builder
+    pub fn new() -> Self {
+        Self(Vec::new())
+    }
+
+    /// Add a name to match
+    pub fn add_qname(mut self, s: impl ToString) -> Self {
+        self.0.push(FullQnameResource::Qname(s.to_string()));
+        self
+    }
+
+    /// Add a file of names to match
+    pub fn add_file(mut self, s: impl Into<PathBuf>) -> Self {
+        self.0.push(FullQnameResource::File(s.into()));
+        self
+    }
+}
+
+#[async_trait]
+impl AsyncTryInto<FullQname> for FullQnameBuilder {
+    type Error = MatchError;
+
+    async fn async_try_into(self) -> Result<FullQname> {
+        FullQname::new(self.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        super::{Matcher, State},
+        FullQnameBuilder,
+    };
+    use crate::{AsyncTryInto, MAX_LEN};
+    use bytes::{Bytes, BytesMut};
+    use domain::base::{Dname, MessageBuilder, Rtype};
+    use std::str::FromStr;
+
+    fn create_state(name: &str) -> State {
+        let name = Dname::<Bytes>::from_str(name).unwrap();
+        let mut builder = MessageBuilder::from_target(BytesMut::with_capacity(MAX_LEN))
+            .unwrap()
+            .question();
+        builder.push((&name, Rtype::A)).unwrap();
+        State {
+            query: builder.into_message(),
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn exact() {
+        let matcher = FullQnameBuilder::new()
+            .add_qname("example.com")
+            .add_qname("Corp.Internal.")
+            .add_qname("例え.jp")
+            .async_try_into()
+            .await
+            .unwrap();
+        assert!(matcher.matches(&create_state("example.com")));
+        assert!(matcher.matches(&create_state("EXAMPLE.com.")));
+        assert!(matcher.matches(&create_state("corp.internal")));
+        assert!(matcher.matches(&create_state("xn--r8jz45g.jp")));
+        // Neither subdomains nor parents are matched.
+        assert!(!matcher.matches(&create_state("www.example.com")));
+        assert!(!matcher.matches(&create_state("com")));
+        assert!(!matcher.matches(&create_state("internal")));
+
+        assert!(FullQnameBuilder::new()
+            .add_qname("bad domain")
+            .async_try_into()
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn files() {
+        let dir = std::env::temp_dir().join("droute-full-qname");
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("names.txt");
+        std::fs::write(
+            &path,
+            "# Hosts of our own\nnas.home.arpa\n\n  Printer.Home.Arpa.  \nnot a name\n",
+        )
+        .unwrap();
+
+        let matcher = FullQnameBuilder::new()
+            .add_file(&path)
+            .add_qname("router.home.arpa")
+            .async_try_into()
+            .await
+            .unwrap();
+        assert_eq!(matcher.resources()[0].entries, 2);
+        for (name, matched) in [
+            ("nas.home.arpa", true),
+            ("printer.home.arpa", true),
+            ("router.home.arpa", true),
+            ("home.arpa", false),
+            ("www.nas.home.arpa", false),
+        ] {
+            assert_eq!(matcher.matches(&create_state(name)), matched, "{}", name);
+        }
+
+        // Lists are decompressed like the ones of `domain`.
+        let matcher = FullQnameBuilder::new()
+            .add_file("../data/apple.txt.gz")
+            .async_try_into()
+            .await
+            .unwrap();
+        assert!(matcher.resources()[0].entries > 0);
+        assert!(matcher.matches(&create_state("apps.apple.com")));
+        assert!(!matcher.matches(&create_state("apple.com")));
+    }
+}
@@ -17,46 +17,141 @@ use super::{super::super::State, MatchError, Matcher, Result};
 use crate::AsyncTryInto;
 use async_trait::async_trait;
 use log::info;
-use maxminddb::{geoip2::Country, Reader};
+use maxminddb::{
+    geoip2::{Asn, Country},
+    Reader,
+};
 use serde::Deserialize;
-use std::{collections::HashSet, path::PathBuf, str::FromStr};
+use std::{collections::HashSet, net::IpAddr, path::PathBuf, str::FromStr};
 
-/// A matcher that matches if IP address in the record of the first A/AAAA response is in the list of countries.
+/// Which IP address the `GeoIp` matcher looks up.
+#[derive(Deserialize, Clone, Copy, Eq, PartialEq, Debug, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum GeoIpMode {
+    /// IP address in the record of the first A/AAAA response.
+    #[default]
+    Resp,
+    /// IP address the query is sent from. Queries without context never match.
+    Src,
+}
+
+// Prefix of continent codes in the list of country codes
+const CONTINENT_PREFIX: &str = "cont:";
+
+// What the looked up IP address is matched against.
+enum Target {
+    Regions {
+        countries: HashSet<String>,
+        continents: HashSet<String>,
+    },
+    Asns(HashSet<u32>),
+}
+
+/// A matcher that matches if the IP address selected by the mode is in the list of countries, continents, or autonomous systems.
 pub struct GeoIp {
     db: Reader<Vec<u8>>,
-    list: HashSet<String>,
+    mode: GeoIpMode,
+    target: Target,
+    invert: bool,
 }
 
 impl GeoIp {
-    /// Create a new `Geoip` matcher from a set of ISO country codes like `CN`, `AU`.
-    pub fn new(list: HashSet<String>, buf: Vec<u8>) -> Result<Self> {
+    /// Create a new `Geoip` matcher from a set of ISO country codes like `CN`, `AU`, and continent codes prefixed with `cont:` like `cont:EU`.
+    pub fn new(mode: GeoIpMode, list: HashSet<String>, buf: Vec<u8>) -> Result<Self> {
+        let db = Reader::from_source(buf)?;
+        if is_asn_db(&db) {
+            return Err(MatchError::GeoIpDbType(db.metadata.database_type));
+        }
+        let (continents, countries) = list
+            .into_iter()
+            .partition::<HashSet<_>, _>(|c| c.starts_with(CONTINENT_PREFIX));
         Ok(Self {
-            list,
-            db: Reader::from_source(buf)?,
+            mode,
+            target: Target::Regions {
+                countries,
+                continents: continents
+                    .into_iter()
+                    .map(|c| c[CONTINENT_PREFIX.len()..].to_string())
+                    .collect(),
+            },
+            db,
+            invert: false,
         })
     }
+
+    /// Create a new `Geoip` matcher from a set of AS numbers like `13335`, which requires an ASN database.
+    pub fn with_asns(mode: GeoIpMode, asns: HashSet<u32>, buf: Vec<u8>) -> Result<Self> {
+        let db = Reader::from_source(buf)?;
+        if !is_asn_db(&db) {
+            return Err(MatchError::GeoIpDbType(db.metadata.database_type));
+        }
+        Ok(Self {
+            mode,
+            target: Target::Asns(asns),
+            db,
+            invert: false,
+        })
+    }
+
+    /// Match IP addresses outside the list instead, including those the database knows nothing about.
+    /// Without any IP address to look up, it still never matches.
+    pub fn inverted(mut self, invert: bool) -> Self {
+        self.invert = invert;
+        self
+    }
+
+    fn ip(&self, state: &State) -> Option<IpAddr> {
+        match self.mode {
+            GeoIpMode::Resp => state.resp_ip().ok().flatten(),
+            GeoIpMode::Src => state.qctx.as_ref().map(|qctx| qctx.ip()),
+        }
+    }
+}
+
+// MaxMind names its ASN databases like `GeoLite2-ASN`.
+fn is_asn_db(db: &Reader<Vec<u8>>) -> bool {
+    db.metadata.database_type.contains("ASN")
 }
 
 impl Matcher for GeoIp {
     fn matches(&self, state: &State) -> bool {
-        if let Ok(Some(ip)) = state.resp_ip() {
-            let r = if let Ok(r) = self.db.lookup::<Country>(ip) {
-                r
-            } else {
-                return false;
-            };
-
-            r.country
-                .and_then(|c| {
-                    c.iso_code.map(|n| {
+        let ip = if let Some(ip) = self.ip(state) {
+            ip
+        } else {
+            return false;
+        };
+
+        // IP addresses the database knows nothing about are outside the list.
+        let found = match &self.target {
+            Target::Regions {
+                countries,
+                continents,
+            } => match self.db.lookup::<Country>(ip) {
+                Ok(r) => {
+                    let country = r.country.and_then(|c| c.iso_code).is_some_and(|n| {
                         info!("IP `{}` has ISO country code `{}`", ip, n);
-                        self.list.contains(n)
-                    })
+                        countries.contains(n)
+                    });
+                    country
+                        || r.continent.and_then(|c| c.code).is_some_and(|n| {
+                            info!("IP `{}` has continent code `{}`", ip, n);
+                            continents.contains(n)
+                        })
+                }
+                Err(_) => false,
+            },
+            Target::Asns(list) => self
+                .db
+                .lookup::<Asn>(ip)
+                .ok()
+                .and_then(|r| r.autonomous_system_number)
+                .map(|n| {
+                    info!("IP `{}` is in AS{}", ip, n);
+                    list.contains(&n)
                 })
-                .unwrap_or(false)
-        } else {
-            false
-        }
+                .unwrap_or(false),
+        };
+        found != self.invert
     }
 }
 
@@ -64,8 +159,18 @@ impl Matcher for GeoIp {
 #[serde(rename_all = "lowercase")]
 /// Arguments of the GeoIp.
 pub struct GeoIpBuilder {
+    /// Which IP address to look up
+    #[serde(default)]
+    mode: GeoIpMode,
     /// Country codes to match on
+    #[serde(default)]
     codes: HashSet<String>,
+    /// AS numbers to match on
+    #[serde(default)]
+    asns: HashSet<u32>,
+    /// Whether to match IP addresses outside the list instead
+    #[serde(default)]
+    invert: bool,
     /// Buf
     buf: Vec<u8>,
 }
@@ -75,32 +180,56 @@ impl GeoIpBuilder {
     pub async fn from_path(path: impl AsRef<str>) -> Result<Self> {
         // Per std documentation, this is infallible
         let buf: Vec<u8> = tokio::fs::read(PathBuf::from_str(path.as_ref()).unwrap()).await?;
-        Ok(Self {
-            codes: HashSet::new(),
-            buf,
-        })
+        Ok(Self::from_buf(buf))
     }
 
     /// Create a GeoIpBuilder from the buffer
     pub fn from_buf(buf: Vec<u8>) -> Self {
         Self {
+            mode: GeoIpMode::default(),
             codes: HashSet::new(),
+            asns: HashSet::new(),
+            invert: false,
             buf,
         }
     }
 
+    /// Set which IP address the matcher looks up.
+    pub fn mode(mut self, mode: GeoIpMode) -> Self {
+        self.mode = mode;
+        self
+    }
+
     /// Add a country code for the matcher to match.
     pub fn add_code(mut self, code: impl ToString) -> Self {
         self.codes.insert(code.to_string());
         self
     }
+
+    /// Set whether the matcher matches IP addresses outside the list instead, including those the database knows nothing about.
+    pub fn invert(mut self, invert: bool) -> Self {
+        self.invert = invert;
+        self
+    }
+
+    /// Add an AS number for the matcher to match. The database provided has to be an ASN database.
+    pub fn add_asn(mut self, asn: u32) -> Self {
+        self.asns.insert(asn);
+        self
+    }
 }
 
 #[async_trait]
 impl AsyncTryInto<GeoIp> for GeoIpBuilder {
     async fn async_try_into(self) -> Result<GeoIp> {
-        // By default, we don't provide any builtin database.
-        Ok(GeoIp::new(self.codes, self.buf)?)
+        // Country codes and AS numbers live in different databases, while we only have one.
+        let geoip = match (self.codes.is_empty(), self.asns.is_empty()) {
+            (false, false) => Err(MatchError::GeoIpMixed),
+            (true, false) => GeoIp::with_asns(self.mode, self.asns, self.buf),
+            // By default, we don't provide any builtin database.
+            _ => GeoIp::new(self.mode, self.codes, self.buf),
+        }?;
+        Ok(geoip.inverted(self.invert))
     }
 
     type Error = MatchError;
@@ -108,19 +237,22 @@ impl AsyncTryInto<GeoIp> for GeoIpBuilder {
 
 #[cfg(test)]
 mod tests {
-    use super::{super::Matcher, GeoIpBuilder, State};
-    use crate::{AsyncTryInto, MAX_LEN};
+    use super::{super::Matcher, GeoIpBuilder, GeoIpMode, MatchError, State};
+    use crate::{AsyncTryInto, Protocol, QueryContext, MAX_LEN};
     use bytes::{Bytes, BytesMut};
     use domain::{
         base::{Dname, Message, MessageBuilder},
         rdata::A,
     };
     use once_cell::sync::Lazy;
-    use std::str::FromStr;
+    use std::{net::SocketAddr, str::FromStr};
 
     // Starting from droute's crate root
     static PATH: Lazy<Vec<u8>> =
         Lazy::new(|| include_bytes!("../../../../../../data/full.mmdb").to_vec());
+    // Only contains 1.1.1.0/24 (AS13335), 8.8.8.0/24 (AS15169), and 180.101.49.0/24 (AS4134).
+    static ASN_PATH: Lazy<Vec<u8>> =
+        Lazy::new(|| include_bytes!("../../../../../../data/asn-test.mmdb").to_vec());
     static MESSAGE_NOT_CHINA: Lazy<Message<Bytes>> = Lazy::new(|| {
         let name = Dname::<Bytes>::from_str("cloudflare-dns.com").unwrap();
         let mut builder = MessageBuilder::from_target(BytesMut::with_capacity(MAX_LEN))
@@ -151,34 +283,28 @@ mod tests {
         State {
             resp: m.clone(),
             query: m,
-            qctx: None,
+            ..Default::default()
         }
     }
 
     #[tokio::test]
     async fn builtin_db_not_china() {
-        assert_eq!(
-            GeoIpBuilder::from_buf(PATH.clone())
-                .add_code("CN")
-                .async_try_into()
-                .await
-                .unwrap()
-                .matches(&create_state(MESSAGE_NOT_CHINA.clone())),
-            false
-        )
+        assert!(!GeoIpBuilder::from_buf(PATH.clone())
+            .add_code("CN")
+            .async_try_into()
+            .await
+            .unwrap()
+            .matches(&create_state(MESSAGE_NOT_CHINA.clone())))
     }
 
     #[tokio::test]
     async fn not_china() {
-        assert_eq!(
-            GeoIpBuilder::from_buf(PATH.clone())
-                .add_code("CN")
-                .async_try_into()
-                .await
-                .unwrap()
-                .matches(&create_state(MESSAGE_NOT_CHINA.clone())),
-            false
-        )
+        assert!(!GeoIpBuilder::from_buf(PATH.clone())
+            .add_code("CN")
+            .async_try_into()
+            .await
+            .unwrap()
+            .matches(&create_state(MESSAGE_NOT_CHINA.clone())))
     }
 
     #[tokio::test]
@@ -189,38 +315,191 @@ mod tests {
             .async_try_into()
             .await
             .unwrap();
-        assert_eq!(geoip.matches(&create_state(MESSAGE_CHINA.clone())), true);
-        assert_eq!(
-            geoip.matches(&create_state(MESSAGE_NOT_CHINA.clone())),
-            true
-        )
+        assert!(geoip.matches(&create_state(MESSAGE_CHINA.clone())));
+        assert!(geoip.matches(&create_state(MESSAGE_NOT_CHINA.clone())))
     }
 
     #[tokio::test]
     async fn empty_records() {
-        assert_eq!(
+        assert!(!GeoIpBuilder::from_buf(PATH.clone())
+            .add_code("CN")
+            .async_try_into()
+            .await
+            .unwrap()
+            .matches(&create_state(
+                Message::from_octets(Bytes::from_static(&[0_u8; 55])).unwrap()
+            )))
+    }
+
+    #[tokio::test]
+    async fn is_china() {
+        assert!(GeoIpBuilder::from_buf(PATH.clone())
+            .add_code("CN")
+            .async_try_into()
+            .await
+            .unwrap()
+            .matches(&create_state(MESSAGE_CHINA.clone())))
+    }
+
+    // A response with a single A record of the address given
+    fn create_ip_state(ip: [u8; 4]) -> State {
+        let name = Dname::<Bytes>::from_str("example.com").unwrap();
+        let mut builder = MessageBuilder::from_target(BytesMut::with_capacity(MAX_LEN))
+            .unwrap()
+            .answer();
+        builder
+            .push((&name, 10, A::from_octets(ip[0], ip[1], ip[2], ip[3])))
+            .unwrap();
+        create_state(builder.into_message())
+    }
+
+    #[tokio::test]
+    async fn continents() {
+        let geoip = GeoIpBuilder::from_buf(PATH.clone())
+            .add_code("cont:EU")
+            .add_code("CN")
+            .async_try_into()
+            .await
+            .unwrap();
+        // NL
+        assert!(geoip.matches(&create_ip_state([193, 0, 0, 1])));
+        assert!(geoip.matches(&create_state(MESSAGE_CHINA.clone())));
+        // AU, which is in Oceania
+        assert!(!geoip.matches(&create_state(MESSAGE_NOT_CHINA.clone())));
+        // US
+        assert!(!geoip.matches(&create_ip_state([8, 8, 8, 8])));
+
+        // Without the prefix, it is a country code.
+        assert!(!GeoIpBuilder::from_buf(PATH.clone())
+            .add_code("EU")
+            .async_try_into()
+            .await
+            .unwrap()
+            .matches(&create_ip_state([193, 0, 0, 1])));
+    }
+
+    #[tokio::test]
+    async fn invert() {
+        let geoip = GeoIpBuilder::from_buf(PATH.clone())
+            .add_code("CN")
+            .add_code("cont:NA")
+            .invert(true)
+            .async_try_into()
+            .await
+            .unwrap();
+        assert!(!geoip.matches(&create_state(MESSAGE_CHINA.clone())));
+        assert!(!geoip.matches(&create_ip_state([8, 8, 8, 8])));
+        assert!(geoip.matches(&create_state(MESSAGE_NOT_CHINA.clone())));
+        // Private addresses have no data in the database.
+        assert!(geoip.matches(&create_ip_state([10, 0, 0, 1])));
+        assert!(!GeoIpBuilder::from_buf(PATH.clone())
+            .add_code("CN")
+            .async_try_into()
+            .await
+            .unwrap()
+            .matches(&create_ip_state([10, 0, 0, 1])));
+        // Yet there has to be an address to look up.
+        assert!(!geoip.matches(&create_state(
+            Message::from_octets(Bytes::from_static(&[0_u8; 55])).unwrap()
+        )));
+
+        let geoip = GeoIpBuilder::from_buf(ASN_PATH.clone())
+            .add_asn(13335)
+            .invert(true)
+            .async_try_into()
+            .await
+            .unwrap();
+        assert!(!geoip.matches(&create_state(MESSAGE_NOT_CHINA.clone())));
+        assert!(geoip.matches(&create_state(MESSAGE_CHINA.clone())));
+        assert!(geoip.matches(&create_ip_state([9, 9, 9, 9])));
+    }
+
+    fn create_src_state(src: Option<&str>) -> State {
+        State {
+            qctx: src.map(|s| QueryContext::new(s.parse::<SocketAddr>().unwrap(), Protocol::Udp)),
+            // Answer records should have no say in `src` mode.
+            resp: MESSAGE_CHINA.clone(),
+            query: MESSAGE_CHINA.clone(),
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn src_china() {
+        let geoip = GeoIpBuilder::from_buf(PATH.clone())
+            .mode(GeoIpMode::Src)
+            .add_code("CN")
+            .async_try_into()
+            .await
+            .unwrap();
+        assert!(geoip.matches(&create_src_state(Some("180.101.49.12:53"))));
+        assert!(!geoip.matches(&create_src_state(Some("1.1.1.1:53"))));
+    }
+
+    #[tokio::test]
+    async fn src_no_context() {
+        assert!(!GeoIpBuilder::from_buf(PATH.clone())
+            .mode(GeoIpMode::Src)
+            .add_code("CN")
+            .async_try_into()
+            .await
+            .unwrap()
+            .matches(&create_src_state(None)))
+    }
+
+    #[tokio::test]
+    async fn asn() {
+        let geoip = GeoIpBuilder::from_buf(ASN_PATH.clone())
+            .add_asn(13335)
+            .add_asn(15169)
+            .async_try_into()
+            .await
+            .unwrap();
+        assert!(geoip.matches(&create_state(MESSAGE_NOT_CHINA.clone())));
+        assert!(!geoip.matches(&create_state(MESSAGE_CHINA.clone())));
+    }
+
+    #[tokio::test]
+    async fn asn_src() {
+        let geoip = GeoIpBuilder::from_buf(ASN_PATH.clone())
+            .mode(GeoIpMode::Src)
+            .add_asn(4134)
+            .async_try_into()
+            .await
+            .unwrap();
+        assert!(geoip.matches(&create_src_state(Some("180.101.49.12:53"))));
+        // Not in the database at all
+        assert!(!geoip.matches(&create_src_state(Some("9.9.9.9:53"))));
+        assert!(!geoip.matches(&create_src_state(None)));
+    }
+
+    #[tokio::test]
+    async fn wrong_db() {
+        assert!(matches!(
             GeoIpBuilder::from_buf(PATH.clone())
+                .add_asn(13335)
+                .async_try_into()
+                .await,
+            Err(MatchError::GeoIpDbType(_))
+        ));
+        assert!(matches!(
+            GeoIpBuilder::from_buf(ASN_PATH.clone())
                 .add_code("CN")
                 .async_try_into()
-                .await
-                .unwrap()
-                .matches(&create_state(
-                    Message::from_octets(Bytes::from_static(&[0_u8; 55])).unwrap()
-                )),
-            false,
-        )
+                .await,
+            Err(MatchError::GeoIpDbType(_))
+        ));
     }
 
     #[tokio::test]
-    async fn is_china() {
-        assert_eq!(
-            GeoIpBuilder::from_buf(PATH.clone())
+    async fn mixed_targets() {
+        assert!(matches!(
+            GeoIpBuilder::from_buf(ASN_PATH.clone())
                 .add_code("CN")
+                .add_asn(13335)
                 .async_try_into()
-                .await
-                .unwrap()
-                .matches(&create_state(MESSAGE_CHINA.clone())),
-            true
-        )
+                .await,
+            Err(MatchError::GeoIpMixed)
+        ));
     }
 }
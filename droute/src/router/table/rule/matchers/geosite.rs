@@ -0,0 +1,326 @@
+// Copyright 2020 LEXUGE
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Loader of v2ray's `geosite.dat`, which is a `GeoSiteList` encoded in Protocol Buffers:
+//!
+//! ```protobuf
+//! message Domain {
+//!   enum Type { Plain = 0; Regex = 1; Domain = 2; Full = 3; }
+//!   message Attribute { string key = 1; oneof typed_value { bool bool_value = 2; int64 int_value = 3; } }
+//!   Type type = 1;
+//!   string value = 2;
+//!   repeated Attribute attribute = 3;
+//! }
+//! message GeoSite { string country_code = 1; repeated Domain domain = 2; }
+//! message GeoSiteList { repeated GeoSite entry = 1; }
+//! ```
+
+use super::{MatchError, Result};
+use bytes::Bytes;
+use dmatcher::domain::{to_ascii, Domain as DomainAlg};
+use domain::base::Dname;
+use log::{info, warn};
+use std::str::FromStr;
+
+// A value of a field on the wire.
+enum Value<'a> {
+    Varint(u64),
+    Bytes(&'a [u8]),
+    // Fixed-size values, which are not used by `geosite.dat`.
+    Fixed,
+}
+
+// A reader of the fields of a message.
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Result<&'a [u8]> {
+        if self.0.len() < n {
+            return Err(MatchError::Malformatted);
+        }
+        let (taken, rest) = self.0.split_at(n);
+        self.0 = rest;
+        Ok(taken)
+    }
+
+    fn varint(&mut self) -> Result<u64> {
+        let mut v = 0;
+        for shift in (0..64).step_by(7) {
+            let b = self.take(1)?[0];
+            v |= u64::from(b & 0x7f) << shift;
+            if b & 0x80 == 0 {
+                return Ok(v);
+            }
+        }
+        Err(MatchError::Malformatted)
+    }
+
+    // Read the number and the value of the next field.
+    fn field(&mut self) -> Result<Option<(u64, Value<'a>)>> {
+        if self.0.is_empty() {
+            return Ok(None);
+        }
+        let key = self.varint()?;
+        let value = match key & 7 {
+            0 => Value::Varint(self.varint()?),
+            1 => {
+                self.take(8)?;
+                Value::Fixed
+            }
+            2 => {
+                let len = usize::try_from(self.varint()?).map_err(|_| MatchError::Malformatted)?;
+                Value::Bytes(self.take(len)?)
+            }
+            5 => {
+                self.take(4)?;
+                Value::Fixed
+            }
+            _ => return Err(MatchError::Malformatted),
+        };
+        Ok(Some((key >> 3, value)))
+    }
+}
+
+// Entries to keep by one of their attributes.
+struct Filter<'a> {
+    attr: &'a str,
+    with: bool,
+}
+
+impl<'a> Filter<'a> {
+    // Parse a filter like `@cn` to keep the entries with the attribute `cn`, or `@!cn` to keep those without it. Empty filters keep everything.
+    fn parse(s: &'a str) -> Result<Option<Self>> {
+        if s.is_empty() {
+            return Ok(None);
+        }
+        let (attr, with) = match s.strip_prefix('@') {
+            Some(attr) => match attr.strip_prefix('!') {
+                Some(attr) => (attr, false),
+                None => (attr, true),
+            },
+            None => ("", true),
+        };
+        if attr.is_empty() {
+            return Err(MatchError::Other(format!(
+                "invalid geosite attribute filter `{}`, expecting the form of `@attr` or `@!attr`",
+                s
+            )));
+        }
+        Ok(Some(Self { attr, with }))
+    }
+
+    fn keeps(&self, attrs: &[&[u8]]) -> bool {
+        attrs
+            .iter()
+            .any(|a| a.eq_ignore_ascii_case(self.attr.as_bytes()))
+            == self.with
+    }
+}
+
+/// Load the domains of the category (case-insensitive, e.g. `cn`) in the `geosite.dat`, which are filtered by the attribute filter (e.g. `@cn` or `@!cn`) unless it is empty.
+/// `Domain` entries match the domain and its subdomains, while `Full` entries only match the domain itself. Keyword and regular expression entries are skipped.
+pub(super) fn load(data: &[u8], category: &str, filter: &str) -> Result<DomainAlg> {
+    let parsed = Filter::parse(filter)?;
+    let mut list = Reader(data);
+    while let Some((num, value)) = list.field()? {
+        let site = match (num, value) {
+            (1, Value::Bytes(site)) => site,
+            _ => continue,
+        };
+        let (mut code, mut domains) = (None, Vec::new());
+        let mut site = Reader(site);
+        while let Some((num, value)) = site.field()? {
+            match (num, value) {
+                (1, Value::Bytes(c)) => code = Some(c),
+                (2, Value::Bytes(d)) => domains.push(d),
+                _ => (),
+            }
+        }
+        if code.is_some_and(|c| c.eq_ignore_ascii_case(category.as_bytes())) {
+            let (matcher, filtered) = insert(&domains, category, parsed.as_ref())?;
+            if parsed.is_some() {
+                info!(
+                    "kept {} of {} entries of the geosite category `{}` by `{}`, {} filtered out",
+                    domains.len() - filtered,
+                    domains.len(),
+                    category,
+                    filter,
+                    filtered
+                );
+            }
+            return Ok(matcher);
+        }
+    }
+    Err(MatchError::Other(format!(
+        "category `{}` is not found in the geosite file",
+        category
+    )))
+}
+
+// Insert the entries kept by the filter, returning the number of those filtered out along with the matcher.
+fn insert(
+    domains: &[&[u8]],
+    category: &str,
+    filter: Option<&Filter>,
+) -> Result<(DomainAlg, usize)> {
+    let mut matcher = DomainAlg::new();
+    let (mut skipped, mut filtered) = (0, 0);
+    for d in domains {
+        let (mut kind, mut value, mut attrs) = (0, None, Vec::new());
+        let mut d = Reader(d);
+        while let Some((num, v)) = d.field()? {
+            match (num, v) {
+                (1, Value::Varint(k)) => kind = k,
+                (2, Value::Bytes(v)) => {
+                    value = Some(std::str::from_utf8(v).map_err(|_| MatchError::Malformatted)?)
+                }
+                (3, Value::Bytes(a)) => attrs.push(key(a)?),
+                _ => (),
+            }
+        }
+        if filter.is_some_and(|f| !f.keeps(&attrs)) {
+            filtered += 1;
+            continue;
+        }
+        let name = value
+            .and_then(to_ascii)
+            .and_then(|v| Dname::<Bytes>::from_str(&v).ok());
+        match (kind, name) {
+            (2, Some(name)) => matcher.insert(&name),
+            (3, Some(name)) => matcher.insert_exact(&name),
+            _ => skipped += 1,
+        }
+    }
+    if skipped > 0 {
+        warn!(
+            "skipped {} keyword, regular expression, or invalid entries of the geosite category `{}`",
+            skipped, category
+        );
+    }
+    Ok((matcher, filtered))
+}
+
+// The key of an attribute, e.g. `cn`.
+fn key(attr: &[u8]) -> Result<&[u8]> {
+    let mut key = &[][..];
+    let mut attr = Reader(attr);
+    while let Some((num, v)) = attr.field()? {
+        if let (1, Value::Bytes(k)) = (num, v) {
+            key = k;
+        }
+    }
+    Ok(key)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::load;
+    use domain::base::Dname;
+    use std::str::FromStr;
+
+    fn field(num: u8, value: &[u8]) -> Vec<u8> {
+        // Lengths used here always fit in a single byte varint.
+        assert!(value.len() < 0x80);
+        [&[num << 3 | 2, value.len() as u8][..], value].concat()
+    }
+
+    fn entry(kind: u8, value: &str) -> Vec<u8> {
+        [&[1 << 3, kind][..], &field(2, value.as_bytes())].concat()
+    }
+
+    // An entry with attributes like `@cn`, whose values are all `true`.
+    fn attributed(kind: u8, value: &str, attrs: &[&str]) -> Vec<u8> {
+        let mut entry = entry(kind, value);
+        for attr in attrs {
+            entry.extend(field(
+                3,
+                &[&field(1, attr.as_bytes())[..], &[2 << 3, 1]].concat(),
+            ));
+        }
+        entry
+    }
+
+    fn site(code: &str, entries: &[Vec<u8>]) -> Vec<u8> {
+        let mut site = field(1, code.as_bytes());
+        entries.iter().for_each(|e| site.extend(field(2, e)));
+        field(1, &site)
+    }
+
+    #[test]
+    fn categories() {
+        let data = [
+            site("GFW", &[entry(2, "google.com")]),
+            site(
+                "CN",
+                &[
+                    entry(2, "baidu.com"),
+                    entry(3, "www.qq.com"),
+                    entry(0, "taobao"),
+                    entry(1, "^.*\\.cn$"),
+                ],
+            ),
+        ]
+        .concat();
+
+        let matcher = load(&data, "cn", "").unwrap();
+        assert_eq!(matcher.len(), 2);
+        assert!(matcher.matches(&Dname::from_str("www.baidu.com").unwrap()));
+        assert!(matcher.matches(&Dname::from_str("www.qq.com").unwrap()));
+        assert!(!matcher.matches(&Dname::from_str("im.qq.com").unwrap()));
+        assert!(!matcher.matches(&Dname::from_str("google.com").unwrap()));
+        assert!(load(&data, "GFW", "")
+            .unwrap()
+            .matches(&Dname::from_str("google.com").unwrap()));
+
+        assert!(load(&data, "private", "").is_err());
+        assert!(load(&data[..data.len() - 1], "cn", "").is_err());
+    }
+
+    #[test]
+    fn attributes() {
+        let data = site(
+            "CATEGORY-ADS-ALL",
+            &[
+                attributed(2, "ads.baidu.com", &["cn"]),
+                attributed(3, "ad.qq.com", &["cn", "ads"]),
+                attributed(2, "doubleclick.net", &["ads"]),
+                entry(2, "adnxs.com"),
+            ],
+        );
+        let name = |s| Dname::from_str(s).unwrap();
+
+        let matcher = load(&data, "category-ads-all", "@cn").unwrap();
+        assert_eq!(matcher.len(), 2);
+        assert!(matcher.matches(&name("ads.baidu.com")));
+        assert!(matcher.matches(&name("ad.qq.com")));
+        assert!(!matcher.matches(&name("doubleclick.net")));
+        assert!(!matcher.matches(&name("adnxs.com")));
+
+        let matcher = load(&data, "category-ads-all", "@!cn").unwrap();
+        assert_eq!(matcher.len(), 2);
+        assert!(!matcher.matches(&name("ads.baidu.com")));
+        assert!(!matcher.matches(&name("ad.qq.com")));
+        assert!(matcher.matches(&name("doubleclick.net")));
+        assert!(matcher.matches(&name("adnxs.com")));
+
+        // Attributes are case-insensitive.
+        assert_eq!(load(&data, "category-ads-all", "@ADS").unwrap().len(), 2);
+        assert_eq!(load(&data, "category-ads-all", "@gfw").unwrap().len(), 0);
+        assert_eq!(load(&data, "category-ads-all", "").unwrap().len(), 4);
+        for filter in ["cn", "@", "@!"] {
+            assert!(load(&data, "category-ads-all", filter).is_err());
+        }
+    }
+}
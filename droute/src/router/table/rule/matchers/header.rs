@@ -22,24 +22,25 @@ use super::Matcher;
 // You should have received a copy of the GNU General Public License
 // along with this program.  If not, see <http://www.gnu.org/licenses/>.
 
-use serde::Deserialize;
+use serde::{Deserialize, Deserializer};
 
 #[derive(Deserialize, Clone, PartialEq, Eq, Hash, Debug)]
 #[serde(rename_all = "UPPERCASE")]
+/// Flags in the header
 pub enum HeaderBit {
-    // AA bit
+    /// Authoritative Answer
     AA,
-    // TC bit
+    /// Truncated, e.g. responses too large for UDP
     TC,
-    // RD bit
+    /// Recursion Desired
     RD,
-    // RA bit
+    /// Recursion Available
     RA,
-    // Z bit
+    /// Reserved
     Z,
-    // AD bit
+    /// Authentic Data, i.e. validated with DNSSEC
     AD,
-    // CD bit
+    /// Checking Disabled
     CD,
 }
 
@@ -60,7 +61,7 @@ impl HeaderBit {
 #[derive(Deserialize, Clone, PartialEq, Eq, Hash, Debug)]
 #[serde(rename_all = "UPPERCASE")]
 #[serde(remote = "Rcode")]
-enum RcodeDef {
+pub(crate) enum RcodeDef {
     NoError,
     FormErr,
     ServFail,
@@ -78,7 +79,7 @@ enum RcodeDef {
 #[derive(Deserialize, Clone, PartialEq, Eq, Hash, Debug)]
 #[serde(rename_all = "UPPERCASE")]
 #[serde(remote = "Opcode")]
-enum OpcodeDef {
+pub(super) enum OpcodeDef {
     Query,
     IQuery,
     Status,
@@ -110,22 +111,181 @@ impl HeaderCond {
     }
 }
 
-#[derive(Deserialize, Clone, PartialEq, Eq, Hash, Debug)]
+#[derive(Deserialize, Clone, Copy, PartialEq, Eq, Hash, Debug, Default)]
 #[serde(rename_all = "lowercase")]
+/// The message whose header is matched on
+pub enum HeaderSide {
+    /// The query received
+    Query,
+    /// The current response, which never matches before any action has set a response
+    #[default]
+    Resp,
+}
+
+#[derive(Deserialize, Clone, PartialEq, Eq, Hash, Debug)]
+#[serde(try_from = "HeaderDef")]
 /// Header matcher
 pub struct Header {
     /// Matching condition
     pub cond: HeaderCond,
-    /// Should we match on query msg?
-    pub query: bool,
+    /// The message to match on, the current response by default
+    pub side: HeaderSide,
+}
+
+// Header as written in configurations, which may still give the side as `query: bool`.
+#[derive(Deserialize)]
+struct HeaderDef {
+    cond: HeaderCond,
+    #[serde(default, deserialize_with = "present")]
+    side: Option<HeaderSide>,
+    #[serde(default, deserialize_with = "present")]
+    query: Option<bool>,
+}
+
+// RON would otherwise require `Some(..)` around the optional fields.
+fn present<'de, D: Deserializer<'de>, T: Deserialize<'de>>(
+    deserializer: D,
+) -> Result<Option<T>, D::Error> {
+    T::deserialize(deserializer).map(Some)
+}
+
+impl TryFrom<HeaderDef> for Header {
+    type Error = &'static str;
+
+    fn try_from(def: HeaderDef) -> Result<Self, Self::Error> {
+        let side = match (def.side, def.query) {
+            (Some(_), Some(_)) => return Err("`side` and `query` of `header` are exclusive"),
+            (Some(side), None) => side,
+            (None, Some(true)) => HeaderSide::Query,
+            (None, Some(false)) => HeaderSide::Resp,
+            (None, None) => HeaderSide::default(),
+        };
+        Ok(Self {
+            cond: def.cond,
+            side,
+        })
+    }
 }
 
 impl Matcher for Header {
     fn matches(&self, state: &State) -> bool {
-        if self.query {
-            self.cond.matches(&state.query.header())
-        } else {
-            self.cond.matches(&state.resp.header())
+        match self.side {
+            HeaderSide::Query => self.cond.matches(&state.query.header()),
+            // Before being answered, `resp` is merely the query echoed.
+            HeaderSide::Resp => state.answered && self.cond.matches(&state.resp.header()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        super::{Matcher, State},
+        Header, HeaderBit, HeaderCond, HeaderSide,
+    };
+    use crate::MAX_LEN;
+    use bytes::{Bytes, BytesMut};
+    use domain::base::{
+        iana::{Opcode, Rcode},
+        Dname, MessageBuilder, Rtype,
+    };
+    use std::str::FromStr;
+
+    const BITS: [HeaderBit; 4] = [HeaderBit::AA, HeaderBit::TC, HeaderBit::RA, HeaderBit::AD];
+
+    // Build a query with the CD bit set, and a response to it with only the bit given set.
+    fn create_state(bit: &HeaderBit) -> State {
+        let name = Dname::<Bytes>::from_str("example.com").unwrap();
+        let mut builder = MessageBuilder::from_target(BytesMut::with_capacity(MAX_LEN)).unwrap();
+        builder.header_mut().set_cd(true);
+        let mut builder = builder.question();
+        builder.push((&name, Rtype::A)).unwrap();
+        let query = builder.into_message();
+
+        let mut builder = MessageBuilder::from_target(BytesMut::with_capacity(MAX_LEN))
+            .unwrap()
+            .start_answer(&query, Rcode::NoError)
+            .unwrap();
+        let header = builder.header_mut();
+        header.set_cd(false);
+        match bit {
+            HeaderBit::AA => header.set_aa(true),
+            HeaderBit::TC => header.set_tc(true),
+            HeaderBit::RA => header.set_ra(true),
+            HeaderBit::AD => header.set_ad(true),
+            _ => unreachable!(),
+        }
+        let mut state = State {
+            query,
+            ..Default::default()
+        };
+        state.set_resp(builder.into_message());
+        state
+    }
+
+    fn matcher(cond: HeaderCond, side: HeaderSide) -> Header {
+        Header { cond, side }
+    }
+
+    #[test]
+    fn response_bits() {
+        for set in &BITS {
+            let state = create_state(set);
+            for bit in &BITS {
+                assert_eq!(
+                    matcher(HeaderCond::Bit(bit.clone()), HeaderSide::Resp).matches(&state),
+                    bit == set,
+                    "{:?} with {:?} set",
+                    bit,
+                    set
+                );
+                // None of them are set on the query
+                assert!(!matcher(HeaderCond::Bit(bit.clone()), HeaderSide::Query).matches(&state));
+            }
+        }
+    }
+
+    #[test]
+    fn sides() {
+        let state = create_state(&HeaderBit::TC);
+        assert!(matcher(HeaderCond::Bit(HeaderBit::CD), HeaderSide::Query).matches(&state));
+        assert!(!matcher(HeaderCond::Bit(HeaderBit::CD), HeaderSide::Resp).matches(&state));
+        assert!(!matcher(HeaderCond::Bit(HeaderBit::TC), HeaderSide::Query).matches(&state));
+        assert!(matcher(HeaderCond::Bit(HeaderBit::TC), HeaderSide::Resp).matches(&state));
+        assert!(matcher(HeaderCond::Opcode(Opcode::Query), HeaderSide::Query).matches(&state));
+        assert!(matcher(HeaderCond::Rcode(Rcode::NoError), HeaderSide::Resp).matches(&state));
+        assert!(!matcher(HeaderCond::Rcode(Rcode::ServFail), HeaderSide::Resp).matches(&state));
+
+        let state = create_state(&HeaderBit::RA);
+        assert!(!matcher(HeaderCond::Bit(HeaderBit::RA), HeaderSide::Query).matches(&state));
+        assert!(matcher(HeaderCond::Bit(HeaderBit::RA), HeaderSide::Resp).matches(&state));
+    }
+
+    #[test]
+    fn unanswered() {
+        let state = State {
+            query: create_state(&HeaderBit::AA).query,
+            ..Default::default()
+        };
+        assert!(matcher(HeaderCond::Bit(HeaderBit::CD), HeaderSide::Query).matches(&state));
+        // The response side never matches before a response is set.
+        assert!(!matcher(HeaderCond::Bit(HeaderBit::CD), HeaderSide::Resp).matches(&state));
+        assert!(!matcher(HeaderCond::Rcode(Rcode::NoError), HeaderSide::Resp).matches(&state));
+    }
+
+    #[test]
+    fn parse() {
+        for (ron, side) in [
+            ("(cond: bit(RD), side: query)", HeaderSide::Query),
+            ("(cond: bit(RD), side: resp)", HeaderSide::Resp),
+            ("(cond: bit(RD))", HeaderSide::Resp),
+            // `query: bool` of earlier configurations
+            ("(cond: bit(RD), query: true)", HeaderSide::Query),
+            ("(cond: bit(RD), query: false)", HeaderSide::Resp),
+        ] {
+            let header: Header = ron::from_str(ron).unwrap();
+            assert_eq!(header, matcher(HeaderCond::Bit(HeaderBit::RD), side));
         }
+        assert!(ron::from_str::<Header>("(cond: bit(RD), side: query, query: true)").is_err());
     }
 }
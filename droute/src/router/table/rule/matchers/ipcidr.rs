@@ -13,56 +13,259 @@
 // You should have received a copy of the GNU General Public License
 // along with this program.  If not, see <http://www.gnu.org/licenses/>.
 
-use super::{super::super::State, MatchError, Matcher, Result};
-use crate::AsyncTryInto;
+use super::{super::super::State, decompress, remote, MatchError, Matcher, Result};
+use crate::{preflight::Resource, AsyncTryInto};
+use arc_swap::ArcSwap;
 use async_trait::async_trait;
-use cidr_utils::{
-    cidr::{IpCidr as Cidr, IpCidrError},
-    utils::IpCidrCombiner as CidrCombiner,
-};
+use cidr_utils::cidr::{IpCidr as Cidr, IpCidrError};
+use dmatcher::ip::IpCidrs;
+use log::{info, warn};
 use serde::Deserialize;
+use std::{
+    io::Read,
+    net::IpAddr,
+    path::Path,
+    sync::{Arc, Weak},
+    time::Duration,
+};
+
+/// Where a list of IP CIDRs is loaded from.
+#[derive(Deserialize, Clone, Debug, PartialEq, Eq)]
+#[serde(untagged)]
+pub enum CidrSource {
+    /// Path to a local file
+    File(String),
+
+    /// An http(s) URL to download the list from
+    Remote {
+        /// URL of the list
+        url: String,
+        /// Path to store the list downloaded at, which is used instead if the list cannot be downloaded on building
+        cache: String,
+        /// Interval in seconds to download the list again. If not set, the list is only downloaded on building.
+        #[serde(default)]
+        refresh: Option<u64>,
+    },
+}
+
+// A list downloaded, which may be swapped for a newer one at any time.
+struct RemoteList {
+    url: String,
+    list: Arc<ArcSwap<List>>,
+}
+
+struct List {
+    matcher: IpCidrs,
+    entries: usize,
+}
+
+impl List {
+    fn parse(file: impl Read, source: &str) -> Result<Self> {
+        let mut matcher = IpCidrs::new();
+        let entries = IpCidr::push(file, source, &mut matcher)?;
+        Ok(Self { matcher, entries })
+    }
+
+    // Download the list and store it at the cache path once it is known to be valid.
+    async fn download(url: &str, cache: &str) -> Result<Self> {
+        let data = remote::download(url, None).await?;
+        let file = decompress::decompress(Box::new(data.as_ref()))?;
+        let list = Self::parse(file, url)?;
+        remote::store(Path::new(cache), &data).await?;
+        Ok(list)
+    }
+
+    // Download the list, or fall back to the cached one.
+    async fn fetch(url: &str, cache: &str) -> Result<Self> {
+        match Self::download(url, cache).await {
+            Ok(list) => Ok(list),
+            Err(e) if Path::new(cache).exists() => {
+                warn!(
+                    "failed to download IP CIDRs from `{}`, using the copy cached at `{}`: {}",
+                    url, cache, e
+                );
+                decompress::open(cache)
+                    .and_then(|file| Self::parse(file, cache))
+                    .map_err(MatchError::resource(cache))
+            }
+            Err(e) => Err(MatchError::resource(url)(e)),
+        }
+    }
+}
 
 /// A matcher that matches the IP on dst.
 pub struct IpCidr {
-    matcher: CidrCombiner,
+    matcher: IpCidrs,
+    remotes: Vec<RemoteList>,
+    resources: Vec<Resource>,
 }
 
 impl IpCidr {
-    /// Create a new `IpCidr` matcher from a list of files where each IP CIDR is seperated from one another by `\n`.
-    pub async fn new(path: Vec<String>) -> Result<Self> {
-        Ok({
-            let mut matcher = CidrCombiner::new();
-            for r in path {
-                let (mut file, _) = niffler::from_path(r)?;
-                let mut data = String::new();
-                file.read_to_string(&mut data)?;
-                // This gets rid of empty substrings for stability reasons. See also https://github.com/LEXUGE/dcompass/issues/33.
-                data.split('\n').filter(|&x| !x.is_empty()).try_for_each(
-                    |x| -> std::result::Result<(), IpCidrError> {
-                        matcher.push(Cidr::from_str(x)?);
-                        Ok(())
-                    },
-                )?;
+    /// Create a new `IpCidr` matcher from a list of sources where each IP CIDR is seperated from one another by `\n`.
+    /// Lists to be refreshed are downloaded again in the background until the matcher is dropped.
+    pub async fn new(sources: Vec<CidrSource>) -> Result<Self> {
+        let mut matcher = IpCidrs::new();
+        let mut remotes = Vec::new();
+        let mut resources = Vec::new();
+        for s in sources {
+            match s {
+                CidrSource::File(r) => {
+                    let entries = Self::load(&r, &mut matcher).map_err(MatchError::resource(&r))?;
+                    resources.push(Resource::new("ipcidr", r, entries));
+                }
+                CidrSource::Remote {
+                    url,
+                    cache,
+                    refresh,
+                } => {
+                    let interval = match refresh {
+                        Some(0) => {
+                            return Err(MatchError::Other(format!(
+                                "refresh interval of `{}` must be positive",
+                                url
+                            )))
+                        }
+                        r => r.map(Duration::from_secs),
+                    };
+                    let list = Arc::new(ArcSwap::from_pointee(List::fetch(&url, &cache).await?));
+                    info!("loaded {} IP CIDRs from `{}`", list.load().entries, url);
+                    if let Some(interval) = interval {
+                        tokio::spawn(Self::refresh(
+                            Arc::downgrade(&list),
+                            url.clone(),
+                            cache,
+                            interval,
+                        ));
+                    }
+                    remotes.push(RemoteList { url, list });
+                }
             }
-            Self { matcher }
+        }
+        Ok(Self {
+            matcher,
+            remotes,
+            resources,
         })
     }
+
+    // Download the list again every interval until the matcher is dropped. The previous list is kept on failures.
+    async fn refresh(list: Weak<ArcSwap<List>>, url: String, cache: String, interval: Duration) {
+        loop {
+            tokio::time::sleep(interval).await;
+            let list = if let Some(list) = list.upgrade() {
+                list
+            } else {
+                break;
+            };
+            Self::reload(&list, &url, &cache).await;
+        }
+    }
+
+    async fn reload(list: &ArcSwap<List>, url: &str, cache: &str) {
+        match List::download(url, cache).await {
+            Ok(new) => {
+                info!("refreshed {} IP CIDRs from `{}`", new.entries, url);
+                list.store(Arc::new(new));
+            }
+            Err(e) => warn!(
+                "failed to refresh IP CIDRs from `{}`, keeping the previous list: {}",
+                url, e
+            ),
+        }
+    }
+
+    // Push the IP CIDRs in the file to the matcher, returning the number of them.
+    fn load(path: &str, matcher: &mut IpCidrs) -> Result<usize> {
+        Self::push(decompress::open(path)?, path, matcher)
+    }
+
+    // Comments from `#` or `;` to the end of the line and blank lines are skipped, and so are invalid lines with a warning.
+    // The list fails if none of the lines is valid, even if there is no invalid line either, as an empty list is most likely a mistake.
+    fn push(mut file: impl Read, source: &str, matcher: &mut IpCidrs) -> Result<usize> {
+        let mut data = String::new();
+        file.read_to_string(&mut data)?;
+        let mut entries = 0;
+        // Number of invalid lines, and the first of them
+        let mut invalid = 0;
+        let mut first_invalid = None;
+        // Lines may end with `\r\n`, and the last one may have no ending at all. See also https://github.com/LEXUGE/dcompass/issues/33.
+        for (n, line) in data.lines().enumerate() {
+            let line = line.split(['#', ';']).next().unwrap().trim();
+            if line.is_empty() {
+                continue;
+            }
+            match Self::insert(line, matcher) {
+                Ok(()) => entries += 1,
+                Err(e) => {
+                    invalid += 1;
+                    first_invalid.get_or_insert((n + 1, line.to_string(), e));
+                }
+            }
+        }
+        match first_invalid {
+            Some((n, line, e)) if entries == 0 => Err(MatchError::NoValidCidr(n, line, e)),
+            Some((n, line, e)) => {
+                warn!(
+                    "skipped {} invalid IP CIDRs in `{}`, loaded {} valid ones. The first invalid one is `{}` at line {}: {}",
+                    invalid, source, entries, line, n, e
+                );
+                Ok(entries)
+            }
+            None if entries == 0 => Err(MatchError::NoCidr(source.to_string())),
+            None => Ok(entries),
+        }
+    }
+
+    // Insert an IP CIDR, or a bare IP address.
+    pub(super) fn insert(
+        line: &str,
+        matcher: &mut IpCidrs,
+    ) -> std::result::Result<(), IpCidrError> {
+        // A bare IP address is the CIDR of itself alone.
+        match line.parse::<IpAddr>() {
+            Ok(IpAddr::V4(addr)) => matcher.insert_v4(addr, 32),
+            Ok(IpAddr::V6(addr)) => matcher.insert_v6(addr, 128),
+            Err(_) => match Cidr::from_str(line)? {
+                Cidr::V4(cidr) => {
+                    matcher.insert_v4(cidr.get_prefix_as_ipv4_addr(), cidr.get_bits())
+                }
+                Cidr::V6(cidr) => {
+                    matcher.insert_v6(cidr.get_prefix_as_ipv6_addr(), cidr.get_bits())
+                }
+            },
+        }
+        Ok(())
+    }
 }
 
 impl Matcher for IpCidr {
     fn matches(&self, state: &State) -> bool {
         if let Ok(Some(ip)) = state.resp_ip() {
             self.matcher.contains(ip)
+                || self
+                    .remotes
+                    .iter()
+                    .any(|r| r.list.load().matcher.contains(ip))
         } else {
             false
         }
     }
+
+    fn resources(&self) -> Vec<Resource> {
+        let mut resources = self.resources.clone();
+        resources.extend(
+            self.remotes
+                .iter()
+                .map(|r| Resource::new("ipcidr", &r.url, r.list.load().entries)),
+        );
+        resources
+    }
 }
 
 /// A builder for IpCidr matcher plugin
 #[derive(Deserialize, Clone, Debug, PartialEq, Eq)]
 #[serde(transparent)]
-pub struct IpCidrBuilder(Vec<String>);
+pub struct IpCidrBuilder(Vec<CidrSource>);
 
 impl Default for IpCidrBuilder {
     fn default() -> Self {
@@ -78,7 +281,22 @@ impl IpCidrBuilder {
 
     /// Add a file of IP CIDR addresses to the matcher builder
     pub fn add_file(mut self, s: impl ToString) -> Self {
-        self.0.push(s.to_string());
+        self.0.push(CidrSource::File(s.to_string()));
+        self
+    }
+
+    /// Add a list of IP CIDR addresses to download from an http(s) URL, which is stored at the cache path. If `refresh` is set, the list is downloaded again every `refresh` seconds.
+    pub fn add_remote(
+        mut self,
+        url: impl ToString,
+        cache: impl ToString,
+        refresh: Option<u64>,
+    ) -> Self {
+        self.0.push(CidrSource::Remote {
+            url: url.to_string(),
+            cache: cache.to_string(),
+            refresh,
+        });
         self
     }
 }
@@ -98,7 +316,8 @@ mod tests {
 
     use super::{
         super::{Matcher, State},
-        IpCidrBuilder,
+        remote::tests::serve,
+        IpCidr, IpCidrBuilder, List, MatchError,
     };
     use bytes::{Bytes, BytesMut};
     use domain::{
@@ -106,7 +325,7 @@ mod tests {
         rdata::A,
     };
     use once_cell::sync::Lazy;
-    use std::str::FromStr;
+    use std::{path::PathBuf, str::FromStr};
 
     static MESSAGE_NOT_CHINA: Lazy<Message<Bytes>> = Lazy::new(|| {
         let name = Dname::<Bytes>::from_str("cloudflare-dns.com").unwrap();
@@ -133,7 +352,7 @@ mod tests {
         State {
             resp: m.clone(),
             query: m,
-            qctx: None,
+            ..Default::default()
         }
     }
 
@@ -147,6 +366,17 @@ mod tests {
             .unwrap();
     }
 
+    #[cfg(feature = "zstd")]
+    #[tokio::test]
+    async fn zstd() {
+        let matcher = IpCidrBuilder::new()
+            .add_file("../data/ipcidr-test.txt.zst")
+            .async_try_into()
+            .await
+            .unwrap();
+        assert_eq!(matcher.resources()[0].entries, 1);
+    }
+
     #[tokio::test]
     async fn test() {
         let matcher = IpCidrBuilder::new()
@@ -154,13 +384,155 @@ mod tests {
             .async_try_into()
             .await
             .unwrap();
+        assert!(matcher.matches(&create_state((*MESSAGE_CHINA).clone())));
+        assert!(!matcher.matches(&create_state((*MESSAGE_NOT_CHINA).clone())))
+    }
+
+    fn cache_path(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("droute-ipcidr-{}", std::process::id()));
+        let path = dir.join(name);
+        let _ = std::fs::remove_file(&path);
+        path
+    }
+
+    #[tokio::test]
+    async fn remote() {
+        let url = serve(vec!["180.101.49.0/24\n"]).await + "/ipcn.txt";
+        let cache = cache_path("remote.txt");
+        let matcher = IpCidrBuilder::new()
+            .add_remote(&url, cache.display(), None)
+            .async_try_into()
+            .await
+            .unwrap();
+        assert!(matcher.matches(&create_state((*MESSAGE_CHINA).clone())));
+        assert!(!matcher.matches(&create_state((*MESSAGE_NOT_CHINA).clone())));
+        assert_eq!(matcher.resources()[0].entries, 1);
         assert_eq!(
-            matcher.matches(&create_state((*MESSAGE_CHINA).clone())),
-            true
+            std::fs::read_to_string(&cache).unwrap(),
+            "180.101.49.0/24\n"
         );
+
+        // The server fails now, so the cached copy is used.
+        let matcher = IpCidrBuilder::new()
+            .add_remote(&url, cache.display(), None)
+            .async_try_into()
+            .await
+            .unwrap();
+        assert!(matcher.matches(&create_state((*MESSAGE_CHINA).clone())));
+
+        // Nothing is cached to fall back to.
+        assert!(IpCidrBuilder::new()
+            .add_remote(&url, cache_path("none.txt").display(), None)
+            .async_try_into()
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn refresh() {
+        let url =
+            serve(vec!["1.1.1.0/24\n", "180.101.49.0/24\n", "malformatted\n"]).await + "/ipcn.txt";
+        let cache = cache_path("refresh.txt");
+        let matcher = IpCidrBuilder::new()
+            .add_file("../data/ipcidr-test.txt")
+            .add_remote(&url, cache.display(), Some(3600))
+            .async_try_into()
+            .await
+            .unwrap();
+        assert!(matcher.matches(&create_state((*MESSAGE_NOT_CHINA).clone())));
+        assert!(!matcher.matches(&create_state((*MESSAGE_CHINA).clone())));
+
+        let cache = cache.display().to_string();
+        let list = &matcher.remotes[0].list;
+        IpCidr::reload(list, &url, &cache).await;
+        assert!(matcher.matches(&create_state((*MESSAGE_CHINA).clone())));
+        assert!(!matcher.matches(&create_state((*MESSAGE_NOT_CHINA).clone())));
+
+        // Neither a malformatted list nor a failed download replaces the list.
+        IpCidr::reload(list, &url, &cache).await;
+        IpCidr::reload(list, &url, &cache).await;
+        assert!(matcher.matches(&create_state((*MESSAGE_CHINA).clone())));
         assert_eq!(
-            matcher.matches(&create_state((*MESSAGE_NOT_CHINA).clone())),
-            false
+            std::fs::read_to_string(&cache).unwrap(),
+            "180.101.49.0/24\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn messy() {
+        let matcher = IpCidrBuilder::new()
+            .add_file("../data/ipcidr-messy.txt")
+            .async_try_into()
+            .await
+            .unwrap();
+        // Comments, blank lines, and the invalid lines are skipped.
+        assert_eq!(matcher.resources()[0].entries, 4);
+        assert!(matcher.matches(&create_state((*MESSAGE_CHINA).clone())));
+        assert!(matcher.matches(&create_state((*MESSAGE_NOT_CHINA).clone())));
+        // Lists with nothing but comments and blank lines are not valid either.
+        assert!(matches!(
+            IpCidrBuilder::new()
+                .add_file("../data/ipcidr-comments.txt")
+                .async_try_into()
+                .await,
+            Err(MatchError::Resource(_, e)) if matches!(*e, MatchError::NoCidr(_))
+        ));
+
+        let list = List::parse(
+            &b"1.1.1.1\r\n2001:db8::1 ; host\r\n2400:da00::/32\r\n10.0.0.0/40"[..],
+            "crlf",
+        )
+        .unwrap();
+        assert_eq!(list.entries, 3);
+        let contains = |ip: &str| list.matcher.contains(ip.parse().unwrap());
+        // Bare IP addresses are the only ones in their CIDRs.
+        assert!(contains("1.1.1.1"));
+        assert!(!contains("1.1.1.2"));
+        assert!(contains("2001:db8::1"));
+        assert!(!contains("2001:db8::2"));
+        assert!(contains("2400:da00::1"));
+        assert!(!contains("10.0.0.1"));
+    }
+
+    #[test]
+    fn invalid() {
+        assert!(matches!(
+            List::parse(&b"# nothing valid\n\nnot-an-ip\n300.1.1.1/8\n"[..], "invalid"),
+            Err(MatchError::NoValidCidr(3, line, _)) if line == "not-an-ip"
+        ));
+        // Nor are lists with nothing but comments and blank lines.
+        assert!(matches!(
+            List::parse(&b"# nothing yet\r\n; still nothing\r\n   \r\n"[..], "empty"),
+            Err(MatchError::NoCidr(source)) if source == "empty"
+        ));
+        assert!(matches!(
+            List::parse(&b""[..], "empty"),
+            Err(MatchError::NoCidr(_))
+        ));
+    }
+
+    #[test]
+    fn prefixes() {
+        let list = List::parse(
+            &b"180.101.49.12/32\n2001:db8::1/128\n2400:da00::/33\n2400:da00::/33\n0.0.0.0/0\n"[..],
+            "prefixes",
         )
+        .unwrap();
+        assert_eq!(list.entries, 5);
+        let contains = |ip: &str| list.matcher.contains(ip.parse().unwrap());
+        assert!(contains("1.1.1.1"));
+        assert!(contains("2001:db8::1"));
+        assert!(!contains("2001:db8::2"));
+        assert!(contains("2400:da00:7fff::1"));
+        assert!(!contains("2400:da00:8000::"));
+    }
+
+    #[tokio::test]
+    async fn zero_refresh() {
+        assert!(IpCidrBuilder::new()
+            .add_remote("http://127.0.0.1/ipcn.txt", "ipcn.txt", Some(0))
+            .async_try_into()
+            .await
+            .is_err());
     }
 }
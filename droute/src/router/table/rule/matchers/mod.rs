@@ -13,29 +13,86 @@
 // You should have received a copy of the GNU General Public License
 // along with this program.  If not, see <http://www.gnu.org/licenses/>.
 
+mod answer_count;
+mod answered_by;
 /// Builders for built-in matchers and more.
 pub mod builder;
+mod cached;
+mod client_rate;
+mod cname_chain;
+mod cookie;
+mod decompress;
 mod domain;
+mod duplicate;
+mod ecs;
+mod edns;
+mod empty_answer;
 pub(crate) mod expr;
+mod full_qname;
 #[cfg(feature = "geoip")]
 mod geoip;
+#[cfg(feature = "geosite")]
+mod geosite;
 mod header;
 mod ipcidr;
+mod opcode;
+mod ptr_addr;
+mod qclass;
+mod qname_contains;
+#[cfg(feature = "regex")]
+mod qname_regex;
 mod qtype;
+mod random;
+mod rcode;
+mod remote;
+mod resp_rtype;
+mod resp_source;
+mod resp_ttl;
+mod schedule;
+mod src_ip;
+mod transport;
 
 #[cfg(feature = "geoip")]
-pub use self::geoip::GeoIp;
+pub use self::geoip::{GeoIp, GeoIpMode};
+#[cfg(feature = "regex")]
+pub use self::qname_regex::QNameRegex;
 pub use self::{
-    domain::{Domain, ResourceType},
-    header::{Header, HeaderCond},
-    ipcidr::IpCidr,
+    answer_count::AnswerCount,
+    answered_by::AnsweredBy,
+    cached::Cached,
+    client_rate::ClientRate,
+    cname_chain::CnameChain,
+    cookie::Cookie,
+    domain::{Domain, RemoteFormat, RemoteOptions, ResourceType},
+    duplicate::Duplicate,
+    ecs::Ecs,
+    edns::{Edns, EdnsCond},
+    empty_answer::EmptyAnswer,
+    full_qname::{FullQname, FullQnameResource},
+    header::{Header, HeaderBit, HeaderCond, HeaderSide},
+    ipcidr::{CidrSource, IpCidr},
+    opcode::OpCode,
+    ptr_addr::PtrAddr,
+    qclass::QClass,
+    qname_contains::QNameContains,
     qtype::QType,
+    random::Random,
+    rcode::RCode,
+    resp_rtype::{RespRType, RespRTypeMode},
+    resp_source::{RespSource, SourceCond},
+    resp_ttl::RespTtl,
+    schedule::{Day, Schedule, Window},
+    src_ip::SrcIp,
+    transport::Transport,
 };
+// Response codes as written in configurations, which actions take as well.
+pub(crate) use self::header::RcodeDef;
 use super::super::State;
+use crate::preflight::Resource;
 use ::domain::base::{name::FromStrError, octets::ParseError};
 #[cfg(feature = "geoip")]
 use maxminddb::MaxMindDBError;
-use std::fmt::Debug;
+use std::{fmt::Debug, path::PathBuf};
 use thiserror::Error;
 
 /// A shorthand for returning action error.
@@ -57,19 +114,63 @@ pub enum MatchError {
     #[error("An error encountered in the IP CIDR matcher.")]
     IpCidrError(#[from] cidr_utils::cidr::IpCidrError),
 
+    /// Malformed regular expression provided to the `qname_regex` matcher.
+    #[cfg(feature = "regex")]
+    #[error("Invalid regular expression `{0}`: {1}")]
+    RegexError(String, #[source] regex::Error),
+
+    /// None of the lines of an IP CIDR list is valid, with the first invalid one and its line number.
+    #[error("None of the IP CIDRs in the list is valid. The first invalid one is `{1}` at line {0}: {2}")]
+    NoValidCidr(usize, String, #[source] cidr_utils::cidr::IpCidrError),
+
+    /// An IP CIDR list has no entry at all, e.g. it has nothing but comments, with where it is loaded from.
+    #[error("No IP CIDR is found in `{0}`")]
+    NoCidr(String),
+
     /// Malformatted file provided to a matcher.
     #[error("File provided for matcher(s) is malformatted.")]
     Malformatted,
 
+    /// Malformed domain provided to a `qname` resource.
+    #[error("Malformed domain `{0}` in `qname`")]
+    InvalidDomain(String),
+
     /// No path to GeoIP database specified while no builtin database is provided.
     #[cfg(feature = "geoip")]
     #[error("This build doesn't contain a built-in GeoIP database, please specify your own database or use other builds.")]
     NoBuiltInDb,
 
+    /// Database provided to the `geoip` matcher doesn't fit what it matches on.
+    #[cfg(feature = "geoip")]
+    #[error("The database provided to `geoip` matcher is of type `{0}`. Matching on AS numbers requires an ASN database, while matching on country codes requires a Country or City database.")]
+    GeoIpDbType(String),
+
+    /// Both country codes and AS numbers provided to one `geoip` matcher.
+    #[cfg(feature = "geoip")]
+    #[error("A `geoip` matcher can match on either country codes or AS numbers but not both, as they require different databases. Use two `geoip` matchers instead.")]
+    GeoIpMixed,
+
+    /// Failed to download a list.
+    #[error("Failed to download: {0}")]
+    ReqwestError(#[from] reqwest::Error),
+
+    /// URL provided to download a list from is invalid.
+    #[error("The URL `{0}` is not a valid http(s) URL")]
+    InvalidUrl(String),
+
+    /// Failed to watch the files for changes.
+    #[cfg(feature = "watch")]
+    #[error("Failed to watch files: {0}")]
+    WatchError(#[from] notify::Error),
+
     /// Compression error
     #[error("Error encountered during decompression")]
     DecompError(#[from] niffler::Error),
 
+    /// List compressed in a format this build doesn't support.
+    #[error("The list is compressed with {0}, which this build doesn't support. Rebuild with the `{0}` feature enabled.")]
+    UnsupportedCompression(&'static str),
+
     /// Other error.
     #[error("An error encountered in matcher: {0}")]
     Other(String),
@@ -81,10 +182,25 @@ pub enum MatchError {
     /// Failed to parse the record
     #[error(transparent)]
     ParseError(#[from] ParseError),
+
+    /// Failed to load the resource at the path.
+    #[error("Failed to load `{0}`: {1}")]
+    Resource(PathBuf, #[source] Box<MatchError>),
+}
+
+impl MatchError {
+    pub(crate) fn resource(path: impl Into<PathBuf>) -> impl FnOnce(MatchError) -> Self {
+        move |e| Self::Resource(path.into(), Box::new(e))
+    }
 }
 
 /// A matcher determines if something matches or not given the current state.
 pub trait Matcher: Sync + Send {
     /// Determine if match.
     fn matches(&self, state: &State) -> bool;
+
+    /// Resources loaded by this matcher, e.g. files of lists. Default to none.
+    fn resources(&self) -> Vec<Resource> {
+        Vec::new()
+    }
 }
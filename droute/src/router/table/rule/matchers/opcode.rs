@@ -0,0 +1,120 @@
+// Copyright 2020 LEXUGE
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use super::{super::super::State, header::OpcodeDef, MatchError, Matcher, Result};
+use crate::AsyncTryInto;
+use async_trait::async_trait;
+use domain::base::iana::Opcode;
+use serde::Deserialize;
+use std::collections::HashSet;
+
+/// A matcher that matches if the opcode of the query is any of the ones provided.
+pub struct OpCode(HashSet<Opcode>);
+
+impl OpCode {
+    /// Create a new `OpCode` matcher.
+    pub fn new(opcodes: HashSet<Opcode>) -> Result<Self> {
+        Ok(Self(opcodes))
+    }
+}
+
+impl Matcher for OpCode {
+    fn matches(&self, state: &State) -> bool {
+        self.0.contains(&state.query.header().opcode())
+    }
+}
+
+#[derive(Deserialize, Clone, PartialEq, Eq, Hash, Debug)]
+#[serde(transparent)]
+struct Adaptor(#[serde(with = "OpcodeDef")] Opcode);
+
+/// A builder for opcode matcher plugin
+#[derive(Deserialize, Clone, Debug, PartialEq, Eq)]
+#[serde(transparent)]
+pub struct OpCodeBuilder(HashSet<Adaptor>);
+
+impl Default for OpCodeBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl OpCodeBuilder {
+    /// Create an empty builder
+    pub fn new() -> Self {
+        Self(HashSet::new())
+    }
+
+    /// Add an opcode to match
+    pub fn add_opcode(mut self, opcode: Opcode) -> Self {
+        self.0.insert(Adaptor(opcode));
+        self
+    }
+}
+
+#[async_trait]
+impl AsyncTryInto<OpCode> for OpCodeBuilder {
+    type Error = MatchError;
+
+    async fn async_try_into(self) -> Result<OpCode> {
+        OpCode::new(self.0.iter().map(|x| x.0).collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        super::{Matcher, State},
+        OpCodeBuilder,
+    };
+    use crate::{AsyncTryInto, MAX_LEN};
+    use bytes::BytesMut;
+    use domain::base::{iana::Opcode, MessageBuilder};
+
+    fn create_state(opcode: Opcode) -> State {
+        let mut builder = MessageBuilder::from_target(BytesMut::with_capacity(MAX_LEN)).unwrap();
+        builder.header_mut().set_opcode(opcode);
+        State {
+            query: builder.into_message(),
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn test() {
+        let matcher = OpCodeBuilder::new()
+            .add_opcode(Opcode::Notify)
+            .add_opcode(Opcode::Update)
+            .async_try_into()
+            .await
+            .unwrap();
+        assert!(matcher.matches(&create_state(Opcode::Notify)));
+        assert!(matcher.matches(&create_state(Opcode::Update)));
+        assert!(!matcher.matches(&create_state(Opcode::Query)));
+        assert!(!matcher.matches(&create_state(Opcode::Status)));
+    }
+
+    #[test]
+    fn parse() {
+        assert_eq!(
+            ron::from_str::<OpCodeBuilder>("[NOTIFY, UPDATE, INT(7)]").unwrap(),
+            OpCodeBuilder::new()
+                .add_opcode(Opcode::Notify)
+                .add_opcode(Opcode::Update)
+                .add_opcode(Opcode::Int(7))
+        );
+        assert!(ron::from_str::<OpCodeBuilder>("[NOTIFY, update]").is_err());
+    }
+}
@@ -0,0 +1,222 @@
+// Copyright 2020 LEXUGE
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use super::{super::super::State, ipcidr::IpCidr, MatchError, Matcher, Result};
+use crate::AsyncTryInto;
+use async_trait::async_trait;
+use dmatcher::ip::IpCidrs;
+use serde::Deserialize;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+// The address a reverse lookup name stands for, if it is a whole one in `in-addr.arpa` or `ip6.arpa`.
+// Partial names like `168.192.in-addr.arpa` and classless delegations stand for no single address.
+fn decode(qname: &str) -> Option<IpAddr> {
+    let qname = qname.trim_end_matches('.').to_ascii_lowercase();
+    if let Some(labels) = qname.strip_suffix(".in-addr.arpa") {
+        let mut labels = labels.split('.');
+        let mut octets = [0u8; 4];
+        // The last octet comes first.
+        for octet in octets.iter_mut().rev() {
+            let label = labels.next()?;
+            // Only decimals without any sign or leading zero
+            *octet = label.parse().ok().filter(|o: &u8| o.to_string() == label)?;
+        }
+        labels
+            .next()
+            .is_none()
+            .then(|| IpAddr::V4(Ipv4Addr::from(octets)))
+    } else if let Some(labels) = qname.strip_suffix(".ip6.arpa") {
+        let nibbles: Vec<&str> = labels.split('.').collect();
+        if nibbles.len() != 32 {
+            return None;
+        }
+        let mut addr = 0u128;
+        // The lowest nibble comes first.
+        for (i, nibble) in nibbles.into_iter().enumerate() {
+            if nibble.len() != 1 {
+                return None;
+            }
+            addr |= u128::from(u8::from_str_radix(nibble, 16).ok()?) << (4 * i);
+        }
+        Some(IpAddr::V6(Ipv6Addr::from(addr)))
+    } else {
+        None
+    }
+}
+
+/// A matcher that matches reverse lookups, i.e. queries of names in `in-addr.arpa` or `ip6.arpa`, of the addresses in the list of IP CIDR.
+/// Names that don't stand for a whole address never match.
+pub struct PtrAddr {
+    matcher: IpCidrs,
+}
+
+impl PtrAddr {
+    /// Create a new `PtrAddr` matcher from a list of IP CIDRs, or bare IP addresses.
+    pub fn new(cidrs: Vec<String>) -> Result<Self> {
+        let mut matcher = IpCidrs::new();
+        for c in cidrs {
+            IpCidr::insert(&c, &mut matcher)?;
+        }
+        Ok(Self { matcher })
+    }
+}
+
+impl Matcher for PtrAddr {
+    fn matches(&self, state: &State) -> bool {
+        decode(&state.query.first_question().unwrap().qname().to_string())
+            .is_some_and(|ip| self.matcher.contains(ip))
+    }
+}
+
+/// A builder for PtrAddr matcher plugin
+#[derive(Deserialize, Clone, Debug, PartialEq, Eq)]
+#[serde(transparent)]
+pub struct PtrAddrBuilder(Vec<String>);
+
+impl Default for PtrAddrBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl PtrAddrBuilder {
+    /// Create an empty builder
+    pub fn new() -> Self {
+        PtrAddrBuilder(Vec::new())
+    }
+
+    /// Add an IP CIDR like `192.168.0.0/16` to the matcher builder
+    pub fn add_cidr(mut self, s: impl ToString) -> Self {
+        self.0.push(s.to_string());
+        self
+    }
+}
+
+#[async_trait]
+impl AsyncTryInto<PtrAddr> for PtrAddrBuilder {
+    type Error = MatchError;
+
+    async fn async_try_into(self) -> Result<PtrAddr> {
+        PtrAddr::new(self.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        super::{Matcher, State},
+        decode, PtrAddrBuilder,
+    };
+    use crate::{AsyncTryInto, MAX_LEN};
+    use bytes::{Bytes, BytesMut};
+    use domain::base::{Dname, MessageBuilder, Rtype};
+    use std::str::FromStr;
+
+    fn create_state(name: &str) -> State {
+        let name = Dname::<Bytes>::from_str(name).unwrap();
+        let mut builder = MessageBuilder::from_target(BytesMut::with_capacity(MAX_LEN))
+            .unwrap()
+            .question();
+        builder.push((&name, Rtype::Ptr)).unwrap();
+        State {
+            query: builder.into_message(),
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn ipv4() {
+        let matcher = PtrAddrBuilder::new()
+            .add_cidr("192.168.0.0/16")
+            .add_cidr("10.0.0.1")
+            .async_try_into()
+            .await
+            .unwrap();
+        assert!(matcher.matches(&create_state("4.3.168.192.in-addr.arpa")));
+        assert!(matcher.matches(&create_state("1.0.0.10.IN-ADDR.ARPA.")));
+        assert!(!matcher.matches(&create_state("2.0.0.10.in-addr.arpa")));
+        assert!(!matcher.matches(&create_state("4.3.2.1.in-addr.arpa")));
+        // Not a reverse lookup at all
+        assert!(!matcher.matches(&create_state("192.168.3.4")));
+    }
+
+    #[tokio::test]
+    async fn ipv6() {
+        let matcher = PtrAddrBuilder::new()
+            .add_cidr("fd00::/8")
+            .async_try_into()
+            .await
+            .unwrap();
+        let name = "b.a.9.8.7.6.5.0.4.0.0.0.3.0.0.0.2.0.0.0.1.0.0.0.0.0.0.0.1.2.d.f.ip6.arpa";
+        assert_eq!(
+            decode(name),
+            Some("fd21:0:1:2:3:4:567:89ab".parse().unwrap())
+        );
+        assert!(matcher.matches(&create_state(name)));
+        assert!(!matcher.matches(&create_state(
+            "1.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.8.b.d.0.1.0.0.2.ip6.arpa"
+        )));
+        assert_eq!(
+            decode("1.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.8.B.D.0.1.0.0.2.ip6.arpa."),
+            Some("2001:db8::1".parse().unwrap())
+        );
+    }
+
+    #[tokio::test]
+    async fn garbage() {
+        let matcher = PtrAddrBuilder::new()
+            .add_cidr("0.0.0.0/0")
+            .add_cidr("::/0")
+            .async_try_into()
+            .await
+            .unwrap();
+        for name in [
+            "4.3.2.1.in-addr.arpa",
+            "1.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.8.b.d.0.1.0.0.2.ip6.arpa",
+        ] {
+            assert!(matcher.matches(&create_state(name)));
+        }
+        assert!(!matcher.matches(&create_state("168.192.in-addr.arpa")));
+        for name in [
+            // Partial names
+            "168.192.in-addr.arpa",
+            "in-addr.arpa",
+            "8.b.d.0.1.0.0.2.ip6.arpa",
+            // Too many labels
+            "5.4.3.2.1.in-addr.arpa",
+            // Octets out of range, signed, or with leading zeros
+            "256.3.2.1.in-addr.arpa",
+            "+4.3.2.1.in-addr.arpa",
+            "04.3.2.1.in-addr.arpa",
+            // A classless delegation
+            "4.0/25.2.1.in-addr.arpa",
+            // Nibbles that are not single hex digits
+            "g.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.8.b.d.0.1.0.0.2.ip6.arpa",
+            "10.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.8.b.d.0.1.0.0.2.ip6.arpa",
+            "4.3.2.1.in-addr.arpa.example.com",
+        ] {
+            assert_eq!(decode(name), None, "{}", name);
+        }
+    }
+
+    #[tokio::test]
+    async fn invalid() {
+        assert!(PtrAddrBuilder::new()
+            .add_cidr("192.168.0.0/33")
+            .async_try_into()
+            .await
+            .is_err());
+    }
+}
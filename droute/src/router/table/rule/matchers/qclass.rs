@@ -0,0 +1,147 @@
+// Copyright 2020 LEXUGE
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use super::{super::super::State, MatchError, Matcher, Result};
+use crate::AsyncTryInto;
+use async_trait::async_trait;
+use domain::base::iana::Class;
+use serde::Deserialize;
+use std::collections::HashSet;
+
+/// A matcher that matches if first query is of any of the classes provided.
+pub struct QClass(HashSet<Class>);
+
+impl QClass {
+    /// Create a new `QClass` matcher.
+    pub fn new(classes: HashSet<Class>) -> Result<Self> {
+        // Normalize classes given by number like `INT(3)` to the well-known ones parsed from the wire.
+        Ok(Self(
+            classes
+                .into_iter()
+                .map(|c| Class::from_int(c.to_int()))
+                .collect(),
+        ))
+    }
+}
+
+impl Matcher for QClass {
+    fn matches(&self, state: &State) -> bool {
+        self.0
+            .contains(&state.query.first_question().unwrap().qclass())
+    }
+}
+
+// Only used to drive the deserialization of `Class`, fields are never read directly.
+#[allow(dead_code)]
+#[derive(Deserialize, Clone)]
+#[serde(rename_all = "UPPERCASE")]
+#[serde(remote = "Class")]
+enum ClassDef {
+    In,
+    Ch,
+    Hs,
+    None,
+    Any,
+    Int(u16),
+}
+
+#[derive(Deserialize, Clone, PartialEq, Eq, Hash, Debug)]
+#[serde(transparent)]
+struct Adaptor(#[serde(with = "ClassDef")] Class);
+
+/// A builder for qclass matcher plugin
+#[derive(Deserialize, Clone, Debug, PartialEq, Eq)]
+#[serde(transparent)]
+pub struct QClassBuilder(HashSet<Adaptor>);
+
+impl Default for QClassBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl QClassBuilder {
+    /// Create an empty builder
+    pub fn new() -> Self {
+        Self(HashSet::new())
+    }
+
+    /// Add a class to match
+    pub fn add_class(mut self, class: Class) -> Self {
+        self.0.insert(Adaptor(class));
+        self
+    }
+}
+
+#[async_trait]
+impl AsyncTryInto<QClass> for QClassBuilder {
+    type Error = MatchError;
+
+    async fn async_try_into(self) -> Result<QClass> {
+        QClass::new(self.0.iter().map(|x| x.0).collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        super::{Matcher, State},
+        QClassBuilder,
+    };
+    use crate::{AsyncTryInto, MAX_LEN};
+    use bytes::{Bytes, BytesMut};
+    use domain::base::{iana::Class, Dname, MessageBuilder, Rtype};
+    use std::str::FromStr;
+
+    fn create_state(name: &str, class: Class) -> State {
+        let name = Dname::<Bytes>::from_str(name).unwrap();
+        let mut builder = MessageBuilder::from_target(BytesMut::with_capacity(MAX_LEN))
+            .unwrap()
+            .question();
+        builder.push((&name, Rtype::Txt, class)).unwrap();
+        State {
+            query: builder.into_message(),
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn test() {
+        let matcher = QClassBuilder::new()
+            .add_class(Class::Ch)
+            .add_class(Class::Hs)
+            .async_try_into()
+            .await
+            .unwrap();
+        assert!(matcher.matches(&create_state("version.bind", Class::Ch)));
+        assert!(matcher.matches(&create_state("id.server", Class::Hs)));
+        assert!(!matcher.matches(&create_state("example.com", Class::In)));
+        assert!(!matcher.matches(&create_state("example.com", Class::Int(42))));
+    }
+
+    #[tokio::test]
+    async fn numeric() {
+        // Classes by number match the well-known ones as well as the unknown ones.
+        let matcher = QClassBuilder::new()
+            .add_class(Class::Int(3))
+            .add_class(Class::Int(42))
+            .async_try_into()
+            .await
+            .unwrap();
+        assert!(matcher.matches(&create_state("version.bind", Class::Ch)));
+        assert!(matcher.matches(&create_state("example.com", Class::Int(42))));
+        assert!(!matcher.matches(&create_state("example.com", Class::In)));
+    }
+}
@@ -0,0 +1,141 @@
+// Copyright 2020 LEXUGE
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use super::{super::super::State, MatchError, Matcher, Result};
+use crate::AsyncTryInto;
+use aho_corasick::AhoCorasick;
+use async_trait::async_trait;
+use serde::Deserialize;
+
+/// A matcher that matches if the name of the first query contains any of the keywords provided, case-insensitively.
+/// Names are matched without the trailing dot, and keywords may span labels like `-analytics.`.
+pub struct QNameContains(AhoCorasick);
+
+impl QNameContains {
+    /// Create a new `QNameContains` matcher from a list of keywords.
+    pub fn new(keywords: Vec<String>) -> Result<Self> {
+        // All the keywords are searched for in a single pass over the name.
+        Ok(Self(
+            AhoCorasick::builder()
+                .ascii_case_insensitive(true)
+                .build(keywords)
+                .map_err(|e| MatchError::Other(e.to_string()))?,
+        ))
+    }
+}
+
+impl Matcher for QNameContains {
+    fn matches(&self, state: &State) -> bool {
+        let qname = state.query.first_question().unwrap().qname().to_string();
+        self.0.is_match(&qname)
+    }
+}
+
+/// A builder for qname keyword matcher plugin
+#[derive(Deserialize, Clone, Debug, PartialEq, Eq)]
+#[serde(transparent)]
+pub struct QNameContainsBuilder(Vec<String>);
+
+impl Default for QNameContainsBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl QNameContainsBuilder {
+    /// Create an empty builder
+    pub fn new() -> Self {
+        Self(Vec::new())
+    }
+
+    /// Add a keyword to match
+    pub fn add_keyword(mut self, s: impl ToString) -> Self {
+        self.0.push(s.to_string());
+        self
+    }
+}
+
+#[async_trait]
+impl AsyncTryInto<QNameContains> for QNameContainsBuilder {
+    type Error = MatchError;
+
+    async fn async_try_into(self) -> Result<QNameContains> {
+        QNameContains::new(self.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        super::{Matcher, State},
+        QNameContainsBuilder,
+    };
+    use crate::{AsyncTryInto, MAX_LEN};
+    use bytes::{Bytes, BytesMut};
+    use domain::base::{Dname, MessageBuilder, Rtype};
+    use std::str::FromStr;
+
+    fn create_state(name: &str) -> State {
+        let name = Dname::<Bytes>::from_str(name).unwrap();
+        let mut builder = MessageBuilder::from_target(BytesMut::with_capacity(MAX_LEN))
+            .unwrap()
+            .question();
+        builder.push((&name, Rtype::A)).unwrap();
+        State {
+            query: builder.into_message(),
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn test() {
+        let matcher = QNameContainsBuilder::new()
+            .add_keyword("telemetry")
+            .add_keyword("-analytics.")
+            .async_try_into()
+            .await
+            .unwrap();
+        assert!(matcher.matches(&create_state("ads.telemetry.example.com")));
+        assert!(matcher.matches(&create_state("TeleMetry.example.com")));
+        assert!(matcher.matches(&create_state("foo-analytics.example.com")));
+        assert!(!matcher.matches(&create_state("foo-analytics-bar.example.com")));
+        assert!(!matcher.matches(&create_state("example.com")));
+    }
+
+    #[tokio::test]
+    async fn label_boundary() {
+        let matcher = QNameContainsBuilder::new()
+            .add_keyword("stele")
+            .async_try_into()
+            .await
+            .unwrap();
+        assert!(!matcher.matches(&create_state("ads.telemetry.example.com")));
+        assert!(matcher.matches(&create_state("adstelemetry.example.com")));
+
+        let matcher = QNameContainsBuilder::new()
+            .add_keyword("s.tele")
+            .async_try_into()
+            .await
+            .unwrap();
+        assert!(matcher.matches(&create_state("ads.telemetry.example.com")));
+        assert!(!matcher.matches(&create_state("adstelemetry.example.com")));
+    }
+
+    #[tokio::test]
+    async fn empty() {
+        let matcher = QNameContainsBuilder::new().async_try_into().await.unwrap();
+        assert!(!matcher.matches(&create_state("telemetry.example.com")));
+    }
+}
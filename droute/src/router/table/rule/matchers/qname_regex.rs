@@ -0,0 +1,133 @@
+// Copyright 2020 LEXUGE
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use super::{super::super::State, MatchError, Matcher, Result};
+use crate::AsyncTryInto;
+use async_trait::async_trait;
+use regex::{Regex, RegexSet};
+use serde::Deserialize;
+
+/// A matcher that matches if the name of the first query matches any of the regular expressions provided.
+/// Names are matched in lowercase without the trailing dot, like `www.example.com`.
+pub struct QNameRegex(RegexSet);
+
+impl QNameRegex {
+    /// Create a new `QNameRegex` matcher from a list of regular expressions.
+    pub fn new(patterns: Vec<String>) -> Result<Self> {
+        // `RegexSet` doesn't tell which pattern is at fault, so compile them one by one first.
+        for p in &patterns {
+            Regex::new(p).map_err(|e| MatchError::RegexError(p.clone(), e))?;
+        }
+        // Each pattern compiles, so only the size limit of the whole set could be exceeded here.
+        Ok(Self(
+            RegexSet::new(&patterns).map_err(|e| MatchError::Other(e.to_string()))?,
+        ))
+    }
+}
+
+impl Matcher for QNameRegex {
+    fn matches(&self, state: &State) -> bool {
+        let qname = state.query.first_question().unwrap().qname().to_string();
+        self.0.is_match(&qname.to_ascii_lowercase())
+    }
+}
+
+/// A builder for qname regex matcher plugin
+#[derive(Deserialize, Clone, Debug, PartialEq, Eq)]
+#[serde(transparent)]
+pub struct QNameRegexBuilder(Vec<String>);
+
+impl Default for QNameRegexBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl QNameRegexBuilder {
+    /// Create an empty builder
+    pub fn new() -> Self {
+        Self(Vec::new())
+    }
+
+    /// Add a regular expression to match
+    pub fn add_pattern(mut self, s: impl ToString) -> Self {
+        self.0.push(s.to_string());
+        self
+    }
+}
+
+#[async_trait]
+impl AsyncTryInto<QNameRegex> for QNameRegexBuilder {
+    type Error = MatchError;
+
+    async fn async_try_into(self) -> Result<QNameRegex> {
+        QNameRegex::new(self.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        super::{MatchError, Matcher, State},
+        QNameRegexBuilder,
+    };
+    use crate::{AsyncTryInto, MAX_LEN};
+    use bytes::{Bytes, BytesMut};
+    use domain::base::{Dname, MessageBuilder, Rtype};
+    use std::str::FromStr;
+
+    fn create_state(name: &str) -> State {
+        let name = Dname::<Bytes>::from_str(name).unwrap();
+        let mut builder = MessageBuilder::from_target(BytesMut::with_capacity(MAX_LEN))
+            .unwrap()
+            .question();
+        builder.push((&name, Rtype::A)).unwrap();
+        State {
+            query: builder.into_message(),
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn test() {
+        let matcher = QNameRegexBuilder::new()
+            .add_pattern(r"^ad[sx]?[0-9]*\.")
+            .add_pattern(r"(^|\.)[a-z0-9]{24,}\.com$")
+            .async_try_into()
+            .await
+            .unwrap();
+        assert!(matcher.matches(&create_state("ads.example.com")));
+        assert!(matcher.matches(&create_state("ad42.example.com")));
+        // Names are lowercased before matching.
+        assert!(matcher.matches(&create_state("ADX.example.com")));
+        assert!(matcher.matches(&create_state("x.q8hjv2k1m0z9wl3t7rbc4pnd.com")));
+        assert!(!matcher.matches(&create_state("example.com")));
+        assert!(!matcher.matches(&create_state("bad.example.com")));
+        assert!(!matcher.matches(&create_state("q8hjv2k1m0z9wl3t7rbc4pnd.com.cn")));
+    }
+
+    #[tokio::test]
+    async fn bad_pattern() {
+        match QNameRegexBuilder::new()
+            .add_pattern(r"^ads\.")
+            .add_pattern(r"ad(s")
+            .async_try_into()
+            .await
+        {
+            Err(MatchError::RegexError(p, _)) => assert_eq!(p, "ad(s"),
+            _ => panic!("the malformed pattern is accepted"),
+        }
+    }
+}
@@ -17,8 +17,11 @@ use super::{super::super::State, MatchError, Matcher, Result};
 use crate::AsyncTryInto;
 use async_trait::async_trait;
 use domain::base::iana::rtype::Rtype;
-use serde::Deserialize;
-use std::collections::HashSet;
+use serde::{
+    de::{self, EnumAccess, IntoDeserializer, VariantAccess, Visitor},
+    Deserialize, Deserializer,
+};
+use std::{collections::HashSet, fmt};
 
 /// A matcher that matches if first query is of any of the record types provided.
 pub struct QType(HashSet<Rtype>);
@@ -37,6 +40,8 @@ impl Matcher for QType {
     }
 }
 
+// Only used to look up mnemonics, fields are never read directly.
+#[allow(dead_code)]
 #[derive(Deserialize, Clone)]
 #[serde(rename_all = "UPPERCASE")]
 #[serde(remote = "Rtype")]
@@ -130,15 +135,93 @@ pub enum RtypeDef {
     Int(u16),
 }
 
-// TODO: remove it once domain supports deserializing rtype
-#[derive(Deserialize, Clone, PartialEq, Eq, Hash, Debug)]
-#[serde(transparent)]
-struct Adaptor(#[serde(with = "RtypeDef")] Rtype);
+// The name of an entry in the list, which is the variant name in RON.
+struct Name(String);
+
+impl<'de> Deserialize<'de> for Name {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
+        struct NameVisitor;
+
+        impl<'de> Visitor<'de> for NameVisitor {
+            type Value = Name;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("a record type")
+            }
+
+            fn visit_str<E: de::Error>(self, v: &str) -> std::result::Result<Name, E> {
+                Ok(Name(v.to_string()))
+            }
+        }
+
+        deserializer.deserialize_identifier(NameVisitor)
+    }
+}
+
+// An entry in the list, which is one of
+// - a mnemonic like `AAAA`
+// - `TYPE<number>` as in RFC 3597, or `INT(<number>)`, for types without a mnemonic
+// - `addr` for both `A` and `AAAA`
+struct Entry(Vec<Rtype>);
+
+impl Entry {
+    fn parse(name: &str) -> std::result::Result<Self, String> {
+        if name == "addr" {
+            return Ok(Self(vec![Rtype::A, Rtype::Aaaa]));
+        }
+        if let Some(n) = name
+            .strip_prefix("TYPE")
+            .filter(|n| n.bytes().all(|b| b.is_ascii_digit()))
+            .and_then(|n| n.parse().ok())
+        {
+            // Types given by number are the same as the ones given by mnemonic, e.g. `TYPE28` is `AAAA`.
+            return Ok(Self(vec![Rtype::from_int(n)]));
+        }
+        RtypeDef::deserialize(name.into_deserializer())
+            .map(|t| Self(vec![t]))
+            // Extend the list of valid mnemonics in the error with the other forms.
+            .map_err(|e: de::value::Error| format!("{}, `addr`, or `TYPE<number>`", e))
+    }
+}
+
+impl<'de> Deserialize<'de> for Entry {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
+        struct EntryVisitor;
+
+        impl<'de> Visitor<'de> for EntryVisitor {
+            type Value = Entry;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("a record type or a group of record types")
+            }
+
+            fn visit_enum<A: EnumAccess<'de>>(
+                self,
+                data: A,
+            ) -> std::result::Result<Entry, A::Error> {
+                let (Name(name), variant) = data.variant()?;
+                if name == "INT" {
+                    return Ok(Entry(vec![Rtype::from_int(variant.newtype_variant()?)]));
+                }
+                variant.unit_variant()?;
+                Entry::parse(&name).map_err(de::Error::custom)
+            }
+        }
+
+        deserializer.deserialize_enum("Rtype", &[], EntryVisitor)
+    }
+}
 
 /// A builder for qtype matcher plugin
 #[derive(Deserialize, Clone, Debug, PartialEq, Eq)]
-#[serde(transparent)]
-pub struct QTypeBuilder(HashSet<Adaptor>);
+#[serde(from = "Vec<Entry>")]
+pub struct QTypeBuilder(HashSet<Rtype>);
+
+impl From<Vec<Entry>> for QTypeBuilder {
+    fn from(entries: Vec<Entry>) -> Self {
+        Self(entries.into_iter().flat_map(|e| e.0).collect())
+    }
+}
 
 impl Default for QTypeBuilder {
     fn default() -> Self {
@@ -154,9 +237,14 @@ impl QTypeBuilder {
 
     /// Add a record type to match
     pub fn add_rr(mut self, rr: Rtype) -> Self {
-        self.0.insert(Adaptor(rr));
+        self.0.insert(rr);
         self
     }
+
+    // Record types added, shared with other matchers taking a list of record types.
+    pub(super) fn into_types(self) -> HashSet<Rtype> {
+        self.0
+    }
 }
 
 #[async_trait]
@@ -164,6 +252,69 @@ impl AsyncTryInto<QType> for QTypeBuilder {
     type Error = MatchError;
 
     async fn async_try_into(self) -> Result<QType> {
-        QType::new(self.0.iter().map(|x| x.0).collect())
+        QType::new(self.into_types())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        super::{Matcher, State},
+        QTypeBuilder,
+    };
+    use crate::{AsyncTryInto, MAX_LEN};
+    use bytes::{Bytes, BytesMut};
+    use domain::base::{Dname, MessageBuilder, Rtype};
+    use std::str::FromStr;
+
+    fn create_state(qtype: Rtype) -> State {
+        let name = Dname::<Bytes>::from_str("example.com").unwrap();
+        let mut builder = MessageBuilder::from_target(BytesMut::with_capacity(MAX_LEN))
+            .unwrap()
+            .question();
+        builder.push((&name, qtype)).unwrap();
+        State {
+            query: builder.into_message(),
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn groups_and_numbers() {
+        let builder = ron::from_str::<QTypeBuilder>("[TYPE65, addr]").unwrap();
+        assert_eq!(
+            builder,
+            QTypeBuilder::new()
+                .add_rr(Rtype::Int(65))
+                .add_rr(Rtype::A)
+                .add_rr(Rtype::Aaaa)
+        );
+        let matcher = builder.async_try_into().await.unwrap();
+        assert!(matcher.matches(&create_state(Rtype::A)));
+        assert!(matcher.matches(&create_state(Rtype::Aaaa)));
+        assert!(matcher.matches(&create_state(Rtype::from_int(65))));
+        assert!(!matcher.matches(&create_state(Rtype::Mx)));
+    }
+
+    #[test]
+    fn parse() {
+        // Types given by number are the same as the ones given by mnemonic.
+        for s in ["[MX]", "[TYPE15]", "[INT(15)]"] {
+            assert_eq!(
+                ron::from_str::<QTypeBuilder>(s).unwrap(),
+                QTypeBuilder::new().add_rr(Rtype::Mx),
+                "{}",
+                s
+            );
+        }
+
+        for s in ["[aaaa]", "[TYPE]", "[TYPE65536]"] {
+            let e = ron::from_str::<QTypeBuilder>(s).unwrap_err().to_string();
+            assert!(
+                e.contains("`AAAA`, ") && e.contains("`addr`, or `TYPE<number>`"),
+                "{}",
+                e
+            );
+        }
     }
 }
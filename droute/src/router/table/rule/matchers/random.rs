@@ -0,0 +1,170 @@
+// Copyright 2020 LEXUGE
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use super::{super::super::State, MatchError, Matcher, Result};
+use crate::AsyncTryInto;
+use async_trait::async_trait;
+use serde::Deserialize;
+
+/// A matcher that matches a fraction of evaluations, or a fraction of query names if sticky.
+pub struct Random {
+    probability: f64,
+    sticky: bool,
+}
+
+impl Random {
+    /// Create a new `Random` matcher matching with `probability` in `[0, 1]`. If `sticky`, whether it matches is decided by the query name, so that the same name always gets the same result.
+    pub fn new(probability: f64, sticky: bool) -> Result<Self> {
+        if !(0.0..=1.0).contains(&probability) {
+            return Err(MatchError::Other(format!(
+                "probability `{}` is not in [0, 1]",
+                probability
+            )));
+        }
+        Ok(Self {
+            probability,
+            sticky,
+        })
+    }
+}
+
+// A hash of the name which stays the same across runs and builds, mapped to [0, 1).
+fn stable_hash(name: &str) -> f64 {
+    // FNV-1a
+    let mut h = name.bytes().fold(0xcbf2_9ce4_8422_2325_u64, |h, b| {
+        (h ^ b.to_ascii_lowercase() as u64).wrapping_mul(0x0000_0100_0000_01b3)
+    });
+    // Finalizer of SplitMix64 to spread the bits, as FNV-1a is weak on the high bits of short inputs.
+    h = (h ^ (h >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    h = (h ^ (h >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    h ^= h >> 31;
+    (h >> 11) as f64 / (1_u64 << 53) as f64
+}
+
+impl Matcher for Random {
+    fn matches(&self, state: &State) -> bool {
+        let x = if self.sticky {
+            stable_hash(&state.query.first_question().unwrap().qname().to_string())
+        } else {
+            fastrand::f64()
+        };
+        x < self.probability
+    }
+}
+
+/// A builder for random matcher plugin, which sets the probability of matching.
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+#[serde(transparent)]
+pub struct RandomBuilder {
+    probability: f64,
+    #[serde(skip)]
+    sticky: bool,
+}
+
+impl RandomBuilder {
+    /// Create a new builder matching with `probability`
+    pub fn new(probability: f64) -> Self {
+        Self {
+            probability,
+            sticky: false,
+        }
+    }
+
+    /// Decide whether to match by the query name instead of at random
+    pub fn sticky(mut self) -> Self {
+        self.sticky = true;
+        self
+    }
+}
+
+#[async_trait]
+impl AsyncTryInto<Random> for RandomBuilder {
+    type Error = MatchError;
+
+    async fn async_try_into(self) -> Result<Random> {
+        Random::new(self.probability, self.sticky)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        super::{Matcher, State},
+        RandomBuilder,
+    };
+    use crate::{AsyncTryInto, MAX_LEN};
+    use bytes::{Bytes, BytesMut};
+    use domain::base::{Dname, MessageBuilder, Rtype};
+    use std::str::FromStr;
+
+    fn create_state(name: &str) -> State {
+        let name = Dname::<Bytes>::from_str(name).unwrap();
+        let mut builder = MessageBuilder::from_target(BytesMut::with_capacity(MAX_LEN))
+            .unwrap()
+            .question();
+        builder.push((&name, Rtype::A)).unwrap();
+        State {
+            query: builder.into_message(),
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn plain() {
+        let state = create_state("example.com");
+        let matcher = RandomBuilder::new(0.3).async_try_into().await.unwrap();
+        let hits = (0..10000).filter(|_| matcher.matches(&state)).count();
+        // More than six standard deviations away
+        assert!((2700..=3300).contains(&hits), "{} hits", hits);
+
+        let never = RandomBuilder::new(0.0).async_try_into().await.unwrap();
+        let always = RandomBuilder::new(1.0).async_try_into().await.unwrap();
+        for _ in 0..1000 {
+            assert!(!never.matches(&state));
+            assert!(always.matches(&state));
+        }
+    }
+
+    #[tokio::test]
+    async fn sticky() {
+        let matcher = RandomBuilder::new(0.3)
+            .sticky()
+            .async_try_into()
+            .await
+            .unwrap();
+        let other = RandomBuilder::new(0.3)
+            .sticky()
+            .async_try_into()
+            .await
+            .unwrap();
+        let mut hits = 0;
+        for i in 0..2000 {
+            let name = format!("host{}.example.com", i);
+            let result = matcher.matches(&create_state(&name));
+            // The same name always takes the same branch, regardless of the case.
+            assert_eq!(matcher.matches(&create_state(&name)), result);
+            assert_eq!(other.matches(&create_state(&name.to_uppercase())), result);
+            hits += result as usize;
+        }
+        assert!((450..=750).contains(&hits), "{} hits", hits);
+    }
+
+    #[tokio::test]
+    async fn bad_probability() {
+        for p in [-0.1, 1.1, f64::NAN] {
+            assert!(RandomBuilder::new(p).async_try_into().await.is_err());
+        }
+    }
+}
@@ -0,0 +1,120 @@
+// Copyright 2020 LEXUGE
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use super::{super::super::State, header::RcodeDef, MatchError, Matcher, Result};
+use crate::AsyncTryInto;
+use async_trait::async_trait;
+use domain::base::iana::Rcode;
+use serde::Deserialize;
+use std::collections::HashSet;
+
+/// A matcher that matches if the response code of the current response is any of the ones provided.
+/// It never matches before any action has set a response.
+pub struct RCode(HashSet<Rcode>);
+
+impl RCode {
+    /// Create a new `RCode` matcher.
+    pub fn new(rcodes: HashSet<Rcode>) -> Result<Self> {
+        Ok(Self(rcodes))
+    }
+}
+
+impl Matcher for RCode {
+    fn matches(&self, state: &State) -> bool {
+        // Before being answered, `resp` is merely the query echoed, whose rcode means nothing.
+        state.answered && self.0.contains(&state.resp.header().rcode())
+    }
+}
+
+#[derive(Deserialize, Clone, PartialEq, Eq, Hash, Debug)]
+#[serde(transparent)]
+struct Adaptor(#[serde(with = "RcodeDef")] Rcode);
+
+/// A builder for rcode matcher plugin
+#[derive(Deserialize, Clone, Debug, PartialEq, Eq)]
+#[serde(transparent)]
+pub struct RCodeBuilder(HashSet<Adaptor>);
+
+impl Default for RCodeBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl RCodeBuilder {
+    /// Create an empty builder
+    pub fn new() -> Self {
+        Self(HashSet::new())
+    }
+
+    /// Add a response code to match
+    pub fn add_rcode(mut self, rcode: Rcode) -> Self {
+        self.0.insert(Adaptor(rcode));
+        self
+    }
+}
+
+#[async_trait]
+impl AsyncTryInto<RCode> for RCodeBuilder {
+    type Error = MatchError;
+
+    async fn async_try_into(self) -> Result<RCode> {
+        RCode::new(self.0.iter().map(|x| x.0).collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        super::{Matcher, State},
+        RCodeBuilder,
+    };
+    use crate::{AsyncTryInto, MAX_LEN};
+    use bytes::BytesMut;
+    use domain::base::{iana::Rcode, MessageBuilder};
+
+    fn create_state(rcode: Rcode) -> State {
+        let mut builder = MessageBuilder::from_target(BytesMut::with_capacity(MAX_LEN)).unwrap();
+        builder.header_mut().set_rcode(rcode);
+        let mut state = State::default();
+        state.set_resp(builder.into_message());
+        state
+    }
+
+    #[tokio::test]
+    async fn test() {
+        let matcher = RCodeBuilder::new()
+            .add_rcode(Rcode::NXDomain)
+            .add_rcode(Rcode::ServFail)
+            .async_try_into()
+            .await
+            .unwrap();
+        assert!(matcher.matches(&create_state(Rcode::NXDomain)));
+        assert!(matcher.matches(&create_state(Rcode::ServFail)));
+        assert!(!matcher.matches(&create_state(Rcode::NoError)));
+    }
+
+    #[tokio::test]
+    async fn unanswered() {
+        let matcher = RCodeBuilder::new()
+            .add_rcode(Rcode::NoError)
+            .async_try_into()
+            .await
+            .unwrap();
+        assert!(matcher.matches(&create_state(Rcode::NoError)));
+        // The default state is never answered, though its rcode is `NOERROR`.
+        assert!(!matcher.matches(&State::default()));
+    }
+}
@@ -0,0 +1,96 @@
+// Copyright 2021 LEXUGE
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+// Lists of matchers downloaded from http(s) URLs instead of being read from the disk.
+
+use super::{MatchError, Result};
+use bytes::Bytes;
+use reqwest::{Client, Proxy, Url};
+use std::{path::Path, str::FromStr, time::Duration};
+
+static APP_USER_AGENT: &str = concat!(env!("CARGO_PKG_NAME"), "/", env!("CARGO_PKG_VERSION"),);
+
+// Download the content at the URL, through the proxy if any.
+pub(super) async fn download(url: &str, proxy: Option<&str>) -> Result<Bytes> {
+    let parsed = Url::from_str(url).map_err(|_| MatchError::InvalidUrl(url.to_string()))?;
+    if !matches!(parsed.scheme(), "http" | "https") {
+        return Err(MatchError::InvalidUrl(url.to_string()));
+    }
+    let client = Client::builder()
+        .user_agent(APP_USER_AGENT)
+        .connect_timeout(Duration::from_secs(3))
+        .timeout(Duration::from_secs(60));
+    let client = if let Some(proxy) = proxy {
+        client.proxy(Proxy::all(proxy)?)
+    } else {
+        client
+    }
+    .build()?;
+    Ok(client
+        .get(parsed)
+        .send()
+        .await?
+        .error_for_status()?
+        .bytes()
+        .await?)
+}
+
+// Store what is downloaded at the cache path. The file there is only replaced once the content is fully written.
+pub(super) async fn store(cache: &Path, data: &[u8]) -> Result<()> {
+    if let Some(parent) = cache.parent() {
+        tokio::fs::create_dir_all(parent).await?;
+    }
+    let mut part = cache.as_os_str().to_owned();
+    part.push(".part");
+    tokio::fs::write(&part, data).await?;
+    tokio::fs::rename(&part, cache).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+pub(super) mod tests {
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+
+    // Serve the bodies in turn over HTTP, and fail afterwards. Returns the URL of the server without a path.
+    pub async fn serve(bodies: Vec<&'static str>) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut bodies = bodies.into_iter();
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let mut req = Vec::new();
+                let mut buf = [0; 1024];
+                while !req.ends_with(b"\r\n\r\n") {
+                    let n = stream.read(&mut buf).await.unwrap();
+                    req.extend_from_slice(&buf[..n]);
+                }
+                let resp = match bodies.next() {
+                    Some(body) => format!(
+                        "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                        body.len(),
+                        body
+                    ),
+                    None => "HTTP/1.1 500 Internal Server Error\r\nContent-Length: 0\r\nConnection: close\r\n\r\n".to_string(),
+                };
+                stream.write_all(resp.as_bytes()).await.unwrap();
+            }
+        });
+        format!("http://{}", addr)
+    }
+}
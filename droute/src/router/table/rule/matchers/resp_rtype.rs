@@ -0,0 +1,164 @@
+// Copyright 2020 LEXUGE
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use super::{super::super::State, Matcher, Result};
+use domain::base::Rtype;
+use serde::Deserialize;
+use std::collections::HashSet;
+
+/// How the records in the answer section are checked against the record types provided
+#[derive(Deserialize, Clone, Copy, PartialEq, Eq, Hash, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum RespRTypeMode {
+    /// Any of the records is of the types provided
+    Any,
+    /// There are records and all of them are of the types provided
+    All,
+    /// None of the records is of the types provided, which includes the case of no records at all
+    None,
+}
+
+/// A matcher that matches on the types of the records in the answer section of the current response.
+/// It never matches before any action has set a response.
+pub struct RespRType {
+    mode: RespRTypeMode,
+    types: HashSet<Rtype>,
+}
+
+impl RespRType {
+    /// Create a new `RespRType` matcher.
+    pub fn new(mode: RespRTypeMode, types: HashSet<Rtype>) -> Result<Self> {
+        Ok(Self { mode, types })
+    }
+}
+
+impl Matcher for RespRType {
+    fn matches(&self, state: &State) -> bool {
+        let mut rtypes = match state.answer() {
+            Some(answer) => answer.flatten().map(|r| r.rtype()).peekable(),
+            None => return false,
+        };
+        match self.mode {
+            RespRTypeMode::Any => rtypes.any(|t| self.types.contains(&t)),
+            RespRTypeMode::All => {
+                rtypes.peek().is_some() && rtypes.all(|t| self.types.contains(&t))
+            }
+            RespRTypeMode::None => !rtypes.any(|t| self.types.contains(&t)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        super::{Matcher, State},
+        RespRType, RespRTypeMode,
+    };
+    use crate::MAX_LEN;
+    use bytes::{Bytes, BytesMut};
+    use domain::{
+        base::{iana::Rcode, Dname, Message, MessageBuilder, Rtype},
+        rdata::{Aaaa, Cname, Mx, A},
+    };
+    use once_cell::sync::Lazy;
+    use std::{collections::HashSet, str::FromStr};
+
+    static NAME: Lazy<Dname<Bytes>> = Lazy::new(|| Dname::from_str("www.example.com").unwrap());
+
+    static QUERY: Lazy<Message<Bytes>> = Lazy::new(|| {
+        let mut builder = MessageBuilder::from_target(BytesMut::with_capacity(MAX_LEN))
+            .unwrap()
+            .question();
+        builder.push((&*NAME, Rtype::A)).unwrap();
+        builder.into_message()
+    });
+
+    // Build a response to `QUERY` with records of the types given in the answer section.
+    fn create_state(rtypes: &[Rtype]) -> State {
+        let mut builder = MessageBuilder::from_target(BytesMut::with_capacity(MAX_LEN))
+            .unwrap()
+            .start_answer(&*QUERY, Rcode::NoError)
+            .unwrap();
+        for rtype in rtypes {
+            let pushed = match *rtype {
+                Rtype::Cname => builder.push((
+                    &*NAME,
+                    10,
+                    Cname::new(Dname::<Bytes>::from_str("cdn.example.net").unwrap()),
+                )),
+                Rtype::A => builder.push((&*NAME, 10, A::from_octets(1, 1, 1, 1))),
+                Rtype::Aaaa => builder.push((&*NAME, 10, Aaaa::new("::1".parse().unwrap()))),
+                Rtype::Mx => builder.push((
+                    &*NAME,
+                    10,
+                    Mx::new(10, Dname::<Bytes>::from_str("mail.example.com").unwrap()),
+                )),
+                _ => unreachable!(),
+            };
+            pushed.unwrap();
+        }
+        let mut state = State {
+            query: QUERY.clone(),
+            ..Default::default()
+        };
+        state.set_resp(builder.into_message());
+        state
+    }
+
+    fn matcher(mode: RespRTypeMode, types: &[Rtype]) -> RespRType {
+        RespRType::new(mode, types.iter().copied().collect::<HashSet<_>>()).unwrap()
+    }
+
+    #[test]
+    fn any() {
+        let matcher = matcher(RespRTypeMode::Any, &[Rtype::Cname]);
+        assert!(matcher.matches(&create_state(&[Rtype::Cname])));
+        assert!(matcher.matches(&create_state(&[Rtype::Cname, Rtype::A])));
+        assert!(!matcher.matches(&create_state(&[Rtype::A, Rtype::A])));
+        assert!(!matcher.matches(&create_state(&[])));
+    }
+
+    #[test]
+    fn all() {
+        // Dangling CNAME chains
+        let matcher = matcher(RespRTypeMode::All, &[Rtype::Cname]);
+        assert!(matcher.matches(&create_state(&[Rtype::Cname])));
+        assert!(matcher.matches(&create_state(&[Rtype::Cname, Rtype::Cname])));
+        assert!(!matcher.matches(&create_state(&[Rtype::Cname, Rtype::A])));
+        // Empty answers are left to the `empty_answer` matcher
+        assert!(!matcher.matches(&create_state(&[])));
+    }
+
+    #[test]
+    fn none() {
+        let matcher = matcher(RespRTypeMode::None, &[Rtype::A, Rtype::Aaaa]);
+        assert!(matcher.matches(&create_state(&[Rtype::Cname])));
+        assert!(matcher.matches(&create_state(&[Rtype::Cname, Rtype::Mx])));
+        assert!(matcher.matches(&create_state(&[])));
+        assert!(!matcher.matches(&create_state(&[Rtype::Cname, Rtype::Aaaa])));
+        assert!(!matcher.matches(&create_state(&[Rtype::A, Rtype::Mx])));
+    }
+
+    #[test]
+    fn unanswered() {
+        for mode in [RespRTypeMode::Any, RespRTypeMode::All, RespRTypeMode::None] {
+            let state = State {
+                query: QUERY.clone(),
+                ..Default::default()
+            };
+            assert!(!matcher(mode, &[Rtype::A]).matches(&state));
+        }
+    }
+}
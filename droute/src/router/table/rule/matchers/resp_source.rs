@@ -0,0 +1,144 @@
+// Copyright 2020 LEXUGE
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use super::{super::super::State, MatchError, Matcher, Result};
+use crate::{AsyncTryInto, Label, RespSource as Source};
+use async_trait::async_trait;
+use serde::Deserialize;
+use std::collections::HashSet;
+
+/// Where the response should have come from.
+#[derive(Deserialize, Clone, PartialEq, Eq, Hash, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum SourceCond {
+    /// A cached record within its TTL
+    Cache,
+    /// A cached record with its TTL passed
+    StaleCache,
+    /// A fresh response from any upstream
+    Upstream,
+    /// A fresh response from the upstream with the tag
+    UpstreamTag(Label),
+}
+
+/// A matcher that matches if the response of the last `query` action came from any of the sources provided.
+/// It never matches before any `query` action has run.
+pub struct RespSource(HashSet<SourceCond>);
+
+impl RespSource {
+    /// Create a new `RespSource` matcher.
+    pub fn new(conds: HashSet<SourceCond>) -> Result<Self> {
+        Ok(Self(conds))
+    }
+}
+
+impl Matcher for RespSource {
+    fn matches(&self, state: &State) -> bool {
+        match &state.resp_source {
+            None => false,
+            Some(Source::Cache) => self.0.contains(&SourceCond::Cache),
+            Some(Source::StaleCache) => self.0.contains(&SourceCond::StaleCache),
+            Some(Source::Upstream(tag)) => {
+                self.0.contains(&SourceCond::Upstream)
+                    || self.0.contains(&SourceCond::UpstreamTag(tag.clone()))
+            }
+        }
+    }
+}
+
+/// A builder for response source matcher plugin
+#[derive(Deserialize, Clone, Debug, PartialEq, Eq)]
+#[serde(transparent)]
+pub struct RespSourceBuilder(HashSet<SourceCond>);
+
+impl Default for RespSourceBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl RespSourceBuilder {
+    /// Create an empty builder
+    pub fn new() -> Self {
+        Self(HashSet::new())
+    }
+
+    /// Add a source to match
+    pub fn add_source(mut self, cond: SourceCond) -> Self {
+        self.0.insert(cond);
+        self
+    }
+}
+
+#[async_trait]
+impl AsyncTryInto<RespSource> for RespSourceBuilder {
+    type Error = MatchError;
+
+    async fn async_try_into(self) -> Result<RespSource> {
+        RespSource::new(self.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        super::{Matcher, State},
+        RespSourceBuilder, SourceCond,
+    };
+    use crate::{AsyncTryInto, RespSource};
+
+    fn create_state(source: Option<RespSource>) -> State {
+        State {
+            resp_source: source,
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn test() {
+        let matcher = RespSourceBuilder::new()
+            .add_source(SourceCond::Cache)
+            .add_source(SourceCond::UpstreamTag("domestic".into()))
+            .async_try_into()
+            .await
+            .unwrap();
+        assert!(matcher.matches(&create_state(Some(RespSource::Cache))));
+        assert!(!matcher.matches(&create_state(Some(RespSource::StaleCache))));
+        assert!(matcher.matches(&create_state(Some(RespSource::Upstream("domestic".into())))));
+        assert!(!matcher.matches(&create_state(Some(RespSource::Upstream("global".into())))));
+        assert!(!matcher.matches(&create_state(None)));
+
+        let matcher = RespSourceBuilder::new()
+            .add_source(SourceCond::Upstream)
+            .async_try_into()
+            .await
+            .unwrap();
+        assert!(matcher.matches(&create_state(Some(RespSource::Upstream("global".into())))));
+        assert!(!matcher.matches(&create_state(Some(RespSource::Cache))));
+        assert!(!matcher.matches(&create_state(None)));
+    }
+
+    #[test]
+    fn parse() {
+        assert_eq!(
+            ron::from_str::<RespSourceBuilder>(r#"[cache, stale_cache, upstream_tag("domestic")]"#)
+                .unwrap(),
+            RespSourceBuilder::new()
+                .add_source(SourceCond::Cache)
+                .add_source(SourceCond::StaleCache)
+                .add_source(SourceCond::UpstreamTag("domestic".into()))
+        );
+    }
+}
@@ -0,0 +1,144 @@
+// Copyright 2020 LEXUGE
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use super::{super::super::State, MatchError, Matcher, Result};
+
+// Default upper bound, which no TTL exceeds.
+pub(super) fn max_ttl() -> u32 {
+    u32::MAX
+}
+
+/// A matcher that matches on the TTLs of the records in the answer section of the current response.
+/// A record is out of bounds if its TTL is below `min` or above `max`. The matcher matches if any record is out of bounds, or if there are records and all of them are if `all` is set.
+/// It never matches before any action has set a response.
+pub struct RespTtl {
+    min: u32,
+    max: u32,
+    all: bool,
+}
+
+impl RespTtl {
+    /// Create a new `RespTtl` matcher.
+    pub fn new(min: u32, max: u32, all: bool) -> Result<Self> {
+        if min > max {
+            return Err(MatchError::Other(format!(
+                "minimum TTL {} of the resp_ttl matcher is greater than the maximum {}",
+                min, max
+            )));
+        }
+        Ok(Self { min, max, all })
+    }
+}
+
+impl Matcher for RespTtl {
+    fn matches(&self, state: &State) -> bool {
+        let mut ttls = match state.answer() {
+            Some(answer) => answer.flatten().map(|r| r.ttl()).peekable(),
+            None => return false,
+        };
+        let out = |ttl: u32| ttl < self.min || ttl > self.max;
+        if self.all {
+            ttls.peek().is_some() && ttls.all(out)
+        } else {
+            ttls.any(out)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        super::{Matcher, State},
+        max_ttl, RespTtl,
+    };
+    use crate::MAX_LEN;
+    use bytes::{Bytes, BytesMut};
+    use domain::{
+        base::{iana::Rcode, Dname, Message, MessageBuilder, Rtype},
+        rdata::A,
+    };
+    use once_cell::sync::Lazy;
+    use std::str::FromStr;
+
+    static NAME: Lazy<Dname<Bytes>> = Lazy::new(|| Dname::from_str("www.example.com").unwrap());
+
+    static QUERY: Lazy<Message<Bytes>> = Lazy::new(|| {
+        let mut builder = MessageBuilder::from_target(BytesMut::with_capacity(MAX_LEN))
+            .unwrap()
+            .question();
+        builder.push((&*NAME, Rtype::A)).unwrap();
+        builder.into_message()
+    });
+
+    // Build a response to `QUERY` with A records of the TTLs given in the answer section.
+    fn create_state(ttls: &[u32]) -> State {
+        let mut builder = MessageBuilder::from_target(BytesMut::with_capacity(MAX_LEN))
+            .unwrap()
+            .start_answer(&*QUERY, Rcode::NoError)
+            .unwrap();
+        for &ttl in ttls {
+            builder
+                .push((&*NAME, ttl, A::from_octets(1, 1, 1, 1)))
+                .unwrap();
+        }
+        let mut state = State {
+            query: QUERY.clone(),
+            ..Default::default()
+        };
+        state.set_resp(builder.into_message());
+        state
+    }
+
+    #[test]
+    fn any() {
+        let matcher = RespTtl::new(1, 86400, false).unwrap();
+        assert!(matcher.matches(&create_state(&[0])));
+        assert!(matcher.matches(&create_state(&[300, 0])));
+        assert!(matcher.matches(&create_state(&[300, 86401])));
+        assert!(!matcher.matches(&create_state(&[1, 300, 86400])));
+        assert!(!matcher.matches(&create_state(&[])));
+
+        // Only the minimum is bounded by default.
+        let matcher = RespTtl::new(1, max_ttl(), false).unwrap();
+        assert!(matcher.matches(&create_state(&[0])));
+        assert!(!matcher.matches(&create_state(&[1 << 30])));
+    }
+
+    #[test]
+    fn all() {
+        let matcher = RespTtl::new(0, 3600, true).unwrap();
+        assert!(matcher.matches(&create_state(&[7200])));
+        assert!(matcher.matches(&create_state(&[7200, 3601])));
+        assert!(!matcher.matches(&create_state(&[7200, 3600])));
+        assert!(!matcher.matches(&create_state(&[])));
+    }
+
+    #[test]
+    fn bounds() {
+        assert!(RespTtl::new(300, 300, false).is_ok());
+        assert!(RespTtl::new(301, 300, false).is_err());
+    }
+
+    #[test]
+    fn unanswered() {
+        for all in [false, true] {
+            let state = State {
+                query: QUERY.clone(),
+                ..Default::default()
+            };
+            assert!(!RespTtl::new(1, 1, all).unwrap().matches(&state));
+        }
+    }
+}
@@ -0,0 +1,274 @@
+// Copyright 2020 LEXUGE
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use super::{super::super::State, MatchError, Matcher, Result};
+use crate::AsyncTryInto;
+use async_trait::async_trait;
+use chrono::{DateTime, Datelike, Local, NaiveDateTime, NaiveTime, Timelike, Utc, Weekday};
+use serde::Deserialize;
+use std::collections::HashSet;
+
+const DAY_SECS: i64 = 24 * 60 * 60;
+
+/// Day of the week.
+#[derive(Deserialize, Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub enum Day {
+    /// Monday
+    Mon,
+    /// Tuesday
+    Tue,
+    /// Wednesday
+    Wed,
+    /// Thursday
+    Thu,
+    /// Friday
+    Fri,
+    /// Saturday
+    Sat,
+    /// Sunday
+    Sun,
+}
+
+impl From<Day> for Weekday {
+    fn from(day: Day) -> Self {
+        match day {
+            Day::Mon => Weekday::Mon,
+            Day::Tue => Weekday::Tue,
+            Day::Wed => Weekday::Wed,
+            Day::Thu => Weekday::Thu,
+            Day::Fri => Weekday::Fri,
+            Day::Sat => Weekday::Sat,
+            Day::Sun => Weekday::Sun,
+        }
+    }
+}
+
+/// A window of time repeating every week.
+/// It opens at `start` on each of the `days` and closes at the following `end`, which is on the next day if the window crosses midnight.
+#[derive(Deserialize, Clone, PartialEq, Eq, Debug)]
+pub struct Window {
+    /// Days on which the window opens.
+    pub days: Vec<Day>,
+    /// Time the window opens at, like `22:00`.
+    pub start: String,
+    /// Time the window closes at, like `07:00`. The window lasts a whole day if it is the same as `start`.
+    pub end: String,
+    /// Whether the times are of the UTC wall clock instead of the local one.
+    #[serde(default)]
+    pub utc: bool,
+}
+
+// A window ready for matching, with times in seconds since midnight.
+struct Span {
+    days: HashSet<Weekday>,
+    start: i64,
+    len: i64,
+    utc: bool,
+}
+
+impl Span {
+    fn new(window: Window) -> Result<Self> {
+        let parse = |s: &str| {
+            NaiveTime::parse_from_str(s, "%H:%M")
+                .map(|t| t.num_seconds_from_midnight() as i64)
+                .map_err(|_| MatchError::Other(format!("invalid time `{}`, expected `HH:MM`", s)))
+        };
+        let (start, end) = (parse(&window.start)?, parse(&window.end)?);
+        Ok(Self {
+            days: window.days.into_iter().map(Weekday::from).collect(),
+            start,
+            len: match end - start {
+                0 => DAY_SECS,
+                len if len < 0 => len + DAY_SECS,
+                len => len,
+            },
+            utc: window.utc,
+        })
+    }
+
+    fn contains(&self, now: NaiveDateTime) -> bool {
+        let since = now.num_seconds_from_midnight() as i64 - self.start;
+        if since >= 0 {
+            // Opened today
+            self.days.contains(&now.weekday()) && since < self.len
+        } else {
+            // Opened yesterday and crossing midnight
+            self.days.contains(&now.weekday().pred()) && since + DAY_SECS < self.len
+        }
+    }
+}
+
+/// A matcher that matches if the time now falls in any of the windows provided.
+pub struct Schedule {
+    windows: Vec<Span>,
+    clock: Box<dyn Fn() -> DateTime<Utc> + Send + Sync>,
+}
+
+impl Schedule {
+    /// Create a new `Schedule` matcher on the system clock.
+    pub fn new(windows: Vec<Window>) -> Result<Self> {
+        Self::with_clock(windows, Utc::now)
+    }
+
+    /// Create a new `Schedule` matcher telling the time with `clock` instead of the system clock.
+    pub fn with_clock(
+        windows: Vec<Window>,
+        clock: impl Fn() -> DateTime<Utc> + Send + Sync + 'static,
+    ) -> Result<Self> {
+        Ok(Self {
+            windows: windows.into_iter().map(Span::new).collect::<Result<_>>()?,
+            clock: Box::new(clock),
+        })
+    }
+}
+
+impl Matcher for Schedule {
+    fn matches(&self, _: &State) -> bool {
+        let now = (self.clock)();
+        self.windows.iter().any(|w| {
+            w.contains(if w.utc {
+                now.naive_utc()
+            } else {
+                now.with_timezone(&Local).naive_local()
+            })
+        })
+    }
+}
+
+/// A builder for schedule matcher plugin
+#[derive(Deserialize, Clone, Debug, PartialEq, Eq)]
+#[serde(transparent)]
+pub struct ScheduleBuilder(Vec<Window>);
+
+impl Default for ScheduleBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ScheduleBuilder {
+    /// Create an empty builder
+    pub fn new() -> Self {
+        Self(Vec::new())
+    }
+
+    /// Add a window of time to match
+    pub fn add_window(mut self, window: Window) -> Self {
+        self.0.push(window);
+        self
+    }
+}
+
+#[async_trait]
+impl AsyncTryInto<Schedule> for ScheduleBuilder {
+    type Error = MatchError;
+
+    async fn async_try_into(self) -> Result<Schedule> {
+        Schedule::new(self.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        super::{Matcher, State},
+        Day, Schedule, ScheduleBuilder, Window,
+    };
+    use crate::AsyncTryInto;
+    use chrono::{NaiveDate, TimeZone, Utc};
+
+    fn window(days: &[Day], start: &str, end: &str) -> Window {
+        Window {
+            days: days.to_vec(),
+            start: start.to_string(),
+            end: end.to_string(),
+            utc: true,
+        }
+    }
+
+    // Whether the schedule matches at the time given, where 2024-01-01 is a Monday.
+    fn matches_at(windows: &[Window], day: u32, hour: u32, min: u32) -> bool {
+        let now = Utc.from_utc_datetime(
+            &NaiveDate::from_ymd_opt(2024, 1, day)
+                .unwrap()
+                .and_hms_opt(hour, min, 0)
+                .unwrap(),
+        );
+        Schedule::with_clock(windows.to_vec(), move || now)
+            .unwrap()
+            .matches(&State::default())
+    }
+
+    #[test]
+    fn same_day() {
+        let windows = [window(&[Day::Sat, Day::Sun], "09:30", "18:00")];
+        assert!(!matches_at(&windows, 6, 9, 29));
+        assert!(matches_at(&windows, 6, 9, 30));
+        assert!(matches_at(&windows, 7, 17, 59));
+        assert!(!matches_at(&windows, 7, 18, 0));
+        // Monday
+        assert!(!matches_at(&windows, 8, 12, 0));
+    }
+
+    #[test]
+    fn crossing_midnight() {
+        let windows = [window(
+            &[Day::Mon, Day::Tue, Day::Wed, Day::Thu, Day::Fri],
+            "22:00",
+            "07:00",
+        )];
+        // Monday
+        assert!(!matches_at(&windows, 1, 6, 59));
+        assert!(!matches_at(&windows, 1, 21, 59));
+        assert!(matches_at(&windows, 1, 22, 0));
+        // Early Tuesday belongs to the window opened on Monday
+        assert!(matches_at(&windows, 2, 0, 0));
+        assert!(matches_at(&windows, 2, 6, 59));
+        assert!(!matches_at(&windows, 2, 7, 0));
+        assert!(!matches_at(&windows, 2, 12, 0));
+        // Early Saturday belongs to the window opened on Friday, but not Saturday night
+        assert!(matches_at(&windows, 6, 3, 0));
+        assert!(!matches_at(&windows, 6, 23, 0));
+        assert!(!matches_at(&windows, 7, 3, 0));
+    }
+
+    #[test]
+    fn multiple_windows() {
+        let windows = [
+            window(&[Day::Wed], "12:00", "12:00"),
+            window(&[Day::Sun], "23:00", "01:00"),
+        ];
+        // The whole day from Wednesday noon
+        assert!(!matches_at(&windows, 3, 11, 59));
+        assert!(matches_at(&windows, 3, 12, 0));
+        assert!(matches_at(&windows, 4, 11, 59));
+        assert!(!matches_at(&windows, 4, 12, 0));
+        // Sunday night into Monday
+        assert!(matches_at(&windows, 7, 23, 30));
+        assert!(matches_at(&windows, 8, 0, 30));
+        assert!(!matches_at(&windows, 8, 1, 0));
+    }
+
+    #[tokio::test]
+    async fn bad_time() {
+        for (start, end) in [("25:00", "07:00"), ("22:00", "7"), ("", "07:00")] {
+            assert!(ScheduleBuilder::new()
+                .add_window(window(&[Day::Mon], start, end))
+                .async_try_into()
+                .await
+                .is_err());
+        }
+    }
+}
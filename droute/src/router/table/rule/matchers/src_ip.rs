@@ -0,0 +1,142 @@
+// Copyright 2020 LEXUGE
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use super::{super::super::State, MatchError, Matcher, Result};
+use crate::AsyncTryInto;
+use async_trait::async_trait;
+use cidr_utils::{cidr::IpCidr as Cidr, utils::IpCidrCombiner as CidrCombiner};
+use serde::Deserialize;
+
+/// A matcher that matches the IP address the query is sent from.
+pub struct SrcIp {
+    matcher: CidrCombiner,
+}
+
+impl SrcIp {
+    /// Create a new `SrcIp` matcher from a list of IP CIDRs.
+    pub fn new(cidrs: Vec<String>) -> Result<Self> {
+        let mut matcher = CidrCombiner::new();
+        for c in cidrs {
+            matcher.push(Cidr::from_str(&c)?);
+        }
+        Ok(Self { matcher })
+    }
+}
+
+impl Matcher for SrcIp {
+    fn matches(&self, state: &State) -> bool {
+        // Queries without a context (e.g. warm-up queries) have no source to match on.
+        state
+            .qctx
+            .as_ref()
+            .is_some_and(|qctx| self.matcher.contains(qctx.ip()))
+    }
+}
+
+/// A builder for SrcIp matcher plugin
+#[derive(Deserialize, Clone, Debug, PartialEq, Eq)]
+#[serde(transparent)]
+pub struct SrcIpBuilder(Vec<String>);
+
+impl Default for SrcIpBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl SrcIpBuilder {
+    /// Create an empty builder
+    pub fn new() -> Self {
+        SrcIpBuilder(Vec::new())
+    }
+
+    /// Add an IP CIDR like `192.168.0.0/16` to the matcher builder
+    pub fn add_cidr(mut self, s: impl ToString) -> Self {
+        self.0.push(s.to_string());
+        self
+    }
+}
+
+#[async_trait]
+impl AsyncTryInto<SrcIp> for SrcIpBuilder {
+    type Error = MatchError;
+
+    async fn async_try_into(self) -> Result<SrcIp> {
+        SrcIp::new(self.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        super::{Matcher, State},
+        SrcIpBuilder,
+    };
+    use crate::{AsyncTryInto, Protocol, QueryContext};
+
+    fn create_state(src: &str) -> State {
+        State {
+            qctx: Some(QueryContext::new(src.parse().unwrap(), Protocol::Udp)),
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn ipv4() {
+        let matcher = SrcIpBuilder::new()
+            .add_cidr("192.168.3.0/24")
+            .add_cidr("10.0.0.1")
+            .async_try_into()
+            .await
+            .unwrap();
+        assert!(matcher.matches(&create_state("192.168.3.7:53")));
+        assert!(matcher.matches(&create_state("10.0.0.1:5353")));
+        assert!(!matcher.matches(&create_state("192.168.4.7:53")));
+        assert!(!matcher.matches(&create_state("[fd00::1]:53")));
+    }
+
+    #[tokio::test]
+    async fn ipv6() {
+        let matcher = SrcIpBuilder::new()
+            .add_cidr("fd00::/8")
+            .async_try_into()
+            .await
+            .unwrap();
+        assert!(matcher.matches(&create_state("[fd12:3456::1]:53")));
+        assert!(!matcher.matches(&create_state("[2001:db8::1]:53")));
+        assert!(!matcher.matches(&create_state("192.168.3.7:53")));
+    }
+
+    #[tokio::test]
+    async fn no_context() {
+        let matcher = SrcIpBuilder::new()
+            .add_cidr("0.0.0.0/0")
+            .add_cidr("::/0")
+            .async_try_into()
+            .await
+            .unwrap();
+        assert!(matcher.matches(&create_state("127.0.0.1:53")));
+        assert!(!matcher.matches(&State::default()));
+    }
+
+    #[tokio::test]
+    async fn malformatted() {
+        assert!(SrcIpBuilder::new()
+            .add_cidr("192.168.3.0/33")
+            .async_try_into()
+            .await
+            .is_err());
+    }
+}
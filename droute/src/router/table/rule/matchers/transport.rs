@@ -0,0 +1,110 @@
+// Copyright 2020 LEXUGE
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use super::{
+    super::super::{Protocol, State},
+    MatchError, Matcher, Result,
+};
+use crate::AsyncTryInto;
+use async_trait::async_trait;
+use serde::Deserialize;
+use std::collections::HashSet;
+
+/// A matcher that matches if the query is received over any of the transport protocols provided.
+/// Queries without a context never match.
+pub struct Transport(HashSet<Protocol>);
+
+impl Transport {
+    /// Create a new `Transport` matcher.
+    pub fn new(protocols: HashSet<Protocol>) -> Result<Self> {
+        Ok(Self(protocols))
+    }
+}
+
+impl Matcher for Transport {
+    fn matches(&self, state: &State) -> bool {
+        state
+            .qctx
+            .as_ref()
+            .is_some_and(|qctx| self.0.contains(&qctx.protocol))
+    }
+}
+
+/// A builder for transport protocol matcher plugin
+#[derive(Deserialize, Clone, Debug, PartialEq, Eq)]
+#[serde(transparent)]
+pub struct TransportBuilder(HashSet<Protocol>);
+
+impl Default for TransportBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl TransportBuilder {
+    /// Create an empty builder
+    pub fn new() -> Self {
+        Self(HashSet::new())
+    }
+
+    /// Add a transport protocol to match
+    pub fn add_protocol(mut self, protocol: Protocol) -> Self {
+        self.0.insert(protocol);
+        self
+    }
+}
+
+#[async_trait]
+impl AsyncTryInto<Transport> for TransportBuilder {
+    type Error = MatchError;
+
+    async fn async_try_into(self) -> Result<Transport> {
+        Transport::new(self.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        super::{Matcher, State},
+        TransportBuilder,
+    };
+    use crate::{AsyncTryInto, Protocol, QueryContext};
+
+    fn create_state(protocol: Protocol) -> State {
+        State {
+            qctx: Some(QueryContext::new(
+                "192.168.1.1:5353".parse().unwrap(),
+                protocol,
+            )),
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn test() {
+        let matcher = TransportBuilder::new()
+            .add_protocol(Protocol::Tcp)
+            .add_protocol(Protocol::Dot)
+            .async_try_into()
+            .await
+            .unwrap();
+        assert!(matcher.matches(&create_state(Protocol::Tcp)));
+        assert!(matcher.matches(&create_state(Protocol::Dot)));
+        assert!(!matcher.matches(&create_state(Protocol::Udp)));
+        assert!(!matcher.matches(&create_state(Protocol::Doh)));
+        assert!(!matcher.matches(&State::default()));
+    }
+}
@@ -23,67 +23,147 @@ pub mod builders;
 pub mod matchers;
 
 use self::{actions::Action, matchers::Matcher};
-use super::{super::upstreams::Upstreams, Result, State};
-use crate::Label;
+use super::{
+    super::upstreams::Upstreams,
+    describe::{BranchDescription, RuleDescription},
+    Result, State,
+};
+use crate::{preflight::Resource, Label};
 use async_trait::async_trait;
 use bytes::Bytes;
 use domain::base::Dname;
 use log::*;
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+
+/// Where to route after a rule block.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+#[serde(untagged)]
+pub enum Next {
+    /// Go to the rule with the tag. Within a called chain, `end` returns to the caller instead of finishing the routing.
+    Goto(#[cfg_attr(feature = "schema", schemars(with = "String"))] Label),
+
+    /// Route through the chain starting at `call`. Once the chain reaches `end`, continue at `then`.
+    Call {
+        /// The first rule of the chain called
+        #[cfg_attr(feature = "schema", schemars(with = "String"))]
+        call: Label,
+        /// The rule to return to
+        #[cfg_attr(feature = "schema", schemars(with = "String"))]
+        then: Label,
+    },
+}
+
+impl<T: Into<Label>> From<T> for Next {
+    fn from(tag: T) -> Self {
+        Self::Goto(tag.into())
+    }
+}
 
 /// Rule block abstraction
 #[async_trait]
 pub trait Rule: Send + Sync {
     // `name` refers to the name of the Rule itself
-    /// Returns the label of the next rule.
-    async fn route(
-        &self,
+    /// Returns where to route next.
+    async fn route<'a>(
+        &'a self,
         tag: &str,
         state: &mut State,
         upstreams: &Upstreams,
         name: &Dname<Bytes>,
-    ) -> Result<&Label>;
+    ) -> Result<&'a Next>;
 
     /// Possible destinations of this rule block
     // TODO: Can we change it to a more cost friendly version?
-    fn dsts(&self) -> Vec<Label>;
+    fn dsts(&self) -> Vec<Next>;
 
     /// Possibly used upstream tags
     fn used_upstreams(&self) -> Vec<Label>;
+
+    /// The time budget of this rule block, counting from when routing enters it. It covers the chains it calls as well. Default to `None`, which is unlimited.
+    fn deadline(&self) -> Option<Duration> {
+        None
+    }
+
+    /// Resources loaded by the matchers of this rule block. Default to none.
+    fn resources(&self) -> Vec<Resource> {
+        Vec::new()
+    }
+
+    /// A human-readable description of this rule block with the tag given. By default, it only tells the destinations.
+    fn describe(&self, tag: &str) -> RuleDescription {
+        RuleDescription {
+            tag: tag.into(),
+            kind: "custom".to_string(),
+            expr: None,
+            branches: self
+                .dsts()
+                .into_iter()
+                .map(|next| BranchDescription {
+                    name: "next".to_string(),
+                    expr: None,
+                    actions: Vec::new(),
+                    next,
+                })
+                .collect(),
+        }
+    }
 }
 
 /// Sequence
 pub struct SeqBlock {
     // In the form of (Action, Next)
-    acts: (Vec<Box<dyn Action>>, Label),
+    acts: (Vec<Box<dyn Action>>, Next),
 }
 
 impl SeqBlock {
-    pub fn new(acts: (Vec<Box<dyn Action>>, Label)) -> Self {
+    pub fn new(acts: (Vec<Box<dyn Action>>, Next)) -> Self {
         Self { acts }
     }
 }
 
 #[async_trait]
 impl Rule for SeqBlock {
-    async fn route(
-        &self,
+    async fn route<'a>(
+        &'a self,
         tag: &str,
         state: &mut State,
         upstreams: &Upstreams,
         name: &Dname<Bytes>,
-    ) -> Result<&Label> {
-        info!("rule `{}` starts with domain \"{}\"", tag, name);
+    ) -> Result<&'a Next> {
+        info!(
+            "query {}: rule `{}` starts with domain \"{}\"",
+            state.id(),
+            tag,
+            name
+        );
         for action in &self.acts.0 {
             action.act(state, upstreams).await?;
+            state.trace_action(action.as_ref());
         }
-        info!("rule `{}` ends with domain \"{}\"", tag, name);
+        info!(
+            "query {}: rule `{}` ends with domain \"{}\"",
+            state.id(),
+            tag,
+            name
+        );
         Ok(&self.acts.1)
     }
 
-    fn dsts(&self) -> Vec<Label> {
+    fn dsts(&self) -> Vec<Next> {
         vec![self.acts.1.clone()]
     }
 
+    fn describe(&self, tag: &str) -> RuleDescription {
+        RuleDescription {
+            tag: tag.into(),
+            kind: "seq".to_string(),
+            expr: None,
+            branches: vec![BranchDescription::new("then", None, &self.acts)],
+        }
+    }
+
     fn used_upstreams(&self) -> Vec<Label> {
         let mut h = Vec::new();
         self.acts.0.iter().for_each(|a| {
@@ -98,9 +178,12 @@ impl Rule for SeqBlock {
 /// If-like control flow rule
 pub struct IfBlock {
     matcher: Box<dyn Matcher>,
+    // The expression the matcher was built from, used for tracing.
+    expr: Option<String>,
     // In the form of (Action, Next)
-    on_match: (Vec<Box<dyn Action>>, Label),
-    no_match: (Vec<Box<dyn Action>>, Label),
+    on_match: (Vec<Box<dyn Action>>, Next),
+    no_match: (Vec<Box<dyn Action>>, Next),
+    deadline: Option<Duration>,
 }
 
 impl IfBlock {
@@ -109,15 +192,29 @@ impl IfBlock {
     /// - `on_match` and `no_match`: A sequence of actions to take and what the next rule is based on if it matches or not.
     pub fn new(
         matcher: Box<dyn Matcher>,
-        on_match: (Vec<Box<dyn Action>>, Label),
-        no_match: (Vec<Box<dyn Action>>, Label),
+        on_match: (Vec<Box<dyn Action>>, Next),
+        no_match: (Vec<Box<dyn Action>>, Next),
     ) -> Self {
         Self {
             matcher,
+            expr: None,
             on_match,
             no_match,
+            deadline: None,
         }
     }
+
+    /// Attach the expression the matcher was built from, which shows up in route traces.
+    pub fn with_expr(mut self, expr: impl ToString) -> Self {
+        self.expr = Some(expr.to_string());
+        self
+    }
+
+    /// Limit the time routing may spend in this rule and the chains it calls.
+    pub fn with_deadline(mut self, deadline: Duration) -> Self {
+        self.deadline = Some(deadline);
+        self
+    }
 }
 
 #[async_trait]
@@ -136,42 +233,214 @@ impl Rule for IfBlock {
         h
     }
 
-    async fn route(
-        &self,
+    async fn route<'a>(
+        &'a self,
         tag: &str,
         state: &mut State,
         upstreams: &Upstreams,
         name: &Dname<Bytes>,
-    ) -> Result<&Label> {
-        if self.matcher.matches(state) {
-            info!("domain \"{}\" matches at rule `{}`", name, tag);
+    ) -> Result<&'a Next> {
+        let matched = self.matcher.matches(state);
+        state.record_match(self.expr.as_deref(), matched);
+        if matched {
+            info!(
+                "query {}: domain \"{}\" matches at rule `{}`",
+                state.id(),
+                name,
+                tag
+            );
             for action in &self.on_match.0 {
                 action.act(state, upstreams).await?;
+                state.trace_action(action.as_ref());
             }
             Ok(&self.on_match.1)
         } else {
-            info!("Domain \"{}\" doesn't match at rule `{}`", name, tag);
+            info!(
+                "query {}: domain \"{}\" doesn't match at rule `{}`",
+                state.id(),
+                name,
+                tag
+            );
             for action in &self.no_match.0 {
                 action.act(state, upstreams).await?;
+                state.trace_action(action.as_ref());
             }
             Ok(&self.no_match.1)
         }
     }
 
-    fn dsts(&self) -> Vec<Label> {
+    fn dsts(&self) -> Vec<Next> {
         vec![self.on_match.1.clone(), self.no_match.1.clone()]
     }
+
+    fn deadline(&self) -> Option<Duration> {
+        self.deadline
+    }
+
+    fn resources(&self) -> Vec<Resource> {
+        self.matcher.resources()
+    }
+
+    fn describe(&self, tag: &str) -> RuleDescription {
+        RuleDescription {
+            tag: tag.into(),
+            kind: "if".to_string(),
+            expr: self.expr.clone(),
+            branches: vec![
+                BranchDescription::new("on_match", None, &self.on_match),
+                BranchDescription::new("no_match", None, &self.no_match),
+            ],
+        }
+    }
+}
+
+/// Multi-branch control flow rule. Arms are evaluated in order and the first one matches is taken.
+pub struct SwitchBlock {
+    // In the form of (Matcher, Expression, (Action, Next))
+    #[allow(clippy::type_complexity)]
+    arms: Vec<(
+        Box<dyn Matcher>,
+        Option<String>,
+        (Vec<Box<dyn Action>>, Next),
+    )>,
+    default: (Vec<Box<dyn Action>>, Next),
+    deadline: Option<Duration>,
+}
+
+impl SwitchBlock {
+    /// Create a switch-like `Rule` directly.
+    /// - `arms`: Pairs of matcher and the branch to take if the matcher matches, evaluated in order.
+    /// - `default`: The branch to take if none of the arms matches.
+    #[allow(clippy::type_complexity)]
+    pub fn new(
+        arms: Vec<(Box<dyn Matcher>, (Vec<Box<dyn Action>>, Next))>,
+        default: (Vec<Box<dyn Action>>, Next),
+    ) -> Self {
+        Self {
+            arms: arms.into_iter().map(|(m, b)| (m, None, b)).collect(),
+            default,
+            deadline: None,
+        }
+    }
+
+    /// Limit the time routing may spend in this rule and the chains it calls.
+    pub fn with_deadline(mut self, deadline: Duration) -> Self {
+        self.deadline = Some(deadline);
+        self
+    }
+
+    /// Attach the expressions the matchers were built from in the order of arms, which show up in route traces.
+    pub fn with_exprs(mut self, exprs: Vec<String>) -> Self {
+        self.arms
+            .iter_mut()
+            .zip(exprs)
+            .for_each(|(arm, expr)| arm.1 = Some(expr));
+        self
+    }
+}
+
+#[async_trait]
+impl Rule for SwitchBlock {
+    async fn route<'a>(
+        &'a self,
+        tag: &str,
+        state: &mut State,
+        upstreams: &Upstreams,
+        name: &Dname<Bytes>,
+    ) -> Result<&'a Next> {
+        let (acts, next) = match self.arms.iter().position(|(m, _, _)| m.matches(state)) {
+            Some(n) => {
+                info!(
+                    "query {}: domain \"{}\" matches arm {} at rule `{}`",
+                    state.id(),
+                    name,
+                    n,
+                    tag
+                );
+                state.record_match(self.arms[n].1.as_deref(), true);
+                &self.arms[n].2
+            }
+            None => {
+                info!(
+                    "query {}: domain \"{}\" doesn't match any arm at rule `{}`, taking default",
+                    state.id(),
+                    name,
+                    tag
+                );
+                state.record_match(None, false);
+                &self.default
+            }
+        };
+        for action in acts {
+            action.act(state, upstreams).await?;
+            state.trace_action(action.as_ref());
+        }
+        Ok(next)
+    }
+
+    fn dsts(&self) -> Vec<Next> {
+        self.arms
+            .iter()
+            .map(|(_, _, (_, next))| next.clone())
+            .chain(std::iter::once(self.default.1.clone()))
+            .collect()
+    }
+
+    fn deadline(&self) -> Option<Duration> {
+        self.deadline
+    }
+
+    fn resources(&self) -> Vec<Resource> {
+        self.arms
+            .iter()
+            .flat_map(|(m, _, _)| m.resources())
+            .collect()
+    }
+
+    fn used_upstreams(&self) -> Vec<Label> {
+        self.arms
+            .iter()
+            .map(|(_, _, b)| b)
+            .chain(std::iter::once(&self.default))
+            .flat_map(|(acts, _)| acts.iter().filter_map(|a| a.used_upstream()))
+            .collect()
+    }
+
+    fn describe(&self, tag: &str) -> RuleDescription {
+        RuleDescription {
+            tag: tag.into(),
+            kind: "switch".to_string(),
+            expr: None,
+            branches: self
+                .arms
+                .iter()
+                .enumerate()
+                .map(|(n, (_, expr, b))| {
+                    BranchDescription::new(format!("arm {}", n), expr.as_deref(), b)
+                })
+                .chain(std::iter::once(BranchDescription::new(
+                    "default",
+                    None,
+                    &self.default,
+                )))
+                .collect(),
+        }
+    }
 }
 
 // TODO: Add an sequence rule
 
 #[cfg(test)]
 mod tests {
-    use bytes::Bytes;
-    use domain::base::{Dname, Message};
+    use bytes::{Bytes, BytesMut};
+    use domain::base::{iana::Opcode, Dname, Message, MessageBuilder, Rtype};
+    use std::str::FromStr;
 
-    use super::super::{State, Upstreams};
-    use crate::{builders::*, AsyncTryInto};
+    use super::{
+        super::{State, Upstreams},
+        Next,
+    };
+    use crate::{builders::*, AsyncTryInto, MAX_LEN};
 
     #[tokio::test]
     async fn ifblock() {
@@ -189,7 +458,7 @@ mod tests {
                 &mut State {
                     resp: Message::from_octets(Bytes::from_static(&[0_u8; 55])).unwrap(),
                     query: Message::from_octets(Bytes::from_static(&[0_u8; 55])).unwrap(),
-                    qctx: None,
+                    ..Default::default()
                 },
                 &Upstreams::new(
                     vec![].into_iter().collect(),
@@ -199,9 +468,8 @@ mod tests {
                 &Dname::root_bytes()
             )
             .await
-            .unwrap()
-            .as_ref(),
-            "yes"
+            .unwrap(),
+            &Next::from("yes")
         );
     }
 
@@ -219,7 +487,7 @@ mod tests {
                 &mut State {
                     resp: Message::from_octets(Bytes::from_static(&[0_u8; 55])).unwrap(),
                     query: Message::from_octets(Bytes::from_static(&[0_u8; 55])).unwrap(),
-                    qctx: None,
+                    ..Default::default()
                 },
                 &Upstreams::new(
                     vec![].into_iter().collect(),
@@ -229,9 +497,117 @@ mod tests {
                 &Dname::root_bytes()
             )
             .await
-            .unwrap()
-            .as_ref(),
-            "yes"
+            .unwrap(),
+            &Next::from("yes")
+        );
+    }
+
+    #[tokio::test]
+    async fn switch() {
+        let rule: RuleBuilders<BuiltinMatcherBuilders, BuiltinActionBuilders> = ron::from_str(
+            r#"(switch: [(if: "false", then: ["no"]), (if: "true", then: ["yes"]), (if: "true", then: ["shadowed"])], default: ["default"])"#,
+        )
+        .unwrap();
+        let rule = rule.async_try_into().await.unwrap();
+        assert_eq!(
+            rule.dsts(),
+            vec!["no", "yes", "shadowed", "default"]
+                .into_iter()
+                .map(Next::from)
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(
+            rule.route(
+                "mock", // This doesn't matter
+                &mut State {
+                    resp: Message::from_octets(Bytes::from_static(&[0_u8; 55])).unwrap(),
+                    query: Message::from_octets(Bytes::from_static(&[0_u8; 55])).unwrap(),
+                    ..Default::default()
+                },
+                &Upstreams::new(
+                    vec![].into_iter().collect(),
+                    std::num::NonZeroUsize::new(1).unwrap()
+                )
+                .unwrap(),
+                &Dname::root_bytes()
+            )
+            .await
+            .unwrap(),
+            &Next::from("yes")
         );
     }
+
+    fn create_query(name: &str, qtype: Rtype, opcode: Opcode) -> Message<Bytes> {
+        let mut builder = MessageBuilder::from_target(BytesMut::with_capacity(MAX_LEN))
+            .unwrap()
+            .question();
+        builder.header_mut().set_opcode(opcode);
+        builder
+            .push((Dname::<Bytes>::from_str(name).unwrap(), qtype))
+            .unwrap();
+        builder.into_message()
+    }
+
+    // Route the query through an if block on the expression given.
+    async fn route_if(expr: &str, query: Message<Bytes>) -> Next {
+        let rule = RuleBuilders::IfBlock(IfBlockBuilder::<BuiltinMatcherBuilders, _>::new(
+            expr,
+            BranchBuilder::<BuiltinActionBuilders>::new("yes"),
+            BranchBuilder::<BuiltinActionBuilders>::new("no"),
+        ))
+        .async_try_into()
+        .await
+        .unwrap();
+        rule.route(
+            "mock", // This doesn't matter
+            &mut State {
+                resp: query.clone(),
+                query,
+                ..Default::default()
+            },
+            &Upstreams::new(
+                vec![].into_iter().collect(),
+                std::num::NonZeroUsize::new(1).unwrap(),
+            )
+            .unwrap(),
+            &Dname::root_bytes(),
+        )
+        .await
+        .unwrap()
+        .clone()
+    }
+
+    #[tokio::test]
+    async fn opcode() {
+        for (opcode, next) in [(Opcode::Update, "yes"), (Opcode::Query, "no")] {
+            assert_eq!(
+                route_if(
+                    "opcode([NOTIFY, UPDATE])",
+                    create_query("example.com", Rtype::Soa, opcode)
+                )
+                .await,
+                Next::from(next)
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn inline_domains() {
+        for (name, next) in [
+            ("example.com", "yes"),
+            ("www.corp.internal", "yes"),
+            ("xn--r8jz45g.jp", "yes"),
+            ("example.org", "no"),
+            ("internal", "no"),
+        ] {
+            assert_eq!(
+                route_if(
+                    r#"domain(["example.com", "corp.internal", "例え.jp"])"#,
+                    create_query(name, Rtype::A, Opcode::Query)
+                )
+                .await,
+                Next::from(next)
+            );
+        }
+    }
 }
@@ -0,0 +1,66 @@
+// Copyright 2020 LEXUGE
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Per-rule statistics of the routing table.
+
+use serde::{Deserialize, Serialize};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// A snapshot of the counters of a single rule.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct RuleStats {
+    /// Number of times the rule was entered.
+    pub evaluations: u64,
+    /// Number of times the `then` branch was taken. Always zero for rules without a matcher.
+    pub on_match: u64,
+    /// Number of times the `else` branch was taken. Always zero for rules without a matcher.
+    pub no_match: u64,
+}
+
+// Counters are only for statistics, relaxed ordering is fine.
+#[derive(Default)]
+pub(super) struct RuleCounters {
+    evaluations: AtomicU64,
+    on_match: AtomicU64,
+    no_match: AtomicU64,
+}
+
+impl RuleCounters {
+    pub fn evaluated(&self) {
+        self.evaluations.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn matched(&self, matched: bool) {
+        if matched {
+            self.on_match.fetch_add(1, Ordering::Relaxed);
+        } else {
+            self.no_match.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    pub fn snapshot(&self) -> RuleStats {
+        RuleStats {
+            evaluations: self.evaluations.load(Ordering::Relaxed),
+            on_match: self.on_match.load(Ordering::Relaxed),
+            no_match: self.no_match.load(Ordering::Relaxed),
+        }
+    }
+
+    pub fn reset(&self) {
+        self.evaluations.store(0, Ordering::Relaxed);
+        self.on_match.store(0, Ordering::Relaxed);
+        self.no_match.store(0, Ordering::Relaxed);
+    }
+}
@@ -0,0 +1,74 @@
+// Copyright 2020 LEXUGE
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Trace of the path a query took through the routing table.
+
+use crate::{Label, RespSource};
+use serde::{Deserialize, Serialize};
+
+/// The evaluation result of the matcher of a rule.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct MatcherTrace {
+    /// The matching expression, if the rule was built from one.
+    pub expr: Option<String>,
+    /// Whether the matcher matched.
+    pub result: bool,
+}
+
+/// An action executed.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct ActionTrace {
+    /// The upstream the action used, if any.
+    pub upstream: Option<Label>,
+    /// Where the response came from if the action used an upstream.
+    pub source: Option<RespSource>,
+    /// The response code after the action was executed.
+    pub rcode: String,
+}
+
+/// A single rule the query went through.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct TraceStep {
+    /// Tag of the rule.
+    pub tag: Label,
+    /// The matcher evaluated, if the rule has one.
+    pub matcher: Option<MatcherTrace>,
+    /// Actions executed in order.
+    pub actions: Vec<ActionTrace>,
+    /// Time spent in this rule in microseconds.
+    pub elapsed_us: u64,
+}
+
+impl TraceStep {
+    pub(super) fn new(tag: impl Into<Label>) -> Self {
+        Self {
+            tag: tag.into(),
+            matcher: None,
+            actions: Vec::new(),
+            elapsed_us: 0,
+        }
+    }
+}
+
+/// The trace of a query routed through the table.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq, Eq)]
+pub struct RouteTrace {
+    /// Rules the query went through in order.
+    pub steps: Vec<TraceStep>,
+    /// Number of steps counted against the maximum number of steps.
+    pub step_count: usize,
+    /// The error that stopped the routing, if any.
+    pub error: Option<String>,
+}
@@ -19,22 +19,32 @@ use super::{
     error::{Result, UpstreamError},
     QHandleError, Upstreams,
 };
-use crate::{AsyncTryInto, Label, Upstream};
+use crate::{
+    build_all, error::ConfigError, AsyncTryInto, Label, Upstream, DEFAULT_MAX_CONCURRENT_BUILDS,
+};
 use async_trait::async_trait;
-use serde::{Deserialize, Serialize};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
 use std::{collections::HashMap, num::NonZeroUsize};
 
 fn default_cache_size() -> NonZeroUsize {
     NonZeroUsize::new(2048).unwrap()
 }
 
+fn default_max_concurrent_builds() -> usize {
+    DEFAULT_MAX_CONCURRENT_BUILDS
+}
+
 #[derive(Serialize, Deserialize, Clone)]
-#[serde(rename_all = "lowercase")]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+#[serde(rename_all = "lowercase", deny_unknown_fields)]
 /// The Builder for upstreams
 pub struct UpstreamsBuilder<U: AsyncTryInto<Upstream, Error = QHandleError>> {
+    #[cfg_attr(feature = "schema", schemars(with = "HashMap<String, U>"))]
     upstreams: HashMap<Label, U>,
     #[serde(default = "default_cache_size")]
     cache_size: NonZeroUsize,
+    #[serde(skip, default = "default_max_concurrent_builds")]
+    max_concurrent_builds: usize,
 }
 
 impl<U: AsyncTryInto<Upstream, Error = QHandleError>> UpstreamsBuilder<U> {
@@ -43,6 +53,7 @@ impl<U: AsyncTryInto<Upstream, Error = QHandleError>> UpstreamsBuilder<U> {
         Self {
             upstreams: upstreams.into_iter().map(|(k, v)| (k.into(), v)).collect(),
             cache_size,
+            max_concurrent_builds: DEFAULT_MAX_CONCURRENT_BUILDS,
         }
     }
 
@@ -51,6 +62,7 @@ impl<U: AsyncTryInto<Upstream, Error = QHandleError>> UpstreamsBuilder<U> {
         std::num::NonZeroUsize::new(cache_size).map(|c| Self {
             upstreams: HashMap::new(),
             cache_size: c,
+            max_concurrent_builds: DEFAULT_MAX_CONCURRENT_BUILDS,
         })
     }
 
@@ -59,6 +71,37 @@ impl<U: AsyncTryInto<Upstream, Error = QHandleError>> UpstreamsBuilder<U> {
         self.upstreams.insert(tag.into(), upstream);
         self
     }
+
+    /// Set the maximum number of upstreams built at the same time. Default to `DEFAULT_MAX_CONCURRENT_BUILDS`.
+    pub fn with_max_concurrent_builds(mut self, max: NonZeroUsize) -> Self {
+        self.max_concurrent_builds = max.get();
+        self
+    }
+}
+
+impl<U: AsyncTryInto<Upstream, Error = QHandleError> + Clone> UpstreamsBuilder<U> {
+    // Build every upstream on its own without consuming the builder, pairing each result with its tag.
+    pub(crate) async fn build_each(
+        &self,
+    ) -> Vec<(Label, std::result::Result<Upstream, QHandleError>)> {
+        build_all(self.upstreams.clone(), self.max_concurrent_builds).await
+    }
+
+    pub(crate) fn cache_size(&self) -> NonZeroUsize {
+        self.cache_size
+    }
+}
+
+impl<U: AsyncTryInto<Upstream, Error = QHandleError> + DeserializeOwned> UpstreamsBuilder<U> {
+    /// Parse from YAML in the form of `upstreams` and the optional `cache_size` fields of the `dcompass` configuration.
+    pub fn from_yaml(s: &str) -> std::result::Result<Self, ConfigError> {
+        serde_yaml::from_str(s).map_err(ConfigError::yaml("upstreams"))
+    }
+
+    /// Parse from JSON in the same form as `from_yaml`.
+    pub fn from_json(s: &str) -> std::result::Result<Self, ConfigError> {
+        serde_json::from_str(s).map_err(ConfigError::json("upstreams"))
+    }
 }
 
 #[async_trait]
@@ -67,12 +110,29 @@ impl<U: AsyncTryInto<Upstream, Error = QHandleError>> AsyncTryInto<Upstreams>
 {
     type Error = UpstreamError;
 
-    /// Build the Upstreams from an UpstreamsBuilder
+    /// Build the Upstreams from an UpstreamsBuilder. Upstreams are built concurrently, and every one failed to build is reported.
     async fn async_try_into(self) -> Result<Upstreams> {
         let mut v = HashMap::new();
-        for (tag, u) in self.upstreams {
-            v.insert(tag, u.async_try_into().await?);
+        let mut failed = Vec::new();
+        for (tag, u) in build_all(self.upstreams, self.max_concurrent_builds).await {
+            match u {
+                Ok(u) => {
+                    v.insert(tag, u);
+                }
+                Err(e) => failed.push((tag, e)),
+            }
+        }
+        // A sole error is kept as it is.
+        if failed.len() == 1 {
+            return Err(failed.pop().unwrap().1.into());
         }
+        failed.sort_by(|a, b| a.0.cmp(&b.0));
+        UpstreamError::collect(
+            failed
+                .into_iter()
+                .map(|(tag, e)| UpstreamError::InUpstream(tag, Box::new(e.into())))
+                .collect(),
+        )?;
         Upstreams::new(v, self.cache_size)
     }
 }
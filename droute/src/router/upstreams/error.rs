@@ -14,7 +14,7 @@
 // along with this program.  If not, see <http://www.gnu.org/licenses/>.
 
 use super::upstream::QHandleError;
-use crate::Label;
+use crate::{error::numbered, Label};
 use std::{collections::HashSet, fmt::Debug};
 use thiserror::Error;
 
@@ -39,7 +39,26 @@ pub enum UpstreamError {
     #[error(transparent)]
     QHandleError(#[from] QHandleError),
 
+    /// The upstream with the tag failed to build.
+    #[error("The upstream with tag `{0}` failed to build: {1}")]
+    InUpstream(Label, #[source] Box<UpstreamError>),
+
     /// Some of the upstreams are unused.
     #[error("Some of the upstreams are not used: {0:?}")]
     UnusedUpstreams(HashSet<Label>),
+
+    /// More than one problem is found.
+    #[error("{}", numbered(.0))]
+    Multiple(Vec<UpstreamError>),
+}
+
+impl UpstreamError {
+    // Report the problems found, with a sole one kept as it is.
+    pub(super) fn collect(mut errors: Vec<Self>) -> Result<()> {
+        match errors.len() {
+            0 => Ok(()),
+            1 => Err(errors.pop().unwrap()),
+            _ => Err(Self::Multiple(errors)),
+        }
+    }
 }
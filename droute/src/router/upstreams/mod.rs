@@ -26,19 +26,30 @@ use bytes::Bytes;
 pub use upstream::*;
 
 use self::error::{Result, UpstreamError};
-use crate::{actions::CacheMode, cache::RespCache, Label, Validatable, ValidateCell};
+use crate::{
+    actions::CacheMode,
+    cache::{CacheCallback, RespCache},
+    Label, Validatable, ValidateCell,
+};
 use domain::base::Message;
 use futures::future::{select_ok, BoxFuture, FutureExt};
 use std::{
     collections::{HashMap, HashSet},
     num::NonZeroUsize,
+    sync::Arc,
+    time::Duration,
 };
+use tokio::time::Instant;
+
+// Invoked with the tag, the source of the response (`None` if failed), and the time spent, whenever a non-hybrid upstream is queried.
+pub(crate) type Observer = Arc<dyn Fn(&Label, Option<&RespSource>, Duration) + Send + Sync>;
 
 /// [`Upstream`] aggregated, used to create `Router`.
 pub struct Upstreams {
     upstreams: HashMap<Label, Upstream>,
     // All the responses are cached together, however, they are seperately tagged, so there should be no contamination in place.
     cache: RespCache,
+    observer: Option<Observer>,
 }
 
 impl Validatable for Upstreams {
@@ -50,9 +61,10 @@ impl Validatable for Upstreams {
             .iter()
             .map(|(k, v)| (k, (ValidateCell::default(), v)))
             .collect();
+        let mut errors = Vec::new();
         if let Some(u) = used {
             for tag in u {
-                Self::traverse(&mut bucket, tag)?
+                Self::traverse(&mut bucket, tag, &mut errors)
             }
         }
         let unused: HashSet<Label> = bucket
@@ -61,11 +73,10 @@ impl Validatable for Upstreams {
             .map(|(k, _)| k)
             .cloned()
             .collect();
-        if unused.is_empty() {
-            Ok(())
-        } else {
-            Err(UpstreamError::UnusedUpstreams(unused))
+        if !unused.is_empty() {
+            errors.push(UpstreamError::UnusedUpstreams(unused));
         }
+        UpstreamError::collect(errors)
     }
 }
 
@@ -75,55 +86,80 @@ impl Upstreams {
         let u = Self {
             upstreams,
             cache: RespCache::new(cache_size),
+            observer: None,
         };
         // Validate on the assumption that every upstream is gonna be used.
         u.validate(Some(&u.tags()))?;
         Ok(u)
     }
 
+    /// Register a callback invoked whenever a record leaves the response cache, either evicted, removed, or found expired beyond the stale window.
+    /// The callback is invoked after the cache lock is released.
+    pub fn set_cache_callback(&mut self, callback: CacheCallback) {
+        self.cache.set_callback(callback);
+    }
+
+    // Set the hook observing every query sent to non-hybrid upstreams.
+    pub(crate) fn set_observer(&mut self, observer: Observer) {
+        self.observer = Some(observer);
+    }
+
+    /// Remove the cached response of the query sent to the upstream `tag`. Returns whether there was such a record.
+    pub fn remove_cache(&self, tag: &Label, msg: &Message<Bytes>) -> bool {
+        self.cache.remove(tag, msg)
+    }
+
     /// Return the tags of all the upstreams.
     pub fn tags(&self) -> Vec<Label> {
         self.upstreams.keys().cloned().collect()
     }
 
-    // Check any upstream types
+    // Check any upstream types, collecting the problems found.
     fn traverse(
         bucket: &mut HashMap<&Label, (ValidateCell, &Upstream)>,
         tag: &Label,
-    ) -> Result<()> {
-        let (val, u) = if let Some((c, u)) = bucket.get_mut(tag) {
-            (c.val(), u.try_hybrid())
+        errors: &mut Vec<UpstreamError>,
+    ) {
+        let (val, used, u) = if let Some((c, u)) = bucket.get_mut(tag) {
+            (*c.val(), c.used(), u.try_hybrid())
         } else {
-            return Err(UpstreamError::MissingTag(tag.clone()));
+            if !errors
+                .iter()
+                .any(|e| matches!(e, UpstreamError::MissingTag(t) if t == tag))
+            {
+                errors.push(UpstreamError::MissingTag(tag.clone()));
+            }
+            return;
         };
-        if val < &1 {
+        if val >= 1 {
+            errors.push(UpstreamError::HybridRecursion(tag.clone()));
+        } else if !used {
+            // Upstreams fully traversed before are skipped, any recursion through them has already been found.
             bucket.get_mut(tag).unwrap().0.add(1);
-            // Check if it is empty.
             if let Some(v) = u {
+                // Check if it is empty.
                 if v.is_empty() {
-                    return Err(UpstreamError::EmptyHybrid(tag.clone()));
+                    errors.push(UpstreamError::EmptyHybrid(tag.clone()));
                 }
 
                 // Check if it is recursively defined.
                 for t in v {
-                    Self::traverse(bucket, t)?
+                    Self::traverse(bucket, t, errors)
                 }
             }
             bucket.get_mut(tag).unwrap().0.sub(1);
-        } else {
-            return Err(UpstreamError::HybridRecursion(tag.clone()));
-        };
-        Ok(())
+        }
     }
 
     // Write out in this way to allow recursion for async functions
     // Should no be accessible from external crates
+    // Along with the response, it returns the tag of the upstream which isn't a hybrid one that actually answered.
     pub(super) fn resolve<'a>(
         &'a self,
         tag: &'a Label,
         cache_mode: &'a CacheMode,
         msg: &'a Message<Bytes>,
-    ) -> BoxFuture<'a, Result<Message<Bytes>>> {
+    ) -> BoxFuture<'a, Result<(Message<Bytes>, RespSource, Label)>> {
         async move {
             let u = self.upstreams.get(tag).unwrap();
             Ok(if let Some(v) = u.try_hybrid() {
@@ -132,7 +168,24 @@ impl Upstreams {
                 let (r, _) = select_ok(v).await?;
                 r
             } else {
-                u.resolve(tag, &self.cache, cache_mode, msg).await?
+                let start = Instant::now();
+                let r = u.resolve(tag, &self.cache, cache_mode, msg).await;
+                if let Some(observer) = &self.observer {
+                    observer(tag, r.as_ref().ok().map(|(_, s)| s), start.elapsed());
+                }
+                event!(
+                    upstream = %tag,
+                    outcome = %match &r {
+                        Ok((_, RespSource::Cache)) => "cache".to_string(),
+                        Ok((_, RespSource::StaleCache)) => "stale".to_string(),
+                        Ok((_, RespSource::Upstream(_))) => "upstream".to_string(),
+                        Err(e) => e.to_string(),
+                    },
+                    elapsed_us = start.elapsed().as_micros() as u64,
+                    "upstream attempt"
+                );
+                let (r, source) = r?;
+                (r, source, tag.clone())
             })
         }
         .boxed()
@@ -141,12 +194,101 @@ impl Upstreams {
 
 #[cfg(test)]
 mod tests {
-    use crate::AsyncTryInto;
+    use crate::{AsyncTryInto, MAX_TTL};
 
     use super::{
-        builder::{HybridBuilder, UdpBuilder, UpstreamBuilder, UpstreamsBuilder},
-        UpstreamError,
+        builder::{CacheSettings, HybridBuilder, UdpBuilder, UpstreamBuilder, UpstreamsBuilder},
+        QHandleError, Upstream, UpstreamError, Upstreams,
     };
+    use async_trait::async_trait;
+    use std::{num::NonZeroUsize, time::Duration};
+    use tokio::time::Instant;
+
+    // Builds the upstream after a delay in milliseconds, or fails after the delay if there is none.
+    struct Slow(u64, Option<UpstreamBuilder>);
+
+    #[async_trait]
+    impl AsyncTryInto<Upstream> for Slow {
+        type Error = QHandleError;
+
+        async fn async_try_into(self) -> Result<Upstream, QHandleError> {
+            tokio::time::sleep(Duration::from_millis(self.0)).await;
+            match self.1 {
+                Some(u) => u.async_try_into().await,
+                None => Err(QHandleError::IoError(std::io::Error::other(
+                    "failed to build",
+                ))),
+            }
+        }
+    }
+
+    fn udp() -> Option<UpstreamBuilder> {
+        Some(UpstreamBuilder::Udp(UdpBuilder {
+            addr: "127.0.0.1:53533".parse().unwrap(),
+            max_pool_size: 1,
+            timeout: 1,
+            ratelimit: None,
+            cache: CacheSettings::default(),
+        }))
+    }
+
+    #[test]
+    fn cache_settings_default() {
+        let settings: CacheSettings = ron::from_str("(max_stale_secs: 60)").unwrap();
+        assert_eq!(settings.max_ttl, MAX_TTL);
+        assert_eq!(settings.max_stale_secs, 60);
+
+        let settings: CacheSettings = ron::from_str("(max_ttl: 300)").unwrap();
+        assert_eq!(settings.max_ttl, 300);
+        assert_eq!(
+            settings,
+            CacheSettings {
+                max_ttl: 300,
+                ..Default::default()
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn cache_settings_flow() {
+        let upstreams: Upstreams = UpstreamsBuilder::new(1)
+            .unwrap()
+            .add_upstream(
+                "default",
+                UpstreamBuilder::Udp(UdpBuilder {
+                    addr: "127.0.0.1:53533".parse().unwrap(),
+                    max_pool_size: 1,
+                    timeout: 1,
+                    ratelimit: None,
+                    cache: CacheSettings::default(),
+                }),
+            )
+            .add_upstream(
+                "custom",
+                UpstreamBuilder::Udp(UdpBuilder {
+                    addr: "127.0.0.1:53533".parse().unwrap(),
+                    max_pool_size: 1,
+                    timeout: 1,
+                    ratelimit: None,
+                    cache: CacheSettings {
+                        max_ttl: 300,
+                        ..Default::default()
+                    },
+                }),
+            )
+            .async_try_into()
+            .await
+            .unwrap();
+
+        match upstreams.upstreams.get("default").unwrap() {
+            Upstream::Others(_, s) => assert_eq!(s.max_ttl, MAX_TTL),
+            _ => unreachable!(),
+        }
+        match upstreams.upstreams.get("custom").unwrap() {
+            Upstream::Others(_, s) => assert_eq!(s.max_ttl, 300),
+            _ => unreachable!(),
+        }
+    }
 
     #[tokio::test]
     async fn should_not_fail_recursion() {
@@ -161,6 +303,7 @@ mod tests {
                     max_pool_size: 32,
                     timeout: 1,
                     ratelimit: None,
+                    cache: CacheSettings::default(),
                 }),
             )
             .add_upstream(
@@ -188,6 +331,7 @@ mod tests {
                     max_pool_size: 256,
                     timeout: 1,
                     ratelimit: None,
+                    cache: CacheSettings::default(),
                 }),
             )
             .add_upstream(
@@ -207,4 +351,95 @@ mod tests {
             e => panic!("Not the right error type: {}", e),
         }
     }
+
+    #[tokio::test]
+    async fn fail_multiple() {
+        match UpstreamsBuilder::<UpstreamBuilder>::new(1)
+            .unwrap()
+            .add_upstream(
+                "hybrid1",
+                UpstreamBuilder::Hybrid(HybridBuilder::new().add_tag("missing")),
+            )
+            .add_upstream("hybrid2", UpstreamBuilder::Hybrid(HybridBuilder::new()))
+            .async_try_into()
+            .await
+            .err()
+            .unwrap()
+        {
+            UpstreamError::Multiple(v) => {
+                assert_eq!(v.len(), 2);
+                assert!(v
+                    .iter()
+                    .any(|e| matches!(e, UpstreamError::MissingTag(t) if t.as_str() == "missing")));
+                assert!(v.iter().any(
+                    |e| matches!(e, UpstreamError::EmptyHybrid(t) if t.as_str() == "hybrid2")
+                ));
+            }
+            e => panic!("Not the right error type: {}", e),
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn build_concurrently() {
+        let builder = || {
+            UpstreamsBuilder::new(1)
+                .unwrap()
+                .add_upstream("a", Slow(100, udp()))
+                .add_upstream("b", Slow(200, udp()))
+                .add_upstream("c", Slow(300, udp()))
+        };
+
+        let start = Instant::now();
+        let upstreams: Upstreams = builder().async_try_into().await.unwrap();
+        assert_eq!(upstreams.upstreams.len(), 3);
+        // As long as the slowest one rather than all of them together.
+        assert!(start.elapsed() < Duration::from_millis(350));
+
+        let start = Instant::now();
+        builder()
+            .with_max_concurrent_builds(NonZeroUsize::new(1).unwrap())
+            .async_try_into()
+            .await
+            .unwrap();
+        assert!(start.elapsed() >= Duration::from_millis(600));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn fail_multiple_builds() {
+        // `b` fails first, but the failures are reported in the order of tags.
+        match UpstreamsBuilder::new(1)
+            .unwrap()
+            .add_upstream("a", Slow(200, None))
+            .add_upstream("b", Slow(100, None))
+            .add_upstream("c", Slow(0, udp()))
+            .async_try_into()
+            .await
+            .err()
+            .unwrap()
+        {
+            UpstreamError::Multiple(v) => {
+                assert_eq!(v.len(), 2);
+                for (e, tag) in v.iter().zip(["a", "b"]) {
+                    assert!(
+                        matches!(e, UpstreamError::InUpstream(t, e) if t.as_str() == tag && matches!(**e, UpstreamError::QHandleError(_)))
+                    );
+                }
+            }
+            e => panic!("Not the right error type: {}", e),
+        }
+
+        // A sole failure is kept as it is.
+        match UpstreamsBuilder::new(1)
+            .unwrap()
+            .add_upstream("a", Slow(0, None))
+            .add_upstream("c", Slow(0, udp()))
+            .async_try_into()
+            .await
+            .err()
+            .unwrap()
+        {
+            UpstreamError::QHandleError(QHandleError::IoError(_)) => {}
+            e => panic!("Not the right error type: {}", e),
+        }
+    }
 }
@@ -21,7 +21,7 @@ use super::{
     qhandle::{udp::Udp, ConnPool, Result},
     QHandleError, Upstream,
 };
-use crate::{AsyncTryInto, Label};
+use crate::{AsyncTryInto, Label, MAX_TTL};
 use async_trait::async_trait;
 use serde::{Deserialize, Serialize};
 #[cfg(any(feature = "doh-rustls", feature = "doh-native-tls"))]
@@ -71,9 +71,52 @@ const fn default_https_max_pool_size() -> usize {
     1024
 }
 
+// Default value for the stale window, which is a day.
+const fn default_max_stale_secs() -> u64 {
+    86400
+}
+
+// Default value for the maximum TTL a cached record may have.
+const fn default_max_ttl() -> u32 {
+    MAX_TTL
+}
+
+/// Cache related settings of a single upstream.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+#[serde(deny_unknown_fields)]
+pub struct CacheSettings {
+    /// Number of seconds an expired record may still be served (under `persistent` cache policy) after its TTL has passed.
+    /// Records that have been expired for longer are treated as cache misses and evicted.
+    #[serde(default = "default_max_stale_secs")]
+    pub max_stale_secs: u64,
+    /// The maximum TTL in seconds of a cached record. Responses without any answer are cached with this TTL, and larger TTLs from upstreams are capped to it.
+    #[serde(default = "default_max_ttl")]
+    pub max_ttl: u32,
+}
+
+impl Default for CacheSettings {
+    fn default() -> Self {
+        Self {
+            max_stale_secs: default_max_stale_secs(),
+            max_ttl: default_max_ttl(),
+        }
+    }
+}
+
+impl CacheSettings {
+    /// The stale window as a `Duration`.
+    pub fn max_stale(&self) -> Duration {
+        Duration::from_secs(self.max_stale_secs)
+    }
+}
+
 /// A builder for hybrid upstream
 #[derive(Serialize, Deserialize, Clone)]
-pub struct HybridBuilder(Vec<Label>);
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct HybridBuilder(
+    #[cfg_attr(feature = "schema", schemars(with = "Vec<String>"))] Vec<Label>,
+);
 
 impl Default for HybridBuilder {
     fn default() -> Self {
@@ -106,7 +149,8 @@ impl AsyncTryInto<Upstream> for HybridBuilder {
 /// A builder for DNS over HTTPS upstream
 #[cfg(any(feature = "doh-rustls", feature = "doh-native-tls"))]
 #[derive(Serialize, Deserialize, Clone)]
-#[serde(rename_all = "lowercase")]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+#[serde(rename_all = "lowercase", deny_unknown_fields)]
 pub struct HttpsBuilder {
     /// The URL of the DoH server. e.g. `https://cloudflare-dns.com/dns-query`
     pub uri: String,
@@ -126,6 +170,9 @@ pub struct HttpsBuilder {
     /// SNI
     #[serde(default)]
     pub sni: bool,
+    /// Cache settings
+    #[serde(default)]
+    pub cache: CacheSettings,
 }
 
 #[cfg(any(feature = "doh-rustls", feature = "doh-native-tls"))]
@@ -134,19 +181,23 @@ impl AsyncTryInto<Upstream> for HttpsBuilder {
     type Error = QHandleError;
 
     async fn async_try_into(self) -> Result<Upstream> {
-        Ok(Upstream::Others(Arc::new(ConnPool::new(
-            Https::new(self.uri, self.addr, self.proxy, self.sni).await?,
-            self.max_pool_size,
-            Duration::from_secs(self.timeout),
-            self.ratelimit.into(),
-        )?)))
+        Ok(Upstream::Others(
+            Arc::new(ConnPool::new(
+                Https::new(self.uri, self.addr, self.proxy, self.sni).await?,
+                self.max_pool_size,
+                Duration::from_secs(self.timeout),
+                self.ratelimit.into(),
+            )?),
+            self.cache,
+        ))
     }
 }
 
 /// A builder for DNS over TLS upstream
 #[cfg(any(feature = "dot-native-tls", feature = "dot-rustls"))]
 #[derive(Serialize, Deserialize, Clone)]
-#[serde(rename_all = "lowercase")]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+#[serde(rename_all = "lowercase", deny_unknown_fields)]
 pub struct TlsBuilder {
     /// The domain of the DoH server. e.g. `cloudflare-dns.com`
     pub domain: String,
@@ -170,6 +221,9 @@ pub struct TlsBuilder {
     /// SNI
     #[serde(default)]
     pub sni: bool,
+    /// Cache settings
+    #[serde(default)]
+    pub cache: CacheSettings,
 }
 
 #[cfg(any(feature = "dot-native-tls", feature = "dot-rustls"))]
@@ -178,24 +232,28 @@ impl AsyncTryInto<Upstream> for TlsBuilder {
     type Error = QHandleError;
 
     async fn async_try_into(self) -> Result<Upstream> {
-        Ok(Upstream::Others(Arc::new(ConnPool::new(
-            Tls::new(
-                self.domain,
-                self.addr,
-                self.sni,
-                self.reuse_timeout,
-                self.max_reuse,
-            )?,
-            self.max_pool_size,
-            Duration::from_secs(self.timeout),
-            self.ratelimit.into(),
-        )?)))
+        Ok(Upstream::Others(
+            Arc::new(ConnPool::new(
+                Tls::new(
+                    self.domain,
+                    self.addr,
+                    self.sni,
+                    self.reuse_timeout,
+                    self.max_reuse,
+                )?,
+                self.max_pool_size,
+                Duration::from_secs(self.timeout),
+                self.ratelimit.into(),
+            )?),
+            self.cache,
+        ))
     }
 }
 
 /// A builder for UDP upstream
 #[derive(Serialize, Deserialize, Clone)]
-#[serde(rename_all = "lowercase")]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+#[serde(rename_all = "lowercase", deny_unknown_fields)]
 pub struct UdpBuilder {
     /// Address of the remote server
     pub addr: SocketAddr,
@@ -208,6 +266,9 @@ pub struct UdpBuilder {
     /// Timeout length
     #[serde(default = "default_timeout")]
     pub timeout: u64,
+    /// Cache settings
+    #[serde(default)]
+    pub cache: CacheSettings,
 }
 
 #[async_trait]
@@ -215,16 +276,20 @@ impl AsyncTryInto<Upstream> for UdpBuilder {
     type Error = QHandleError;
 
     async fn async_try_into(self) -> Result<Upstream> {
-        Ok(Upstream::Others(Arc::new(ConnPool::new(
-            Udp::new(self.addr).await?,
-            self.max_pool_size,
-            Duration::from_secs(self.timeout),
-            self.ratelimit.into(),
-        )?)))
+        Ok(Upstream::Others(
+            Arc::new(ConnPool::new(
+                Udp::new(self.addr).await?,
+                self.max_pool_size,
+                Duration::from_secs(self.timeout),
+                self.ratelimit.into(),
+            )?),
+            self.cache,
+        ))
     }
 }
 
 #[derive(Serialize, Deserialize, Clone)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
 #[serde(rename_all = "lowercase")]
 /// The builder for `Upstream`
 pub enum UpstreamBuilder {
@@ -19,14 +19,29 @@ mod qhandle;
 use std::sync::Arc;
 
 use bytes::Bytes;
+pub(crate) use qhandle::DUMMY_QUERY;
 pub use qhandle::{QHandle, QHandleError};
 
+use self::builder::CacheSettings;
 use super::{super::table::rule::actions::CacheMode, error::Result};
 use crate::{
     cache::{RecordStatus::*, RespCache},
     Label,
 };
 use domain::base::Message;
+use serde::{Deserialize, Serialize};
+
+/// Where a response came from.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum RespSource {
+    /// A cached record within its TTL
+    Cache,
+    /// A cached record with its TTL passed
+    StaleCache,
+    /// A fresh response from the upstream with the tag
+    Upstream(Label),
+}
 
 /// A single upstream. Opposite to the `Upstreams`.
 #[derive(Clone)]
@@ -34,8 +49,8 @@ pub enum Upstream {
     /// Hybrid upstream type
     // We don't use HashSet because we don't need to look up
     Hybrid(Vec<Label>),
-    /// Other upstream types, like Zone or ClientPool.
-    Others(Arc<dyn QHandle>),
+    /// Other upstream types, like Zone or ClientPool, along with their cache settings.
+    Others(Arc<dyn QHandle>, CacheSettings),
 }
 
 impl Upstream {
@@ -53,21 +68,22 @@ impl Upstream {
         cache: &RespCache,
         cache_mode: &CacheMode,
         msg: &Message<Bytes>,
-    ) -> Result<Message<Bytes>> {
-        if let Self::Others(inner) = &self {
+    ) -> Result<(Message<Bytes>, RespSource)> {
+        if let Self::Others(inner, settings) = &self {
+            let fresh = || RespSource::Upstream(tag.clone());
             log::info!("querying with upstream: {}", tag);
             // Manage cache with caching policies
-            let r = match cache_mode {
-                CacheMode::Disabled => inner.query(msg).await?,
-                CacheMode::Standard => match cache.get(tag, msg) {
+            let (r, source) = match cache_mode {
+                CacheMode::Disabled => (inner.query(msg).await?, fresh()),
+                CacheMode::Standard => match cache.get(tag, msg, settings.max_stale()) {
                     // Cache available within TTL constraints
-                    Some(Alive(r)) => r,
-                    // No cache or cache expired
-                    Some(Expired(_)) | None => inner.query(msg).await?,
+                    Some(Alive(r)) => (r, RespSource::Cache),
+                    // No cache, cache expired, or record stale for too long
+                    Some(Expired(_)) | None => (inner.query(msg).await?, fresh()),
                 },
-                CacheMode::Persistent => match cache.get(tag, msg) {
+                CacheMode::Persistent => match cache.get(tag, msg, settings.max_stale()) {
                     // Cache available within TTL constraints
-                    Some(Alive(r)) => r,
+                    Some(Alive(r)) => (r, RespSource::Cache),
                     Some(Expired(r)) => {
                         // Cache records exists, but TTL exceeded.
                         // We try to update the cache and return back the outdated value.
@@ -76,23 +92,25 @@ impl Upstream {
                         let cache = cache.clone();
                         let msg = msg.clone();
                         let tag = tag.clone();
+                        let max_ttl = settings.max_ttl;
                         tokio::spawn(async move {
                             // We have to update the cache though
                             // We don't care about failures here.
                             if let Ok(r) = inner.query(&msg).await {
-                                cache.put(tag, &msg, r)
+                                cache.put(tag, &msg, r, max_ttl)
                             }
                         });
-                        r
+                        (r, RespSource::StaleCache)
                     }
-                    None => inner.query(msg).await?,
+                    // No cache or record stale for longer than the window allowed
+                    None => (inner.query(msg).await?, fresh()),
                 },
             };
             if cache_mode != &CacheMode::Disabled {
-                cache.put(tag.clone(), msg, r.clone());
+                cache.put(tag.clone(), msg, r.clone(), settings.max_ttl);
             }
             log::info!("query successfully completed.");
-            Ok(r)
+            Ok((r, source))
         } else {
             unreachable!()
         }
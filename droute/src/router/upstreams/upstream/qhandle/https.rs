@@ -114,12 +114,9 @@ impl Https {
 
         Ok(Self {
             client: PostClient(
-                client.build().map_err(|_| {
-                    std::io::Error::new(
-                        std::io::ErrorKind::Other,
-                        "TLS backend failed to initialize",
-                    )
-                })?,
+                client
+                    .build()
+                    .map_err(|_| std::io::Error::other("TLS backend failed to initialize"))?,
                 uri.clone(),
             ),
         })
@@ -40,7 +40,8 @@ use tokio::time::{error::Elapsed, timeout};
 const MAX_ERROR_TOLERANCE: u8 = 2;
 const WAIT_TIMEOUT: Option<Duration> = Some(Duration::from_secs(5));
 
-static DUMMY_QUERY: Lazy<Message<Bytes>> = Lazy::new(|| {
+// Sent to check if a pooled connection is still alive.
+pub(crate) static DUMMY_QUERY: Lazy<Message<Bytes>> = Lazy::new(|| {
     let name = Dname::<Bytes>::from_str("example.com").unwrap();
     let mut builder = MessageBuilder::from_target(BytesMut::with_capacity(1232)).unwrap();
     builder.header_mut().set_id(0);
@@ -70,6 +71,7 @@ impl<T: ConnInitiator> Manager for ConnInitWrapper<T> {
     type Error = std::io::Error;
 
     async fn create(&self) -> std::result::Result<Self::Type, Self::Error> {
+        log::debug!("creating a new {} connection", self.0.conn_type());
         Ok((self.0.create().await?, 0))
     }
 
@@ -139,7 +141,7 @@ pub enum QHandleError {
     #[error("unsuccessful HTTP code: {0}")]
     FailedHttp(StatusCode),
 
-    #[cfg(any(feature = "dot-native-tls"))]
+    #[cfg(feature = "dot-native-tls")]
     #[error(transparent)]
     NativeTlsError(#[from] native_tls::Error),
 
@@ -0,0 +1,282 @@
+// Copyright 2020 LEXUGE
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Cache warm-up. A list of names is resolved through the routing table in the background once the router is up, populating the caches as a side effect.
+
+use super::Router;
+use crate::{AsyncTryInto, MAX_LEN};
+use async_trait::async_trait;
+use bytes::{Bytes, BytesMut};
+use domain::base::{iana::Rcode, Dname, Message, MessageBuilder, Rtype};
+use futures::StreamExt;
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use std::{
+    num::NonZeroUsize,
+    path::PathBuf,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+};
+use thiserror::Error;
+use tokio::task::JoinHandle;
+
+type Result<T> = std::result::Result<T, WarmUpError>;
+
+/// Error related to the warm-up list.
+#[derive(Error, Debug)]
+pub enum WarmUpError {
+    /// Failed to read the warm-up list
+    #[error(transparent)]
+    IoError(#[from] std::io::Error),
+
+    /// A line in the warm-up list is malformatted
+    #[error("line {0} of the warm-up list is malformatted: `{1}`")]
+    Malformatted(usize, String),
+}
+
+fn default_concurrency() -> NonZeroUsize {
+    NonZeroUsize::new(8).unwrap()
+}
+
+/// A builder for the warm-up list.
+#[derive(Serialize, Deserialize, Clone)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+#[serde(rename_all = "lowercase")]
+#[serde(deny_unknown_fields)]
+pub struct WarmUpBuilder {
+    /// Path to the warm-up list. Each line is a domain optionally followed by a query type, e.g. `example.com AAAA`. Both `A` and `AAAA` are queried if the query type is omitted.
+    pub path: PathBuf,
+    /// The maximum number of names resolved at the same time.
+    #[serde(default = "default_concurrency")]
+    pub concurrency: NonZeroUsize,
+}
+
+impl WarmUpBuilder {
+    /// Create a new warm-up builder from the path to the list
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self {
+            path: path.into(),
+            concurrency: default_concurrency(),
+        }
+    }
+
+    /// Set the maximum number of names resolved at the same time.
+    pub fn concurrency(mut self, concurrency: NonZeroUsize) -> Self {
+        self.concurrency = concurrency;
+        self
+    }
+}
+
+#[async_trait]
+impl AsyncTryInto<WarmUp> for WarmUpBuilder {
+    type Error = WarmUpError;
+
+    async fn async_try_into(self) -> Result<WarmUp> {
+        let content = tokio::fs::read_to_string(&self.path).await?;
+        WarmUp::parse(&content, self.concurrency)
+    }
+}
+
+/// A parsed warm-up list.
+#[derive(Clone)]
+pub struct WarmUp {
+    entries: Vec<(Dname<Bytes>, Rtype)>,
+    concurrency: NonZeroUsize,
+}
+
+impl WarmUp {
+    /// Create a warm-up list from the (name, query type) pairs.
+    pub fn new(entries: Vec<(Dname<Bytes>, Rtype)>, concurrency: NonZeroUsize) -> Self {
+        Self {
+            entries,
+            concurrency,
+        }
+    }
+
+    /// Parse the warm-up list. Empty lines and lines starting with `#` are ignored.
+    pub fn parse(content: &str, concurrency: NonZeroUsize) -> Result<Self> {
+        let mut entries = Vec::new();
+        for (n, line) in content.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let malformatted = || WarmUpError::Malformatted(n + 1, line.to_string());
+
+            let mut parts = line.split_whitespace();
+            let name = parts
+                .next()
+                .and_then(|name| Dname::<Bytes>::from_str(name).ok())
+                .ok_or_else(malformatted)?;
+            match (parts.next(), parts.next()) {
+                (None, _) => {
+                    entries.push((name.clone(), Rtype::A));
+                    entries.push((name, Rtype::Aaaa));
+                }
+                (Some(qtype), None) => entries.push((
+                    name,
+                    Rtype::from_str(&qtype.to_uppercase()).map_err(|_| malformatted())?,
+                )),
+                _ => return Err(malformatted()),
+            }
+        }
+        Ok(Self::new(entries, concurrency))
+    }
+
+    /// Number of queries in the list.
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Whether the list is empty.
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Create the query used to warm up the cache for the given name and query type.
+    /// Recursion desired flag is set as stub resolvers normally do, as the flags are part of the cache key.
+    pub fn query(name: &Dname<Bytes>, qtype: Rtype) -> Message<Bytes> {
+        // This should not fail as the buffer is large enough for any single question.
+        let mut builder = MessageBuilder::from_target(BytesMut::with_capacity(MAX_LEN)).unwrap();
+        builder.header_mut().set_rd(true);
+        let mut builder = builder.question();
+        builder.push((name, qtype)).unwrap();
+        builder.into_message()
+    }
+}
+
+/// Handle to a running warm-up, which can be used to observe its progress.
+pub struct WarmUpHandle {
+    total: usize,
+    done: Arc<AtomicUsize>,
+    failed: Arc<AtomicUsize>,
+    handle: JoinHandle<()>,
+}
+
+impl WarmUpHandle {
+    /// Total number of queries to send.
+    pub fn total(&self) -> usize {
+        self.total
+    }
+
+    /// Number of queries finished, including the failed ones.
+    pub fn done(&self) -> usize {
+        self.done.load(Ordering::Relaxed)
+    }
+
+    /// Number of queries failed.
+    pub fn failed(&self) -> usize {
+        self.failed.load(Ordering::Relaxed)
+    }
+
+    /// Wait for the warm-up to finish. Returns the number of queries failed.
+    pub async fn join(self) -> usize {
+        if let Err(e) = self.handle.await {
+            warn!("cache warm-up task panicked: {}", e);
+        }
+        self.failed.load(Ordering::Relaxed)
+    }
+}
+
+impl Router {
+    /// Resolve every entry of the warm-up list through the routing table in the background to populate the caches.
+    /// Failure of a single query doesn't affect the rest.
+    pub fn warm_up(self: Arc<Self>, warm_up: WarmUp) -> WarmUpHandle {
+        let total = warm_up.len();
+        let done = Arc::new(AtomicUsize::new(0));
+        let failed = Arc::new(AtomicUsize::new(0));
+
+        let handle = {
+            let done = done.clone();
+            let failed = failed.clone();
+            tokio::spawn(async move {
+                futures::stream::iter(warm_up.entries)
+                    .for_each_concurrent(warm_up.concurrency.get(), |(name, qtype)| {
+                        let router = self.clone();
+                        let done = done.clone();
+                        let failed = failed.clone();
+                        async move {
+                            // Stop sending queries once the router is shutting down
+                            if router.is_shutting_down() {
+                                failed.fetch_add(1, Ordering::Relaxed);
+                                done.fetch_add(1, Ordering::Relaxed);
+                                return;
+                            }
+                            match router.resolve(WarmUp::query(&name, qtype)).await {
+                                Ok(r) if r.header().rcode() != Rcode::ServFail => {}
+                                _ => {
+                                    warn!("cache warm-up failed for {} {}", name, qtype);
+                                    failed.fetch_add(1, Ordering::Relaxed);
+                                }
+                            }
+                            done.fetch_add(1, Ordering::Relaxed);
+                        }
+                    })
+                    .await;
+                info!(
+                    "cache warm-up finished, {} out of {} queries failed",
+                    failed.load(Ordering::Relaxed),
+                    total
+                );
+            })
+        };
+
+        WarmUpHandle {
+            total,
+            done,
+            failed,
+            handle,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{WarmUp, WarmUpError};
+    use domain::base::Rtype;
+    use std::num::NonZeroUsize;
+
+    #[test]
+    fn parse() {
+        let warm_up = WarmUp::parse(
+            "# top sites\nexample.com\n\napple.com aaaa\n  baidu.com MX  \n",
+            NonZeroUsize::new(1).unwrap(),
+        )
+        .unwrap();
+        assert_eq!(warm_up.len(), 4);
+        assert_eq!(
+            warm_up
+                .entries
+                .iter()
+                .map(|(_, t)| *t)
+                .collect::<Vec<Rtype>>(),
+            vec![Rtype::A, Rtype::Aaaa, Rtype::Aaaa, Rtype::Mx]
+        );
+    }
+
+    #[test]
+    fn fail_malformatted() {
+        match WarmUp::parse(
+            "example.com\nexample.com A extra",
+            NonZeroUsize::new(1).unwrap(),
+        ) {
+            Err(WarmUpError::Malformatted(2, _)) => (),
+            _ => panic!("should fail on the second line"),
+        }
+    }
+}
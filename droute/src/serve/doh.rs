@@ -0,0 +1,243 @@
+// Copyright 2020 LEXUGE
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use crate::{error::DrouteError, Protocol, QueryContext, Router};
+use bytes::{Bytes, BytesMut};
+use domain::base::Message;
+use futures::future::BoxFuture;
+use hyper::{
+    body::HttpBody,
+    header::{CACHE_CONTROL, CONTENT_TYPE},
+    service::Service,
+    Body, Method, Request, Response, StatusCode,
+};
+use log::warn;
+use std::{
+    convert::Infallible,
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+    task::{Context, Poll},
+};
+
+/// The media type of DNS messages over HTTPS.
+pub const DNS_MESSAGE: &str = "application/dns-message";
+
+/// The default maximum size of a query accepted, in bytes.
+pub const DEFAULT_MAX_BODY_SIZE: usize = 4096;
+
+/// A `tower::Service` answering DNS over HTTPS requests of both `GET` and `POST` forms through the router.
+/// Each connection should get its own service from `for_peer`, so that the query context carries the address of the client.
+#[derive(Clone)]
+pub struct DohService {
+    router: Arc<Router>,
+    max_body_size: usize,
+    trust_forwarded: bool,
+    peer: Option<SocketAddr>,
+    local_addr: Option<SocketAddr>,
+}
+
+// Reasons a request cannot be answered with a DNS message.
+enum Rejection {
+    Status(StatusCode, &'static str),
+    Router(DrouteError),
+}
+
+impl From<DrouteError> for Rejection {
+    fn from(e: DrouteError) -> Self {
+        Self::Router(e)
+    }
+}
+
+impl DohService {
+    /// Create a service resolving queries through the router.
+    pub fn new(router: Arc<Router>) -> Self {
+        Self {
+            router,
+            max_body_size: DEFAULT_MAX_BODY_SIZE,
+            trust_forwarded: false,
+            peer: None,
+            local_addr: None,
+        }
+    }
+
+    /// Set the maximum size of a query in bytes. Larger ones are rejected with `413 Payload Too Large`. Default to `DEFAULT_MAX_BODY_SIZE`.
+    pub fn with_max_body_size(mut self, size: usize) -> Self {
+        self.max_body_size = size;
+        self
+    }
+
+    /// Whether to take the client address from the `X-Forwarded-For` header if present. Only enable it behind a reverse proxy which sets the header, as anyone can forge it otherwise. Default to `false`.
+    pub fn with_trust_forwarded(mut self, trust: bool) -> Self {
+        self.trust_forwarded = trust;
+        self
+    }
+
+    /// Set the address of the listener the service is serving on.
+    pub fn with_local_addr(mut self, addr: SocketAddr) -> Self {
+        self.local_addr = Some(addr);
+        self
+    }
+
+    /// A copy of the service for a connection from the peer.
+    pub fn for_peer(&self, peer: SocketAddr) -> Self {
+        Self {
+            peer: Some(peer),
+            ..self.clone()
+        }
+    }
+
+    /// Answer the request.
+    pub async fn handle(&self, req: Request<Body>) -> Response<Body> {
+        match self.try_handle(req).await {
+            Ok(resp) => resp,
+            Err(Rejection::Status(status, reason)) => reply(status, reason),
+            Err(Rejection::Router(e)) => {
+                warn!("failed to resolve the query over HTTPS: {}", e);
+                match e {
+                    DrouteError::ShuttingDown => {
+                        reply(StatusCode::SERVICE_UNAVAILABLE, "shutting down")
+                    }
+                    _ => reply(StatusCode::INTERNAL_SERVER_ERROR, "failed to resolve"),
+                }
+            }
+        }
+    }
+
+    async fn try_handle(&self, req: Request<Body>) -> Result<Response<Body>, Rejection> {
+        let qctx = self.qctx(&req);
+        let query = match *req.method() {
+            Method::GET => {
+                let dns = req
+                    .uri()
+                    .query()
+                    .into_iter()
+                    .flat_map(|q| q.split('&'))
+                    .find_map(|p| p.strip_prefix("dns="))
+                    .ok_or(Rejection::Status(
+                        StatusCode::BAD_REQUEST,
+                        "missing the `dns` parameter",
+                    ))?;
+                // Base64 takes four characters for every three bytes.
+                if dns.len() > self.max_body_size / 3 * 4 + 4 {
+                    return Err(Rejection::Status(
+                        StatusCode::URI_TOO_LONG,
+                        "query too large",
+                    ));
+                }
+                base64::decode_config(dns, base64::URL_SAFE_NO_PAD)
+                    .map(Bytes::from)
+                    .map_err(|_| {
+                        Rejection::Status(StatusCode::BAD_REQUEST, "malformed `dns` parameter")
+                    })?
+            }
+            Method::POST => {
+                if req.headers().get(CONTENT_TYPE).map(|v| v.as_bytes())
+                    != Some(DNS_MESSAGE.as_bytes())
+                {
+                    return Err(Rejection::Status(
+                        StatusCode::UNSUPPORTED_MEDIA_TYPE,
+                        "expecting application/dns-message",
+                    ));
+                }
+                self.read_body(req.into_body()).await?
+            }
+            _ => {
+                return Err(Rejection::Status(
+                    StatusCode::METHOD_NOT_ALLOWED,
+                    "only GET and POST are allowed",
+                ))
+            }
+        };
+        let query = Message::from_octets(query)
+            .map_err(|_| Rejection::Status(StatusCode::BAD_REQUEST, "malformed DNS message"))?;
+        let resp = self.router.resolve_with_ctx(query, qctx).await?;
+
+        let mut builder = Response::builder()
+            .status(StatusCode::OK)
+            .header(CONTENT_TYPE, DNS_MESSAGE);
+        if let Some(ttl) = min_ttl(&resp) {
+            builder = builder.header(CACHE_CONTROL, format!("max-age={}", ttl));
+        }
+        // The headers are all valid.
+        Ok(builder.body(Body::from(resp.into_octets())).unwrap())
+    }
+
+    // Read the body, failing as soon as it gets too large.
+    async fn read_body(&self, mut body: Body) -> Result<Bytes, Rejection> {
+        let too_large = Rejection::Status(StatusCode::PAYLOAD_TOO_LARGE, "query too large");
+        if body.size_hint().lower() > self.max_body_size as u64 {
+            return Err(too_large);
+        }
+        let mut buf = BytesMut::new();
+        while let Some(chunk) = body.data().await {
+            let chunk = chunk.map_err(|_| {
+                Rejection::Status(StatusCode::BAD_REQUEST, "failed to read the body")
+            })?;
+            if buf.len() + chunk.len() > self.max_body_size {
+                return Err(too_large);
+            }
+            buf.extend_from_slice(&chunk);
+        }
+        Ok(buf.freeze())
+    }
+
+    fn qctx(&self, req: &Request<Body>) -> Option<QueryContext> {
+        let forwarded = self
+            .trust_forwarded
+            .then(|| req.headers().get("x-forwarded-for"))
+            .flatten()
+            .and_then(|v| v.to_str().ok())
+            // The leftmost one is the original client.
+            .and_then(|v| v.split(',').next())
+            .and_then(|ip| ip.trim().parse::<IpAddr>().ok())
+            .map(|ip| SocketAddr::new(ip, 0));
+        let mut qctx = QueryContext::new(forwarded.or(self.peer)?, Protocol::Doh);
+        qctx.local_addr = self.local_addr;
+        Some(qctx)
+    }
+}
+
+// The minimum TTL of the records in the answer and authority sections, if any.
+fn min_ttl(msg: &Message<Bytes>) -> Option<u32> {
+    msg.answer()
+        .ok()?
+        .chain(msg.authority().ok()?)
+        .filter_map(|r| r.ok())
+        .map(|r| r.ttl())
+        .min()
+}
+
+fn reply(status: StatusCode, reason: &'static str) -> Response<Body> {
+    // The status is always valid.
+    Response::builder()
+        .status(status)
+        .body(Body::from(reason))
+        .unwrap()
+}
+
+impl Service<Request<Body>> for DohService {
+    type Response = Response<Body>;
+    type Error = Infallible;
+    type Future = BoxFuture<'static, Result<Response<Body>, Infallible>>;
+
+    fn poll_ready(&mut self, _: &mut Context<'_>) -> Poll<Result<(), Infallible>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, req: Request<Body>) -> Self::Future {
+        let service = self.clone();
+        Box::pin(async move { Ok(service.handle(req).await) })
+    }
+}
@@ -0,0 +1,267 @@
+// Copyright 2020 LEXUGE
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use crate::{Protocol, QueryContext, Router};
+use arc_swap::ArcSwap;
+use bytes::Bytes;
+use domain::base::Message;
+use log::{info, warn};
+use rustls::{Certificate, PrivateKey, ServerConfig};
+use rustls_pemfile::Item;
+use std::{
+    fs::File,
+    io::{BufReader, ErrorKind},
+    net::SocketAddr,
+    num::NonZeroUsize,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+use thiserror::Error;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    sync::{mpsc, Semaphore},
+    time::timeout,
+};
+use tokio_rustls::TlsAcceptor;
+
+/// The default time a connection may stay without a new query before getting closed.
+pub const DEFAULT_IDLE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The default number of queries of a connection being resolved at the same time.
+pub const DEFAULT_MAX_PIPELINED: usize = 32;
+
+type Result<T> = std::result::Result<T, DotError>;
+
+/// Errors of setting up the TLS server.
+#[derive(Error, Debug)]
+pub enum DotError {
+    /// Failed to read the certificate or the key.
+    #[error("Failed to read `{path}`: {source}")]
+    Io {
+        /// The file failed
+        path: PathBuf,
+        /// The underlying error
+        source: std::io::Error,
+    },
+
+    /// No certificate found in the file.
+    #[error("No certificate found in `{0}`")]
+    MissingCert(PathBuf),
+
+    /// No private key of a supported format (PKCS#8, PKCS#1, or SEC1) found in the file.
+    #[error("No private key found in `{0}`")]
+    MissingKey(PathBuf),
+
+    /// The certificate chain or the key is rejected.
+    #[error(transparent)]
+    Tls(#[from] rustls::Error),
+
+    /// `reload_cert` is called on a server created from certificates in memory.
+    #[error("The server was not created from certificate files")]
+    NoCertFiles,
+}
+
+/// A server answering DNS over TLS queries through the router.
+/// Queries from the same connection are resolved concurrently, and the responses are written back in the order they are ready.
+pub struct DotServer {
+    router: Arc<Router>,
+    acceptor: ArcSwap<TlsAcceptor>,
+    files: Option<(PathBuf, PathBuf)>,
+    idle_timeout: Duration,
+    max_pipelined: usize,
+}
+
+impl DotServer {
+    /// Create a server presenting the certificate chain, of which the first one is the end-entity certificate, signed by the key.
+    pub fn new(router: Arc<Router>, certs: Vec<Certificate>, key: PrivateKey) -> Result<Self> {
+        Ok(Self {
+            router,
+            acceptor: ArcSwap::from_pointee(acceptor(certs, key)?),
+            files: None,
+            idle_timeout: DEFAULT_IDLE_TIMEOUT,
+            max_pipelined: DEFAULT_MAX_PIPELINED,
+        })
+    }
+
+    /// Create a server like `new` with the certificate chain and the key read from PEM files. The files are read again on `reload_cert`.
+    pub fn from_pem_files(
+        router: Arc<Router>,
+        cert: impl AsRef<Path>,
+        key: impl AsRef<Path>,
+    ) -> Result<Self> {
+        let (cert, key) = (cert.as_ref().to_owned(), key.as_ref().to_owned());
+        let mut server = Self::new(router, read_certs(&cert)?, read_key(&key)?)?;
+        server.files = Some((cert, key));
+        Ok(server)
+    }
+
+    /// Set the time a connection may stay without a new query. The connection is then closed once the pending responses are written. Default to `DEFAULT_IDLE_TIMEOUT`.
+    pub fn with_idle_timeout(mut self, idle_timeout: Duration) -> Self {
+        self.idle_timeout = idle_timeout;
+        self
+    }
+
+    /// Set the number of queries of a connection being resolved at the same time. Further queries are not read until one of them is answered. Default to `DEFAULT_MAX_PIPELINED`.
+    pub fn with_max_pipelined(mut self, max: NonZeroUsize) -> Self {
+        self.max_pipelined = max.get();
+        self
+    }
+
+    /// Read the PEM files the server was created from again, and present the new certificate to the connections accepted afterwards.
+    /// The old certificate is kept if the files are invalid.
+    pub fn reload_cert(&self) -> Result<()> {
+        let (cert, key) = self.files.as_ref().ok_or(DotError::NoCertFiles)?;
+        self.set_cert(read_certs(cert)?, read_key(key)?)
+    }
+
+    /// Present the certificate chain signed by the key to the connections accepted afterwards.
+    pub fn set_cert(&self, certs: Vec<Certificate>, key: PrivateKey) -> Result<()> {
+        self.acceptor.store(Arc::new(acceptor(certs, key)?));
+        Ok(())
+    }
+
+    /// Accept connections from the listener and serve each of them in its own task. Only returns if accepting fails.
+    pub async fn serve(self: Arc<Self>, listener: TcpListener) -> std::io::Result<()> {
+        loop {
+            let (stream, peer) = listener.accept().await?;
+            let server = self.clone();
+            tokio::spawn(async move {
+                if let Err(e) = server.serve_connection(stream, peer).await {
+                    info!("DNS over TLS connection from {} closed: {}", peer, e);
+                }
+            });
+        }
+    }
+
+    /// Serve a single TCP connection from the peer until it is closed or idles for too long.
+    pub async fn serve_connection(
+        &self,
+        stream: TcpStream,
+        peer: SocketAddr,
+    ) -> std::io::Result<()> {
+        let local_addr = stream.local_addr().ok();
+        let stream = timeout(self.idle_timeout, self.acceptor.load().accept(stream))
+            .await
+            .map_err(|_| std::io::Error::new(ErrorKind::TimedOut, "TLS handshake timed out"))??;
+        let (mut reader, mut writer) = tokio::io::split(stream);
+
+        let (tx, mut rx) = mpsc::channel::<Message<Bytes>>(self.max_pipelined);
+        let permits = Arc::new(Semaphore::new(self.max_pipelined));
+
+        let read = async move {
+            loop {
+                let frame = async {
+                    let len = reader.read_u16().await?;
+                    let mut buf = vec![0; len.into()];
+                    reader.read_exact(&mut buf).await?;
+                    Ok::<_, std::io::Error>(buf)
+                };
+                let buf = match timeout(self.idle_timeout, frame).await {
+                    Ok(Ok(buf)) => buf,
+                    Ok(Err(e)) if e.kind() == ErrorKind::UnexpectedEof => break,
+                    Ok(Err(e)) => return Err(e),
+                    // Idled for too long.
+                    Err(_) => break,
+                };
+                // The semaphore is never closed.
+                let permit = permits.clone().acquire_owned().await.unwrap();
+                let (router, tx) = (self.router.clone(), tx.clone());
+                let mut qctx = QueryContext::new(peer, Protocol::Dot);
+                qctx.local_addr = local_addr;
+                tokio::spawn(async move {
+                    let _permit = permit;
+                    let resp = match Message::from_octets(Bytes::from(buf)) {
+                        Ok(msg) => router.resolve_with_ctx(msg, Some(qctx)).await,
+                        Err(_) => {
+                            warn!("malformed query over TLS from {}", peer);
+                            return;
+                        }
+                    };
+                    match resp {
+                        // The writer only goes away if the connection is broken.
+                        Ok(resp) => {
+                            let _ = tx.send(resp).await;
+                        }
+                        Err(e) => warn!("failed to resolve the query over TLS: {}", e),
+                    }
+                });
+            }
+            Ok(())
+        };
+
+        let write = async move {
+            // The channel closes once the reader stops and all the pending queries are answered.
+            while let Some(resp) = rx.recv().await {
+                // Prefix our payload with length per RFC.
+                let len = match u16::try_from(resp.as_slice().len()) {
+                    Ok(len) => len,
+                    Err(_) => {
+                        warn!("response too large to be sent over TLS");
+                        continue;
+                    }
+                };
+                writer.write_all(&len.to_be_bytes()).await?;
+                writer.write_all(resp.as_slice()).await?;
+            }
+            writer.shutdown().await
+        };
+
+        tokio::try_join!(read, write)?;
+        Ok(())
+    }
+}
+
+fn acceptor(certs: Vec<Certificate>, key: PrivateKey) -> Result<TlsAcceptor> {
+    let config = ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_single_cert(certs, key)?;
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
+
+fn read_pem(path: &Path) -> Result<Vec<Item>> {
+    let io = |source| DotError::Io {
+        path: path.to_owned(),
+        source,
+    };
+    rustls_pemfile::read_all(&mut BufReader::new(File::open(path).map_err(io)?)).map_err(io)
+}
+
+fn read_certs(path: &Path) -> Result<Vec<Certificate>> {
+    let certs: Vec<_> = read_pem(path)?
+        .into_iter()
+        .filter_map(|item| match item {
+            Item::X509Certificate(cert) => Some(Certificate(cert)),
+            _ => None,
+        })
+        .collect();
+    if certs.is_empty() {
+        Err(DotError::MissingCert(path.to_owned()))
+    } else {
+        Ok(certs)
+    }
+}
+
+fn read_key(path: &Path) -> Result<PrivateKey> {
+    read_pem(path)?
+        .into_iter()
+        .find_map(|item| match item {
+            Item::PKCS8Key(key) | Item::RSAKey(key) | Item::ECKey(key) => Some(PrivateKey(key)),
+            _ => None,
+        })
+        .ok_or_else(|| DotError::MissingKey(path.to_owned()))
+}
@@ -0,0 +1,24 @@
+// Copyright 2020 LEXUGE
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Helpers serving a `Router` over encrypted DNS protocols, so that embedders don't have to write the glue themselves.
+
+/// DNS over HTTPS as defined in RFC 8484.
+#[cfg(feature = "doh-server")]
+pub mod doh;
+
+/// DNS over TLS as defined in RFC 7858.
+#[cfg(feature = "dot-server")]
+pub mod dot;
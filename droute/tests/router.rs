@@ -13,14 +13,31 @@
 // You should have received a copy of the GNU General Public License
 // along with this program.  If not, see <http://www.gnu.org/licenses/>.
 
-use std::str::FromStr;
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    num::NonZeroUsize,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
 
 use bytes::{Bytes, BytesMut};
 use domain::{
-    base::{Dname, Message, MessageBuilder, Rtype},
+    base::{iana::Rcode, Dname, Message, MessageBuilder, Rtype},
     rdata::A,
 };
-use droute::{actions::CacheMode, builders::*, mock::Server, AsyncTryInto};
+use droute::{
+    actions::CacheMode,
+    builders::*,
+    error::{DrouteError, UpstreamError},
+    mock::{Handler, MockBehavior, Server},
+    trace::{ActionTrace, MatcherTrace},
+    AsyncTryInto, Protocol, QueryContext, RespSource, Router, Table, Upstreams, WarmUp,
+};
 use once_cell::sync::Lazy;
 use tokio::net::UdpSocket;
 
@@ -51,8 +68,7 @@ static QUERY: Lazy<Message<Bytes>> = Lazy::new(|| {
 #[tokio::test]
 async fn test_resolve() {
     let socket = UdpSocket::bind(&"127.0.0.1:53533").await.unwrap();
-    let server = Server::new(socket, vec![0; 1024], None);
-    tokio::spawn(server.run(DUMMY_MSG.clone()));
+    tokio::spawn(Server::answering(socket, &DUMMY_MSG).run());
 
     let router = RouterBuilder::new(
         TableBuilder::new().add_rule(
@@ -70,19 +86,2125 @@ async fn test_resolve() {
                 max_pool_size: 256,
                 timeout: 10,
                 ratelimit: None,
+                cache: CacheSettings::default(),
+            },
+        ),
+    )
+    .async_try_into()
+    .await
+    .unwrap();
+
+    assert_eq!(
+        router.resolve(QUERY.clone()).await.unwrap().into_octets(),
+        DUMMY_MSG.clone().into_octets()
+    );
+}
+
+#[tokio::test]
+async fn test_mock_script() {
+    let socket = UdpSocket::bind(&"127.0.0.1:53561").await.unwrap();
+    let answer = Message::from_octets(DUMMY_MSG.clone().into_octets().freeze()).unwrap();
+    let server = Server::new(
+        socket,
+        Handler::script(vec![
+            MockBehavior::Servfail,
+            MockBehavior::Answer(answer),
+            MockBehavior::Truncate,
+        ]),
+    );
+    let received = server.received();
+    tokio::spawn(server.run());
+
+    let router = Router::new(
+        query_table("mock").await,
+        reload_upstreams("127.0.0.1:53561").await,
+    )
+    .unwrap();
+    let resp = router.resolve(QUERY.clone()).await.unwrap();
+    assert_eq!(resp.header().rcode(), Rcode::ServFail);
+    let resp = router.resolve(QUERY.clone()).await.unwrap();
+    assert_eq!(resp.header().rcode(), Rcode::NoError);
+    assert_eq!(resp.header_counts().ancount(), 1);
+    // The last behavior is repeated
+    for _ in 0..2 {
+        let resp = router.resolve(QUERY.clone()).await.unwrap();
+        assert!(resp.header().tc());
+        assert_eq!(resp.header_counts().ancount(), 0);
+    }
+    // Probes of the connection pool are not counted
+    assert_eq!(received.load(Ordering::SeqCst), 4);
+}
+
+// Answer every query with an empty response except for those asking `fail.example`, counting the number of queries received.
+async fn counting_server(socket: UdpSocket, hits: Arc<AtomicUsize>) {
+    let mut buf = vec![0; 1024];
+    let fail = Dname::<Bytes>::from_str("fail.example").unwrap();
+    loop {
+        let (len, src) = socket.recv_from(&mut buf).await.unwrap();
+        hits.fetch_add(1, Ordering::SeqCst);
+        let query = Message::from_octets(buf[..len].to_vec()).unwrap();
+        if query.first_question().unwrap().qname() == &fail {
+            continue;
+        }
+        let resp = MessageBuilder::from_target(BytesMut::with_capacity(1024))
+            .unwrap()
+            .start_answer(&query, Rcode::NoError)
+            .unwrap()
+            .finish();
+        socket.send_to(&resp, src).await.unwrap();
+    }
+}
+
+#[tokio::test]
+async fn test_warm_up() {
+    let socket = UdpSocket::bind(&"127.0.0.1:53541").await.unwrap();
+    let fail = Dname::<Bytes>::from_str("fail.example").unwrap();
+    let server = Server::new(
+        socket,
+        Handler::from_fn(move |query| {
+            if query.first_question().unwrap().qname() == &fail {
+                MockBehavior::Drop
+            } else {
+                MockBehavior::Answer(
+                    MessageBuilder::from_target(BytesMut::with_capacity(1024))
+                        .unwrap()
+                        .start_answer(query, Rcode::NoError)
+                        .unwrap()
+                        .into_message(),
+                )
+            }
+        }),
+    );
+    let hits = server.received();
+    tokio::spawn(server.run());
+
+    let router: Router = RouterBuilder::new(
+        TableBuilder::new().add_rule(
+            "start",
+            RuleBuilders::<BuiltinMatcherBuilders, _>::SeqBlock(
+                BranchBuilder::new("end").add_action(BuiltinActionBuilders::Query(
+                    QueryBuilder::new("mock", CacheMode::default()),
+                )),
+            ),
+        ),
+        UpstreamsBuilder::new(16).unwrap().add_upstream(
+            "mock",
+            UdpBuilder {
+                addr: "127.0.0.1:53541".parse().unwrap(),
+                max_pool_size: 4,
+                timeout: 1,
+                ratelimit: None,
+                cache: CacheSettings::default(),
+            },
+        ),
+    )
+    .async_try_into()
+    .await
+    .unwrap();
+    let router = Arc::new(router);
+
+    let warm_up = WarmUp::parse(
+        "a.example\nb.example A\nc.example MX\nfail.example A",
+        NonZeroUsize::new(2).unwrap(),
+    )
+    .unwrap();
+    let handle = router.clone().warm_up(warm_up.clone());
+    assert_eq!(handle.total(), 5);
+    // The failed one shouldn't abort the others.
+    assert_eq!(handle.join().await, 1);
+    let warmed = hits.load(Ordering::SeqCst);
+
+    // Everything except the failed one should be answered from the cache now.
+    for (name, qtype) in [
+        ("a.example", Rtype::A),
+        ("a.example", Rtype::Aaaa),
+        ("b.example", Rtype::A),
+        ("c.example", Rtype::Mx),
+    ] {
+        let resp = router
+            .resolve(WarmUp::query(&Dname::from_str(name).unwrap(), qtype))
+            .await
+            .unwrap();
+        assert_eq!(resp.header().rcode(), Rcode::NoError);
+    }
+    assert_eq!(hits.load(Ordering::SeqCst), warmed);
+}
+
+// Answer every query with a single A record after the delay given.
+async fn delayed_server(socket: UdpSocket, delay: Duration) {
+    let socket = Arc::new(socket);
+    let mut buf = vec![0; 1024];
+    loop {
+        let (len, src) = socket.recv_from(&mut buf).await.unwrap();
+        let query = Message::from_octets(buf[..len].to_vec()).unwrap();
+        let socket = socket.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(delay).await;
+            let mut builder = MessageBuilder::from_target(BytesMut::with_capacity(1024))
+                .unwrap()
+                .start_answer(&query, Rcode::NoError)
+                .unwrap();
+            builder
+                .push((
+                    query.first_question().unwrap().qname(),
+                    10,
+                    A::from_octets(1, 1, 1, 1),
+                ))
+                .unwrap();
+            socket.send_to(&builder.finish(), src).await.unwrap();
+        });
+    }
+}
+
+async fn reload_upstreams(addr: &str) -> Upstreams {
+    UpstreamsBuilder::new(16)
+        .unwrap()
+        .add_upstream(
+            "mock",
+            UdpBuilder {
+                addr: addr.parse().unwrap(),
+                max_pool_size: 4,
+                timeout: 5,
+                ratelimit: None,
+                cache: CacheSettings::default(),
+            },
+        )
+        .async_try_into()
+        .await
+        .unwrap()
+}
+
+// Send everything to the upstream given.
+async fn query_table(upstream: &str) -> Table {
+    TableBuilder::new()
+        .add_rule(
+            "start",
+            RuleBuilders::<BuiltinMatcherBuilders, _>::SeqBlock(
+                BranchBuilder::new("end").add_action(BuiltinActionBuilders::Query(
+                    QueryBuilder::new(upstream, CacheMode::Disabled),
+                )),
+            ),
+        )
+        .async_try_into()
+        .await
+        .unwrap()
+}
+
+// Blackhole everything except AAAA queries.
+async fn blackhole_table(upstream: &str) -> Table {
+    TableBuilder::new()
+        .add_rule(
+            "start",
+            RuleBuilders::<BuiltinMatcherBuilders, _>::IfBlock(IfBlockBuilder::new(
+                "qtype([AAAA])",
+                BranchBuilder::new("end").add_action(BuiltinActionBuilders::Query(
+                    QueryBuilder::new(upstream, CacheMode::Disabled),
+                )),
+                BranchBuilder::new("end").add_action(BuiltinActionBuilders::Blackhole),
+            )),
+        )
+        .async_try_into()
+        .await
+        .unwrap()
+}
+
+#[tokio::test]
+async fn test_reload() {
+    let socket = UdpSocket::bind(&"127.0.0.1:53542").await.unwrap();
+    tokio::spawn(delayed_server(socket, Duration::from_millis(500)));
+
+    let router = Arc::new(
+        Router::new(
+            query_table("mock").await,
+            reload_upstreams("127.0.0.1:53542").await,
+        )
+        .unwrap(),
+    );
+    let query = WarmUp::query(&Dname::from_str("example.com").unwrap(), Rtype::A);
+
+    let in_flight = {
+        let router = router.clone();
+        let query = query.clone();
+        tokio::spawn(async move { router.resolve(query).await.unwrap() })
+    };
+    // Make sure the query above is being processed before we swap the table.
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    // Upstream tag doesn't exist, the old table should stay in effect.
+    assert!(router
+        .reload(blackhole_table("undefined").await, None)
+        .is_err());
+
+    router.reload(blackhole_table("mock").await, None).unwrap();
+    let resp = router.resolve(query.clone()).await.unwrap();
+    assert_eq!(resp.header().rcode(), Rcode::NXDomain);
+    assert_eq!(resp.header_counts().ancount(), 0);
+    assert_eq!(resp.header_counts().nscount(), 1);
+
+    // The query sent before the swap should still be answered by the upstream.
+    let resp = in_flight.await.unwrap();
+    assert_eq!(resp.header().rcode(), Rcode::NoError);
+    assert_eq!(resp.header_counts().ancount(), 1);
+
+    // Swap back together with the upstreams.
+    router
+        .reload(
+            query_table("mock").await,
+            Some(reload_upstreams("127.0.0.1:53542").await),
+        )
+        .unwrap();
+    let resp = router.resolve(query).await.unwrap();
+    assert_eq!(resp.header_counts().ancount(), 1);
+}
+
+#[tokio::test]
+async fn test_resolve_traced() {
+    let hits = Arc::new(AtomicUsize::new(0));
+    let socket = UdpSocket::bind(&"127.0.0.1:53543").await.unwrap();
+    tokio::spawn(counting_server(socket, hits));
+
+    let router: Router = RouterBuilder::new(
+        TableBuilder::new()
+            .add_rule(
+                "start",
+                RuleBuilders::<BuiltinMatcherBuilders, _>::IfBlock(IfBlockBuilder::new(
+                    "qtype([A])",
+                    BranchBuilder::new("blackhole").add_action(BuiltinActionBuilders::Query(
+                        QueryBuilder::new("mock", CacheMode::Standard),
+                    )),
+                    BranchBuilder::new("end"),
+                )),
+            )
+            .add_rule(
+                "blackhole",
+                RuleBuilders::<BuiltinMatcherBuilders, _>::SeqBlock(
+                    BranchBuilder::new("end").add_action(BuiltinActionBuilders::Blackhole),
+                ),
+            ),
+        UpstreamsBuilder::new(16).unwrap().add_upstream(
+            "mock",
+            UdpBuilder {
+                addr: "127.0.0.1:53543".parse().unwrap(),
+                max_pool_size: 4,
+                timeout: 1,
+                ratelimit: None,
+                cache: CacheSettings::default(),
+            },
+        ),
+    )
+    .async_try_into()
+    .await
+    .unwrap();
+
+    let query = WarmUp::query(&Dname::from_str("example.com").unwrap(), Rtype::A);
+    for source in [RespSource::Upstream("mock".into()), RespSource::Cache] {
+        let (_, trace) = router.resolve_traced(query.clone(), None).await.unwrap();
+        assert_eq!(trace.error, None);
+        assert_eq!(
+            trace
+                .steps
+                .iter()
+                .map(|s| (s.tag.as_str(), s.matcher.clone(), s.actions.clone()))
+                .collect::<Vec<_>>(),
+            vec![
+                (
+                    "start",
+                    Some(MatcherTrace {
+                        expr: Some("qtype([A])".to_string()),
+                        result: true
+                    }),
+                    vec![ActionTrace {
+                        upstream: Some("mock".into()),
+                        source: Some(source),
+                        rcode: "NOERROR".to_string()
+                    }]
+                ),
+                (
+                    "blackhole",
+                    None,
+                    vec![ActionTrace {
+                        upstream: None,
+                        source: None,
+                        rcode: "NXDOMAIN".to_string()
+                    }]
+                )
+            ]
+        );
+    }
+
+    let (_, trace) = router
+        .resolve_traced(
+            WarmUp::query(&Dname::from_str("example.com").unwrap(), Rtype::Aaaa),
+            None,
+        )
+        .await
+        .unwrap();
+    assert_eq!(trace.steps.len(), 1);
+    assert!(!trace.steps[0].matcher.as_ref().unwrap().result);
+    assert!(trace.steps[0].actions.is_empty());
+}
+
+#[tokio::test]
+async fn test_resp_source() {
+    let socket = UdpSocket::bind(&"127.0.0.1:53573").await.unwrap();
+    let server = Server::answering(socket, &DUMMY_MSG);
+    let hits = server.received();
+    tokio::spawn(server.run());
+
+    let router: Router = RouterBuilder::new(
+        TableBuilder::new()
+            .add_rule(
+                "start",
+                RuleBuilders::<BuiltinMatcherBuilders, _>::SeqBlock(
+                    BranchBuilder::new("check").add_action(BuiltinActionBuilders::Query(
+                        QueryBuilder::new("mock", CacheMode::Standard),
+                    )),
+                ),
+            )
+            .add_rule(
+                "check",
+                RuleBuilders::<BuiltinMatcherBuilders, _>::IfBlock(IfBlockBuilder::new(
+                    "resp_source([cache, stale_cache])",
+                    BranchBuilder::new("end"),
+                    BranchBuilder::new("end").add_action(BuiltinActionBuilders::Blackhole),
+                )),
+            ),
+        UpstreamsBuilder::new(16).unwrap().add_upstream(
+            "mock",
+            UdpBuilder {
+                addr: "127.0.0.1:53573".parse().unwrap(),
+                max_pool_size: 4,
+                timeout: 1,
+                ratelimit: None,
+                cache: CacheSettings::default(),
+            },
+        ),
+    )
+    .async_try_into()
+    .await
+    .unwrap();
+
+    // The first query warms up the cache, which answers the second one.
+    for cached in [false, true] {
+        let (_, trace) = router.resolve_traced(QUERY.clone(), None).await.unwrap();
+        assert_eq!(trace.steps[1].tag.as_str(), "check");
+        assert_eq!(trace.steps[1].matcher.as_ref().unwrap().result, cached);
+        assert_eq!(trace.steps[1].actions.is_empty(), cached);
+    }
+    assert_eq!(hits.load(Ordering::SeqCst), 1);
+}
+
+#[tokio::test]
+async fn test_answered_by() {
+    // Each of them only answers its own name, so the one answering is known.
+    let mut upstreams = UpstreamsBuilder::new(16).unwrap().add_upstream(
+        "group",
+        UpstreamBuilder::Hybrid(HybridBuilder::new().add_tag("clean").add_tag("dirty")),
+    );
+    for (tag, port) in [("clean", 53574), ("dirty", 53575)] {
+        let addr = format!("127.0.0.1:{}", port);
+        let name = Dname::<Bytes>::from_str(&format!("{}.example", tag)).unwrap();
+        let server = Server::new(
+            UdpSocket::bind(&addr).await.unwrap(),
+            Handler::from_fn(move |query| {
+                if query.first_question().unwrap().qname() == &name {
+                    MockBehavior::Answer(
+                        MessageBuilder::from_target(BytesMut::with_capacity(1024))
+                            .unwrap()
+                            .start_answer(query, Rcode::NoError)
+                            .unwrap()
+                            .into_message(),
+                    )
+                } else {
+                    MockBehavior::Drop
+                }
+            }),
+        );
+        tokio::spawn(server.run());
+        upstreams = upstreams.add_upstream(
+            tag,
+            UpstreamBuilder::Udp(UdpBuilder {
+                addr: addr.parse().unwrap(),
+                max_pool_size: 4,
+                timeout: 1,
+                ratelimit: None,
+                cache: CacheSettings::default(),
+            }),
+        );
+    }
+
+    let router: Router = RouterBuilder::new(
+        TableBuilder::new()
+            .add_rule(
+                "start",
+                RuleBuilders::<BuiltinMatcherBuilders, _>::SeqBlock(
+                    BranchBuilder::new("check").add_action(BuiltinActionBuilders::Query(
+                        QueryBuilder::new("group", CacheMode::Standard),
+                    )),
+                ),
+            )
+            .add_rule(
+                "check",
+                RuleBuilders::<BuiltinMatcherBuilders, _>::IfBlock(IfBlockBuilder::new(
+                    r#"answered_by(["dirty"])"#,
+                    BranchBuilder::new("end").add_action(BuiltinActionBuilders::Blackhole),
+                    BranchBuilder::new("end"),
+                )),
+            ),
+        upstreams,
+    )
+    .async_try_into()
+    .await
+    .unwrap();
+
+    // The cache of the upstream answered is used the second time, which still tells it.
+    for _ in 0..2 {
+        for (name, dirty) in [("clean.example", false), ("dirty.example", true)] {
+            let (resp, trace) = router
+                .resolve_traced(
+                    WarmUp::query(&Dname::from_str(name).unwrap(), Rtype::A),
+                    None,
+                )
+                .await
+                .unwrap();
+            assert_eq!(
+                resp.header().rcode(),
+                if dirty {
+                    Rcode::NXDomain
+                } else {
+                    Rcode::NoError
+                }
+            );
+            assert_eq!(trace.steps[0].actions[0].upstream.as_deref(), Some("group"));
+            assert_eq!(trace.steps[1].matcher.as_ref().unwrap().result, dirty);
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_switch() {
+    let tags = ["a", "b", "c", "default"];
+    let mut hits = Vec::new();
+    let mut upstreams = UpstreamsBuilder::new(16).unwrap();
+    for (n, tag) in tags.iter().enumerate() {
+        let addr = format!("127.0.0.1:{}", 53544 + n);
+        let h = Arc::new(AtomicUsize::new(0));
+        tokio::spawn(counting_server(
+            UdpSocket::bind(&addr).await.unwrap(),
+            h.clone(),
+        ));
+        hits.push(h);
+        upstreams = upstreams.add_upstream(
+            *tag,
+            UdpBuilder {
+                addr: addr.parse().unwrap(),
+                max_pool_size: 4,
+                timeout: 1,
+                ratelimit: None,
+                cache: CacheSettings::default(),
             },
+        );
+    }
+
+    let query = |tag| {
+        BranchBuilder::new("end").add_action(BuiltinActionBuilders::Query(QueryBuilder::new(
+            tag,
+            CacheMode::Disabled,
+        )))
+    };
+    let router: Router = RouterBuilder::new(
+        TableBuilder::new().add_rule(
+            "start",
+            RuleBuilders::<BuiltinMatcherBuilders, _>::SwitchBlock(
+                SwitchBuilder::new(query("default"))
+                    .add_arm(r#"domain([qname("a.example")])"#, query("a"))
+                    .add_arm(r#"domain([qname("b.example")])"#, query("b"))
+                    // Shadowed by the arm above for `b.example`
+                    .add_arm(
+                        r#"domain([qname("c.example"), qname("b.example")])"#,
+                        query("c"),
+                    ),
+            ),
         ),
+        upstreams,
     )
     .async_try_into()
     .await
     .unwrap();
 
+    for (name, expected) in [
+        ("a.example", 0),
+        ("b.example", 1),
+        ("c.example", 2),
+        ("d.example", 3),
+    ] {
+        let (resp, trace) = router
+            .resolve_traced(
+                WarmUp::query(&Dname::from_str(name).unwrap(), Rtype::A),
+                None,
+            )
+            .await
+            .unwrap();
+        assert_eq!(resp.header().rcode(), Rcode::NoError);
+        assert_eq!(
+            trace.steps[0].actions[0].upstream.as_deref(),
+            Some(tags[expected])
+        );
+        assert_eq!(
+            trace.steps[0].matcher.as_ref().unwrap().result,
+            expected != 3
+        );
+    }
     assert_eq!(
+        hits.iter()
+            .map(|h| h.load(Ordering::Relaxed))
+            .collect::<Vec<_>>(),
+        vec![1, 1, 1, 1]
+    );
+
+    // Rules with unknown destinations in any arm are rejected.
+    assert!(TableBuilder::new()
+        .add_rule(
+            "start",
+            RuleBuilders::<BuiltinMatcherBuilders, BuiltinActionBuilders>::SwitchBlock(
+                SwitchBuilder::new(BranchBuilder::new("end"))
+                    .add_arm("qtype([A])", BranchBuilder::new("nowhere")),
+            ),
+        )
+        .async_try_into()
+        .await
+        .is_err());
+}
+
+#[tokio::test]
+async fn test_rcode_fallback() {
+    let socket = UdpSocket::bind(&"127.0.0.1:53568").await.unwrap();
+    let domestic = Server::new(socket, Handler::script(vec![MockBehavior::Nxdomain]));
+    let domestic_hits = domestic.received();
+    tokio::spawn(domestic.run());
+    let socket = UdpSocket::bind(&"127.0.0.1:53569").await.unwrap();
+    let global = Server::answering(socket, &DUMMY_MSG);
+    let global_hits = global.received();
+    tokio::spawn(global.run());
+
+    let udp = |addr: &str| UdpBuilder {
+        addr: addr.parse().unwrap(),
+        max_pool_size: 4,
+        timeout: 1,
+        ratelimit: None,
+        cache: CacheSettings::default(),
+    };
+    let router: Router = RouterBuilder::new(
+        TableBuilder::new()
+            .add_rule(
+                "start",
+                RuleBuilders::<BuiltinMatcherBuilders, _>::SeqBlock(
+                    BranchBuilder::new("check").add_action(BuiltinActionBuilders::Query(
+                        QueryBuilder::new("domestic", CacheMode::Disabled),
+                    )),
+                ),
+            )
+            .add_rule(
+                "check",
+                RuleBuilders::<BuiltinMatcherBuilders, _>::IfBlock(IfBlockBuilder::new(
+                    "rcode([NXDOMAIN, SERVFAIL])",
+                    BranchBuilder::new("end").add_action(BuiltinActionBuilders::Query(
+                        QueryBuilder::new("global", CacheMode::Disabled),
+                    )),
+                    BranchBuilder::new("end"),
+                )),
+            ),
+        UpstreamsBuilder::new(16)
+            .unwrap()
+            .add_upstream("domestic", udp("127.0.0.1:53568"))
+            .add_upstream("global", udp("127.0.0.1:53569")),
+    )
+    .async_try_into()
+    .await
+    .unwrap();
+
+    let resp = router.resolve(QUERY.clone()).await.unwrap();
+    assert_eq!(resp.header().rcode(), Rcode::NoError);
+    assert_eq!(resp.header_counts().ancount(), 1);
+    assert_eq!(domestic_hits.load(Ordering::SeqCst), 1);
+    assert_eq!(global_hits.load(Ordering::SeqCst), 1);
+}
+
+#[tokio::test]
+async fn test_protocol() {
+    let socket = UdpSocket::bind(&"127.0.0.1:53570").await.unwrap();
+    let server = Server::answering(socket, &DUMMY_MSG);
+    let received = server.received();
+    tokio::spawn(server.run());
+
+    // Only answer queries received over stream transports.
+    let router: Router = RouterBuilder::new(
+        TableBuilder::new().add_rule(
+            "start",
+            RuleBuilders::<BuiltinMatcherBuilders, _>::IfBlock(IfBlockBuilder::new(
+                "protocol([tcp, dot])",
+                BranchBuilder::new("end").add_action(BuiltinActionBuilders::Query(
+                    QueryBuilder::new("mock", CacheMode::Disabled),
+                )),
+                BranchBuilder::new("end").add_action(BuiltinActionBuilders::Blackhole),
+            )),
+        ),
+        UpstreamsBuilder::new(16).unwrap().add_upstream(
+            "mock",
+            UdpBuilder {
+                addr: "127.0.0.1:53570".parse().unwrap(),
+                max_pool_size: 4,
+                timeout: 1,
+                ratelimit: None,
+                cache: CacheSettings::default(),
+            },
+        ),
+    )
+    .async_try_into()
+    .await
+    .unwrap();
+
+    let ctx = |protocol| QueryContext::new("192.168.1.1:5353".parse().unwrap(), protocol);
+    let resp = router
+        .resolve_with_ctx(QUERY.clone(), Some(ctx(Protocol::Tcp)))
+        .await
+        .unwrap();
+    assert_eq!(resp.header_counts().ancount(), 1);
+    assert_eq!(received.load(Ordering::SeqCst), 1);
+    for qctx in [Some(ctx(Protocol::Udp)), None] {
+        let resp = router.resolve_with_ctx(QUERY.clone(), qctx).await.unwrap();
+        assert_eq!(resp.header_counts().ancount(), 0);
+    }
+    assert_eq!(received.load(Ordering::SeqCst), 1);
+}
+
+#[tokio::test]
+async fn test_negation() {
+    let socket = UdpSocket::bind(&"127.0.0.1:53571").await.unwrap();
+    let server = Server::answering(socket, &DUMMY_MSG);
+    let received = server.received();
+    tokio::spawn(server.run());
+
+    // Forward everything except the listed domains.
+    let router: Router = RouterBuilder::new(
+        TableBuilder::new().add_rule(
+            "start",
+            RuleBuilders::<BuiltinMatcherBuilders, _>::IfBlock(IfBlockBuilder::new(
+                r#"!domain([qname("example.com"), qname("example.org")])"#,
+                BranchBuilder::new("end").add_action(BuiltinActionBuilders::Query(
+                    QueryBuilder::new("mock", CacheMode::Disabled),
+                )),
+                BranchBuilder::new("end").add_action(BuiltinActionBuilders::Blackhole),
+            )),
+        ),
+        UpstreamsBuilder::new(16).unwrap().add_upstream(
+            "mock",
+            UdpBuilder {
+                addr: "127.0.0.1:53571".parse().unwrap(),
+                max_pool_size: 4,
+                timeout: 1,
+                ratelimit: None,
+                cache: CacheSettings::default(),
+            },
+        ),
+    )
+    .async_try_into()
+    .await
+    .unwrap();
+
+    let resp = router.resolve(QUERY.clone()).await.unwrap();
+    assert_eq!(resp.header_counts().ancount(), 1);
+    assert_eq!(received.load(Ordering::SeqCst), 1);
+    for name in ["example.com", "www.example.org"] {
+        let resp = router
+            .resolve(WarmUp::query(&Dname::from_str(name).unwrap(), Rtype::A))
+            .await
+            .unwrap();
+        assert_eq!(resp.header_counts().ancount(), 0);
+    }
+    assert_eq!(received.load(Ordering::SeqCst), 1);
+}
+
+#[tokio::test]
+async fn test_compound_expr() {
+    let socket = UdpSocket::bind(&"127.0.0.1:53572").await.unwrap();
+    let server = Server::answering(socket, &DUMMY_MSG);
+    let received = server.received();
+    tokio::spawn(server.run());
+
+    let router: Router = RouterBuilder::new(
+        TableBuilder::new().add_rule(
+            "start",
+            RuleBuilders::<BuiltinMatcherBuilders, _>::IfBlock(IfBlockBuilder::new(
+                r#"(domain([qname("example.com")]) && qtype([A, AAAA])) || src_ip(["10.0.0.0/8"])"#,
+                BranchBuilder::new("end").add_action(BuiltinActionBuilders::Query(
+                    QueryBuilder::new("mock", CacheMode::Disabled),
+                )),
+                BranchBuilder::new("end").add_action(BuiltinActionBuilders::Blackhole),
+            )),
+        ),
+        UpstreamsBuilder::new(16).unwrap().add_upstream(
+            "mock",
+            UdpBuilder {
+                addr: "127.0.0.1:53572".parse().unwrap(),
+                max_pool_size: 4,
+                timeout: 1,
+                ratelimit: None,
+                cache: CacheSettings::default(),
+            },
+        ),
+    )
+    .async_try_into()
+    .await
+    .unwrap();
+
+    let ctx = |src: &str| Some(QueryContext::new(src.parse().unwrap(), Protocol::Udp));
+    for (name, rtype, qctx, forwarded) in [
+        ("example.com", Rtype::A, ctx("192.168.1.1:5353"), true),
+        ("www.example.com", Rtype::Aaaa, None, true),
+        ("example.com", Rtype::Mx, ctx("192.168.1.1:5353"), false),
+        ("example.org", Rtype::A, ctx("192.168.1.1:5353"), false),
+        ("example.org", Rtype::Mx, ctx("10.1.2.3:5353"), true),
+    ] {
+        let before = received.load(Ordering::SeqCst);
         router
-            .resolve(QUERY.clone(), None)
+            .resolve_with_ctx(WarmUp::query(&Dname::from_str(name).unwrap(), rtype), qctx)
+            .await
+            .unwrap();
+        assert_eq!(
+            received.load(Ordering::SeqCst) - before,
+            usize::from(forwarded),
+            "{} {}",
+            name,
+            rtype
+        );
+    }
+}
+
+#[tokio::test]
+async fn test_call() {
+    let hits = Arc::new(AtomicUsize::new(0));
+    let socket = UdpSocket::bind(&"127.0.0.1:53548").await.unwrap();
+    tokio::spawn(counting_server(socket, hits.clone()));
+
+    // `sanitize` -> `log` is the shared chain called by both branches.
+    let router: Router = RouterBuilder::new(
+        TableBuilder::new()
+            .add_rule(
+                "start",
+                RuleBuilders::<BuiltinMatcherBuilders, _>::IfBlock(IfBlockBuilder::new(
+                    "qtype([A])",
+                    BranchBuilder::call("sanitize", "forward"),
+                    BranchBuilder::call("sanitize", "blackhole"),
+                )),
+            )
+            .add_rule(
+                "sanitize",
+                RuleBuilders::<BuiltinMatcherBuilders, _>::SeqBlock(BranchBuilder::new("log")),
+            )
+            .add_rule(
+                "log",
+                RuleBuilders::<BuiltinMatcherBuilders, _>::SeqBlock(BranchBuilder::new("end")),
+            )
+            .add_rule(
+                "forward",
+                RuleBuilders::<BuiltinMatcherBuilders, _>::SeqBlock(
+                    BranchBuilder::new("end").add_action(BuiltinActionBuilders::Query(
+                        QueryBuilder::new("mock", CacheMode::Disabled),
+                    )),
+                ),
+            )
+            .add_rule(
+                "blackhole",
+                RuleBuilders::<BuiltinMatcherBuilders, _>::SeqBlock(
+                    BranchBuilder::new("end").add_action(BuiltinActionBuilders::Blackhole),
+                ),
+            ),
+        UpstreamsBuilder::new(16).unwrap().add_upstream(
+            "mock",
+            UdpBuilder {
+                addr: "127.0.0.1:53548".parse().unwrap(),
+                max_pool_size: 4,
+                timeout: 1,
+                ratelimit: None,
+                cache: CacheSettings::default(),
+            },
+        ),
+    )
+    .async_try_into()
+    .await
+    .unwrap();
+
+    for (qtype, last) in [(Rtype::A, "forward"), (Rtype::Aaaa, "blackhole")] {
+        let (_, trace) = router
+            .resolve_traced(
+                WarmUp::query(&Dname::from_str("example.com").unwrap(), qtype),
+                None,
+            )
             .await
+            .unwrap();
+        assert_eq!(trace.error, None);
+        assert_eq!(
+            trace
+                .steps
+                .iter()
+                .map(|s| s.tag.as_str())
+                .collect::<Vec<_>>(),
+            vec!["start", "sanitize", "log", last]
+        );
+    }
+    assert_eq!(hits.load(Ordering::Relaxed), 1);
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_timeout() {
+    let socket = UdpSocket::bind(&"127.0.0.1:53549").await.unwrap();
+    let server = Server::new(
+        socket,
+        Handler::script(vec![MockBehavior::Timeout(Duration::from_secs(10))]),
+    );
+    tokio::spawn(server.run());
+
+    let router: Router = RouterBuilder::new(
+        TableBuilder::new()
+            .add_rule(
+                "start",
+                RuleBuilders::<BuiltinMatcherBuilders, _>::SeqBlock(BranchBuilder::<
+                    BuiltinActionBuilders,
+                >::new("slow")),
+            )
+            .add_rule(
+                "slow",
+                RuleBuilders::<BuiltinMatcherBuilders, _>::SeqBlock(
+                    BranchBuilder::new("end").add_action(BuiltinActionBuilders::Query(
+                        QueryBuilder::new("mock", CacheMode::Disabled),
+                    )),
+                ),
+            ),
+        UpstreamsBuilder::new(16).unwrap().add_upstream(
+            "mock",
+            UdpBuilder {
+                addr: "127.0.0.1:53549".parse().unwrap(),
+                max_pool_size: 4,
+                timeout: 30,
+                ratelimit: None,
+                cache: CacheSettings::default(),
+            },
+        ),
+    )
+    .timeout(Duration::from_millis(100))
+    .async_try_into()
+    .await
+    .unwrap();
+
+    let start = tokio::time::Instant::now();
+    let (resp, trace) = router
+        .resolve_traced(
+            WarmUp::query(&Dname::from_str("example.com").unwrap(), Rtype::A),
+            None,
+        )
+        .await
+        .unwrap();
+    let elapsed = start.elapsed();
+    assert_eq!(resp.header().rcode(), Rcode::ServFail);
+    assert!(elapsed >= Duration::from_millis(100) && elapsed < Duration::from_secs(1));
+    assert_eq!(
+        trace.error.unwrap(),
+        "Routing timed out at the rule with tag `slow`"
+    );
+}
+
+// Real time is used, as the paused clock would run out the budgets while the upstreams are still answering on sockets.
+#[tokio::test]
+async fn test_rule_deadline() {
+    let socket = UdpSocket::bind(&"127.0.0.1:53558").await.unwrap();
+    tokio::spawn(delayed_server(socket, Duration::from_secs(10)));
+    let socket = UdpSocket::bind(&"127.0.0.1:53559").await.unwrap();
+    tokio::spawn(delayed_server(socket, Duration::from_millis(1500)));
+    let hits = Arc::new(AtomicUsize::new(0));
+    let socket = UdpSocket::bind(&"127.0.0.1:53560").await.unwrap();
+    tokio::spawn(counting_server(socket, hits.clone()));
+
+    let router = |table: &'static str| async move {
+        let udp = |addr: &str| UdpBuilder {
+            addr: addr.parse().unwrap(),
+            max_pool_size: 4,
+            timeout: 30,
+            ratelimit: None,
+            cache: CacheSettings::default(),
+        };
+        let router: Router = RouterBuilder::new(
+            TableBuilder::<RuleBuilders<BuiltinMatcherBuilders, BuiltinActionBuilders>>::from_yaml(
+                table,
+            )
+            .unwrap(),
+            UpstreamsBuilder::new(16)
+                .unwrap()
+                .add_upstream("slow", udp("127.0.0.1:53558"))
+                .add_upstream("medium", udp("127.0.0.1:53559"))
+                .add_upstream("fast", udp("127.0.0.1:53560")),
+        )
+        .async_try_into()
+        .await
+        .unwrap();
+        router
+    };
+    let query = || WarmUp::query(&Dname::from_str("example.com").unwrap(), Rtype::A);
+
+    // The budget of the rule cuts the slow upstream short, long before the router gives up.
+    let r = router(
+        r#"
+start:
+  if: "true"
+  deadline_ms: 800
+  then:
+    - query: slow
+    - end
+  else:
+    - query: medium
+    - query: fast
+    - end
+"#,
+    )
+    .await;
+    let start = tokio::time::Instant::now();
+    let (resp, trace) = r.resolve_traced(query(), None).await.unwrap();
+    let elapsed = start.elapsed();
+    assert_eq!(resp.header().rcode(), Rcode::ServFail);
+    assert!(elapsed >= Duration::from_millis(800) && elapsed < Duration::from_secs(3));
+    assert_eq!(
+        trace.error.unwrap(),
+        "Routing timed out at the rule with tag `start`"
+    );
+
+    // Chains called inherit the tightest budget, the one of the rule inside doesn't extend it.
+    let r = router(
+        r#"
+start:
+  if: "true"
+  deadline_ms: 300
+  then:
+    - call: chain
+      then: end
+  else:
+    - query: slow
+    - query: fast
+    - end
+chain:
+  if: "true"
+  deadline_ms: 5000
+  then:
+    - query: medium
+    - query: fast
+    - end
+  else:
+    - end
+"#,
+    )
+    .await;
+    let start = tokio::time::Instant::now();
+    let (resp, trace) = r.resolve_traced(query(), None).await.unwrap();
+    assert_eq!(resp.header().rcode(), Rcode::ServFail);
+    assert!(start.elapsed() < Duration::from_millis(1500));
+    assert_eq!(
+        trace.error.unwrap(),
+        "Routing timed out at the rule with tag `chain`"
+    );
+    // The attempt after the budget is used up is never even started.
+    assert_eq!(hits.load(Ordering::SeqCst), 0);
+
+    // The budget only covers the rule and the chains it calls, not the rules after them.
+    let r = router(
+        r#"
+start:
+  if: "true"
+  deadline_ms: 300
+  then:
+    - call: chain
+      then: after
+  else:
+    - query: slow
+    - end
+chain:
+  - query: fast
+  - end
+after:
+  - query: medium
+  - end
+"#,
+    )
+    .await;
+    let (resp, trace) = r.resolve_traced(query(), None).await.unwrap();
+    assert_eq!(resp.header().rcode(), Rcode::NoError);
+    assert_eq!(trace.error, None);
+    assert_eq!(hits.load(Ordering::SeqCst), 1);
+}
+
+#[tokio::test]
+async fn test_metrics() {
+    let hits = Arc::new(AtomicUsize::new(0));
+    let socket = UdpSocket::bind(&"127.0.0.1:53550").await.unwrap();
+    tokio::spawn(counting_server(socket, hits));
+
+    let router: Router = RouterBuilder::new(
+        TableBuilder::new().add_rule(
+            "start",
+            RuleBuilders::<BuiltinMatcherBuilders, _>::IfBlock(IfBlockBuilder::new(
+                r#"domain([qname("dead.example")])"#,
+                BranchBuilder::new("end").add_action(BuiltinActionBuilders::Query(
+                    QueryBuilder::new("dead", CacheMode::Standard),
+                )),
+                BranchBuilder::new("end").add_action(BuiltinActionBuilders::Query(
+                    QueryBuilder::new("mock", CacheMode::Standard),
+                )),
+            )),
+        ),
+        UpstreamsBuilder::new(16)
             .unwrap()
-            .into_octets(),
-        DUMMY_MSG.clone().into_octets()
+            .add_upstream(
+                "mock",
+                UdpBuilder {
+                    addr: "127.0.0.1:53550".parse().unwrap(),
+                    max_pool_size: 4,
+                    timeout: 1,
+                    ratelimit: None,
+                    cache: CacheSettings::default(),
+                },
+            )
+            // Nothing is listening here
+            .add_upstream(
+                "dead",
+                UdpBuilder {
+                    addr: "127.0.0.1:53551".parse().unwrap(),
+                    max_pool_size: 4,
+                    timeout: 1,
+                    ratelimit: None,
+                    cache: CacheSettings::default(),
+                },
+            ),
+    )
+    .async_try_into()
+    .await
+    .unwrap();
+
+    let query = |name| WarmUp::query(&Dname::from_str(name).unwrap(), Rtype::A);
+    // The second one is answered by cache
+    for name in ["example.com", "example.com", "dead.example"] {
+        router.resolve(query(name)).await.unwrap();
+    }
+    // Malformatted query without question
+    router
+        .resolve(
+            MessageBuilder::from_target(BytesMut::with_capacity(1232))
+                .unwrap()
+                .into_message(),
+        )
+        .await
+        .unwrap();
+
+    let metrics = router.metrics();
+    assert_eq!(metrics.queries, 4);
+    assert_eq!(
+        metrics.rcodes,
+        vec![
+            ("FORMERR".to_string(), 1),
+            ("NOERROR".to_string(), 2),
+            ("SERVFAIL".to_string(), 1)
+        ]
+        .into_iter()
+        .collect()
+    );
+    assert_eq!(metrics.errors, 2);
+    assert_eq!(metrics.cache_hits, 1);
+    assert_eq!(metrics.stale_hits, 0);
+    assert_eq!(metrics.upstream_responses, 1);
+    assert_eq!(metrics.upstream_errors, 1);
+    assert_eq!(metrics.cache_hit_ratio(), Some(0.5));
+    assert_eq!(metrics.latency.count(), 4);
+    assert_eq!(metrics.upstream_latency.count(), 3);
+    assert_eq!(metrics.upstreams["mock"].responses, 1);
+    assert_eq!(metrics.upstreams["mock"].cache_hits, 1);
+    assert_eq!(metrics.upstreams["dead"].errors, 1);
+    assert!(metrics.p50().is_some());
+    assert!(metrics.p50() <= metrics.p99());
+
+    router.reset_metrics();
+    let metrics = router.metrics();
+    assert_eq!(metrics.queries, 0);
+    assert!(metrics.rcodes.is_empty());
+    assert!(metrics.upstreams.is_empty());
+    assert_eq!(metrics.latency.count(), 0);
+    assert_eq!(metrics.cache_hit_ratio(), None);
+}
+
+#[tokio::test]
+async fn test_shutdown() {
+    let socket = UdpSocket::bind(&"127.0.0.1:53552").await.unwrap();
+    tokio::spawn(delayed_server(socket, Duration::from_millis(500)));
+
+    let router = Arc::new(
+        Router::new(
+            query_table("mock").await,
+            reload_upstreams("127.0.0.1:53552").await,
+        )
+        .unwrap(),
+    );
+    let query = WarmUp::query(&Dname::from_str("example.com").unwrap(), Rtype::A);
+
+    let slow = {
+        let router = router.clone();
+        let query = query.clone();
+        tokio::spawn(async move { router.resolve(query).await })
+    };
+    // Make sure the slow query is in flight
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    // Grace period too short
+    assert!(!router.shutdown(Duration::from_millis(50)).await);
+    assert!(router.is_shutting_down());
+    // New queries are rejected right away
+    assert!(matches!(
+        router.resolve(query.clone()).await,
+        Err(DrouteError::ShuttingDown)
+    ));
+
+    assert!(router.shutdown(Duration::from_secs(2)).await);
+    let resp = slow.await.unwrap().unwrap();
+    assert_eq!(resp.header().rcode(), Rcode::NoError);
+    assert_eq!(resp.header_counts().ancount(), 1);
+
+    // Nothing in flight
+    assert!(router.shutdown(Duration::ZERO).await);
+
+    // SERVFAIL instead of error if configured
+    let router = Router::new(
+        blackhole_table("mock").await,
+        reload_upstreams("127.0.0.1:53552").await,
+    )
+    .unwrap()
+    .with_servfail_on_shutdown(true);
+    router.shutdown(Duration::ZERO).await;
+    assert_eq!(
+        router.resolve(query).await.unwrap().header().rcode(),
+        Rcode::ServFail
+    );
+}
+
+// Answers queries after the delay, recording the highest number of queries being answered at the same time.
+async fn concurrency_server(socket: UdpSocket, delay: Duration, peak: Arc<AtomicUsize>) {
+    let socket = Arc::new(socket);
+    let current = Arc::new(AtomicUsize::new(0));
+    // Sent by the connection pool to check whether the connection is reusable.
+    let probe = Dname::<Bytes>::from_str("example.com").unwrap();
+    let mut buf = vec![0; 1024];
+    loop {
+        let (len, src) = socket.recv_from(&mut buf).await.unwrap();
+        let query = Message::from_octets(buf[..len].to_vec()).unwrap();
+        if query.first_question().unwrap().qname() == &probe {
+            continue;
+        }
+        let socket = socket.clone();
+        let current = current.clone();
+        let peak = peak.clone();
+        tokio::spawn(async move {
+            peak.fetch_max(current.fetch_add(1, Ordering::SeqCst) + 1, Ordering::SeqCst);
+            tokio::time::sleep(delay).await;
+            current.fetch_sub(1, Ordering::SeqCst);
+            let builder = MessageBuilder::from_target(BytesMut::with_capacity(1024))
+                .unwrap()
+                .start_answer(&query, Rcode::NoError)
+                .unwrap();
+            socket.send_to(&builder.finish(), src).await.unwrap();
+        });
+    }
+}
+
+#[tokio::test]
+async fn test_max_concurrent_queries() {
+    let peak = Arc::new(AtomicUsize::new(0));
+    let socket = UdpSocket::bind(&"127.0.0.1:53553").await.unwrap();
+    tokio::spawn(concurrency_server(
+        socket,
+        Duration::from_millis(100),
+        peak.clone(),
+    ));
+
+    let router = |wait| async move {
+        Arc::new(
+            RouterBuilder::new(
+                TableBuilder::new().add_rule(
+                    "start",
+                    RuleBuilders::<BuiltinMatcherBuilders, _>::SeqBlock(
+                        BranchBuilder::new("end").add_action(BuiltinActionBuilders::Query(
+                            QueryBuilder::new("mock", CacheMode::Disabled),
+                        )),
+                    ),
+                ),
+                // Connection pool is large enough not to limit the concurrency itself
+                UpstreamsBuilder::new(16).unwrap().add_upstream(
+                    "mock",
+                    UdpBuilder {
+                        addr: "127.0.0.1:53553".parse().unwrap(),
+                        max_pool_size: 128,
+                        timeout: 5,
+                        ratelimit: None,
+                        cache: CacheSettings::default(),
+                    },
+                ),
+            )
+            .max_concurrent_queries(NonZeroUsize::new(10).unwrap(), wait)
+            .async_try_into()
+            .await
+            .unwrap(),
+        )
+    };
+    let burst = |router: Arc<Router>| async move {
+        futures::future::join_all((0..100).map(|n| {
+            let router = router.clone();
+            async move {
+                router
+                    .resolve(WarmUp::query(
+                        &Dname::from_str(&format!("{}.example.com", n)).unwrap(),
+                        Rtype::A,
+                    ))
+                    .await
+                    .unwrap()
+                    .header()
+                    .rcode()
+            }
+        }))
+        .await
+    };
+
+    // Queries beyond the limit wait for their turns
+    let r = router(Some(Duration::from_secs(10))).await;
+    assert!(burst(r.clone())
+        .await
+        .into_iter()
+        .all(|rcode| rcode == Rcode::NoError));
+    assert_eq!(peak.load(Ordering::SeqCst), 10);
+    let metrics = r.metrics();
+    assert_eq!(metrics.peak_concurrent, 10);
+    assert_eq!(metrics.concurrent, 0);
+    assert_eq!(metrics.rejected, 0);
+
+    // Queries beyond the limit fail right away
+    peak.store(0, Ordering::SeqCst);
+    let r = router(None).await;
+    let rcodes = burst(r.clone()).await;
+    let metrics = r.metrics();
+    assert!(peak.load(Ordering::SeqCst) <= 10);
+    assert!(metrics.rejected >= 90);
+    assert_eq!(
+        rcodes.iter().filter(|r| **r == Rcode::ServFail).count() as u64,
+        metrics.rejected
+    );
+    assert_eq!(metrics.rcodes.get("SERVFAIL"), Some(&metrics.rejected));
+    assert_eq!(metrics.errors, 0);
+}
+
+#[tokio::test]
+async fn test_multiple_tables() {
+    let kids_hits = Arc::new(AtomicUsize::new(0));
+    let adults_hits = Arc::new(AtomicUsize::new(0));
+    let socket = UdpSocket::bind(&"127.0.0.1:53555").await.unwrap();
+    tokio::spawn(counting_server(socket, kids_hits.clone()));
+    let socket = UdpSocket::bind(&"127.0.0.1:53556").await.unwrap();
+    tokio::spawn(counting_server(socket, adults_hits.clone()));
+
+    let upstreams = || async {
+        UpstreamsBuilder::new(16)
+            .unwrap()
+            .add_upstream(
+                "kids",
+                UdpBuilder {
+                    addr: "127.0.0.1:53555".parse().unwrap(),
+                    max_pool_size: 4,
+                    timeout: 1,
+                    ratelimit: None,
+                    cache: CacheSettings::default(),
+                },
+            )
+            .add_upstream(
+                "adults",
+                UdpBuilder {
+                    addr: "127.0.0.1:53556".parse().unwrap(),
+                    max_pool_size: 4,
+                    timeout: 1,
+                    ratelimit: None,
+                    cache: CacheSettings::default(),
+                },
+            )
+            .async_try_into()
+            .await
+            .unwrap()
+    };
+    let tables = |kids: &'static str| async move {
+        HashMap::from([
+            ("kids".into(), query_table(kids).await),
+            ("adults".into(), query_table("adults").await),
+        ])
+    };
+
+    // Each upstream is only used by one table, while every table is validated against the same upstreams.
+    let router = Router::new_multi(tables("kids").await, upstreams().await)
+        .unwrap()
+        .with_table_selector(
+            |qctx| match qctx.and_then(|c| c.local_addr).map(|a| a.port()) {
+                Some(5300) => "kids".into(),
+                _ => "adults".into(),
+            },
+        );
+    let query = WarmUp::query(&Dname::from_str("example.com").unwrap(), Rtype::A);
+    let ctx = |port: u16| {
+        QueryContext::new("192.168.1.1:5353".parse().unwrap(), Protocol::Udp)
+            .with_local_addr(SocketAddr::from(([127, 0, 0, 1], port)))
+    };
+    // The upstream pool may send a probe of its own, so only count the difference.
+    let hits = || {
+        (
+            kids_hits.load(Ordering::SeqCst),
+            adults_hits.load(Ordering::SeqCst),
+        )
+    };
+
+    let (kids, adults) = hits();
+    router
+        .resolve_with_ctx(query.clone(), Some(ctx(5300)))
+        .await
+        .unwrap();
+    assert!(hits().0 > kids);
+    assert_eq!(hits().1, adults);
+
+    let (kids, adults) = hits();
+    router
+        .resolve_with_ctx(query.clone(), Some(ctx(5301)))
+        .await
+        .unwrap();
+    router.resolve(query.clone()).await.unwrap();
+    assert_eq!(hits().0, kids);
+    assert!(hits().1 > adults);
+
+    // Naming the table explicitly overrides the selector
+    let (kids, _) = hits();
+    router
+        .resolve_in_table("kids", query.clone(), Some(ctx(5301)))
+        .await
+        .unwrap();
+    assert!(hits().0 > kids);
+
+    assert!(matches!(
+        router.resolve_in_table("teens", query.clone(), None).await,
+        Err(DrouteError::UnknownTable(t)) if t.as_str() == "teens"
+    ));
+    assert_eq!(
+        router.table_stats_of("kids").unwrap()["start"].evaluations,
+        2
+    );
+    assert!(router.table_stats_of("teens").is_none());
+
+    // The upstreams used by any of the tables have to be defined, and the ones used by none are reported.
+    match Router::new_multi(tables("missing").await, upstreams().await) {
+        Err(DrouteError::Multiple(v)) => {
+            assert_eq!(v.len(), 2);
+            assert!(matches!(
+                &v[0],
+                DrouteError::UpstreamError(UpstreamError::MissingTag(t)) if t.as_str() == "missing"
+            ));
+            assert!(matches!(
+                &v[1],
+                DrouteError::UpstreamError(UpstreamError::UnusedUpstreams(_))
+            ));
+        }
+        _ => panic!("Not the right error type"),
+    }
+}
+
+// Answer every query with an empty response after as many tens of milliseconds as the first label of the name, e.g. `3.example`.
+async fn varied_server(socket: UdpSocket) {
+    let socket = Arc::new(socket);
+    let mut buf = vec![0; 1024];
+    loop {
+        let (len, src) = socket.recv_from(&mut buf).await.unwrap();
+        let query = Message::from_octets(buf[..len].to_vec()).unwrap();
+        let delay = query
+            .first_question()
+            .unwrap()
+            .qname()
+            .first()
+            .to_string()
+            .parse()
+            .unwrap_or(0);
+        let socket = socket.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(delay * 10)).await;
+            let resp = MessageBuilder::from_target(BytesMut::with_capacity(1024))
+                .unwrap()
+                .start_answer(&query, Rcode::NoError)
+                .unwrap()
+                .finish();
+            socket.send_to(&resp, src).await.unwrap();
+        });
+    }
+}
+
+#[tokio::test]
+async fn test_resolve_batch() {
+    let socket = UdpSocket::bind(&"127.0.0.1:53557").await.unwrap();
+    tokio::spawn(varied_server(socket));
+
+    let router = Router::new(
+        query_table("mock").await,
+        reload_upstreams("127.0.0.1:53557").await,
+    )
+    .unwrap();
+    let query = |n: usize| {
+        WarmUp::query(
+            &Dname::from_str(&format!("{}.example", n)).unwrap(),
+            Rtype::A,
+        )
+    };
+    // Earlier queries take longer, so they finish in the reverse order.
+    let names: Vec<usize> = (0..10).rev().collect();
+    let mut batch: Vec<_> = names.iter().map(|n| (query(*n), None)).collect();
+    // A malformed query in the middle is answered on its own.
+    let malformed =
+        Message::from_octets(Bytes::copy_from_slice(&query(0).as_slice()[..14])).unwrap();
+    batch.insert(5, (malformed, None));
+
+    for max in [None, NonZeroUsize::new(3)] {
+        let resps = router.resolve_batch(batch.clone(), max).await;
+        assert_eq!(resps.len(), batch.len());
+        for (resp, (query, _)) in resps.into_iter().zip(batch.iter()) {
+            let resp = resp.unwrap();
+            assert_eq!(resp.header().id(), query.header().id());
+            if query.as_slice().len() == 14 {
+                assert_eq!(resp.header().rcode(), Rcode::FormErr);
+            } else {
+                assert_eq!(resp.header().rcode(), Rcode::NoError);
+                assert_eq!(
+                    resp.first_question().unwrap().qname(),
+                    query.first_question().unwrap().qname()
+                );
+            }
+        }
+    }
+    assert!(router.resolve_batch(vec![], None).await.is_empty());
+}
+
+#[tokio::test]
+async fn test_preflight() {
+    use droute::preflight::{PreflightOptions, Status};
+
+    let socket = UdpSocket::bind(&"127.0.0.1:53564").await.unwrap();
+    tokio::spawn(Server::answering(socket, &DUMMY_MSG).run());
+    // Never answers.
+    let socket = UdpSocket::bind(&"127.0.0.1:53565").await.unwrap();
+    tokio::spawn(Server::new(socket, Handler::script(vec![MockBehavior::Drop])).run());
+
+    let builder = |table: &str| {
+        let udp = |addr: &str| UdpBuilder {
+            addr: addr.parse().unwrap(),
+            max_pool_size: 4,
+            timeout: 5,
+            ratelimit: None,
+            cache: CacheSettings::default(),
+        };
+        RouterBuilder::new(
+            TableBuilder::<RuleBuilders<BuiltinMatcherBuilders, BuiltinActionBuilders>>::from_yaml(
+                table,
+            )
+            .unwrap(),
+            UpstreamsBuilder::new(16)
+                .unwrap()
+                .add_upstream("alive", udp("127.0.0.1:53564"))
+                .add_upstream("dead", udp("127.0.0.1:53565")),
+        )
+    };
+    let table = |list: &str| {
+        format!(
+            r#"
+start:
+  if: 'domain([file("../data/apple.txt")])'
+  then:
+    - query: alive
+    - end
+  else:
+    - check
+check:
+  if: 'ipcidr(["{}"])'
+  then:
+    - query: dead
+    - end
+  else:
+    - end
+"#,
+            list
+        )
+    };
+    let probe = PreflightOptions::new().with_probe(
+        Dname::from_str("cloudflare-dns.com").unwrap(),
+        Duration::from_millis(200),
     );
+
+    // The dead upstream is warned about, but the router can still be built.
+    let good = builder(&table("../data/ipcidr-test.txt"));
+    let report = good.preflight(&probe).await;
+    assert_eq!(report.status, Status::Warn);
+    assert!(report.errors.is_empty());
+    let (check, start) = (&report.rules[0], &report.rules[1]);
+    assert_eq!((check.tag.as_str(), check.status), ("check", Status::Pass));
+    assert_eq!(check.resources[0].matcher, "ipcidr");
+    assert_eq!(check.resources[0].entries, 1);
+    assert_eq!((start.tag.as_str(), start.status), ("start", Status::Pass));
+    assert_eq!(start.resources[0].source, "../data/apple.txt");
+    assert_eq!(start.resources[0].entries, 126);
+    let (alive, dead) = (&report.upstreams[0], &report.upstreams[1]);
+    assert_eq!((alive.tag.as_str(), alive.status), ("alive", Status::Pass));
+    assert!(alive.latency_ms.is_some() && alive.error.is_none());
+    assert_eq!((dead.tag.as_str(), dead.status), ("dead", Status::Warn));
+    assert!(dead.latency_ms.is_none() && dead.error.is_some());
+    assert_eq!(
+        serde_json::to_value(&report).unwrap()["status"],
+        serde_json::json!("warn")
+    );
+
+    // Unless in strict mode.
+    let report = good.preflight(&probe.clone().with_strict(true)).await;
+    assert_eq!(report.status, Status::Fail);
+    assert_eq!(report.upstreams[1].status, Status::Fail);
+    // Nothing is probed by default.
+    let report = good.preflight(&PreflightOptions::new()).await;
+    assert_eq!(report.status, Status::Pass);
+    // The builder is still usable.
+    let _: Router = good.async_try_into().await.unwrap();
+
+    // A missing file is attributed to its rule.
+    let report = builder(&table("../data/missing.txt"))
+        .preflight(&probe)
+        .await;
+    assert_eq!(report.status, Status::Fail);
+    assert_eq!(report.rules[0].status, Status::Fail);
+    assert!(report.rules[0]
+        .error
+        .as_ref()
+        .unwrap()
+        .contains("../data/missing.txt"));
+    assert_eq!(report.rules[1].status, Status::Pass);
+    assert!(report.errors.is_empty());
+}
+
+#[cfg(feature = "metrics-export")]
+#[tokio::test]
+async fn test_metrics_export() {
+    use droute::metrics_export::render_prometheus;
+
+    let hits = Arc::new(AtomicUsize::new(0));
+    let socket = UdpSocket::bind(&"127.0.0.1:53566").await.unwrap();
+    tokio::spawn(counting_server(socket, hits));
+
+    let udp = |addr: &str| UdpBuilder {
+        addr: addr.parse().unwrap(),
+        max_pool_size: 4,
+        timeout: 1,
+        ratelimit: None,
+        cache: CacheSettings::default(),
+    };
+    let router: Router = RouterBuilder::new(
+        TableBuilder::new()
+            .add_rule(
+                "start",
+                RuleBuilders::<BuiltinMatcherBuilders, _>::IfBlock(IfBlockBuilder::new(
+                    r#"domain([qname("dead.example")])"#,
+                    BranchBuilder::new("dead"),
+                    BranchBuilder::new("mock"),
+                )),
+            )
+            .add_rule(
+                "dead",
+                RuleBuilders::SeqBlock(BranchBuilder::new("end").add_action(
+                    BuiltinActionBuilders::Query(QueryBuilder::new("dead", CacheMode::Standard)),
+                )),
+            )
+            .add_rule(
+                "mock",
+                RuleBuilders::SeqBlock(BranchBuilder::new("end").add_action(
+                    BuiltinActionBuilders::Query(QueryBuilder::new("mock", CacheMode::Standard)),
+                )),
+            ),
+        UpstreamsBuilder::new(16)
+            .unwrap()
+            .add_upstream("mock", udp("127.0.0.1:53566"))
+            // Nothing is listening here
+            .add_upstream("dead", udp("127.0.0.1:53567")),
+    )
+    .async_try_into()
+    .await
+    .unwrap();
+
+    let query = |name| WarmUp::query(&Dname::from_str(name).unwrap(), Rtype::A);
+    // The second one is answered by cache
+    for name in ["example.com", "example.com", "dead.example"] {
+        router.resolve(query(name)).await.unwrap();
+    }
+
+    let rendered = render_prometheus(&router);
+    // Names and labels of the samples are stable, while latencies are not.
+    let shape: Vec<_> = rendered
+        .lines()
+        .map(|l| match l.starts_with('#') {
+            true => l,
+            false => l.rsplit_once(' ').unwrap().0,
+        })
+        .collect();
+    assert_eq!(
+        shape,
+        include_str!("golden/metrics.prom")
+            .lines()
+            .collect::<Vec<_>>()
+    );
+
+    let samples: HashMap<_, _> = rendered
+        .lines()
+        .filter(|l| !l.starts_with('#'))
+        .map(|l| l.rsplit_once(' ').unwrap())
+        .collect();
+    for (sample, value) in [
+        ("droute_queries_total", "3"),
+        (r#"droute_responses_total{rcode="NOERROR"}"#, "2"),
+        (r#"droute_responses_total{rcode="SERVFAIL"}"#, "1"),
+        ("droute_query_errors_total", "1"),
+        ("droute_queries_rejected_total", "0"),
+        ("droute_queries_in_flight", "0"),
+        ("droute_queries_in_flight_peak", "1"),
+        (r#"droute_query_duration_seconds_bucket{le="+Inf"}"#, "3"),
+        ("droute_query_duration_seconds_count", "3"),
+        (
+            r#"droute_upstream_queries_total{upstream="dead",outcome="error"}"#,
+            "1",
+        ),
+        (
+            r#"droute_upstream_queries_total{upstream="mock",outcome="cache"}"#,
+            "1",
+        ),
+        (
+            r#"droute_upstream_queries_total{upstream="mock",outcome="upstream"}"#,
+            "1",
+        ),
+        (
+            r#"droute_upstream_queries_total{upstream="mock",outcome="stale"}"#,
+            "0",
+        ),
+        ("droute_upstream_query_duration_seconds_count", "3"),
+        (
+            r#"droute_rule_evaluations_total{table="default",rule="start"}"#,
+            "3",
+        ),
+        (
+            r#"droute_rule_evaluations_total{table="default",rule="mock"}"#,
+            "2",
+        ),
+        (
+            r#"droute_rule_evaluations_total{table="default",rule="dead"}"#,
+            "1",
+        ),
+        (
+            r#"droute_rule_branches_total{table="default",rule="start",branch="then"}"#,
+            "1",
+        ),
+        (
+            r#"droute_rule_branches_total{table="default",rule="start",branch="else"}"#,
+            "2",
+        ),
+    ] {
+        assert_eq!(samples[sample], value, "{}", sample);
+    }
+    // Query names never end up in the metrics.
+    assert!(!rendered.contains("example"));
+}
+
+#[cfg(feature = "doh-server")]
+#[tokio::test]
+async fn test_doh_server() {
+    use droute::serve::doh::{DohService, DNS_MESSAGE};
+    use hyper::{
+        server::conn::AddrStream,
+        service::{make_service_fn, Service},
+    };
+    use reqwest::{header, Client, StatusCode};
+    use std::convert::Infallible;
+
+    let socket = UdpSocket::bind(&"127.0.0.1:53562").await.unwrap();
+    tokio::spawn(Server::answering(socket, &DUMMY_MSG).run());
+    let router = Router::new(
+        query_table("mock").await,
+        reload_upstreams("127.0.0.1:53562").await,
+    )
+    .unwrap();
+
+    let doh = DohService::new(Arc::new(router)).with_max_body_size(512);
+    let make = make_service_fn(move |conn: &AddrStream| {
+        let service = doh.for_peer(conn.remote_addr());
+        async move { Ok::<_, Infallible>(service) }
+    });
+    let server = hyper::Server::bind(&"127.0.0.1:0".parse().unwrap()).serve(make);
+    let url = format!("http://{}/dns-query", server.local_addr());
+    tokio::spawn(server);
+
+    let client = Client::new();
+    let check = |resp: reqwest::Response| async move {
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(resp.headers()[header::CONTENT_TYPE], DNS_MESSAGE);
+        // The sole record has a TTL of 10 seconds.
+        assert_eq!(resp.headers()[header::CACHE_CONTROL], "max-age=10");
+        let msg = Message::from_octets(resp.bytes().await.unwrap()).unwrap();
+        assert_eq!(msg.header_counts().ancount(), 1);
+    };
+
+    check(
+        client
+            .post(&url)
+            .header(header::CONTENT_TYPE, DNS_MESSAGE)
+            .body(QUERY.as_slice().to_vec())
+            .send()
+            .await
+            .unwrap(),
+    )
+    .await;
+    check(
+        client
+            .get(format!(
+                "{}?dns={}",
+                url,
+                base64::encode_config(QUERY.as_slice(), base64::URL_SAFE_NO_PAD)
+            ))
+            .send()
+            .await
+            .unwrap(),
+    )
+    .await;
+
+    let status = |req: reqwest::RequestBuilder| async move { req.send().await.unwrap().status() };
+    assert_eq!(
+        status(client.post(&url).body(QUERY.as_slice().to_vec())).await,
+        StatusCode::UNSUPPORTED_MEDIA_TYPE
+    );
+    assert_eq!(
+        status(
+            client
+                .post(&url)
+                .header(header::CONTENT_TYPE, DNS_MESSAGE)
+                .body(vec![0; 513])
+        )
+        .await,
+        StatusCode::PAYLOAD_TOO_LARGE
+    );
+    assert_eq!(
+        status(
+            client
+                .post(&url)
+                .header(header::CONTENT_TYPE, DNS_MESSAGE)
+                .body(vec![0; 4])
+        )
+        .await,
+        StatusCode::BAD_REQUEST
+    );
+    assert_eq!(
+        status(client.get(format!("{}?dns=!!", url))).await,
+        StatusCode::BAD_REQUEST
+    );
+    assert_eq!(status(client.get(&url)).await, StatusCode::BAD_REQUEST);
+    assert_eq!(
+        status(client.put(&url)).await,
+        StatusCode::METHOD_NOT_ALLOWED
+    );
+
+    // The service can also be called directly.
+    let mut service = DohService::new(Arc::new(
+        Router::new(
+            query_table("mock").await,
+            reload_upstreams("127.0.0.1:53562").await,
+        )
+        .unwrap(),
+    ));
+    let resp = service
+        .call(
+            hyper::Request::post("/dns-query")
+                .header(header::CONTENT_TYPE, DNS_MESSAGE)
+                .body(hyper::Body::from(QUERY.as_slice().to_vec()))
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+}
+
+#[cfg(feature = "dot-server")]
+#[tokio::test]
+async fn test_dot_server() {
+    use droute::serve::dot::DotServer;
+    use rustls::{Certificate, ClientConfig, RootCertStore, ServerName};
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::{TcpListener, TcpStream},
+    };
+    use tokio_rustls::{client::TlsStream, TlsConnector};
+
+    // Write a fresh self-signed certificate for `localhost`, returning it for clients to trust.
+    let dir = std::env::temp_dir().join(format!("droute-dot-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let (cert_path, key_path) = (dir.join("cert.pem"), dir.join("key.pem"));
+    let issue = || {
+        let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+        std::fs::write(&cert_path, cert.serialize_pem().unwrap()).unwrap();
+        std::fs::write(&key_path, cert.serialize_private_key_pem()).unwrap();
+        Certificate(cert.serialize_der().unwrap())
+    };
+    let connect = |addr: SocketAddr, trusted: Certificate| async move {
+        let mut roots = RootCertStore::empty();
+        roots.add(&trusted).unwrap();
+        let config = ClientConfig::builder()
+            .with_safe_defaults()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        TlsConnector::from(Arc::new(config))
+            .connect(
+                ServerName::try_from("localhost").unwrap(),
+                TcpStream::connect(addr).await.unwrap(),
+            )
+            .await
+    };
+    async fn send(stream: &mut TlsStream<TcpStream>, id: u16) {
+        let mut query = BytesMut::from(QUERY.as_slice());
+        query[..2].copy_from_slice(&id.to_be_bytes());
+        stream.write_u16(query.len() as u16).await.unwrap();
+        stream.write_all(&query).await.unwrap();
+    }
+    async fn recv(stream: &mut TlsStream<TcpStream>) -> Message<Bytes> {
+        let mut buf = vec![0; stream.read_u16().await.unwrap().into()];
+        stream.read_exact(&mut buf).await.unwrap();
+        Message::from_octets(Bytes::from(buf)).unwrap()
+    }
+
+    let socket = UdpSocket::bind(&"127.0.0.1:53563").await.unwrap();
+    tokio::spawn(Server::answering(socket, &DUMMY_MSG).run());
+    let router = Router::new(
+        query_table("mock").await,
+        reload_upstreams("127.0.0.1:53563").await,
+    )
+    .unwrap();
+
+    let old = issue();
+    let server = Arc::new(
+        DotServer::from_pem_files(Arc::new(router), &cert_path, &key_path)
+            .unwrap()
+            .with_idle_timeout(Duration::from_millis(500))
+            .with_max_pipelined(NonZeroUsize::new(2).unwrap()),
+    );
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(server.clone().serve(listener));
+
+    // Several queries are sent before reading any response, more than the pipelining limit.
+    let mut stream = connect(addr, old.clone()).await.unwrap();
+    for id in 1..=4 {
+        send(&mut stream, id).await;
+    }
+    let mut ids = Vec::new();
+    for _ in 1..=4 {
+        let resp = recv(&mut stream).await;
+        assert_eq!(resp.header_counts().ancount(), 1);
+        ids.push(resp.header().id());
+    }
+    ids.sort_unstable();
+    assert_eq!(ids, vec![1, 2, 3, 4]);
+
+    // The connection is closed after idling.
+    tokio::time::sleep(Duration::from_millis(800)).await;
+    let mut buf = [0; 1];
+    assert_eq!(stream.read(&mut buf).await.unwrap(), 0);
+
+    // Connections accepted after the reload get the new certificate.
+    let new = issue();
+    server.reload_cert().unwrap();
+    assert!(connect(addr, old).await.is_err());
+    let mut stream = connect(addr, new).await.unwrap();
+    send(&mut stream, 5).await;
+    assert_eq!(recv(&mut stream).await.header().id(), 5);
+
+    // The old certificate is kept if the files are broken.
+    std::fs::write(&key_path, "").unwrap();
+    assert!(server.reload_cert().is_err());
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+// A subscriber recording the fields of every span and event, and the span each event happened in.
+#[cfg(feature = "tracing")]
+mod capture {
+    use std::{
+        collections::HashMap,
+        fmt::Debug,
+        sync::{Arc, Mutex},
+    };
+    use tracing::{
+        field::{Field, Visit},
+        span::{Attributes, Id, Record},
+        Event, Metadata, Subscriber,
+    };
+
+    pub type Fields = HashMap<String, String>;
+
+    struct Visitor<'a>(&'a mut Fields);
+
+    impl Visit for Visitor<'_> {
+        fn record_str(&mut self, field: &Field, value: &str) {
+            self.0.insert(field.name().to_string(), value.to_string());
+        }
+
+        fn record_debug(&mut self, field: &Field, value: &dyn Debug) {
+            self.0
+                .insert(field.name().to_string(), format!("{:?}", value));
+        }
+    }
+
+    #[derive(Default)]
+    pub struct Captured {
+        // Name and fields of spans, indexed by ID minus one
+        pub spans: Vec<(String, Fields)>,
+        // Index of the span the event happened in, and fields of the event
+        pub events: Vec<(Option<usize>, Fields)>,
+        stack: Vec<usize>,
+    }
+
+    #[derive(Clone, Default)]
+    pub struct Capture(pub Arc<Mutex<Captured>>);
+
+    impl Subscriber for Capture {
+        fn enabled(&self, _: &Metadata<'_>) -> bool {
+            true
+        }
+
+        fn new_span(&self, attrs: &Attributes<'_>) -> Id {
+            let mut fields = Fields::new();
+            attrs.record(&mut Visitor(&mut fields));
+            let mut c = self.0.lock().unwrap();
+            c.spans.push((attrs.metadata().name().to_string(), fields));
+            Id::from_u64(c.spans.len() as u64)
+        }
+
+        fn record(&self, span: &Id, values: &Record<'_>) {
+            let mut c = self.0.lock().unwrap();
+            values.record(&mut Visitor(&mut c.spans[span.into_u64() as usize - 1].1));
+        }
+
+        fn record_follows_from(&self, _: &Id, _: &Id) {}
+
+        fn event(&self, event: &Event<'_>) {
+            let mut fields = Fields::new();
+            event.record(&mut Visitor(&mut fields));
+            let mut c = self.0.lock().unwrap();
+            let span = match event.parent() {
+                Some(id) => Some(id.into_u64() as usize - 1),
+                None if event.is_contextual() => c.stack.last().copied(),
+                None => None,
+            };
+            c.events.push((span, fields));
+        }
+
+        fn enter(&self, span: &Id) {
+            self.0
+                .lock()
+                .unwrap()
+                .stack
+                .push(span.into_u64() as usize - 1);
+        }
+
+        fn exit(&self, _: &Id) {
+            self.0.lock().unwrap().stack.pop();
+        }
+    }
+}
+
+#[cfg(feature = "tracing")]
+#[tokio::test]
+async fn test_tracing() {
+    let socket = UdpSocket::bind(&"127.0.0.1:53554").await.unwrap();
+    tokio::spawn(counting_server(socket, Arc::new(AtomicUsize::new(0))));
+
+    let router: Router = RouterBuilder::new(
+        TableBuilder::new().add_rule(
+            "start",
+            RuleBuilders::<BuiltinMatcherBuilders, _>::SeqBlock(
+                BranchBuilder::new("end").add_action(BuiltinActionBuilders::Query(
+                    QueryBuilder::new("mock", CacheMode::Disabled),
+                )),
+            ),
+        ),
+        UpstreamsBuilder::new(16).unwrap().add_upstream(
+            "mock",
+            UdpBuilder {
+                addr: "127.0.0.1:53554".parse().unwrap(),
+                max_pool_size: 4,
+                timeout: 1,
+                ratelimit: None,
+                cache: CacheSettings::default(),
+            },
+        ),
+    )
+    .async_try_into()
+    .await
+    .unwrap();
+
+    // The test runtime is single-threaded, so everything happens under the default subscriber of this thread.
+    let capture = capture::Capture::default();
+    let _guard = tracing::subscriber::set_default(capture.clone());
+    let query = |name| WarmUp::query(&Dname::from_str(name).unwrap(), Rtype::A);
+    router
+        .resolve_with_ctx(
+            query("example.com"),
+            Some(QueryContext::new(
+                "10.0.0.1:5353".parse().unwrap(),
+                Protocol::Udp,
+            )),
+        )
+        .await
+        .unwrap();
+    router.resolve(query("example.org")).await.unwrap();
+
+    let c = capture.0.lock().unwrap();
+    let spans: Vec<_> = c.spans.iter().filter(|(n, _)| n == "query").collect();
+    assert_eq!(spans.len(), 2);
+    let (_, first) = spans[0];
+    assert_eq!(first["qname"], "example.com");
+    assert_eq!(first["qtype"], "A");
+    assert_eq!(first["client"], "10.0.0.1");
+    let (_, second) = spans[1];
+    assert_eq!(second["qname"], "example.org");
+    assert!(!second.contains_key("client"));
+    // Query IDs are distinct
+    assert_ne!(first["id"], second["id"]);
+
+    // Every query goes through the rule, the upstream, and finishes, all within its own span.
+    for n in [0, 1] {
+        let span = c.spans.iter().position(|s| s == spans[n]).unwrap();
+        let events: Vec<_> = c
+            .events
+            .iter()
+            .filter(|(s, _)| *s == Some(span))
+            .map(|(_, f)| f)
+            .collect();
+        let messages: Vec<_> = events.iter().map(|f| f["message"].as_str()).collect();
+        assert_eq!(
+            messages,
+            ["upstream attempt", "rule finished", "routing finished"]
+        );
+        assert_eq!(events[0]["upstream"], "mock");
+        assert_eq!(events[0]["outcome"], "upstream");
+        assert!(events[0].contains_key("elapsed_us"));
+        assert_eq!(events[1]["rule"], "start");
+        assert_eq!(events[2]["rcode"], "NOERROR");
+        assert_eq!(events[2]["steps"], "1");
+        assert_eq!(events[2]["answered_by"], "Some(\"mock\")");
+    }
 }